@@ -0,0 +1,298 @@
+//! The access log: one record per completed WebSocket session, with who
+//! connected, for how long and how much they were sent. Records go to the
+//! log as `axact::access` events, or as JSON lines to a file that is rotated
+//! once it reaches `access_log.max_size_mb`.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+use serde::Serialize;
+
+use crate::{
+    config::AccessLogConfig,
+    ws::{CloseReason, SessionOptions},
+};
+
+/// A completed session.
+#[derive(Serialize, Debug)]
+pub struct SessionRecord<'a> {
+    /// The id its connect and disconnect log events carry.
+    pub conn: u64,
+    pub peer: SocketAddr,
+    pub endpoint: &'static str,
+    pub options: &'a SessionOptions,
+    pub connected_ms: u64,
+    pub disconnected_ms: u64,
+    /// Data messages sent, not counting pings and the close frame.
+    pub messages: u64,
+    /// The bytes of their text.
+    pub bytes: u64,
+    pub lagged: u64,
+    pub skipped: u64,
+    pub reason: CloseReason,
+}
+
+/// Where records go; cheap to clone. The default drops them.
+#[derive(Clone, Default)]
+pub struct AccessLog(Option<Arc<Sink>>);
+
+enum Sink {
+    Tracing,
+    File(Mutex<RotatingFile>),
+}
+
+impl AccessLog {
+    /// Opens the file `config` names, if it is enabled and names one.
+    pub fn open(config: &AccessLogConfig) -> Result<Self, String> {
+        if !config.enabled {
+            return Ok(Self(None));
+        }
+        let sink = match &config.path {
+            Some(path) => Sink::File(Mutex::new(
+                RotatingFile::open(
+                    path.clone(),
+                    Rotation {
+                        max_size: config.max_size_mb * 1024 * 1024,
+                        max_age: None,
+                        keep: config.keep,
+                        gzip: false,
+                    },
+                )
+                .map_err(|err| format!("cannot open {}: {err}", path.display()))?,
+            )),
+            None => Sink::Tracing,
+        };
+        Ok(Self(Some(Arc::new(sink))))
+    }
+
+    pub fn record(&self, record: &SessionRecord) {
+        let Some(sink) = &self.0 else { return };
+        let json = match serde_json::to_string(record) {
+            Ok(json) => json,
+            Err(err) => {
+                tracing::error!(conn = record.conn, %err, "cannot serialize access log record");
+                return;
+            }
+        };
+        match sink.as_ref() {
+            Sink::Tracing => tracing::info!(
+                target: "axact::access",
+                conn = record.conn,
+                peer = %record.peer,
+                endpoint = record.endpoint,
+                duration_ms = record.disconnected_ms.saturating_sub(record.connected_ms),
+                messages = record.messages,
+                bytes = record.bytes,
+                reason = ?record.reason,
+                record = %json,
+                "session"
+            ),
+            Sink::File(file) => {
+                let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+                if let Err(err) = file.write_line(&json) {
+                    tracing::error!(path = %file.path.display(), %err, "cannot write access log");
+                }
+            }
+        }
+    }
+}
+
+/// When a [`RotatingFile`] is rotated and what is kept.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_size: u64,
+    /// Also rotate once the file has been written to for this long since it
+    /// was opened or last rotated.
+    pub max_age: Option<Duration>,
+    pub keep: u32,
+    /// Compress rotated files, to `path.<n>.gz`.
+    pub gzip: bool,
+}
+
+/// A file appended to until a line would take it past `max_size`, when it
+/// is renamed to `path.1`, shifting older ones up to `path.<keep>`.
+pub struct RotatingFile {
+    pub path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    rotation: Rotation,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            rotation,
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        // A line longer than `max_size` still gets a file of its own.
+        let full = self.size + len > self.rotation.max_size;
+        let old = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| self.opened.elapsed() >= max_age);
+        if self.size > 0 && (full || old) {
+            self.rotate()?;
+        }
+        // One write, so that a full disk leaves at most one partial line.
+        self.file.write_all(format!("{line}\n").as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Flushes what was written to the disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..keep).rev() {
+                match fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            if self.rotation.gzip {
+                // Compressed once writing has moved on to a new file.
+                let plain = self.suffixed(".1");
+                fs::rename(&self.path, &plain)?;
+                self.reopen()?;
+                if let Err(err) = compress(&plain, &self.rotated(1)) {
+                    tracing::warn!(path = %plain.display(), %err, "cannot compress rotated file");
+                }
+                return Ok(());
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.reopen()
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = append(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        match self.rotation.gzip {
+            true => self.suffixed(&format!(".{n}.gz")),
+            false => self.suffixed(&format!(".{n}")),
+        }
+    }
+
+    fn suffixed(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        path.into()
+    }
+}
+
+/// Compresses `plain` to `gz` and removes it.
+fn compress(plain: &Path, gz: &Path) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(plain)?);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(gz)?), Compression::default());
+    io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    fs::remove_file(plain)
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest() {
+        let dir = std::env::temp_dir().join(format!("axact-access-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.jsonl");
+
+        let rotation = Rotation {
+            max_size: 8,
+            max_age: None,
+            keep: 2,
+            gzip: false,
+        };
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        for line in ["aaaa", "bbbb", "cccc", "dddd"] {
+            file.write_line(line).unwrap();
+        }
+        drop(file);
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+        assert_eq!(read("access.jsonl").as_deref(), Some("dddd\n"));
+        assert_eq!(read("access.jsonl.1").as_deref(), Some("cccc\n"));
+        assert_eq!(read("access.jsonl.2").as_deref(), Some("bbbb\n"));
+        assert_eq!(read("access.jsonl.3"), None);
+
+        // Picks up where it left off after a restart.
+        let mut file = RotatingFile::open(path, rotation).unwrap();
+        file.write_line("ee").unwrap();
+        assert_eq!(read("access.jsonl").as_deref(), Some("dddd\nee\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_age_into_gzipped_files() {
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("axact-metrics-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.jsonl");
+
+        let rotation = Rotation {
+            max_size: 1024,
+            max_age: Some(Duration::ZERO),
+            keep: 2,
+            gzip: true,
+        };
+        let mut file = RotatingFile::open(path, rotation).unwrap();
+        for line in ["aaaa", "bbbb", "cccc"] {
+            file.write_line(line).unwrap();
+        }
+        drop(file);
+
+        let gunzip = |name: &str| {
+            let mut text = String::new();
+            flate2::read::GzDecoder::new(File::open(dir.join(name)).ok()?)
+                .read_to_string(&mut text)
+                .ok()?;
+            Some(text)
+        };
+        assert_eq!(
+            fs::read_to_string(dir.join("metrics.jsonl")).unwrap(),
+            "cccc\n"
+        );
+        assert_eq!(gunzip("metrics.jsonl.1.gz").as_deref(), Some("bbbb\n"));
+        assert_eq!(gunzip("metrics.jsonl.2.gz").as_deref(), Some("aaaa\n"));
+        assert!(!dir.join("metrics.jsonl.1").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,946 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    extract::{
+        ws::{CloseFrame, Message, WebSocket},
+        ConnectInfo, FromRequestParts, Path, State,
+    },
+    http::{header, request::Parts, HeaderMap, Method, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+use crate::{
+    config::{Config, Role, SamplerConfig, SamplerConfigPatch},
+    server::{error_response, AppState},
+    share::{unix_secs, Shares},
+    stats::Stats,
+};
+
+/// The close code for a WebSocket client that did not authenticate, in the
+/// range left to applications, after HTTP's 401.
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+
+/// The bearer tokens clients may present, with their roles.
+pub struct Tokens(Vec<(Box<str>, Role)>);
+
+impl Tokens {
+    pub fn new(config: &Config) -> Self {
+        let admin_token = config
+            .admin_token
+            .clone()
+            .or_else(|| std::env::var("AXACT_ADMIN_TOKEN").ok());
+        let tokens = config
+            .tokens
+            .iter()
+            .map(|token| (token.token.as_str().into(), token.role))
+            .chain(admin_token.map(|token| (token.into(), Role::Admin)))
+            .collect();
+        Self(tokens)
+    }
+
+    /// The role of `presented`, if it is one of the tokens. Every token is
+    /// compared in constant time, so that timing does not tell how much of
+    /// one matched or which one it was.
+    pub fn role(&self, presented: &str) -> Option<Role> {
+        self.0.iter().fold(None, |role, (token, token_role)| {
+            if constant_time_eq(token.as_bytes(), presented.as_bytes()) {
+                role.max(Some(*token_role))
+            } else {
+                role
+            }
+        })
+    }
+
+    /// Whether reading metrics needs a token.
+    pub fn read_required(&self) -> bool {
+        self.0.iter().any(|(_, role)| *role == Role::Read)
+    }
+
+    pub fn admin_enabled(&self) -> bool {
+        self.0.iter().any(|(_, role)| *role == Role::Admin)
+    }
+
+    /// The role of `token`, `None` without one.
+    fn authenticate(
+        &self,
+        token: Option<&str>,
+    ) -> Result<Option<Role>, (StatusCode, &'static str)> {
+        let Some(token) = token else {
+            return Ok(None);
+        };
+        match self.role(token) {
+            Some(role) => Ok(Some(role)),
+            None => Err((StatusCode::UNAUTHORIZED, "missing or invalid bearer token")),
+        }
+    }
+
+    /// Checks that the request carries a read or admin token, whether or not
+    /// the read endpoints need one. There is none to carry while no token
+    /// is configured.
+    pub fn authorize_read(
+        &self,
+        headers: &HeaderMap,
+        disabled: &'static str,
+    ) -> Result<Role, (StatusCode, &'static str)> {
+        if self.0.is_empty() {
+            return Err((StatusCode::FORBIDDEN, disabled));
+        }
+        match self.authenticate(bearer(headers))? {
+            Some(role) => Ok(role),
+            None => Err((StatusCode::UNAUTHORIZED, "missing or invalid bearer token")),
+        }
+    }
+
+    /// Checks that the request carries an admin token: 401 without a valid
+    /// token, 403 with a read one.
+    pub fn authorize_admin(
+        &self,
+        headers: &HeaderMap,
+        disabled: &'static str,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        if !self.admin_enabled() {
+            return Err((StatusCode::FORBIDDEN, disabled));
+        }
+        match self.authenticate(bearer(headers))? {
+            Some(Role::Admin) => Ok(()),
+            Some(Role::Read) => Err((
+                StatusCode::FORBIDDEN,
+                "this needs an admin token, not a read one",
+            )),
+            None => Err((StatusCode::UNAUTHORIZED, "missing or invalid bearer token")),
+        }
+    }
+}
+
+/// Extractor that only succeeds for requests carrying an admin token.
+pub struct AdminAuth;
+
+/// Extractor that only succeeds for requests carrying a read or admin
+/// token, for read endpoints too sensitive to serve anonymously.
+pub struct ReadAuth;
+
+/// Extractor that only succeeds for requests carrying the ingest bearer
+/// token, which agents push to `/ingest` with.
+pub struct IngestAuth;
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let requests = &state.stats.requests;
+        match state.tokens.authorize_admin(
+            &parts.headers,
+            "admin API is disabled, set admin_token, AXACT_ADMIN_TOKEN or an admin token in tokens to enable it",
+        ) {
+            Ok(()) => requests.record(Some(Role::Admin)),
+            Err((status, err)) => {
+                match status {
+                    StatusCode::UNAUTHORIZED => requests.record_unauthorized(),
+                    _ => requests.record_forbidden(),
+                }
+                return Err(error_response(status, err));
+            }
+        }
+        Ok(AdminAuth)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ReadAuth {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let requests = &state.stats.requests;
+        match state.tokens.authorize_read(
+            &parts.headers,
+            "this needs a token, add a read or admin token to tokens to enable it",
+        ) {
+            Ok(role) => requests.record(Some(role)),
+            Err((status, err)) => {
+                match status {
+                    StatusCode::UNAUTHORIZED => requests.record_unauthorized(),
+                    _ => requests.record_forbidden(),
+                }
+                return Err(error_response(status, err));
+            }
+        }
+        Ok(ReadAuth)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for IngestAuth {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        check_bearer(
+            &parts.headers,
+            state.ingest_token.as_deref(),
+            "ingest is disabled, set ingest_token or AXACT_INGEST_TOKEN to enable it",
+        )
+        .map_err(|(status, err)| error_response(status, err))?;
+        Ok(IngestAuth)
+    }
+}
+
+/// What [`require_read`] checks requests against.
+#[derive(Clone)]
+pub struct ReadGate {
+    pub tokens: Arc<Tokens>,
+    pub shares: Arc<Shares>,
+    pub stats: Arc<Stats>,
+    /// How long a WebSocket client has to send its token, see
+    /// [`FirstMessageAuth`].
+    pub first_message_timeout: Duration,
+}
+
+impl From<&AppState> for ReadGate {
+    fn from(state: &AppState) -> Self {
+        Self {
+            tokens: state.tokens.clone(),
+            shares: state.shares.clone(),
+            stats: state.stats.clone(),
+            first_message_timeout: state.websocket.pong_timeout(),
+        }
+    }
+}
+
+/// Lets requests to the read endpoints through once they carry a read or
+/// admin token or a valid share signature, or without either while no read
+/// token is configured. A token or signature that is not valid is refused
+/// either way.
+///
+/// The token is taken from the `token` query parameter as well, for clients
+/// that cannot set headers, such as browsers opening a WebSocket or an
+/// `EventSource`. A WebSocket client may also connect without one and send
+/// it as its first message, see [`FirstMessageAuth`]; any other request,
+/// or a handshake to an endpoint that is not a WebSocket, is refused.
+pub async fn require_read<B>(
+    State(gate): State<ReadGate>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let requests = &gate.stats.requests;
+    let query_token = query_token(request.uri());
+    let token = bearer(request.headers()).or(query_token.as_deref());
+    match gate.tokens.authenticate(token) {
+        Ok(None) => match gate.shares.check(request.uri(), unix_secs()) {
+            Some(Ok(())) => {
+                requests.record_shared();
+                next.run(request).await
+            }
+            Some(Err(err)) => {
+                requests.record_unauthorized();
+                error_response(StatusCode::UNAUTHORIZED, err)
+            }
+            None if gate.tokens.read_required() && is_websocket_handshake(&request) => {
+                // Recorded once the client has sent its token.
+                let auth = FirstMessageAuth {
+                    tokens: gate.tokens.clone(),
+                    stats: gate.stats.clone(),
+                    timeout: gate.first_message_timeout,
+                    claimed: Arc::default(),
+                };
+                let claimed = auth.claimed.clone();
+                request.extensions_mut().insert(auth);
+                let response = next.run(request).await;
+                // Only an endpoint that takes a `ws::Upgrade` checks the
+                // first message; anything else would have been served
+                // without a token.
+                if response.status() != StatusCode::SWITCHING_PROTOCOLS
+                    || !claimed.load(Ordering::Relaxed)
+                {
+                    requests.record_unauthorized();
+                    return error_response(
+                        StatusCode::UNAUTHORIZED,
+                        "missing or invalid bearer token",
+                    );
+                }
+                response
+            }
+            None if gate.tokens.read_required() => {
+                requests.record_unauthorized();
+                error_response(StatusCode::UNAUTHORIZED, "missing or invalid bearer token")
+            }
+            None => {
+                requests.record(None);
+                next.run(request).await
+            }
+        },
+        Ok(role) => {
+            requests.record(role);
+            next.run(request).await
+        }
+        Err((status, err)) => {
+            requests.record_unauthorized();
+            error_response(status, err)
+        }
+    }
+}
+
+/// Set on a WebSocket upgrade to a read endpoint that carries no token while
+/// one is needed. Once upgraded, the client has to send `{"token":"..."}` as
+/// its first message, within the pong timeout; otherwise it is closed with
+/// code 4401.
+#[derive(Clone)]
+pub struct FirstMessageAuth {
+    tokens: Arc<Tokens>,
+    stats: Arc<Stats>,
+    timeout: Duration,
+    /// Set once an endpoint takes it, see [`Self::claim`].
+    claimed: Arc<AtomicBool>,
+}
+
+#[derive(Deserialize)]
+struct TokenMessage {
+    token: String,
+}
+
+impl FirstMessageAuth {
+    /// Tells [`require_read`] that the endpoint will check the first
+    /// message, so that its response may go out.
+    pub fn claim(&self) {
+        self.claimed.store(true, Ordering::Relaxed);
+    }
+
+    /// Reads the client's token from `ws`, closing it unless the token is a
+    /// read or admin one.
+    pub async fn authenticate(&self, ws: &mut WebSocket) -> bool {
+        let requests = &self.stats.requests;
+        let token = tokio::time::timeout(self.timeout, async {
+            loop {
+                match ws.recv().await? {
+                    Ok(Message::Text(text)) => {
+                        return serde_json::from_str::<TokenMessage>(&text).ok();
+                    }
+                    Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                    Ok(_) | Err(_) => return None,
+                }
+            }
+        })
+        .await;
+        if let Some(role) = token
+            .ok()
+            .flatten()
+            .and_then(|msg| self.tokens.role(&msg.token))
+        {
+            requests.record(Some(role));
+            return true;
+        }
+        requests.record_unauthorized();
+        let close = Message::Close(Some(CloseFrame {
+            code: CLOSE_UNAUTHORIZED,
+            reason: "missing or invalid bearer token".into(),
+        }));
+        let _ = tokio::time::timeout(self.timeout, ws.send(close)).await;
+        false
+    }
+}
+
+/// Whether `request` opens a WebSocket, as the `ws::Upgrade` extractor
+/// would accept it.
+fn is_websocket_handshake<B>(request: &Request<B>) -> bool {
+    let headers = request.headers();
+    let header_has = |name, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    request.method() == Method::GET
+        && header_has(header::CONNECTION, "upgrade")
+        && header_has(header::UPGRADE, "websocket")
+        && header_has(header::SEC_WEBSOCKET_VERSION, "13")
+        && headers.contains_key(header::SEC_WEBSOCKET_KEY)
+}
+
+#[derive(Deserialize)]
+struct TokenParam {
+    token: Option<String>,
+}
+
+/// The value of the `token` query parameter, decoded as `Query` decodes the
+/// others, so that tokens with `+`, `/` or `=` can be given encoded.
+fn query_token(uri: &Uri) -> Option<String> {
+    serde_urlencoded::from_str::<TokenParam>(uri.query()?)
+        .ok()?
+        .token
+}
+
+/// `uri` with the value of its `token` query parameter blanked out, for
+/// logging.
+pub fn redact_token(uri: &Uri) -> String {
+    let Some(query) = uri.query().filter(|_| query_token(uri).is_some()) else {
+        return uri.to_string();
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| match pair.starts_with("token=") {
+            true => "token=redacted",
+            false => pair,
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+/// Checks the request's bearer token against `expected`; without one, the
+/// endpoint is `disabled`.
+pub fn check_bearer(
+    headers: &HeaderMap,
+    expected: Option<&str>,
+    disabled: &'static str,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Some(expected) = expected else {
+        return Err((StatusCode::FORBIDDEN, disabled));
+    };
+    match bearer(headers) {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, "missing or invalid bearer token")),
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Compares every byte whatever the first difference, so only the length
+/// can be told from how long it takes.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[axum::debug_handler]
+pub async fn config_get(_: AdminAuth, State(state): State<AppState>) -> Json<SamplerConfig> {
+    Json(state.sampler_config.borrow().clone())
+}
+
+#[axum::debug_handler]
+pub async fn config_patch(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Json(patch): Json<SamplerConfigPatch>,
+) -> Response {
+    let config = match state.sampler_config.borrow().patched(patch) {
+        Ok(config) => config,
+        Err(err) => return error_response(StatusCode::UNPROCESSABLE_ENTITY, err),
+    };
+    tracing::info!(?config, "sampler config updated through the admin API");
+    state.sampler_config.send_replace(config.clone());
+    Json(config).into_response()
+}
+
+/// The signals `POST /processes/:pid/signal` can send.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SignalName {
+    Term,
+    Kill,
+    Hup,
+    Stop,
+    Cont,
+}
+
+impl From<SignalName> for sysinfo::Signal {
+    fn from(signal: SignalName) -> Self {
+        match signal {
+            SignalName::Term => sysinfo::Signal::Term,
+            SignalName::Kill => sysinfo::Signal::Kill,
+            SignalName::Hup => sysinfo::Signal::Hangup,
+            SignalName::Stop => sysinfo::Signal::Stop,
+            SignalName::Cont => sysinfo::Signal::Continue,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SignalRequest {
+    signal: SignalName,
+    /// Refuse unless the process still has this name, in case its pid was
+    /// reused since the client looked.
+    expected_name: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SignalResult {
+    pid: u32,
+    signal: SignalName,
+    success: bool,
+    /// The process name when it was found.
+    name: Option<String>,
+    error: Option<String>,
+    /// The OS error number when sending the signal failed.
+    errno: Option<i32>,
+}
+
+/// Sends a signal to a process. Needs the admin token and
+/// `--enable-process-control`; every request is logged.
+#[axum::debug_handler]
+pub async fn process_signal_post(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(pid): Path<u32>,
+    Json(request): Json<SignalRequest>,
+) -> Response {
+    let (status, result) = if state.process_control {
+        let expected_name = request.expected_name.clone();
+        tokio::task::spawn_blocking(move || send_signal(pid, request.signal, expected_name))
+            .await
+            .unwrap_or_else(|err| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    SignalResult::failed(pid, request.signal, err.to_string()),
+                )
+            })
+    } else {
+        (
+            StatusCode::FORBIDDEN,
+            SignalResult::failed(
+                pid,
+                request.signal,
+                "process control is disabled, start with --enable-process-control".into(),
+            ),
+        )
+    };
+    tracing::warn!(
+        %peer,
+        pid,
+        signal = ?request.signal,
+        expected_name = ?request.expected_name,
+        name = ?result.name,
+        success = result.success,
+        error = ?result.error,
+        "process signal requested through the admin API"
+    );
+    (status, Json(result)).into_response()
+}
+
+/// Why a process cannot be acted on.
+struct Refusal {
+    status: StatusCode,
+    error: String,
+    /// The process name, if it was found.
+    name: Option<String>,
+}
+
+/// Looks up `pid` in `sys` and checks that it is still called
+/// `expected_name`, if given.
+fn find_process<'a>(
+    sys: &'a mut System,
+    pid: u32,
+    expected_name: Option<&str>,
+) -> Result<&'a sysinfo::Process, Refusal> {
+    let target = Pid::from_u32(pid);
+    let refresh = sys.refresh_process_specifics(target, ProcessRefreshKind::new());
+    let process = sys.process(target).filter(|_| refresh).ok_or(Refusal {
+        status: StatusCode::NOT_FOUND,
+        error: "no such process".into(),
+        name: None,
+    })?;
+    match expected_name {
+        Some(expected) if expected != process.name() => Err(Refusal {
+            status: StatusCode::CONFLICT,
+            error: format!("process is {:?}, not {expected:?}", process.name()),
+            name: Some(process.name().to_string()),
+        }),
+        _ => Ok(process),
+    }
+}
+
+/// Blocks.
+fn send_signal(
+    pid: u32,
+    signal: SignalName,
+    expected_name: Option<String>,
+) -> (StatusCode, SignalResult) {
+    let mut sys = System::new();
+    let process = match find_process(&mut sys, pid, expected_name.as_deref()) {
+        Ok(process) => process,
+        Err(refusal) => {
+            let mut result = SignalResult::failed(pid, signal, refusal.error);
+            result.name = refusal.name;
+            return (refusal.status, result);
+        }
+    };
+    let name = process.name().to_string();
+
+    let (status, mut result) = match process.kill_with(signal.into()) {
+        Some(true) => (
+            StatusCode::OK,
+            SignalResult {
+                pid,
+                signal,
+                success: true,
+                name: None,
+                error: None,
+                errno: None,
+            },
+        ),
+        Some(false) => {
+            let err = std::io::Error::last_os_error();
+            let status = match err.kind() {
+                std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let mut result = SignalResult::failed(pid, signal, err.to_string());
+            result.errno = err.raw_os_error();
+            (status, result)
+        }
+        None => (
+            StatusCode::NOT_IMPLEMENTED,
+            SignalResult::failed(pid, signal, "signal not supported on this platform".into()),
+        ),
+    };
+    result.name = Some(name);
+    (status, result)
+}
+
+impl SignalResult {
+    fn failed(pid: u32, signal: SignalName, error: String) -> Self {
+        Self {
+            pid,
+            signal,
+            success: false,
+            name: None,
+            error: Some(error),
+            errno: None,
+        }
+    }
+}
+
+/// The nice values `POST /processes/:pid/priority` accepts.
+const NICE_RANGE: std::ops::RangeInclusive<i32> = -20..=19;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PriorityRequest {
+    nice: i32,
+    /// As for signals, refuse unless the process still has this name.
+    expected_name: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PriorityResult {
+    pid: u32,
+    success: bool,
+    name: Option<String>,
+    /// The nice value before the change.
+    previous: Option<i32>,
+    /// The nice value after it, the previous one if it failed.
+    nice: Option<i32>,
+    error: Option<String>,
+    errno: Option<i32>,
+}
+
+/// Changes the nice value of a process. Needs the same token and opt-in as
+/// signals, and is logged the same way.
+#[axum::debug_handler]
+pub async fn process_priority_post(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(pid): Path<u32>,
+    Json(request): Json<PriorityRequest>,
+) -> Response {
+    let (status, result) = if !state.process_control {
+        (
+            StatusCode::FORBIDDEN,
+            PriorityResult::failed(
+                pid,
+                "process control is disabled, start with --enable-process-control".into(),
+            ),
+        )
+    } else if !NICE_RANGE.contains(&request.nice) {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            PriorityResult::failed(
+                pid,
+                format!(
+                    "nice must be between {} and {}",
+                    NICE_RANGE.start(),
+                    NICE_RANGE.end()
+                ),
+            ),
+        )
+    } else {
+        let expected_name = request.expected_name.clone();
+        tokio::task::spawn_blocking(move || set_priority(pid, request.nice, expected_name))
+            .await
+            .unwrap_or_else(|err| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    PriorityResult::failed(pid, err.to_string()),
+                )
+            })
+    };
+    tracing::warn!(
+        %peer,
+        pid,
+        nice = request.nice,
+        expected_name = ?request.expected_name,
+        name = ?result.name,
+        previous = ?result.previous,
+        success = result.success,
+        error = ?result.error,
+        "process priority change requested through the admin API"
+    );
+    (status, Json(result)).into_response()
+}
+
+/// Blocks.
+fn set_priority(
+    pid: u32,
+    nice: i32,
+    expected_name: Option<String>,
+) -> (StatusCode, PriorityResult) {
+    let mut sys = System::new();
+    let name = match find_process(&mut sys, pid, expected_name.as_deref()) {
+        Ok(process) => process.name().to_string(),
+        Err(refusal) => {
+            let mut result = PriorityResult::failed(pid, refusal.error);
+            result.name = refusal.name;
+            return (refusal.status, result);
+        }
+    };
+    let mut result = PriorityResult::failed(pid, String::new());
+    result.name = Some(name);
+
+    #[cfg(unix)]
+    {
+        let failed = |mut result: PriorityResult, err: std::io::Error| {
+            let status = match err.kind() {
+                std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            result.errno = err.raw_os_error();
+            result.error = Some(err.to_string());
+            (status, result)
+        };
+        let previous = match priority::get(pid) {
+            Ok(previous) => previous,
+            Err(err) => return failed(result, err),
+        };
+        result.previous = Some(previous);
+        result.nice = Some(previous);
+        if let Err(err) = priority::set(pid, nice) {
+            return failed(result, err);
+        }
+        result.nice = Some(nice);
+        result.success = true;
+        result.error = None;
+        (StatusCode::OK, result)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = nice;
+        result.error = Some("changing priorities is only supported on Unix".into());
+        (StatusCode::NOT_IMPLEMENTED, result)
+    }
+}
+
+impl PriorityResult {
+    fn failed(pid: u32, error: String) -> Self {
+        Self {
+            pid,
+            success: false,
+            name: None,
+            previous: None,
+            nice: None,
+            error: Some(error),
+            errno: None,
+        }
+    }
+}
+
+/// `getpriority`/`setpriority` for a single process.
+#[cfg(unix)]
+mod priority {
+    use std::io;
+
+    pub fn get(pid: u32) -> io::Result<i32> {
+        // -1 is a valid nice value, so only errno tells a failure apart.
+        clear_errno();
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid as libc::id_t) };
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(errno) if nice == -1 && errno != 0 => Err(err),
+            _ => Ok(nice),
+        }
+    }
+
+    pub fn set(pid: u32, nice: i32) -> io::Result<()> {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, pid as libc::id_t, nice) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn clear_errno() {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        unsafe {
+            *libc::__errno_location() = 0
+        };
+        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+        unsafe {
+            *libc::__error() = 0
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::config::ApiToken;
+
+    fn tokens() -> Tokens {
+        Tokens::new(&Config {
+            admin_token: Some("root".into()),
+            tokens: vec![
+                ApiToken {
+                    token: "dashboard".into(),
+                    role: Role::Read,
+                },
+                ApiToken {
+                    token: "ops".into(),
+                    role: Role::Admin,
+                },
+            ],
+            ..Config::default()
+        })
+    }
+
+    fn headers(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {token}")).unwrap();
+        headers.insert(header::AUTHORIZATION, value);
+        headers
+    }
+
+    #[test]
+    fn tokens_resolve_to_their_role() {
+        let tokens = tokens();
+        assert_eq!(tokens.role("dashboard"), Some(Role::Read));
+        assert_eq!(tokens.role("ops"), Some(Role::Admin));
+        assert_eq!(tokens.role("root"), Some(Role::Admin));
+        assert_eq!(tokens.role("dash"), None);
+        assert_eq!(tokens.role("dashboard2"), None);
+        assert_eq!(tokens.role(""), None);
+        assert!(tokens.read_required());
+    }
+
+    #[test]
+    fn read_tokens_are_forbidden_from_the_admin_api() {
+        let tokens = tokens();
+        let disabled = "disabled";
+        assert_eq!(tokens.authorize_admin(&headers("ops"), disabled), Ok(()));
+        assert_eq!(
+            tokens
+                .authorize_admin(&headers("dashboard"), disabled)
+                .unwrap_err()
+                .0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            tokens
+                .authorize_admin(&headers("guess"), disabled)
+                .unwrap_err()
+                .0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            tokens
+                .authorize_admin(&HeaderMap::new(), disabled)
+                .unwrap_err()
+                .0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn tokens_in_the_query_are_found_and_redacted() {
+        let uri: Uri = "/realtime/ram?v=2&token=dashboard&round=1".parse().unwrap();
+        assert_eq!(query_token(&uri).as_deref(), Some("dashboard"));
+        assert_eq!(
+            redact_token(&uri),
+            "/realtime/ram?v=2&token=redacted&round=1"
+        );
+
+        let uri: Uri = "/realtime/ram?token=c2VjcmV0%2B%2F%3D%3D".parse().unwrap();
+        assert_eq!(query_token(&uri).as_deref(), Some("c2VjcmV0+/=="));
+        assert_eq!(redact_token(&uri), "/realtime/ram?token=redacted");
+
+        let uri: Uri = "/realtime/ram?v=2&not_token=1".parse().unwrap();
+        assert_eq!(query_token(&uri), None);
+        assert_eq!(redact_token(&uri), "/realtime/ram?v=2&not_token=1");
+    }
+
+    /// Serves a REST route and a WebSocket one behind [`require_read`],
+    /// with a read token required, and sends each a WebSocket handshake
+    /// without a token.
+    #[tokio::test]
+    async fn handshakes_to_rest_endpoints_need_a_token() {
+        use axum::{middleware, routing::get, Router};
+
+        let gate = ReadGate {
+            tokens: Arc::new(tokens()),
+            shares: Arc::new(Shares::new(&crate::config::ShareConfig::default())),
+            stats: Arc::new(Stats::new()),
+            first_message_timeout: Duration::from_secs(1),
+        };
+        let app = Router::new()
+            .route("/stats", get(|| async { "secret" }))
+            .route(
+                "/realtime/ram",
+                get(|ws: crate::ws::Upgrade| async move { ws.on_upgrade(|_| async {}) }),
+            )
+            .route_layer(middleware::from_fn_with_state(gate, require_read));
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let handshake = |path: &str| {
+            Request::get(format!("http://{addr}{path}"))
+                .header(header::CONNECTION, "Upgrade")
+                .header(header::UPGRADE, "websocket")
+                .header(header::SEC_WEBSOCKET_VERSION, "13")
+                .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        let client = hyper::Client::new();
+        let status = |request| async { client.request(request).await.unwrap().status() };
+        assert_eq!(status(handshake("/stats")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(handshake("/realtime/ram")).await,
+            StatusCode::SWITCHING_PROTOCOLS
+        );
+        let upgrade_only = Request::get(format!("http://{addr}/stats"))
+            .header(header::UPGRADE, "websocket")
+            .body(hyper::Body::empty())
+            .unwrap();
+        assert_eq!(status(upgrade_only).await, StatusCode::UNAUTHORIZED);
+    }
+}
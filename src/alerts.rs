@@ -0,0 +1,880 @@
+//! Alert rules, evaluated against the samples the streams broadcast.
+
+use std::{
+    future, io,
+    process::{ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hyper::{
+    client::HttpConnector,
+    header::{CONTENT_TYPE, USER_AGENT},
+    Body, Client, Request, StatusCode, Uri,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+    time,
+};
+
+use crate::{
+    config::Stream,
+    sampler::Publisher,
+    smart::SmartReport,
+    stats::Stats,
+    types::{CpuState, MemState, Payload, ProcessInfo, Sample},
+    ws::{Frame, Protocol},
+};
+
+/// Fires once `metric op threshold` has held for `for_ms`, and resolves once
+/// it no longer holds for `clear_threshold` (by default the threshold).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: Metric,
+    pub op: Comparison,
+    pub threshold: f64,
+    /// How long the condition has to hold before the rule fires.
+    #[serde(default)]
+    pub for_ms: u64,
+    /// Where a firing rule resolves, to keep a reading hovering around the
+    /// threshold from flapping. Has to be on the near side of the
+    /// threshold, e.g. 80 for `> 85`.
+    #[serde(default)]
+    pub clear_threshold: Option<f64>,
+    /// How long after resolving the rule cannot fire again.
+    #[serde(default)]
+    pub cooldown_ms: u64,
+    #[serde(default)]
+    pub severity: Severity,
+    /// For `process.*` metrics, only look at processes whose name contains
+    /// this. Only the top processes the stream reports are seen.
+    #[serde(default)]
+    pub process: Option<String>,
+    /// For `smart.*` metrics, only look at disks whose device name contains
+    /// this, e.g. `nvme0`.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// A program and its arguments to run whenever the rule fires or
+    /// resolves, e.g. `["/usr/local/bin/notify", "--urgent"]`. It is not run
+    /// through a shell. It gets the event in `AXACT_ALERT_*` environment
+    /// variables and as JSON on stdin. A run that is still going when the
+    /// next event comes skips that event.
+    #[serde(default)]
+    pub exec: Option<Vec<String>>,
+    /// How long a run of `exec` may take before it is killed.
+    #[serde(default = "default_hook_timeout_ms")]
+    pub exec_timeout_ms: u64,
+    /// A URL to POST the event to as JSON whenever the rule fires or
+    /// resolves, e.g. that of a chat webhook. `https` URLs need the `tls`
+    /// feature. Failed requests are logged and not retried.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// How long a webhook request may take before it is given up.
+    #[serde(default = "default_hook_timeout_ms")]
+    pub webhook_timeout_ms: u64,
+}
+
+fn default_hook_timeout_ms() -> u64 {
+    10_000
+}
+
+/// A value read from one of the streams, named by its path in the config.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Metric {
+    /// Average usage over all cores, in percent.
+    CpuUsage,
+    CpuMaxCoreUsage,
+    /// Package temperature in °C.
+    CpuTemp,
+    CpuMaxCoreTemp,
+    /// Bytes.
+    MemUsed,
+    MemUsedPercent,
+    /// The highest among the matching processes, in percent of one CPU.
+    ProcessCpuUsage,
+    /// The highest among the matching processes, in bytes.
+    ProcessMemory,
+    /// 1 when any of the matching disks fails its SMART health check, 0
+    /// when all pass.
+    SmartFailed,
+    /// The highest among the matching disks, as are the other `smart.*`
+    /// metrics.
+    SmartReallocatedSectors,
+    SmartPowerOnHours,
+    /// NVMe wear, in percent.
+    SmartPercentageUsed,
+    SmartMediaErrors,
+}
+
+const METRICS: [Metric; 13] = [
+    Metric::CpuUsage,
+    Metric::CpuMaxCoreUsage,
+    Metric::CpuTemp,
+    Metric::CpuMaxCoreTemp,
+    Metric::MemUsed,
+    Metric::MemUsedPercent,
+    Metric::ProcessCpuUsage,
+    Metric::ProcessMemory,
+    Metric::SmartFailed,
+    Metric::SmartReallocatedSectors,
+    Metric::SmartPowerOnHours,
+    Metric::SmartPercentageUsed,
+    Metric::SmartMediaErrors,
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = ">=")]
+    AtLeast,
+    #[serde(rename = "<")]
+    Below,
+    #[serde(rename = "<=")]
+    AtMost,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+/// A rule starting or stopping to fire, as sent on `/realtime/alerts`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub severity: Severity,
+    pub state: AlertState,
+    /// The reading that caused the change.
+    pub value: f64,
+    /// When the condition started to hold, for a firing rule, or stopped
+    /// holding, for a resolved one. Milliseconds since the Unix epoch.
+    pub since: u64,
+}
+
+impl Payload for AlertEvent {}
+
+impl Metric {
+    fn path(self) -> &'static str {
+        match self {
+            Metric::CpuUsage => "cpu.usage",
+            Metric::CpuMaxCoreUsage => "cpu.max_core_usage",
+            Metric::CpuTemp => "cpu.temp",
+            Metric::CpuMaxCoreTemp => "cpu.max_core_temp",
+            Metric::MemUsed => "mem.used",
+            Metric::MemUsedPercent => "mem.used_percent",
+            Metric::ProcessCpuUsage => "process.cpu_usage",
+            Metric::ProcessMemory => "process.memory",
+            Metric::SmartFailed => "smart.failed",
+            Metric::SmartReallocatedSectors => "smart.reallocated_sectors",
+            Metric::SmartPowerOnHours => "smart.power_on_hours",
+            Metric::SmartPercentageUsed => "smart.percentage_used",
+            Metric::SmartMediaErrors => "smart.media_errors",
+        }
+    }
+
+    /// The stream the metric is read from, `None` for the SMART readings,
+    /// which are not streamed.
+    pub fn stream(self) -> Option<Stream> {
+        match self {
+            Metric::CpuUsage
+            | Metric::CpuMaxCoreUsage
+            | Metric::CpuTemp
+            | Metric::CpuMaxCoreTemp => Some(Stream::Cpus),
+            Metric::MemUsed | Metric::MemUsedPercent => Some(Stream::Ram),
+            Metric::ProcessCpuUsage | Metric::ProcessMemory => Some(Stream::Processes),
+            Metric::SmartFailed
+            | Metric::SmartReallocatedSectors
+            | Metric::SmartPowerOnHours
+            | Metric::SmartPercentageUsed
+            | Metric::SmartMediaErrors => None,
+        }
+    }
+
+    /// The cargo feature the metric needs, if it was compiled out.
+    fn missing_feature(self) -> Option<&'static str> {
+        match self {
+            Metric::CpuUsage | Metric::CpuMaxCoreUsage if !cfg!(feature = "cpu") => Some("cpu"),
+            Metric::CpuTemp if !cfg!(feature = "temps") => Some("temps"),
+            Metric::CpuMaxCoreTemp if !cfg!(feature = "core_temp") => Some("core_temp"),
+            Metric::MemUsed | Metric::MemUsedPercent if !cfg!(feature = "mem") => Some("mem"),
+            Metric::ProcessCpuUsage | Metric::ProcessMemory if !cfg!(feature = "processes") => {
+                Some("processes")
+            }
+            _ if self.stream().is_none() && !cfg!(feature = "smart") => Some("smart"),
+            _ => None,
+        }
+    }
+
+    /// Reads the metric, `None` if the sample does not have it, e.g. because
+    /// there is no such sensor. `filter` is the rule's `process` or `device`.
+    fn read(self, data: &Data, filter: Option<&str>) -> Option<f64> {
+        match (self, data) {
+            (Metric::CpuUsage, Data::Cpus(cpus)) => {
+                let total: f32 = cpus.cores.iter().map(|core| core.usage).sum();
+                (!cpus.cores.is_empty()).then(|| f64::from(total) / cpus.cores.len() as f64)
+            }
+            (Metric::CpuMaxCoreUsage, Data::Cpus(cpus)) => cpus
+                .cores
+                .iter()
+                .map(|core| core.usage)
+                .reduce(f32::max)
+                .map(f64::from),
+            (Metric::CpuTemp, Data::Cpus(cpus)) => cpus.temp.map(f64::from),
+            (Metric::CpuMaxCoreTemp, Data::Cpus(cpus)) => cpus
+                .cores
+                .iter()
+                .filter_map(|core| core.temp)
+                .reduce(f32::max)
+                .map(f64::from),
+            (Metric::MemUsed, Data::Ram(ram)) => Some(ram.used as f64),
+            (Metric::MemUsedPercent, Data::Ram(ram)) => {
+                (ram.total > 0).then(|| ram.used as f64 * 100. / ram.total as f64)
+            }
+            (Metric::ProcessCpuUsage | Metric::ProcessMemory, Data::Processes(processes)) => {
+                processes
+                    .iter()
+                    .filter(|info| filter.is_none_or(|name| info.name.contains(name)))
+                    .map(|info| match self {
+                        Metric::ProcessCpuUsage => f64::from(info.cpu_usage),
+                        _ => info.memory as f64,
+                    })
+                    .reduce(f64::max)
+            }
+            (Metric::SmartFailed, Data::Smart(report)) => report
+                .available(filter)
+                .map(|(_, values)| if values.passed { 0. } else { 1. })
+                .reduce(f64::max),
+            (_, Data::Smart(report)) => report
+                .available(filter)
+                .filter_map(|(_, values)| match self {
+                    Metric::SmartReallocatedSectors => values.reallocated_sectors,
+                    Metric::SmartPowerOnHours => values.power_on_hours,
+                    Metric::SmartPercentageUsed => values.percentage_used.map(u64::from),
+                    _ => values.media_errors,
+                })
+                .map(|value| value as f64)
+                .reduce(f64::max),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<String> for Metric {
+    type Error = String;
+
+    fn try_from(path: String) -> Result<Self, String> {
+        METRICS
+            .into_iter()
+            .find(|metric| metric.path() == path)
+            .ok_or_else(|| {
+                let known: Vec<&str> = METRICS.iter().map(|metric| metric.path()).collect();
+                format!(
+                    "unknown metric {path:?}, expected one of {}",
+                    known.join(", ")
+                )
+            })
+    }
+}
+
+impl From<Metric> for String {
+    fn from(metric: Metric) -> Self {
+        metric.path().into()
+    }
+}
+
+impl Comparison {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Above => value > threshold,
+            Comparison::AtLeast => value >= threshold,
+            Comparison::Below => value < threshold,
+            Comparison::AtMost => value <= threshold,
+        }
+    }
+}
+
+impl AlertRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("alert rules need a name".into());
+        }
+        if let Some(feature) = self.metric.missing_feature() {
+            return Err(format!(
+                "alert {:?}: {} needs the '{feature}' feature, which was compiled out",
+                self.name,
+                self.metric.path()
+            ));
+        }
+        if !self.threshold.is_finite() {
+            return Err(format!("alert {:?}: threshold must be a number", self.name));
+        }
+        if let Some(clear) = self.clear_threshold {
+            let near_side = match self.op {
+                Comparison::Above | Comparison::AtLeast => clear <= self.threshold,
+                Comparison::Below | Comparison::AtMost => clear >= self.threshold,
+            };
+            if !clear.is_finite() || !near_side {
+                return Err(format!(
+                    "alert {:?}: clear_threshold must not be past the threshold",
+                    self.name
+                ));
+            }
+        }
+        if self.exec.as_ref().is_some_and(|exec| exec.is_empty()) {
+            return Err(format!(
+                "alert {:?}: exec needs a program to run",
+                self.name
+            ));
+        }
+        if self.exec_timeout_ms == 0 {
+            return Err(format!(
+                "alert {:?}: exec_timeout_ms must be greater than 0",
+                self.name
+            ));
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook_uri(webhook).map_err(|err| format!("alert {:?}: webhook {err}", self.name))?;
+        }
+        if self.webhook_timeout_ms == 0 {
+            return Err(format!(
+                "alert {:?}: webhook_timeout_ms must be greater than 0",
+                self.name
+            ));
+        }
+        if self.process.is_some() && self.metric.stream() != Some(Stream::Processes) {
+            return Err(format!(
+                "alert {:?}: process only applies to process.* metrics",
+                self.name
+            ));
+        }
+        if self.device.is_some() && self.metric.stream().is_some() {
+            return Err(format!(
+                "alert {:?}: device only applies to smart.* metrics",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parses the URL of a webhook, which has to be one this build can send to.
+fn webhook_uri(url: &str) -> Result<Uri, String> {
+    let uri: Uri = url.parse().map_err(|err| format!("is not a URL: {err}"))?;
+    match uri.scheme_str() {
+        Some("http") => Ok(uri),
+        #[cfg(feature = "tls")]
+        Some("https") => Ok(uri),
+        #[cfg(not(feature = "tls"))]
+        Some("https") => Err("needs the 'tls' feature for https, which was compiled out".into()),
+        _ => Err("must be an http or https URL".into()),
+    }
+}
+
+/// A decoded sample of one of the streams.
+enum Data {
+    Cpus(CpuState),
+    Ram(MemState),
+    Processes(Vec<ProcessInfo>),
+    Smart(SmartReport),
+}
+
+/// Where a rule is in its cycle.
+#[derive(Debug, Default)]
+struct RuleState {
+    /// When the condition started to hold, while it has not fired yet.
+    pending_since: Option<u64>,
+    firing: bool,
+    /// When the rule last resolved, for the cooldown.
+    resolved_at: Option<u64>,
+    /// Set while the rule's `exec` hook runs.
+    hook_running: Arc<AtomicBool>,
+}
+
+impl RuleState {
+    /// Moves on with a reading taken at `now`, returning the event if the
+    /// rule started or stopped firing. Missing readings change nothing.
+    fn update(&mut self, rule: &AlertRule, value: Option<f64>, now: u64) -> Option<AlertEvent> {
+        let value = value?;
+        let holds = rule.op.holds(value, rule.threshold);
+        let event = |state, since| AlertEvent {
+            rule: rule.name.clone(),
+            severity: rule.severity,
+            state,
+            value,
+            since,
+        };
+
+        if self.firing {
+            let clear = rule.clear_threshold.unwrap_or(rule.threshold);
+            if rule.op.holds(value, clear) {
+                return None;
+            }
+            self.firing = false;
+            self.resolved_at = Some(now);
+            return Some(event(AlertState::Resolved, now));
+        }
+        if !holds {
+            self.pending_since = None;
+            return None;
+        }
+        let since = *self.pending_since.get_or_insert(now);
+        let cooling_down = self
+            .resolved_at
+            .is_some_and(|at| now.saturating_sub(at) < rule.cooldown_ms);
+        if now.saturating_sub(since) < rule.for_ms || cooling_down {
+            return None;
+        }
+        self.pending_since = None;
+        self.firing = true;
+        Some(event(AlertState::Firing, since))
+    }
+}
+
+/// Evaluates `rules` against every sample of the streams they read, and
+/// every SMART reading on `smart`, until those streams close. Subscribing
+/// keeps the streams sampled while no client is connected. Events go out
+/// through `publisher`, and `firing` always holds the latest firing event of
+/// every rule that is firing.
+pub async fn evaluate(
+    rules: Vec<AlertRule>,
+    subscribe: impl Fn(Stream) -> Option<broadcast::Receiver<Frame>>,
+    smart: watch::Receiver<Option<SmartReport>>,
+    publisher: Publisher<AlertEvent>,
+    firing: watch::Sender<Vec<AlertEvent>>,
+    stats: Arc<Stats>,
+) {
+    let wants = |stream| rules.iter().any(|rule| rule.metric.stream() == stream);
+    let subscribe = |stream| wants(Some(stream)).then(|| subscribe(stream)).flatten();
+    let mut cpus = subscribe(Stream::Cpus);
+    let mut ram = subscribe(Stream::Ram);
+    let mut processes = subscribe(Stream::Processes);
+    let mut smart = wants(None).then_some(smart);
+    tracing::info!(rules = rules.len(), "evaluating alert rules");
+    let mut rules = Rules {
+        states: rules.iter().map(|_| RuleState::default()).collect(),
+        rules,
+        client: webhook_client(),
+        publisher,
+        firing,
+        stats,
+    };
+
+    loop {
+        let (stream, frame) = tokio::select! {
+            frame = recv(&mut cpus) => (Stream::Cpus, frame),
+            frame = recv(&mut ram) => (Stream::Ram, frame),
+            frame = recv(&mut processes) => (Stream::Processes, frame),
+            report = changed(&mut smart) => {
+                let Some(report) = report else { continue };
+                let now = report.timestamp_ms;
+                rules.update(None, &Data::Smart(report), now);
+                continue;
+            }
+        };
+        let frame = match frame {
+            Ok(frame) => frame,
+            // Alerts only look at the latest readings anyway.
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let text = frame.text(Protocol::V2);
+        let decoded = match stream {
+            Stream::Cpus => decode(text).map(|(now, data)| (now, Data::Cpus(data))),
+            Stream::Ram => decode(text).map(|(now, data)| (now, Data::Ram(data))),
+            Stream::Processes => decode(text).map(|(now, data)| (now, Data::Processes(data))),
+        };
+        let Some((now, data)) = decoded else {
+            continue;
+        };
+        rules.update(Some(stream), &data, now);
+    }
+}
+
+/// The rules with their states, and where their events go.
+struct Rules {
+    rules: Vec<AlertRule>,
+    states: Vec<RuleState>,
+    client: Client<Connector>,
+    publisher: Publisher<AlertEvent>,
+    firing: watch::Sender<Vec<AlertEvent>>,
+    stats: Arc<Stats>,
+}
+
+impl Rules {
+    /// Moves the rules reading from `stream` on with `data`.
+    fn update(&mut self, stream: Option<Stream>, data: &Data, now: u64) {
+        let Self {
+            rules,
+            states,
+            client,
+            publisher,
+            firing,
+            stats,
+        } = self;
+        for (rule, state) in rules.iter().zip(states.iter_mut()) {
+            if rule.metric.stream() != stream {
+                continue;
+            }
+            let filter = rule.process.as_deref().or(rule.device.as_deref());
+            let value = rule.metric.read(data, filter);
+            let Some(event) = state.update(rule, value, now) else {
+                continue;
+            };
+            match event.state {
+                AlertState::Firing => tracing::warn!(
+                    rule = rule.name,
+                    severity = ?rule.severity,
+                    value = event.value,
+                    threshold = rule.threshold,
+                    "alert firing"
+                ),
+                AlertState::Resolved => {
+                    tracing::info!(rule = rule.name, value = event.value, "alert resolved")
+                }
+            }
+            firing.send_modify(|firing| {
+                firing.retain(|firing| firing.rule != event.rule);
+                if event.state == AlertState::Firing {
+                    firing.push(event.clone());
+                }
+            });
+            spawn_hook(rule, &state.hook_running, &event);
+            spawn_webhook(rule, client, &event);
+            publisher.publish(&event, None, &stats.alerts);
+        }
+    }
+}
+
+/// Runs the `exec` hook of `rule` for `event` in the background, unless
+/// its previous run is still going.
+fn spawn_hook(rule: &AlertRule, running: &Arc<AtomicBool>, event: &AlertEvent) {
+    let Some(command) = rule.exec.clone() else {
+        return;
+    };
+    if running.swap(true, Ordering::AcqRel) {
+        tracing::warn!(
+            rule = rule.name,
+            state = ?event.state,
+            "alert hook is still running, skipping it for this event"
+        );
+        return;
+    }
+    let running = running.clone();
+    let timeout = Duration::from_millis(rule.exec_timeout_ms);
+    let event = event.clone();
+    tokio::spawn(async move {
+        let result = run_hook(&command, timeout, &event).await;
+        running.store(false, Ordering::Release);
+        let program = &command[0];
+        match result {
+            Ok(Some(status)) if status.success() => {
+                tracing::info!(rule = event.rule, program, "alert hook finished")
+            }
+            Ok(Some(status)) => {
+                tracing::warn!(rule = event.rule, program, %status, "alert hook failed")
+            }
+            Ok(None) => tracing::warn!(
+                rule = event.rule,
+                program,
+                timeout_ms = timeout.as_millis() as u64,
+                "alert hook timed out and was killed"
+            ),
+            Err(err) => tracing::error!(rule = event.rule, program, %err, "cannot run alert hook"),
+        }
+    });
+}
+
+/// Runs `command` to completion, or kills it after `timeout` and returns
+/// `None`.
+async fn run_hook(
+    command: &[String],
+    timeout: Duration,
+    event: &AlertEvent,
+) -> io::Result<Option<ExitStatus>> {
+    let state = match event.state {
+        AlertState::Firing => "firing",
+        AlertState::Resolved => "resolved",
+    };
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .env("AXACT_ALERT_RULE", &event.rule)
+        .env("AXACT_ALERT_STATE", state)
+        .env("AXACT_ALERT_VALUE", event.value.to_string())
+        .env("AXACT_ALERT_SINCE", event.since.to_string())
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let json = serde_json::to_vec(event)?;
+    let stdin = child.stdin.take();
+
+    let run = async {
+        if let Some(mut stdin) = stdin {
+            // Hooks that do not read their input close the pipe early.
+            let _ = stdin.write_all(&json).await;
+        }
+        child.wait().await
+    };
+    match time::timeout(timeout, run).await {
+        Ok(status) => status.map(Some),
+        Err(_) => {
+            child.kill().await?;
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+type Connector = hyper_rustls::HttpsConnector<HttpConnector>;
+#[cfg(not(feature = "tls"))]
+type Connector = HttpConnector;
+
+fn webhook_client() -> Client<Connector> {
+    #[cfg(feature = "tls")]
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    #[cfg(not(feature = "tls"))]
+    let connector = HttpConnector::new();
+    Client::builder().build(connector)
+}
+
+/// POSTs `event` to the `webhook` of `rule` in the background. The URL is
+/// left out of the logs, as webhooks often carry their token in it.
+fn spawn_webhook(rule: &AlertRule, client: &Client<Connector>, event: &AlertEvent) {
+    let Some(url) = rule.webhook.clone() else {
+        return;
+    };
+    let client = client.clone();
+    let timeout = Duration::from_millis(rule.webhook_timeout_ms);
+    let event = event.clone();
+    tokio::spawn(async move {
+        match time::timeout(timeout, post(&client, &url, &event)).await {
+            Ok(Ok(status)) if status.is_success() => {
+                tracing::info!(rule = event.rule, "alert webhook sent")
+            }
+            Ok(Ok(status)) => {
+                tracing::warn!(rule = event.rule, %status, "alert webhook was refused")
+            }
+            Ok(Err(err)) => tracing::error!(rule = event.rule, %err, "cannot send alert webhook"),
+            Err(_) => tracing::warn!(
+                rule = event.rule,
+                timeout_ms = timeout.as_millis() as u64,
+                "alert webhook timed out"
+            ),
+        }
+    });
+}
+
+async fn post(
+    client: &Client<Connector>,
+    url: &str,
+    event: &AlertEvent,
+) -> Result<StatusCode, String> {
+    let json = serde_json::to_vec(event).map_err(|err| err.to_string())?;
+    let request = Request::post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(USER_AGENT, concat!("axact/", env!("CARGO_PKG_VERSION")))
+        .body(Body::from(json))
+        .map_err(|err| err.to_string())?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(response.status())
+}
+
+/// The next SMART reading on `rx`, or never if there is none. Stops
+/// waiting on `rx` once its sender is gone.
+async fn changed(rx: &mut Option<watch::Receiver<Option<SmartReport>>>) -> Option<SmartReport> {
+    let Some(receiver) = rx else {
+        return future::pending().await;
+    };
+    if receiver.changed().await.is_err() {
+        *rx = None;
+        return None;
+    }
+    receiver.borrow_and_update().clone()
+}
+
+/// Receives from `rx`, or never if there is none.
+async fn recv(rx: &mut Option<broadcast::Receiver<Frame>>) -> Result<Frame, RecvError> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => future::pending().await,
+    }
+}
+
+/// The timestamp and payload of a v2 message.
+fn decode<T: DeserializeOwned>(text: &str) -> Option<(u64, T)> {
+    match serde_json::from_str::<Sample<T>>(text) {
+        Ok(sample) => Some((sample.timestamp_ms, sample.data)),
+        Err(err) => {
+            tracing::error!(%err, "cannot decode sample for alerts");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cpu.usage > 90`, resolving below 80.
+    fn rule() -> AlertRule {
+        AlertRule {
+            name: "cpu busy".into(),
+            metric: Metric::CpuUsage,
+            op: Comparison::Above,
+            threshold: 90.,
+            for_ms: 2_000,
+            clear_threshold: Some(80.),
+            cooldown_ms: 10_000,
+            severity: Severity::Critical,
+            process: None,
+            device: None,
+            exec: None,
+            exec_timeout_ms: default_hook_timeout_ms(),
+            webhook: None,
+            webhook_timeout_ms: default_hook_timeout_ms(),
+        }
+    }
+
+    /// Feeds `readings`, `(ms, value)`, to a fresh state and gives the
+    /// state and value of every event, with its `since`.
+    fn run(rule: &AlertRule, readings: &[(u64, f64)]) -> Vec<(AlertState, f64, u64)> {
+        let mut state = RuleState::default();
+        readings
+            .iter()
+            .filter_map(|&(now, value)| state.update(rule, Some(value), now))
+            .map(|event| (event.state, event.value, event.since))
+            .collect()
+    }
+
+    #[test]
+    fn fires_once_the_condition_held_for_for_ms() {
+        let rule = rule();
+        // Pending from 1s, interrupted at 2s, then held from 3s to 5s.
+        let readings = [
+            (1_000, 95.),
+            (2_000, 50.),
+            (3_000, 95.),
+            (4_000, 96.),
+            (5_000, 97.),
+        ];
+        assert_eq!(run(&rule, &readings), [(AlertState::Firing, 97., 3_000)]);
+    }
+
+    #[test]
+    fn holds_between_the_clear_threshold_and_the_threshold_then_resolves() {
+        let rule = AlertRule {
+            for_ms: 0,
+            ..rule()
+        };
+        let readings = [
+            (0, 95.),
+            (1_000, 85.),
+            (2_000, 91.),
+            (3_000, 81.),
+            (4_000, 79.),
+        ];
+        assert_eq!(
+            run(&rule, &readings),
+            [
+                (AlertState::Firing, 95., 0),
+                (AlertState::Resolved, 79., 4_000),
+            ]
+        );
+        // Without one, it resolves as soon as the condition stops holding.
+        let rule = AlertRule {
+            clear_threshold: None,
+            cooldown_ms: 0,
+            ..rule
+        };
+        assert_eq!(
+            run(&rule, &readings),
+            [
+                (AlertState::Firing, 95., 0),
+                (AlertState::Resolved, 85., 1_000),
+                (AlertState::Firing, 91., 2_000),
+                (AlertState::Resolved, 81., 3_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn cooldown_suppresses_flapping_and_refires_with_the_original_since() {
+        let rule = AlertRule {
+            for_ms: 0,
+            ..rule()
+        };
+        let readings = [
+            (0, 95.),
+            (1_000, 70.),
+            // Within the cooldown: held back, but counted as holding.
+            (3_000, 95.),
+            (6_000, 96.),
+            (11_000, 97.),
+        ];
+        assert_eq!(
+            run(&rule, &readings),
+            [
+                (AlertState::Firing, 95., 0),
+                (AlertState::Resolved, 70., 1_000),
+                (AlertState::Firing, 97., 3_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_readings_change_nothing() {
+        let rule = rule();
+        let mut state = RuleState::default();
+        assert_eq!(state.update(&rule, Some(95.), 0), None);
+        assert_eq!(state.update(&rule, None, 1_000), None);
+        assert!(state.update(&rule, Some(95.), 2_000).is_some());
+    }
+
+    #[cfg(feature = "cpu")]
+    #[test]
+    fn clear_threshold_past_the_threshold_is_rejected() {
+        assert_eq!(rule().validate(), Ok(()));
+        let past = AlertRule {
+            clear_threshold: Some(95.),
+            ..rule()
+        };
+        assert!(past.validate().unwrap_err().contains("clear_threshold"));
+        let below = AlertRule {
+            op: Comparison::Below,
+            threshold: 10.,
+            clear_threshold: Some(5.),
+            ..rule()
+        };
+        assert!(below.validate().unwrap_err().contains("clear_threshold"));
+    }
+}
@@ -0,0 +1,223 @@
+//! `axact client`: a terminal view of a (possibly remote) server's streams.
+
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use clap::Args as ClapArgs;
+use futures::StreamExt;
+use tokio::sync::mpsc;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::types::{CpuState, MemState, ProcessInfo, Sample};
+
+pub const STREAMS: [&str; 3] = ["cpus", "ram", "processes"];
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const BAR_WIDTH: usize = 30;
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct ClientArgs {
+    /// Base URL of the server to watch.
+    #[arg(default_value = "ws://127.0.0.1:7032")]
+    pub url: String,
+    /// Print every message as a `{"stream", "seq", "timestamp_ms", "data"}`
+    /// JSON line instead of drawing a summary.
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// What the stream followers report; the name is the stream's.
+pub enum Event {
+    Connected(&'static str),
+    Disconnected(&'static str, String),
+    Message(&'static str, String),
+}
+
+#[derive(Default)]
+struct View {
+    cpus: Option<CpuState>,
+    ram: Option<MemState>,
+    processes: Option<Vec<ProcessInfo>>,
+    offline: Vec<(&'static str, String)>,
+}
+
+pub async fn run(args: ClientArgs) {
+    let base = args.url.trim_end_matches('/').to_string();
+    let (tx, mut rx) = mpsc::channel(16);
+    for stream in STREAMS {
+        tokio::spawn(follow(
+            format!("{base}/realtime/{stream}?v=2"),
+            stream,
+            tx.clone(),
+        ));
+    }
+
+    let mut view = View::default();
+    if !args.json {
+        // Hide the cursor while we keep redrawing the screen.
+        print!("\x1b[?25l");
+    }
+
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => event,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let Some(event) = event else { break };
+
+        if args.json {
+            if let Event::Message(stream, text) = event {
+                let Ok(sample) = serde_json::from_str::<Sample<serde_json::Value>>(&text) else {
+                    continue;
+                };
+                let line = serde_json::json!({
+                    "stream": stream,
+                    "seq": sample.seq,
+                    "timestamp_ms": sample.timestamp_ms,
+                    "data": sample.data,
+                });
+                // Stop quietly once whatever we are piped into goes away.
+                if writeln!(std::io::stdout(), "{line}").is_err() {
+                    break;
+                }
+            }
+            continue;
+        }
+
+        view.apply(event);
+        print!("\x1b[2J\x1b[H{}", view.render(&base));
+        let _ = std::io::stdout().flush();
+    }
+
+    if !args.json {
+        println!("\x1b[?25h");
+    }
+}
+
+/// Keeps one stream connected, reconnecting with exponential backoff.
+pub async fn follow(url: String, stream: &'static str, tx: mpsc::Sender<Event>) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let reason = match connect_async(url.as_str()).await {
+            Ok((mut socket, _)) => {
+                backoff = Duration::from_secs(1);
+                if tx.send(Event::Connected(stream)).await.is_err() {
+                    return;
+                }
+                loop {
+                    match socket.next().await {
+                        Some(Ok(Message::Text(text))) => {
+                            if tx.send(Event::Message(stream, text)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | None => break "connection closed".into(),
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => break err.to_string(),
+                    }
+                }
+            }
+            Err(err) => err.to_string(),
+        };
+
+        let retry_at = Instant::now() + backoff;
+        let reason = format!("{reason}, retrying in {}s", backoff.as_secs());
+        if tx.send(Event::Disconnected(stream, reason)).await.is_err() {
+            return;
+        }
+        tokio::time::sleep_until(retry_at.into()).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+impl View {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Connected(stream) => self.offline.retain(|(name, _)| *name != stream),
+            Event::Disconnected(stream, reason) => {
+                self.offline.retain(|(name, _)| *name != stream);
+                self.offline.push((stream, reason));
+            }
+            Event::Message("cpus", text) => self.cpus = data(&text),
+            Event::Message("ram", text) => self.ram = data(&text),
+            Event::Message("processes", text) => self.processes = data(&text),
+            Event::Message(..) => {}
+        }
+    }
+
+    fn render(&self, base: &str) -> String {
+        let mut out = format!("axact @ {base}\n\n");
+
+        if let Some(cpus) = &self.cpus {
+            match cpus.temp {
+                Some(temp) => out += &format!("CPU {temp:.1}°C\n"),
+                None => out += "CPU\n",
+            }
+            for (i, core) in cpus.cores.iter().enumerate() {
+                out += &format!("{i:>3} {}", bar(core.usage / 100.));
+                if let Some(temp) = core.temp {
+                    out += &format!(" {temp:.0}°C");
+                }
+                out += "\n";
+            }
+            out += "\n";
+        }
+
+        if let Some(ram) = &self.ram {
+            let gib = |bytes: u64| bytes as f64 / (1024. * 1024. * 1024.);
+            let fraction = if ram.total == 0 {
+                0.
+            } else {
+                ram.used as f32 / ram.total as f32
+            };
+            out += &format!(
+                "MEM {} {:.1} / {:.1} GiB\n\n",
+                bar(fraction),
+                gib(ram.used),
+                gib(ram.total)
+            );
+        }
+
+        if let Some(processes) = &self.processes {
+            out += "TOP PROCESSES\n";
+            for process in processes {
+                out += &format!(
+                    "{:>6.1}%  {:>7}  {}",
+                    process.cpu_usage, process.pid, process.name
+                );
+                if process.self_process {
+                    out += " (axact)";
+                }
+                if let Some(instances) = process.instances {
+                    out += &format!(" ({instances}x)");
+                }
+                out += "\n";
+            }
+            out += "\n";
+        }
+
+        for (stream, reason) in &self.offline {
+            out += &format!("{stream}: {reason}\n");
+        }
+        out
+    }
+}
+
+/// The payload of a v2 message.
+pub fn data<T: serde::de::DeserializeOwned>(text: &str) -> Option<T> {
+    serde_json::from_str::<Sample<T>>(text)
+        .ok()
+        .map(|sample| sample.data)
+}
+
+fn bar(fraction: f32) -> String {
+    let fraction = fraction.clamp(0., 1.);
+    let filled = (fraction * BAR_WIDTH as f32).round() as usize;
+    format!(
+        "[{}{}] {:>5.1}%",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        fraction * 100.
+    )
+}
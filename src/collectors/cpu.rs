@@ -0,0 +1,254 @@
+use std::{
+    sync::Once,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "power")]
+use super::power::PowerMeter;
+#[cfg(feature = "temps")]
+use super::sensors::CpuSensors;
+use super::{source::MetricsSource, Collector, Refresh};
+use crate::{
+    config::{SamplerConfig, Stream},
+    types::{CpuCore, CpuState},
+};
+
+/// A state for `cpu_count` logical CPUs, the number there were at startup,
+/// for [`sample`] to update on every tick.
+pub fn state(source: &impl MetricsSource, cpu_count: usize) -> CpuState {
+    CpuState {
+        cores: (0..cpu_count)
+            .map(|id| CpuCore {
+                id,
+                name: source
+                    .cpu_cores()
+                    .get(id)
+                    .map_or_else(|| format!("cpu{id}"), |cpu| cpu.name.clone()),
+                usage: 0.,
+                temp: None,
+            })
+            .collect(),
+        temp: None,
+        core_temp: cfg!(feature = "core_temp"),
+        power_watts: None,
+    }
+}
+
+/// Updates `cpu_state` in place. CPUs that went away since startup are
+/// reported idle, ones that appeared are left out, so that clients can rely
+/// on the indices. Temperatures are only read again when `sensors` is given
+/// and keep their previous values otherwise.
+pub fn sample(
+    cpu_state: &mut CpuState,
+    source: &impl MetricsSource,
+    #[cfg(feature = "temps")] sensors: Option<&CpuSensors>,
+) {
+    for core in &mut cpu_state.cores {
+        core.usage = source.cpu_cores().get(core.id).map_or(0., |cpu| cpu.usage);
+    }
+
+    #[cfg(feature = "temps")]
+    if let Some(sensors) = sensors {
+        #[cfg(feature = "core_temp")]
+        sensors.core_temps(source, &mut cpu_state.cores);
+        cpu_state.temp = sensors.package_temp(source);
+    }
+
+    sanitize(cpu_state);
+}
+
+/// The CPU stream: usages on every tick, temperatures on a slower schedule
+/// of their own, and the package power.
+pub struct CpuCollector {
+    state: CpuState,
+    /// Detected on the first temperature reading, and again when
+    /// `sampler.sensors` changes.
+    #[cfg(feature = "temps")]
+    sensors: Option<CpuSensors>,
+    /// When temperatures are next read, `None` to read them on the next
+    /// sample.
+    #[cfg(feature = "temps")]
+    next_temps: Option<Instant>,
+    /// Apart from the power stream's, so that either can run alone.
+    #[cfg(feature = "power")]
+    power: PowerMeter,
+}
+
+impl CpuCollector {
+    /// For `cpu_count` logical CPUs, see [`state`].
+    pub fn new(source: &impl MetricsSource, cpu_count: usize) -> Self {
+        Self {
+            state: state(source, cpu_count),
+            #[cfg(feature = "temps")]
+            sensors: None,
+            #[cfg(feature = "temps")]
+            next_temps: None,
+            #[cfg(feature = "power")]
+            power: PowerMeter::default(),
+        }
+    }
+
+    /// Temperatures change slowly, and reading them costs more than the
+    /// usages.
+    #[cfg(feature = "temps")]
+    fn temps_due(&self, tick: Instant) -> bool {
+        self.next_temps.is_none_or(|next| tick >= next)
+    }
+}
+
+impl<S: MetricsSource> Collector<S> for CpuCollector {
+    type State = CpuState;
+
+    fn name(&self) -> &'static str {
+        "cpus"
+    }
+
+    fn period(&self, _config: &SamplerConfig) -> Duration {
+        Duration::ZERO
+    }
+
+    #[cfg_attr(not(feature = "temps"), allow(unused_variables))]
+    fn refresh(&self, tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            cpus: true,
+            energy: cfg!(feature = "power"),
+            #[cfg(feature = "temps")]
+            components: self.temps_due(tick),
+            ..Refresh::default()
+        }
+    }
+
+    fn deltas(&self) -> bool {
+        true
+    }
+
+    #[cfg_attr(not(feature = "power"), allow(unused_variables))]
+    fn baseline(&mut self, source: &S, now: Instant) {
+        #[cfg(feature = "power")]
+        self.power.sample(source, now);
+    }
+
+    #[cfg_attr(not(feature = "temps"), allow(unused_variables))]
+    fn collect(&mut self, source: &S, tick: Instant, config: &SamplerConfig) -> Option<CpuState> {
+        #[cfg(feature = "temps")]
+        let temps_due = self.temps_due(tick);
+        #[cfg(feature = "temps")]
+        if temps_due {
+            self.next_temps = Some(tick + Duration::from_millis(config.temp_interval_ms));
+            match &mut self.sensors {
+                Some(sensors) if *sensors.mapping() == config.sensors => sensors.update(source),
+                sensors => *sensors = Some(CpuSensors::with_mapping(source, &config.sensors)),
+            }
+        }
+        #[cfg(feature = "power")]
+        {
+            self.state.power_watts = self.power.sample(source, Instant::now()).package_watts;
+        }
+        sample(
+            &mut self.state,
+            source,
+            #[cfg(feature = "temps")]
+            self.sensors.as_ref().filter(|_| temps_due),
+        );
+        Some(self.state.clone())
+    }
+
+    fn reset(&mut self) {
+        #[cfg(feature = "temps")]
+        {
+            self.next_temps = None;
+        }
+        #[cfg(feature = "power")]
+        self.power.reset();
+    }
+
+    fn max_silence(&self, config: &SamplerConfig) -> Option<Duration> {
+        config.max_silence(Stream::Cpus)
+    }
+}
+
+/// Flaky sensors can report NaN or infinity, which JSON cannot carry. Such
+/// temperatures become missing and usages become 0.
+fn sanitize(cpu_state: &mut CpuState) {
+    static WARNED: Once = Once::new();
+    let mut found = false;
+
+    if cpu_state.temp.is_some_and(|temp| !temp.is_finite()) {
+        cpu_state.temp = None;
+        found = true;
+    }
+    for core in &mut cpu_state.cores {
+        if !core.usage.is_finite() {
+            core.usage = 0.;
+            found = true;
+        }
+        if core.temp.is_some_and(|temp| !temp.is_finite()) {
+            core.temp = None;
+            found = true;
+        }
+    }
+
+    if found {
+        WARNED.call_once(|| {
+            tracing::warn!("cpu readings that are not finite numbers were dropped");
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::fake::FakeSource;
+
+    #[test]
+    fn cpus_that_went_away_are_reported_idle() {
+        let mut source = FakeSource::with_cpus(&[10., 20., 30.]);
+        let mut cpu_state = state(&source, 3);
+        source.cpus.truncate(2);
+        sample(
+            &mut cpu_state,
+            &source,
+            #[cfg(feature = "temps")]
+            None,
+        );
+        let usages: Vec<_> = cpu_state.cores.iter().map(|core| core.usage).collect();
+        assert_eq!(usages, [10., 20., 0.]);
+        assert_eq!(cpu_state.cores[2].name, "cpu2");
+    }
+
+    #[test]
+    fn sampling_updates_the_state_without_allocating() {
+        let source = FakeSource::with_cpus(&[10., 20., 30., 40.]);
+        let mut cpu_state = state(&source, 4);
+        let allocations = crate::collectors::allocations::count(|| {
+            sample(
+                &mut cpu_state,
+                &source,
+                #[cfg(feature = "temps")]
+                None,
+            )
+        });
+        assert_eq!(allocations, 0);
+    }
+
+    #[cfg(feature = "temps")]
+    #[test]
+    fn nan_readings_are_dropped() {
+        let mut source = FakeSource::with_cpus(&[f32::NAN, 50.]);
+        source.add_component("coretemp Package id 0", f32::NAN);
+        source.add_component("coretemp Core 0", f32::INFINITY);
+        source.add_component("coretemp Core 1", 48.);
+        let sensors = CpuSensors::detect(&source);
+        let mut cpu_state = state(&source, 2);
+        sample(&mut cpu_state, &source, Some(&sensors));
+
+        assert_eq!(cpu_state.temp, None);
+        assert_eq!(cpu_state.cores[0].usage, 0.);
+        assert_eq!(cpu_state.cores[1].usage, 50.);
+        #[cfg(feature = "core_temp")]
+        {
+            assert_eq!(cpu_state.cores[0].temp, None);
+            assert_eq!(cpu_state.cores[1].temp, Some(48.));
+        }
+    }
+}
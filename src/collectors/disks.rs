@@ -0,0 +1,244 @@
+//! Space and I/O of the mounted filesystems. sysinfo lists them with their
+//! space; the I/O counters of the devices they are on come from
+//! `/proc/diskstats`, so there are none outside Linux. The counters only
+//! ever go up, so rates are the bytes moved between two reads over the time
+//! between them.
+
+use std::{
+    collections::HashMap,
+    fs,
+    time::{Duration, Instant},
+};
+
+use sysinfo::DiskExt;
+
+use super::{
+    source::{Disk, DiskIo, MetricsSource},
+    Collector, Refresh,
+};
+use crate::{
+    config::SamplerConfig,
+    types::{DiskInfo, DiskState, MemUnit},
+};
+
+const DISKSTATS: &str = "/proc/diskstats";
+
+/// `/proc/diskstats` counts in sectors of 512 bytes, whatever the device's
+/// own sector size.
+const SECTOR: u64 = 512;
+
+/// Copies what sysinfo lists, along with the counters of each device.
+pub fn read(disks: &[sysinfo::Disk]) -> Vec<Disk> {
+    let counters = match fs::read_to_string(DISKSTATS) {
+        Ok(text) => parse_diskstats(&text),
+        Err(err) => {
+            tracing::debug!(%err, "cannot read {DISKSTATS}, no disk i/o");
+            HashMap::new()
+        }
+    };
+    disks
+        .iter()
+        .map(|disk| {
+            let name = disk.name().to_string_lossy().into_owned();
+            Disk {
+                io: counters.get(&kernel_name(&name)).copied(),
+                name,
+                mount_point: disk.mount_point().display().to_string(),
+                file_system: String::from_utf8_lossy(disk.file_system()).into_owned(),
+                total: disk.total_space(),
+                available: disk.available_space(),
+            }
+        })
+        .collect()
+}
+
+/// The name `/proc/diskstats` knows the device `name` by, following links
+/// such as `/dev/mapper/root` to `dm-0`.
+fn kernel_name(name: &str) -> String {
+    let path = fs::canonicalize(name).unwrap_or_else(|_| name.into());
+    match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => name.to_owned(),
+    }
+}
+
+/// The counters of every block device, by kernel name, e.g. "sda1".
+fn parse_diskstats(text: &str) -> HashMap<String, DiskIo> {
+    text.lines()
+        .filter_map(|line| {
+            // major, minor, name, then reads completed and merged, sectors
+            // read, time reading, writes completed and merged, sectors
+            // written, and more that is not needed.
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let sectors = |index: usize| fields.get(index)?.parse::<u64>().ok();
+            let io = DiskIo {
+                read: sectors(5)? * SECTOR,
+                written: sectors(9)? * SECTOR,
+            };
+            Some((fields[2].to_owned(), io))
+        })
+        .collect()
+}
+
+/// Turns successive counter readings into bytes per second.
+#[derive(Debug, Default)]
+pub struct DiskMeter {
+    /// When the counters were last read, and what they were by mount point.
+    last: Option<(Instant, HashMap<String, DiskIo>)>,
+}
+
+impl DiskMeter {
+    /// Forgets the last readings, so that the next sample has no rates
+    /// rather than an average over a pause.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    /// The rates since the last sample, from a freshly refreshed `source`.
+    pub fn sample(&mut self, source: &impl MetricsSource, now: Instant) -> DiskState {
+        let last = self.last.take().filter(|(at, _)| now > *at);
+        let disks = source
+            .disks()
+            .iter()
+            .map(|disk| {
+                let previous = last.as_ref().and_then(|(at, counters)| {
+                    Some((now - *at, *counters.get(&disk.mount_point)?))
+                });
+                let (read, written) = match (disk.io, previous) {
+                    (Some(io), Some((elapsed, previous))) => (
+                        Some(per_sec(io.read, previous.read, elapsed)),
+                        Some(per_sec(io.written, previous.written, elapsed)),
+                    ),
+                    _ => (None, None),
+                };
+                DiskInfo {
+                    name: disk.name.clone(),
+                    mount_point: disk.mount_point.clone(),
+                    file_system: disk.file_system.clone(),
+                    total: disk.total,
+                    used: disk.total.saturating_sub(disk.available),
+                    available: disk.available,
+                    read_bytes_per_sec: read,
+                    write_bytes_per_sec: written,
+                }
+            })
+            .collect();
+        self.last = Some((
+            now,
+            source
+                .disks()
+                .iter()
+                .filter_map(|disk| Some((disk.mount_point.clone(), disk.io?)))
+                .collect(),
+        ));
+        DiskState {
+            disks,
+            unit: MemUnit::Bytes,
+        }
+    }
+}
+
+/// Counters start over when a device is attached again, which counts as no
+/// I/O rather than a huge rate.
+fn per_sec(current: u64, previous: u64, elapsed: Duration) -> u64 {
+    (current.saturating_sub(previous) as f64 / elapsed.as_secs_f64()) as u64
+}
+
+impl<S: MetricsSource> Collector<S> for DiskMeter {
+    type State = DiskState;
+
+    fn name(&self) -> &'static str {
+        "disks"
+    }
+
+    fn period(&self, config: &SamplerConfig) -> Duration {
+        config.disk_period()
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            disks: true,
+            ..Refresh::default()
+        }
+    }
+
+    fn deltas(&self) -> bool {
+        true
+    }
+
+    fn baseline(&mut self, source: &S, now: Instant) {
+        DiskMeter::sample(self, source, now);
+    }
+
+    fn collect(
+        &mut self,
+        source: &S,
+        _tick: Instant,
+        _config: &SamplerConfig,
+    ) -> Option<DiskState> {
+        Some(DiskMeter::sample(self, source, Instant::now()))
+    }
+
+    fn reset(&mut self) {
+        DiskMeter::reset(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::fake::FakeSource;
+
+    #[test]
+    fn diskstats_are_parsed_in_bytes() {
+        let text = "\
+   8       0 sda 5012 1021 412345 2210 8811 9120 800000 40011 0 23010 42221 0 0 0 0 0 0
+   8       1 sda1 4890 1021 408121 2190 8811 9120 800000 40011 0 22980 42201 0 0 0 0 0 0
+ 253       0 dm-0 12 0 96 4
+";
+        let counters = parse_diskstats(text);
+        assert_eq!(
+            counters["sda1"],
+            DiskIo {
+                read: 408121 * 512,
+                written: 800000 * 512,
+            }
+        );
+        assert_eq!(counters.len(), 2, "dm-0 has too few fields");
+    }
+
+    #[test]
+    fn meter_computes_rates_from_the_second_sample() {
+        let disk = |mount_point: &str, io: Option<DiskIo>| Disk {
+            name: "/dev/sda1".into(),
+            mount_point: mount_point.into(),
+            file_system: "ext4".into(),
+            total: 1000,
+            available: 400,
+            io,
+        };
+        let mut source = FakeSource {
+            disks: vec![disk("/", Some(DiskIo::default())), disk("/mnt/share", None)],
+            ..FakeSource::default()
+        };
+        let mut meter = DiskMeter::default();
+        let start = Instant::now();
+
+        let first = meter.sample(&source, start);
+        assert_eq!(first.disks[0].used, 600);
+        assert_eq!(first.disks[0].read_bytes_per_sec, None);
+
+        source.disks[0].io = Some(DiskIo {
+            read: 1000,
+            written: 500,
+        });
+        let second = meter.sample(&source, start + Duration::from_millis(500));
+        assert_eq!(second.disks[0].read_bytes_per_sec, Some(2000));
+        assert_eq!(second.disks[0].write_bytes_per_sec, Some(1000));
+        assert_eq!(second.disks[1].read_bytes_per_sec, None);
+
+        meter.reset();
+        let after_pause = meter.sample(&source, start + Duration::from_secs(1));
+        assert_eq!(after_pause.disks[0].read_bytes_per_sec, None);
+    }
+}
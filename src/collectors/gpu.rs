@@ -0,0 +1,406 @@
+//! GPU utilization, memory, temperature and power. NVIDIA cards are read
+//! through NVML with the `nvml` feature; the library comes with NVIDIA's
+//! driver and is loaded at startup, so machines without one simply have no
+//! NVIDIA cards. AMD cards are read from the files the amdgpu driver puts
+//! under `/sys/class/drm`, so only on Linux. Every reading is a gauge and is
+//! reported as read.
+//!
+//! NVML also tells which processes use a card: their memory, and their
+//! share of its time where the card supports it. They are named after the
+//! process table, which is refreshed with the cards while they have any.
+
+#[cfg(feature = "nvml")]
+use std::collections::HashMap;
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use sysinfo::{Pid, PidExt};
+
+use super::{
+    source::{Gpu, MetricsSource},
+    Collector, Refresh,
+};
+use crate::{
+    config::SamplerConfig,
+    types::{GpuInfo, GpuProcessInfo, GpuState, MemUnit},
+};
+
+const DRM: &str = "/sys/class/drm";
+
+/// The PCI vendor id of AMD, in a card's `device/vendor`.
+const AMD: &str = "0x1002";
+
+/// Reads every GPU the compiled in drivers find.
+#[derive(Default)]
+pub struct GpuReader {
+    #[cfg(feature = "nvml")]
+    nvml: Option<nvml_wrapper::Nvml>,
+    /// The timestamp of the last process utilization sample read of each
+    /// NVIDIA card, by index, so that every read gets the newer ones.
+    #[cfg(feature = "nvml")]
+    last_seen: HashMap<u32, u64>,
+}
+
+impl GpuReader {
+    /// Loads NVML, where there is one.
+    pub fn open() -> Self {
+        Self {
+            #[cfg(feature = "nvml")]
+            nvml: nvml_wrapper::Nvml::init()
+                .inspect_err(|err| tracing::info!(%err, "cannot load NVML, no NVIDIA gpus"))
+                .ok(),
+            #[cfg(feature = "nvml")]
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Blocks.
+    pub fn read(&mut self) -> Vec<Gpu> {
+        #[cfg_attr(not(feature = "nvml"), allow(unused_mut))]
+        let mut gpus = vec![];
+        #[cfg(feature = "nvml")]
+        if let Some(nvml) = &self.nvml {
+            gpus.extend(read_nvml(nvml, &mut self.last_seen));
+        }
+        gpus.extend(read_amdgpu(Path::new(DRM)));
+        gpus
+    }
+}
+
+#[cfg(feature = "nvml")]
+fn read_nvml(nvml: &nvml_wrapper::Nvml, last_seen: &mut HashMap<u32, u64>) -> Vec<Gpu> {
+    use nvml_wrapper::enum_wrappers::device::TemperatureSensor;
+
+    let count = match nvml.device_count() {
+        Ok(count) => count,
+        Err(err) => {
+            tracing::debug!(%err, "cannot count the NVIDIA gpus");
+            return vec![];
+        }
+    };
+    (0..count)
+        .filter_map(|index| {
+            let device = nvml.device_by_index(index).ok()?;
+            let memory = device.memory_info().ok();
+            Some(Gpu {
+                name: device.name().unwrap_or_else(|_| format!("nvidia{index}")),
+                driver: "nvml",
+                utilization: device
+                    .utilization_rates()
+                    .ok()
+                    .map(|rates| rates.gpu as f32),
+                memory_used: memory.as_ref().map(|memory| memory.used),
+                memory_total: memory.as_ref().map(|memory| memory.total),
+                temp: device
+                    .temperature(TemperatureSensor::Gpu)
+                    .ok()
+                    .map(|temp| temp as f32),
+                // In mW.
+                power_watts: device.power_usage().ok().map(|mw| mw as f32 / 1000.),
+                processes: read_nvml_processes(&device, last_seen.entry(index).or_default()),
+            })
+        })
+        .collect()
+}
+
+/// The compute and graphics processes on `device`. Their utilization is
+/// read from the samples taken since `last_seen`, which is moved on; a
+/// process without one was idle.
+#[cfg(feature = "nvml")]
+fn read_nvml_processes(
+    device: &nvml_wrapper::Device,
+    last_seen: &mut u64,
+) -> Vec<super::source::GpuProcess> {
+    use nvml_wrapper::enums::device::UsedGpuMemory;
+
+    let mut running = device.running_compute_processes().unwrap_or_default();
+    running.extend(device.running_graphics_processes().unwrap_or_default());
+    if running.is_empty() {
+        return vec![];
+    }
+    // Not every card samples it, those only account the memory.
+    let samples = device
+        .process_utilization_stats((*last_seen != 0).then_some(*last_seen))
+        .inspect_err(|err| tracing::trace!(%err, "cannot read the GPU utilization of processes"))
+        .ok();
+    let mut sm_util = HashMap::new();
+    for sample in samples.iter().flatten() {
+        *last_seen = (*last_seen).max(sample.timestamp);
+        let latest = sm_util.entry(sample.pid).or_insert(sample);
+        if sample.timestamp > latest.timestamp {
+            *latest = sample;
+        }
+    }
+    let mut processes: Vec<super::source::GpuProcess> = vec![];
+    for process in running {
+        // A process doing both compute and graphics is listed twice.
+        if processes.iter().any(|seen| seen.pid == process.pid) {
+            continue;
+        }
+        processes.push(super::source::GpuProcess {
+            pid: process.pid,
+            memory_used: match process.used_gpu_memory {
+                UsedGpuMemory::Used(bytes) => Some(bytes),
+                UsedGpuMemory::Unavailable => None,
+            },
+            sm_util: samples.as_ref().map(|_| {
+                sm_util
+                    .get(&process.pid)
+                    .map_or(0., |sample| sample.sm_util as f32)
+            }),
+        });
+    }
+    processes
+}
+
+/// The AMD cards under `root`, by card name, e.g. "card0".
+fn read_amdgpu(root: &Path) -> Vec<Gpu> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return vec![];
+    };
+    let mut cards: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        // The others are its connectors, e.g. "card0-DP-1".
+        .filter(|name| name.starts_with("card") && !name.contains('-'))
+        .collect();
+    cards.sort();
+    cards
+        .into_iter()
+        .filter_map(|card| {
+            let device = root.join(&card).join("device");
+            if read_string(&device.join("vendor"))? != AMD {
+                return None;
+            }
+            let hwmon = fs::read_dir(device.join("hwmon"))
+                .ok()
+                .and_then(|mut dirs| Some(dirs.next()?.ok()?.path()));
+            let hwmon = |file: &str| read_u64(&hwmon.as_ref()?.join(file));
+            Some(Gpu {
+                name: card,
+                driver: "amdgpu",
+                utilization: read_u64(&device.join("gpu_busy_percent")).map(|busy| busy as f32),
+                memory_used: read_u64(&device.join("mem_info_vram_used")),
+                memory_total: read_u64(&device.join("mem_info_vram_total")),
+                // In m°C.
+                temp: hwmon("temp1_input").map(|temp| temp as f32 / 1000.),
+                // In µW, averaged by the driver on older kernels.
+                power_watts: hwmon("power1_average")
+                    .or_else(|| hwmon("power1_input"))
+                    .map(|uw| uw as f32 / 1_000_000.),
+                processes: vec![],
+            })
+        })
+        .collect()
+}
+
+fn read_string(path: &Path) -> Option<String> {
+    Some(fs::read_to_string(path).ok()?.trim().to_owned())
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    read_string(path)?.parse().ok()
+}
+
+/// Reports the GPUs as last read.
+#[derive(Debug, Default)]
+pub struct GpuCollector {
+    /// Whether the cards had processes when last read, whose names need
+    /// the process table.
+    #[cfg(feature = "nvml")]
+    has_processes: bool,
+}
+
+impl<S: MetricsSource> Collector<S> for GpuCollector {
+    type State = GpuState;
+
+    fn name(&self) -> &'static str {
+        "gpu"
+    }
+
+    fn period(&self, config: &SamplerConfig) -> Duration {
+        config.gpu_period()
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            gpus: true,
+            #[cfg(feature = "nvml")]
+            processes: self.has_processes,
+            ..Refresh::default()
+        }
+    }
+
+    fn collect(&mut self, source: &S, _tick: Instant, config: &SamplerConfig) -> Option<GpuState> {
+        #[cfg(feature = "nvml")]
+        {
+            self.has_processes = source.gpus().iter().any(|gpu| !gpu.processes.is_empty());
+        }
+        let hides_any = !config.process_allow.is_empty() || !config.process_deny.is_empty();
+        let gpus = source
+            .gpus()
+            .iter()
+            .map(|gpu| GpuInfo {
+                name: gpu.name.clone(),
+                driver: gpu.driver.to_owned(),
+                utilization: gpu.utilization,
+                memory_used: gpu.memory_used,
+                memory_total: gpu.memory_total,
+                temp: gpu.temp,
+                power_watts: gpu.power_watts,
+                processes: gpu
+                    .processes
+                    .iter()
+                    .filter_map(|process| {
+                        let name = source
+                            .process(Pid::from_u32(process.pid))
+                            .map(|found| found.name.to_owned());
+                        let reported = match &name {
+                            Some(name) => config.reports_process(name),
+                            None => !hides_any,
+                        };
+                        reported.then_some(GpuProcessInfo {
+                            pid: process.pid,
+                            name,
+                            used_vram: process.memory_used,
+                            sm_util: process.sm_util,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+        Some(GpuState {
+            gpus,
+            unit: MemUnit::Bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::{fake::FakeSource, GpuProcess};
+
+    #[test]
+    fn amdgpu_cards_are_read_and_others_skipped() {
+        let root = std::env::temp_dir().join(format!("axact-drm-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let write = |path: &str, text: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        };
+        write("card0/device/vendor", "0x1002\n");
+        write("card0/device/gpu_busy_percent", "37\n");
+        write("card0/device/mem_info_vram_used", "1048576\n");
+        write("card0/device/mem_info_vram_total", "8589934592\n");
+        write("card0/device/hwmon/hwmon3/temp1_input", "54000\n");
+        write("card0/device/hwmon/hwmon3/power1_input", "42500000\n");
+        write("card0-DP-1/status", "connected\n");
+        write("card1/device/vendor", "0x8086\n");
+
+        let gpus = read_amdgpu(&root);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            gpus,
+            [Gpu {
+                name: "card0".into(),
+                driver: "amdgpu",
+                utilization: Some(37.),
+                memory_used: Some(1 << 20),
+                memory_total: Some(8 << 30),
+                temp: Some(54.),
+                power_watts: Some(42.5),
+                processes: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn gpu_processes_are_named_and_hidden_ones_left_out() {
+        let mut source = FakeSource::default();
+        source.add_process(100, "transcode", 1.);
+        source.add_process(200, "notebook", 1.);
+        let process = |pid, sm_util| GpuProcess {
+            pid,
+            memory_used: Some(1 << 30),
+            sm_util,
+        };
+        source.gpus.push(Gpu {
+            name: "NVIDIA GeForce RTX 3060".into(),
+            driver: "nvml",
+            utilization: Some(90.),
+            memory_used: Some(3 << 30),
+            memory_total: Some(12 << 30),
+            temp: None,
+            power_watts: None,
+            // 300 runs in another container.
+            processes: vec![
+                process(100, Some(85.)),
+                process(200, None),
+                process(300, None),
+            ],
+        });
+        let collect = |config: &SamplerConfig| {
+            let state = GpuCollector::default()
+                .collect(&source, Instant::now(), config)
+                .unwrap();
+            state.gpus[0]
+                .processes
+                .iter()
+                .map(|process| (process.pid, process.name.clone(), process.sm_util))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            collect(&SamplerConfig::default()),
+            [
+                (100, Some("transcode".into()), Some(85.)),
+                (200, Some("notebook".into()), None),
+                (300, None, None),
+            ]
+        );
+        let config = SamplerConfig {
+            process_deny: vec![serde_json::from_str(r#""^notebook$""#).unwrap()],
+            ..SamplerConfig::default()
+        };
+        assert_eq!(
+            collect(&config),
+            [(100, Some("transcode".into()), Some(85.))]
+        );
+    }
+
+    #[cfg(feature = "nvml")]
+    #[test]
+    fn process_table_is_refreshed_while_gpus_have_processes() {
+        let mut source = FakeSource::default();
+        source.gpus.push(Gpu {
+            name: "NVIDIA GeForce RTX 3060".into(),
+            driver: "nvml",
+            utilization: None,
+            memory_used: None,
+            memory_total: None,
+            temp: None,
+            power_watts: None,
+            processes: vec![],
+        });
+        let config = SamplerConfig::default();
+        let mut collector = GpuCollector::default();
+        let mut refreshes_processes = |source: &FakeSource| {
+            collector.collect(source, Instant::now(), &config);
+            Collector::<FakeSource>::refresh(&collector, Instant::now(), &config).processes
+        };
+
+        assert!(!refreshes_processes(&source));
+        source.gpus[0].processes.push(GpuProcess {
+            pid: 100,
+            memory_used: None,
+            sm_util: None,
+        });
+        assert!(refreshes_processes(&source));
+        source.gpus[0].processes.clear();
+        assert!(!refreshes_processes(&source));
+    }
+}
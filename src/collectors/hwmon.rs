@@ -0,0 +1,212 @@
+//! Every sensor: the temperatures sysinfo lists as components, and the fans
+//! and voltages it does not, read from `/sys/class/hwmon` and so only on
+//! Linux. Every reading is a gauge and is reported as read.
+
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use super::{
+    source::{HwmonSensor, MetricsSource},
+    Collector, Refresh,
+};
+use crate::{
+    config::SamplerConfig,
+    types::{SensorInfo, SensorKind, SensorState},
+};
+
+const HWMON: &str = "/sys/class/hwmon";
+
+/// Reads the fans and voltages of every chip. Blocks.
+pub fn read() -> Vec<HwmonSensor> {
+    read_chips(Path::new(HWMON))
+}
+
+/// The fans and voltages of the chips under `root`, by chip, e.g. "hwmon2",
+/// then by kind and number.
+fn read_chips(root: &Path) -> Vec<HwmonSensor> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return vec![];
+    };
+    let mut chips: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect();
+    // "hwmon10" after "hwmon9".
+    chips.sort_by_key(|path| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let number: Option<u32> = name.strip_prefix("hwmon").and_then(|n| n.parse().ok());
+        (number, name.into_owned())
+    });
+    chips
+        .iter()
+        .flat_map(|chip| {
+            let name = read_string(&chip.join("name")).unwrap_or_else(|| "hwmon".into());
+            let mut sensors = read_kind(chip, &name, SensorKind::Fan);
+            sensors.extend(read_kind(chip, &name, SensorKind::Voltage));
+            sensors
+        })
+        .collect()
+}
+
+/// The sensors of one `kind` on a chip: `fanN_*` in RPM, `inN_*` in mV.
+fn read_kind(chip: &Path, name: &str, kind: SensorKind) -> Vec<HwmonSensor> {
+    let (prefix, scale) = match kind {
+        SensorKind::Fan => ("fan", 1.),
+        SensorKind::Voltage => ("in", 1000.),
+        SensorKind::Temperature => return vec![],
+    };
+    let Ok(entries) = fs::read_dir(chip) else {
+        return vec![];
+    };
+    let mut numbers: Vec<u32> = entries
+        .filter_map(|entry| {
+            let file = entry.ok()?.file_name().into_string().ok()?;
+            file.strip_prefix(prefix)?
+                .strip_suffix("_input")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    numbers.sort_unstable();
+    numbers
+        .into_iter()
+        .filter_map(|number| {
+            let file = |suffix: &str| chip.join(format!("{prefix}{number}_{suffix}"));
+            let value = |suffix: &str| Some(read_f32(&file(suffix))? / scale);
+            let label = read_string(&file("label")).unwrap_or_else(|| format!("{prefix}{number}"));
+            Some(HwmonSensor {
+                kind,
+                label: format!("{name} {label}"),
+                value: value("input")?,
+                max: value("max"),
+                critical: value("crit"),
+            })
+        })
+        .collect()
+}
+
+fn read_string(path: &Path) -> Option<String> {
+    Some(fs::read_to_string(path).ok()?.trim().to_owned())
+}
+
+fn read_f32(path: &Path) -> Option<f32> {
+    read_string(path)?.parse().ok()
+}
+
+/// Reports the sensors as last read.
+#[derive(Debug, Default)]
+pub struct SensorCollector;
+
+impl<S: MetricsSource> Collector<S> for SensorCollector {
+    type State = SensorState;
+
+    fn name(&self) -> &'static str {
+        "sensors"
+    }
+
+    fn period(&self, config: &SamplerConfig) -> Duration {
+        config.sensor_period()
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            components: true,
+            hwmon: true,
+            ..Refresh::default()
+        }
+    }
+
+    fn collect(
+        &mut self,
+        source: &S,
+        _tick: Instant,
+        _config: &SamplerConfig,
+    ) -> Option<SensorState> {
+        let temps = source.components().iter().map(|component| SensorInfo {
+            kind: SensorKind::Temperature,
+            label: component.label.clone(),
+            value: component.temperature,
+            max: component.max,
+            critical: component.critical,
+        });
+        let others = source.hwmon().iter().map(|sensor| SensorInfo {
+            kind: sensor.kind,
+            label: sensor.label.clone(),
+            value: sensor.value,
+            max: sensor.max,
+            critical: sensor.critical,
+        });
+        // Flaky sensors can read NaN, which JSON cannot carry.
+        let sensors = temps
+            .chain(others)
+            .filter(|sensor| sensor.value.is_finite())
+            .map(|mut sensor| {
+                sensor.max = sensor.max.filter(|max| max.is_finite());
+                sensor.critical = sensor.critical.filter(|critical| critical.is_finite());
+                sensor
+            })
+            .collect();
+        Some(SensorState { sensors })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::fake::FakeSource;
+
+    #[test]
+    fn fans_and_voltages_are_read_in_rpm_and_volts() {
+        let root = std::env::temp_dir().join(format!("axact-hwmon-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let write = |path: &str, text: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        };
+        write("hwmon10/name", "nct6798\n");
+        write("hwmon10/fan2_input", "1214\n");
+        write("hwmon10/fan2_label", "CPU FAN\n");
+        write("hwmon10/in0_input", "1032\n");
+        write("hwmon10/in0_max", "1744\n");
+        write("hwmon10/in0_crit", "2040\n");
+        write("hwmon2/name", "nvme\n");
+        write("hwmon2/temp1_input", "38850\n");
+
+        let sensors = read_chips(&root);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            sensors,
+            [
+                HwmonSensor {
+                    kind: SensorKind::Fan,
+                    label: "nct6798 CPU FAN".into(),
+                    value: 1214.,
+                    max: None,
+                    critical: None,
+                },
+                HwmonSensor {
+                    kind: SensorKind::Voltage,
+                    label: "nct6798 in0".into(),
+                    value: 1.032,
+                    max: Some(1.744),
+                    critical: Some(2.04),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unreadable_temperatures_are_left_out() {
+        let mut source = FakeSource::default();
+        source.add_component("nvme Composite", 38.85);
+        source.add_component("acpitz temp1", f32::NAN);
+        let state = SensorCollector
+            .collect(&source, Instant::now(), &SamplerConfig::default())
+            .unwrap();
+        let labels: Vec<_> = state.sensors.iter().map(|sensor| &sensor.label).collect();
+        assert_eq!(labels, ["nvme Composite"]);
+    }
+}
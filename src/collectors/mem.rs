@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use super::{source::MetricsSource, Collector, Refresh};
+use crate::{
+    config::{SamplerConfig, Stream},
+    types::{MemMode, MemState, MemUnit},
+};
+
+const MIB: u64 = 1024 * 1024;
+
+pub fn sample(source: &impl MetricsSource, mode: MemMode) -> MemState {
+    let memory = source.memory();
+    let (total, free, available) = (memory.total, memory.free, memory.available);
+    let used = match mode {
+        MemMode::Available => total.saturating_sub(available),
+        MemMode::Strict => total.saturating_sub(free),
+    };
+    MemState {
+        total,
+        used,
+        free,
+        available,
+        mode,
+        unit: MemUnit::Bytes,
+        total_mib: total / MIB,
+        used_mib: used / MIB,
+        swap_total: memory.swap_total,
+        swap_used: memory.swap_total.saturating_sub(memory.swap_free),
+    }
+}
+
+/// The memory stream.
+#[derive(Debug, Default)]
+pub struct MemCollector;
+
+impl<S: MetricsSource> Collector<S> for MemCollector {
+    type State = MemState;
+
+    fn name(&self) -> &'static str {
+        "ram"
+    }
+
+    fn period(&self, config: &SamplerConfig) -> Duration {
+        config.period(Stream::Ram)
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            memory: true,
+            ..Refresh::default()
+        }
+    }
+
+    fn collect(&mut self, source: &S, _tick: Instant, config: &SamplerConfig) -> Option<MemState> {
+        Some(sample(source, config.mem_mode))
+    }
+
+    fn max_silence(&self, config: &SamplerConfig) -> Option<Duration> {
+        config.max_silence(Stream::Ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sysinfo::RefreshKind;
+
+    use super::*;
+    use crate::collectors::source::SysinfoSource;
+
+    /// In KiB, the machine's memory would read as a thousandth of what it
+    /// has: under 64 MiB for anything with less than 64 GiB.
+    #[test]
+    fn total_is_in_bytes_on_this_machine() {
+        let mut source = SysinfoSource::with_specifics(RefreshKind::new().with_memory());
+        source.refresh_memory();
+        let state = sample(&source, MemMode::Available);
+        assert!(
+            (64 * MIB..=64 * 1024 * 1024 * MIB).contains(&state.total),
+            "{} bytes of memory",
+            state.total
+        );
+        assert!(state.used <= state.total);
+        assert_eq!(state.total_mib, state.total / MIB);
+    }
+}
@@ -0,0 +1,158 @@
+//! What the sampler reads, and the [`Collector`]s that turn it into the
+//! samples of each stream.
+
+use std::{
+    fmt::Debug,
+    ops::{BitOr, BitOrAssign},
+    time::{Duration, Instant},
+};
+
+use sysinfo::{CpuRefreshKind, ProcessRefreshKind, RefreshKind};
+
+use crate::{config::SamplerConfig, types::Payload};
+use source::MetricsSource;
+
+#[cfg(all(test, any(feature = "cpu", feature = "processes")))]
+mod allocations;
+#[cfg(feature = "cpu")]
+pub mod cpu;
+#[cfg(feature = "disks")]
+pub mod disks;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+#[cfg(feature = "sensors")]
+pub mod hwmon;
+#[cfg(feature = "mem")]
+pub mod mem;
+#[cfg(feature = "network")]
+pub mod network;
+#[cfg(feature = "power")]
+pub mod power;
+#[cfg(feature = "processes")]
+pub mod processes;
+#[cfg(feature = "temps")]
+pub mod sensors;
+pub mod source;
+#[cfg(feature = "system")]
+pub mod system;
+#[cfg(all(windows, feature = "temps"))]
+pub mod thermal_zones;
+
+/// Only ask sysinfo for the categories that are compiled in.
+pub fn refresh_kind() -> RefreshKind {
+    let refresh = RefreshKind::new();
+    #[cfg(feature = "cpu")]
+    let refresh = refresh.with_cpu(cpu_refresh_kind());
+    #[cfg(feature = "mem")]
+    let refresh = refresh.with_memory();
+    #[cfg(feature = "processes")]
+    let refresh = refresh
+        .with_processes(process_refresh_kind())
+        .with_users_list();
+    #[cfg(any(feature = "temps", feature = "sensors"))]
+    let refresh = refresh.with_components_list();
+    #[cfg(feature = "disks")]
+    let refresh = refresh.with_disks_list();
+    #[cfg(feature = "network")]
+    let refresh = refresh.with_networks_list();
+    refresh
+}
+
+/// Per-CPU usage is all we report; frequencies are not read.
+pub fn cpu_refresh_kind() -> CpuRefreshKind {
+    CpuRefreshKind::new().with_cpu_usage()
+}
+
+/// Process CPU usage and the owning user; sysinfo always reads memory and
+/// the command line along with them. Disk usage is never reported, so it is
+/// not read. Users are named from the list read when the source is created.
+/// Also used for the server's own process when the processes stream is
+/// compiled out.
+pub fn process_refresh_kind() -> ProcessRefreshKind {
+    ProcessRefreshKind::new().with_cpu().with_user()
+}
+
+/// Samples one stream. The sampler runs each registered collector on a
+/// schedule of its own while the stream has subscribers: it refreshes what
+/// the collector reads, then publishes what it collects.
+pub trait Collector<S: MetricsSource>: Send {
+    /// What the stream publishes.
+    type State: Payload + Debug + Send + 'static;
+
+    /// The stream's name, for the logs.
+    fn name(&self) -> &'static str;
+
+    /// How long to wait between samples; zero samples on every tick.
+    fn period(&self, config: &SamplerConfig) -> Duration;
+
+    /// What to refresh before collecting at `tick`.
+    fn refresh(&self, tick: Instant, config: &SamplerConfig) -> Refresh;
+
+    /// Whether samples are deltas between two refreshes, which makes the
+    /// first refresh after the stream starts a baseline rather than a sample.
+    fn deltas(&self) -> bool {
+        false
+    }
+
+    /// Takes the baseline from a freshly refreshed `source`.
+    fn baseline(&mut self, _source: &S, _now: Instant) {}
+
+    /// The sample at `tick` from a freshly refreshed `source`, `None` to
+    /// publish nothing.
+    fn collect(&mut self, source: &S, tick: Instant, config: &SamplerConfig)
+        -> Option<Self::State>;
+
+    /// Forgets what was read before the stream went idle.
+    fn reset(&mut self) {}
+
+    /// Unchanged samples are not published again for up to this long;
+    /// `None` publishes every one.
+    fn max_silence(&self, _config: &SamplerConfig) -> Option<Duration> {
+        None
+    }
+
+    /// Whether something besides the stream's subscribers wants it
+    /// collected.
+    fn wanted(&self) -> bool {
+        false
+    }
+}
+
+/// What a collector reads. The sampler refreshes each at most once a tick,
+/// however many collectors read it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Refresh {
+    pub cpus: bool,
+    pub memory: bool,
+    pub processes: bool,
+    pub components: bool,
+    pub energy: bool,
+    pub disks: bool,
+    pub networks: bool,
+    pub gpus: bool,
+    pub hwmon: bool,
+}
+
+impl BitOr for Refresh {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self {
+            cpus: self.cpus || other.cpus,
+            memory: self.memory || other.memory,
+            processes: self.processes || other.processes,
+            components: self.components || other.components,
+            energy: self.energy || other.energy,
+            disks: self.disks || other.disks,
+            networks: self.networks || other.networks,
+            gpus: self.gpus || other.gpus,
+            hwmon: self.hwmon || other.hwmon,
+        }
+    }
+}
+
+impl BitOrAssign for Refresh {
+    fn bitor_assign(&mut self, other: Self) {
+        *self = *self | other;
+    }
+}
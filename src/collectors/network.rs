@@ -0,0 +1,190 @@
+//! Network throughput per interface. sysinfo reads the counters, which only
+//! ever go up; the meter turns them into the bytes per second, packets and
+//! errors since the last sample, like the disk I/O rates.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use sysinfo::{NetworkExt, NetworksExt};
+
+use super::{
+    source::{Interface, MetricsSource, NetCounters},
+    Collector, Refresh,
+};
+use crate::{
+    config::SamplerConfig,
+    types::{MemUnit, NetInterface, NetState},
+};
+
+/// Copies what sysinfo read, by interface name.
+pub fn read(networks: &sysinfo::Networks) -> Vec<Interface> {
+    let mut interfaces: Vec<Interface> = networks
+        .iter()
+        .map(|(name, data)| Interface {
+            name: name.clone(),
+            received: NetCounters {
+                bytes: data.total_received(),
+                packets: data.total_packets_received(),
+                errors: data.total_errors_on_received(),
+            },
+            transmitted: NetCounters {
+                bytes: data.total_transmitted(),
+                packets: data.total_packets_transmitted(),
+                errors: data.total_errors_on_transmitted(),
+            },
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+/// Turns successive counter readings into deltas.
+#[derive(Debug, Default)]
+pub struct NetMeter {
+    /// When the counters were last read, and what they were by interface.
+    last: Option<(Instant, HashMap<String, Interface>)>,
+}
+
+impl NetMeter {
+    /// Forgets the last readings, so that the next sample has no deltas
+    /// rather than ones over a pause.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    /// The deltas since the last sample, from a freshly refreshed `source`.
+    pub fn sample(&mut self, source: &impl MetricsSource, now: Instant) -> NetState {
+        let last = self.last.take().filter(|(at, _)| now > *at);
+        let interfaces = source
+            .networks()
+            .iter()
+            .map(|interface| {
+                let previous = last.as_ref().and_then(|(at, interfaces)| {
+                    Some((now - *at, interfaces.get(&interface.name)?))
+                });
+                let Some((elapsed, previous)) = previous else {
+                    return NetInterface {
+                        name: interface.name.clone(),
+                        rx_bytes_per_sec: None,
+                        tx_bytes_per_sec: None,
+                        rx_packets: None,
+                        tx_packets: None,
+                        rx_errors: None,
+                        tx_errors: None,
+                    };
+                };
+                let (rx, tx) = (&interface.received, &interface.transmitted);
+                let (received, transmitted) = (&previous.received, &previous.transmitted);
+                NetInterface {
+                    name: interface.name.clone(),
+                    rx_bytes_per_sec: Some(per_sec(rx.bytes, received.bytes, elapsed)),
+                    tx_bytes_per_sec: Some(per_sec(tx.bytes, transmitted.bytes, elapsed)),
+                    rx_packets: Some(rx.packets.saturating_sub(received.packets)),
+                    tx_packets: Some(tx.packets.saturating_sub(transmitted.packets)),
+                    rx_errors: Some(rx.errors.saturating_sub(received.errors)),
+                    tx_errors: Some(tx.errors.saturating_sub(transmitted.errors)),
+                }
+            })
+            .collect();
+        self.last = Some((
+            now,
+            source
+                .networks()
+                .iter()
+                .map(|interface| (interface.name.clone(), interface.clone()))
+                .collect(),
+        ));
+        NetState {
+            interfaces,
+            unit: MemUnit::Bytes,
+        }
+    }
+}
+
+/// Counters start over when an interface is created again under the same
+/// name, which counts as no traffic rather than a huge rate.
+fn per_sec(current: u64, previous: u64, elapsed: Duration) -> u64 {
+    (current.saturating_sub(previous) as f64 / elapsed.as_secs_f64()) as u64
+}
+
+impl<S: MetricsSource> Collector<S> for NetMeter {
+    type State = NetState;
+
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    fn period(&self, config: &SamplerConfig) -> Duration {
+        config.network_period()
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            networks: true,
+            ..Refresh::default()
+        }
+    }
+
+    fn deltas(&self) -> bool {
+        true
+    }
+
+    fn baseline(&mut self, source: &S, now: Instant) {
+        NetMeter::sample(self, source, now);
+    }
+
+    fn collect(&mut self, source: &S, _tick: Instant, _config: &SamplerConfig) -> Option<NetState> {
+        Some(NetMeter::sample(self, source, Instant::now()))
+    }
+
+    fn reset(&mut self) {
+        NetMeter::reset(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::fake::FakeSource;
+
+    fn interface(name: &str, bytes: u64, packets: u64, errors: u64) -> Interface {
+        let counters = NetCounters {
+            bytes,
+            packets,
+            errors,
+        };
+        Interface {
+            name: name.into(),
+            received: counters,
+            transmitted: counters,
+        }
+    }
+
+    #[test]
+    fn meter_computes_deltas_from_the_second_sample() {
+        let mut source = FakeSource {
+            networks: vec![interface("eth0", 1000, 10, 0)],
+            ..FakeSource::default()
+        };
+        let mut meter = NetMeter::default();
+        let start = Instant::now();
+
+        let first = meter.sample(&source, start);
+        assert_eq!(first.interfaces[0].rx_bytes_per_sec, None);
+
+        source.networks = vec![interface("eth0", 3000, 14, 1), interface("wg0", 500, 5, 0)];
+        let second = meter.sample(&source, start + Duration::from_secs(2));
+        let eth0 = &second.interfaces[0];
+        assert_eq!(eth0.rx_bytes_per_sec, Some(1000));
+        assert_eq!(eth0.tx_packets, Some(4));
+        assert_eq!(eth0.rx_errors, Some(1));
+        assert_eq!(second.interfaces[1].rx_bytes_per_sec, None, "new interface");
+
+        // Recreated, with counters from 0 again.
+        source.networks[0] = interface("eth0", 100, 1, 0);
+        let third = meter.sample(&source, start + Duration::from_secs(3));
+        assert_eq!(third.interfaces[0].rx_bytes_per_sec, Some(0));
+    }
+}
@@ -0,0 +1,358 @@
+//! CPU power from the RAPL energy counters under `/sys/class/powercap`. The
+//! counters only ever go up, so watts are the energy used between two reads
+//! over the time between them. Which zones can be read is found once at
+//! startup: since Linux 5.10 `energy_uj` is only readable by root.
+
+use std::{
+    fs, io,
+    path::Path,
+    sync::Once,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use super::{
+    source::{EnergyZone, MetricsSource},
+    Collector, Refresh,
+};
+use crate::{
+    config::SamplerConfig,
+    types::{PowerState, PowerZone},
+};
+
+const POWERCAP: &str = "/sys/class/powercap";
+
+/// The power part of the `/debug/sensors` response.
+#[derive(Serialize, Debug)]
+pub struct PowerReport {
+    /// Every RAPL zone found, readable or not.
+    zones: Vec<ZoneReport>,
+    /// Why none were found, if so.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ZoneReport {
+    id: String,
+    name: String,
+    readable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A zone as found, with its counter or why it cannot be read.
+struct Probe {
+    id: String,
+    name: String,
+    zone: Result<EnergyZone, String>,
+}
+
+/// Looks for the zones again, as at startup, and reports which can be read.
+/// Blocks.
+pub fn diagnose() -> PowerReport {
+    match probe(Path::new(POWERCAP)) {
+        Ok(probes) => PowerReport {
+            zones: probes
+                .into_iter()
+                .map(|probe| ZoneReport {
+                    id: probe.id,
+                    name: probe.name,
+                    readable: probe.zone.is_ok(),
+                    error: probe.zone.err(),
+                })
+                .collect(),
+            error: None,
+        },
+        Err(err) => PowerReport {
+            zones: vec![],
+            error: Some(err),
+        },
+    }
+}
+
+/// The zones whose counters can be read. Warns once about those that
+/// cannot, rather than on every tick.
+pub fn readable_zones() -> Vec<EnergyZone> {
+    static WARNED: Once = Once::new();
+    let probes = match probe(Path::new(POWERCAP)) {
+        Ok(probes) => probes,
+        Err(err) => {
+            tracing::debug!(%err, "no cpu power readings");
+            return vec![];
+        }
+    };
+    let mut zones = vec![];
+    for probe in probes {
+        match probe.zone {
+            Ok(zone) => zones.push(zone),
+            Err(err) => WARNED.call_once(|| {
+                tracing::warn!(
+                    zone = probe.id,
+                    %err,
+                    "cpu power cannot be read, see /debug/sensors"
+                )
+            }),
+        }
+    }
+    tracing::info!("{} readable rapl energy counters", zones.len());
+    zones
+}
+
+/// Reads the counters again. One that cannot be read keeps its last value.
+pub fn refresh(zones: &mut [EnergyZone]) {
+    for zone in zones {
+        let path = Path::new(POWERCAP).join(&zone.id).join("energy_uj");
+        match read_u64(&path) {
+            Ok(energy_uj) => zone.energy_uj = energy_uj,
+            Err(err) => tracing::debug!(zone = zone.id, %err, "cannot read energy counter"),
+        }
+    }
+}
+
+fn probe(root: &Path) -> Result<Vec<Probe>, String> {
+    let entries = fs::read_dir(root).map_err(|err| format!("{}: {err}", root.display()))?;
+    let mut ids: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        // "intel-rapl" itself is the control type, and AMD's zones are
+        // named the same.
+        .filter(|id| id.starts_with("intel-rapl:"))
+        .collect();
+    ids.sort();
+    Ok(ids
+        .into_iter()
+        .map(|id| {
+            let dir = root.join(&id);
+            let name = fs::read_to_string(dir.join("name"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_default();
+            let zone = read_zone(&dir, &id, &name);
+            Probe { id, name, zone }
+        })
+        .collect())
+}
+
+fn read_zone(dir: &Path, id: &str, name: &str) -> Result<EnergyZone, String> {
+    let read = |file: &str| {
+        read_u64(&dir.join(file)).map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied => format!("{file} is only readable by root"),
+            _ => format!("{file}: {err}"),
+        })
+    };
+    Ok(EnergyZone {
+        id: id.to_string(),
+        name: name.to_string(),
+        max_energy_uj: read("max_energy_range_uj")?,
+        energy_uj: read("energy_uj")?,
+    })
+}
+
+fn read_u64(path: &Path) -> io::Result<u64> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Turns successive counter readings into watts.
+#[derive(Debug, Default)]
+pub struct PowerMeter {
+    last: Option<(Instant, Vec<u64>)>,
+}
+
+impl PowerMeter {
+    /// Forgets the last readings, so that the next sample has no watts
+    /// rather than an average over a pause.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+
+    /// The watts since the last sample, from a freshly refreshed `source`.
+    pub fn sample(&mut self, source: &impl MetricsSource, now: Instant) -> PowerState {
+        let zones = source.energy_zones();
+        let last = self.last.take().filter(|(at, energy)| {
+            energy.len() == zones.len() && now.saturating_duration_since(*at) > Duration::ZERO
+        });
+        let zones: Vec<PowerZone> = zones
+            .iter()
+            .enumerate()
+            .map(|(index, zone)| PowerZone {
+                id: zone.id.clone(),
+                name: zone.name.clone(),
+                watts: last.as_ref().map(|(at, energy)| {
+                    let joules = energy_delta(energy[index], zone.energy_uj, zone.max_energy_uj)
+                        as f64
+                        / 1e6;
+                    (joules / (now - *at).as_secs_f64()) as f32
+                }),
+            })
+            .collect();
+        self.last = Some((
+            now,
+            source
+                .energy_zones()
+                .iter()
+                .map(|zone| zone.energy_uj)
+                .collect(),
+        ));
+        PowerState {
+            package_watts: package_watts(&zones),
+            zones,
+        }
+    }
+}
+
+/// Summed over the top level "package-N" zones; their subzones and "psys",
+/// which covers the whole platform, would count some of it twice.
+fn package_watts(zones: &[PowerZone]) -> Option<f32> {
+    let mut packages = zones
+        .iter()
+        .filter(|zone| zone.name.starts_with("package") && zone.id.matches(':').count() == 1)
+        .peekable();
+    packages.peek()?;
+    packages.map(|zone| zone.watts).sum()
+}
+
+/// The µJ used between two readings of a counter that wraps around to 0
+/// past `max`.
+fn energy_delta(previous: u64, current: u64, max: u64) -> u64 {
+    if current >= previous {
+        current - previous
+    } else {
+        max.saturating_sub(previous) + current
+    }
+}
+
+/// The power stream, sampled on every tick like the CPUs.
+impl<S: MetricsSource> Collector<S> for PowerMeter {
+    type State = PowerState;
+
+    fn name(&self) -> &'static str {
+        "power"
+    }
+
+    fn period(&self, _config: &SamplerConfig) -> Duration {
+        Duration::ZERO
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            energy: true,
+            ..Refresh::default()
+        }
+    }
+
+    fn deltas(&self) -> bool {
+        true
+    }
+
+    fn baseline(&mut self, source: &S, now: Instant) {
+        PowerMeter::sample(self, source, now);
+    }
+
+    fn collect(
+        &mut self,
+        source: &S,
+        _tick: Instant,
+        _config: &SamplerConfig,
+    ) -> Option<PowerState> {
+        Some(PowerMeter::sample(self, source, Instant::now()))
+    }
+
+    fn reset(&mut self) {
+        PowerMeter::reset(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::fake::FakeSource;
+
+    fn zone(id: &str, name: &str, energy_uj: u64) -> EnergyZone {
+        EnergyZone {
+            id: id.into(),
+            name: name.into(),
+            energy_uj,
+            max_energy_uj: 1_000_000,
+        }
+    }
+
+    #[test]
+    fn energy_wraps_around_at_max() {
+        assert_eq!(energy_delta(100, 300, 1_000), 200);
+        assert_eq!(energy_delta(900, 100, 1_000), 200);
+    }
+
+    #[test]
+    fn meter_sums_packages_from_the_second_sample() {
+        let mut source = FakeSource {
+            energy: vec![
+                zone("intel-rapl:0", "package-0", 0),
+                zone("intel-rapl:0:0", "core", 0),
+                zone("intel-rapl:1", "package-1", 900_000),
+                zone("intel-rapl:2", "psys", 0),
+            ],
+            ..FakeSource::default()
+        };
+        let mut meter = PowerMeter::default();
+        let start = Instant::now();
+
+        let first = meter.sample(&source, start);
+        assert_eq!(first.package_watts, None);
+        assert!(first.zones.iter().all(|zone| zone.watts.is_none()));
+
+        source.energy[0].energy_uj = 500_000;
+        source.energy[1].energy_uj = 300_000;
+        source.energy[2].energy_uj = 100_000; // wrapped around
+        source.energy[3].energy_uj = 900_000;
+        let second = meter.sample(&source, start + Duration::from_millis(500));
+        let watts: Vec<_> = second
+            .zones
+            .iter()
+            .map(|zone| zone.watts.unwrap())
+            .collect();
+        for (watts, expected) in watts.into_iter().zip([1., 0.6, 0.4, 1.8]) {
+            assert!((watts - expected).abs() < 1e-4, "{watts} W");
+        }
+        assert!((second.package_watts.unwrap() - 1.4).abs() < 1e-4);
+
+        meter.reset();
+        assert_eq!(meter.sample(&source, start).package_watts, None);
+    }
+
+    #[test]
+    fn probe_reads_zones_and_reports_unreadable_ones() {
+        let root = std::env::temp_dir().join(format!("axact-powercap-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (id, name, energy) in [
+            ("intel-rapl:0", "package-0", Some("12345\n")),
+            ("intel-rapl:0:0", "core", None),
+        ] {
+            let dir = root.join(id);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("name"), format!("{name}\n")).unwrap();
+            fs::write(dir.join("max_energy_range_uj"), "262143328850\n").unwrap();
+            if let Some(energy) = energy {
+                fs::write(dir.join("energy_uj"), energy).unwrap();
+            }
+        }
+        fs::create_dir_all(root.join("intel-rapl")).unwrap();
+
+        let probes = probe(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(probes.len(), 2);
+        let package = EnergyZone {
+            max_energy_uj: 262143328850,
+            ..zone("intel-rapl:0", "package-0", 12345)
+        };
+        assert_eq!(probes[0].zone, Ok(package));
+        assert_eq!(probes[1].name, "core");
+        assert!(probes[1]
+            .zone
+            .as_ref()
+            .unwrap_err()
+            .starts_with("energy_uj:"));
+    }
+}
@@ -0,0 +1,596 @@
+use std::{
+    cmp::Ordering,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use sysinfo::{Pid, PidExt, ProcessExt, System, SystemExt};
+
+use super::{source::MetricsSource, Collector, Refresh};
+use crate::{
+    config::{SamplerConfig, Stream, TopBy},
+    sampler::{Broadcast, ProcessList, ProcessTable},
+    types::ProcessInfo,
+};
+
+/// Everything known about one process, for `GET /processes/:pid`. What the
+/// server is not allowed to read is `null`.
+#[derive(Serialize, Debug)]
+pub struct ProcessDetail {
+    pid: u32,
+    name: String,
+    cmd: Option<Vec<String>>,
+    exe: Option<String>,
+    cwd: Option<String>,
+    /// Only when asked for, as it can contain secrets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    environ: Option<Vec<String>>,
+    status: String,
+    parent: Option<u32>,
+    user_id: Option<String>,
+    /// In seconds since the Unix epoch.
+    start_time: u64,
+    /// In seconds.
+    run_time: u64,
+    /// Percent of one CPU, measured over [`System::MINIMUM_CPU_UPDATE_INTERVAL`].
+    cpu_usage: f32,
+    memory: u64,
+    virtual_memory: u64,
+    open_fds: Option<usize>,
+}
+
+/// Every reported process, ungrouped, for `GET /processes`.
+#[derive(Serialize, Debug)]
+pub struct Table {
+    /// When the processes were refreshed, in ms since the Unix epoch.
+    pub timestamp_ms: u64,
+    pub processes: Vec<ProcessInfo>,
+}
+
+/// What is kept of each process until the top ones are picked, so that only
+/// those get their name copied.
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    pid: Pid,
+    cpu_usage: f32,
+    memory: u64,
+    virtual_memory: u64,
+    instances: usize,
+    self_process: bool,
+}
+
+impl Row {
+    fn usage(&self) -> Usage {
+        (self.cpu_usage, self.memory, self.pid.as_u32())
+    }
+}
+
+/// CPU usage, resident memory and pid, which is all processes are ordered by.
+type Usage = (f32, u64, u32);
+
+/// Busiest by `by` first; the pid keeps the order of equally busy (usually
+/// idle) processes stable from one sample to the next.
+fn busiest_first(by: TopBy, a: Usage, b: Usage) -> Ordering {
+    let busier = match by {
+        TopBy::Cpu => b.0.total_cmp(&a.0),
+        TopBy::Memory => b.1.cmp(&a.1),
+    };
+    busier.then(a.2.cmp(&b.2))
+}
+
+/// Orders `processes` the way the top ones are picked by `by`.
+pub fn sort(processes: &mut [ProcessInfo], by: TopBy) {
+    let usage = |process: &ProcessInfo| (process.cpu_usage, process.memory, process.pid);
+    processes.sort_unstable_by(|a, b| busiest_first(by, usage(a), usage(b)));
+}
+
+/// Reused from one sample to the next so the per-process work allocates
+/// nothing on a large process table.
+#[derive(Debug, Default)]
+pub struct Buffers {
+    rows: Vec<Row>,
+}
+
+/// The `limit` busiest processes by `top_processes_by`; `self_pid` is the
+/// server's own process.
+/// Processes that can no longer be found by pid once listed, having just
+/// exited, are left out.
+pub fn sample(
+    source: &impl MetricsSource,
+    config: &SamplerConfig,
+    self_pid: Option<Pid>,
+    limit: usize,
+    buffers: &mut Buffers,
+) -> Vec<ProcessInfo> {
+    let name = |pid: Pid| source.process(pid).map_or("", |proc| proc.name);
+    let rows = &mut buffers.rows;
+    rows.clear();
+    rows.extend(
+        source
+            .processes()
+            .filter(|proc| config.reports_process(proc.name))
+            .filter(|proc| !(config.exclude_self && Some(proc.pid) == self_pid))
+            .map(|proc| Row {
+                pid: proc.pid,
+                cpu_usage: proc.cpu_usage,
+                memory: proc.memory,
+                virtual_memory: proc.virtual_memory,
+                instances: 1,
+                self_process: Some(proc.pid) == self_pid,
+            }),
+    );
+
+    if config.group_processes {
+        // Merges processes sharing a name into the first of them.
+        rows.sort_unstable_by(|a, b| name(a.pid).cmp(name(b.pid)));
+        rows.dedup_by(|process, group| {
+            if name(process.pid) != name(group.pid) {
+                return false;
+            }
+            group.pid = group.pid.min(process.pid);
+            group.cpu_usage += process.cpu_usage;
+            group.memory += process.memory;
+            group.virtual_memory += process.virtual_memory;
+            group.instances += process.instances;
+            group.self_process |= process.self_process;
+            true
+        });
+    }
+
+    let by = config.top_processes_by;
+    rows.sort_unstable_by(|a, b| busiest_first(by, a.usage(), b.usage()));
+    rows.iter()
+        .filter_map(|row| {
+            let proc = source.process(row.pid)?;
+            Some(ProcessInfo {
+                pid: row.pid.as_u32(),
+                name: proc.name.to_string(),
+                cpu_usage: row.cpu_usage,
+                memory: row.memory,
+                virtual_memory: row.virtual_memory,
+                user: proc.user.map(str::to_string),
+                cmd: proc.cmd.to_vec(),
+                run_time: proc.run_time,
+                instances: config.group_processes.then_some(row.instances),
+                self_process: row.self_process,
+            })
+        })
+        .take(limit)
+        .collect()
+}
+
+/// Every process `process_allow` and `process_deny` let through, in no
+/// particular order.
+pub fn table(source: &impl MetricsSource, config: &SamplerConfig, self_pid: Option<Pid>) -> Table {
+    let processes = source
+        .processes()
+        .filter(|proc| config.reports_process(proc.name))
+        .filter(|proc| !(config.exclude_self && Some(proc.pid) == self_pid))
+        .map(|proc| ProcessInfo {
+            pid: proc.pid.as_u32(),
+            name: proc.name.to_string(),
+            cpu_usage: proc.cpu_usage,
+            memory: proc.memory,
+            virtual_memory: proc.virtual_memory,
+            user: proc.user.map(str::to_string),
+            cmd: proc.cmd.to_vec(),
+            run_time: proc.run_time,
+            instances: None,
+            self_process: Some(proc.pid) == self_pid,
+        })
+        .collect();
+    Table {
+        timestamp_ms: crate::sampler::unix_millis(std::time::SystemTime::now()),
+        processes,
+    }
+}
+
+/// The process stream, cut to `top_processes`, along with the whole list and
+/// the table, which are read from the same refresh.
+pub struct ProcessCollector {
+    self_pid: Option<Pid>,
+    buffers: Buffers,
+    /// The stream's subscribers, to tell whether the top processes are
+    /// wanted besides the list.
+    stream: Arc<Broadcast>,
+    list: ProcessList,
+    table: ProcessTable,
+}
+
+impl ProcessCollector {
+    pub fn new(
+        self_pid: Option<Pid>,
+        stream: Arc<Broadcast>,
+        list: ProcessList,
+        table: ProcessTable,
+    ) -> Self {
+        Self {
+            self_pid,
+            buffers: Buffers::default(),
+            stream,
+            list,
+            table,
+        }
+    }
+}
+
+impl<S: MetricsSource> Collector<S> for ProcessCollector {
+    type State = Vec<ProcessInfo>;
+
+    fn name(&self) -> &'static str {
+        "processes"
+    }
+
+    fn period(&self, config: &SamplerConfig) -> Duration {
+        config.period(Stream::Processes)
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh {
+            processes: true,
+            ..Refresh::default()
+        }
+    }
+
+    fn deltas(&self) -> bool {
+        true
+    }
+
+    fn collect(
+        &mut self,
+        source: &S,
+        _tick: Instant,
+        config: &SamplerConfig,
+    ) -> Option<Vec<ProcessInfo>> {
+        let list_wanted = self.list.receiver_count() > 0;
+        let stream_wanted = self.stream.receiver_count() > 0;
+        let mut top = None;
+        if stream_wanted || list_wanted {
+            let limit = if list_wanted {
+                usize::MAX
+            } else {
+                config.top_processes
+            };
+            let mut processes = sample(source, config, self.self_pid, limit, &mut self.buffers);
+            if list_wanted {
+                self.list.publish(processes.clone());
+                processes.truncate(config.top_processes);
+            }
+            top = stream_wanted.then_some(processes);
+        }
+        if self.table.wanted() {
+            self.table.publish(table(source, config, self.self_pid));
+        }
+        top
+    }
+
+    fn max_silence(&self, config: &SamplerConfig) -> Option<Duration> {
+        config.max_silence(Stream::Processes)
+    }
+
+    fn wanted(&self) -> bool {
+        self.list.receiver_count() > 0 || self.table.wanted()
+    }
+}
+
+/// The processes a connection to the process stream asked for with
+/// `?filter=`: a comma separated list of patterns. A pattern with `*`, `?` or
+/// `[...]` in it is a glob that has to match the whole name, any other one
+/// matches the names that contain it.
+#[derive(Debug, Clone)]
+pub struct NameFilter {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Substring(String),
+    Glob(Vec<Token>),
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `[...]`, or `[!...]` when negated.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl NameFilter {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let patterns = text
+            .split(',')
+            .map(|pattern| {
+                let pattern = pattern.trim();
+                if pattern.is_empty() {
+                    Err("filter has an empty pattern".to_string())
+                } else if pattern.contains(['*', '?', '[']) {
+                    parse_glob(pattern).map(Pattern::Glob)
+                } else {
+                    Ok(Pattern::Substring(pattern.to_string()))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| match pattern {
+            Pattern::Substring(part) => name.contains(part.as_str()),
+            Pattern::Glob(tokens) => glob_matches(tokens, name),
+        })
+    }
+}
+
+fn parse_glob(pattern: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '?' => Token::Any,
+            '*' => Token::Star,
+            '[' => {
+                let mut class: Vec<char> = vec![];
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => class.push(c),
+                        None => return Err(format!("unclosed '[' in pattern {pattern:?}")),
+                    }
+                }
+                let negated = class.first() == Some(&'!');
+                let class = &class[usize::from(negated)..];
+                if class.is_empty() {
+                    return Err(format!("empty '[]' in pattern {pattern:?}"));
+                }
+                let mut ranges = vec![];
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == '-' {
+                        let (low, high) = (class[i], class[i + 2]);
+                        if low > high {
+                            return Err(format!(
+                                "range '{low}-{high}' is reversed in pattern {pattern:?}"
+                            ));
+                        }
+                        ranges.push((low, high));
+                        i += 3;
+                    } else {
+                        ranges.push((class[i], class[i]));
+                        i += 1;
+                    }
+                }
+                Token::Class { negated, ranges }
+            }
+            c => Token::Char(c),
+        });
+    }
+    Ok(tokens)
+}
+
+/// Whether `tokens` match all of `name`. On a mismatch it only backtracks to
+/// the last `*`, so that patterns with many of them stay linear per star.
+fn glob_matches(tokens: &[Token], name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let (mut t, mut n) = (0, 0);
+    // Where the last `*` is in `tokens`, and where in `name` it stops.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match tokens.get(t) {
+            Some(Token::Star) => {
+                star = Some((t, n));
+                t += 1;
+                continue;
+            }
+            Some(Token::Any) => {
+                t += 1;
+                n += 1;
+                continue;
+            }
+            Some(Token::Char(c)) if *c == name[n] => {
+                t += 1;
+                n += 1;
+                continue;
+            }
+            Some(Token::Class { negated, ranges })
+                if ranges
+                    .iter()
+                    .any(|&(low, high)| (low..=high).contains(&name[n]))
+                    != *negated =>
+            {
+                t += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+        // Lets the last `*` take one more character and tries again.
+        let Some((star_t, star_n)) = star else {
+            return false;
+        };
+        star = Some((star_t, star_n + 1));
+        t = star_t + 1;
+        n = star_n + 1;
+    }
+    tokens[t..].iter().all(|token| matches!(token, Token::Star))
+}
+
+/// Looks at `pid` alone, `None` if it does not exist or `config` hides it.
+/// Blocks for a CPU update interval to measure its CPU usage.
+pub fn detail(pid: u32, with_environ: bool, config: &SamplerConfig) -> Option<ProcessDetail> {
+    let pid = Pid::from_u32(pid);
+    let mut sys = System::new();
+    if !sys.refresh_process(pid) || !config.reports_process(sys.process(pid)?.name()) {
+        return None;
+    }
+    std::thread::sleep(System::MINIMUM_CPU_UPDATE_INTERVAL);
+    if !sys.refresh_process(pid) {
+        return None;
+    }
+    let process = sys.process(pid)?;
+
+    let path = |path: &Path| (!path.as_os_str().is_empty()).then(|| path.display().to_string());
+    Some(ProcessDetail {
+        pid: pid.as_u32(),
+        name: process.name().to_string(),
+        cmd: (!process.cmd().is_empty()).then(|| process.cmd().to_vec()),
+        exe: path(process.exe()),
+        cwd: path(process.cwd()),
+        environ: with_environ.then(|| process.environ().to_vec()),
+        status: process.status().to_string(),
+        parent: process.parent().map(Pid::as_u32),
+        user_id: process.user_id().map(|uid| uid.to_string()),
+        start_time: process.start_time(),
+        run_time: process.run_time(),
+        cpu_usage: process.cpu_usage(),
+        memory: process.memory(),
+        virtual_memory: process.virtual_memory(),
+        open_fds: open_fds(pid),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn open_fds(pid: Pid) -> Option<usize> {
+    Some(std::fs::read_dir(format!("/proc/{pid}/fd")).ok()?.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds(_pid: Pid) -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::fake::FakeSource;
+
+    fn names(processes: &[ProcessInfo]) -> Vec<&str> {
+        processes
+            .iter()
+            .map(|process| process.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn warm_samples_allocate_only_for_the_processes_they_report() {
+        let config = SamplerConfig::default();
+        let allocations = |count: u32| {
+            let mut source = FakeSource::default();
+            for pid in 0..count {
+                source.add_process(pid, &format!("worker{pid}"), pid as f32);
+            }
+            let mut buffers = Buffers::default();
+            sample(&source, &config, None, 5, &mut buffers);
+            crate::collectors::allocations::count(|| {
+                sample(&source, &config, None, 5, &mut buffers);
+            })
+        };
+        let few = allocations(100);
+        assert_eq!(allocations(10_000), few);
+        // The names of the five, and the list, which grows once.
+        assert!(few <= 7, "{few} allocations");
+    }
+
+    #[test]
+    fn busiest_first_with_pid_tiebreak() {
+        let mut source = FakeSource::default();
+        source.add_process(30, "idle-b", 0.);
+        source.add_process(10, "busy", 80.);
+        source.add_process(20, "idle-a", 0.);
+        source.add_process(40, "warm", 5.);
+        let config = SamplerConfig::default();
+        let top = sample(&source, &config, None, 3, &mut Buffers::default());
+        assert_eq!(names(&top), ["busy", "warm", "idle-a"]);
+    }
+
+    #[test]
+    fn top_by_memory_with_user_and_command() {
+        let mut source = FakeSource::default();
+        source.add_process(10, "busy", 80.);
+        source.add_process(30, "database", 1.);
+        source.add_process(20, "cache", 5.);
+        source.processes[1].user = Some("postgres".into());
+        source.processes[1].cmd = vec!["database".into(), "--port=5432".into()];
+        let config = SamplerConfig {
+            top_processes_by: TopBy::Memory,
+            ..SamplerConfig::default()
+        };
+        let top = sample(&source, &config, None, 2, &mut Buffers::default());
+        assert_eq!(names(&top), ["database", "cache"]);
+        assert_eq!(top[0].user.as_deref(), Some("postgres"));
+        assert_eq!(top[0].cmd, ["database", "--port=5432"]);
+        assert_eq!(top[0].virtual_memory, 4 * (30 << 20));
+        assert_eq!(top[1].user, None);
+
+        let mut all = sample(&source, &config, None, 10, &mut Buffers::default());
+        sort(&mut all, TopBy::Cpu);
+        assert_eq!(names(&all), ["busy", "cache", "database"]);
+    }
+
+    #[test]
+    fn process_exiting_mid_tick_is_left_out() {
+        let mut source = FakeSource::default();
+        source.add_process(1, "init", 1.);
+        source.add_process(2, "short-lived", 90.);
+        source.add_process(3, "shell", 2.);
+        source.processes[1].exited = true;
+        let config = SamplerConfig::default();
+        let top = sample(&source, &config, None, 2, &mut Buffers::default());
+        // Not reported with an empty name, and its slot goes to the next.
+        assert_eq!(names(&top), ["shell", "init"]);
+    }
+
+    #[test]
+    fn process_gone_by_the_next_tick_is_dropped() {
+        let mut source = FakeSource::default();
+        source.add_process(1, "init", 1.);
+        source.add_process(2, "make", 50.);
+        let config = SamplerConfig::default();
+        let mut buffers = Buffers::default();
+        assert_eq!(
+            names(&sample(&source, &config, None, 10, &mut buffers)),
+            ["make", "init"]
+        );
+
+        source.processes.retain(|process| process.pid != 2);
+        source.refresh_processes();
+        assert_eq!(
+            names(&sample(&source, &config, None, 10, &mut buffers)),
+            ["init"]
+        );
+    }
+
+    #[test]
+    fn grouping_sums_processes_sharing_a_name() {
+        let mut source = FakeSource::default();
+        source.add_process(12, "worker", 10.);
+        source.add_process(11, "worker", 15.);
+        source.add_process(5, "server", 20.);
+        let config = SamplerConfig {
+            group_processes: true,
+            ..SamplerConfig::default()
+        };
+        let top = sample(
+            &source,
+            &config,
+            Some(Pid::from_u32(5)),
+            10,
+            &mut Buffers::default(),
+        );
+        assert_eq!(names(&top), ["worker", "server"]);
+        assert_eq!(
+            (top[0].pid, top[0].cpu_usage, top[0].instances),
+            (11, 25., Some(2))
+        );
+        assert_eq!(top[0].memory, (11 << 20) + (12 << 20));
+        assert!(top[1].self_process);
+    }
+}
@@ -0,0 +1,570 @@
+//! Finds the CPU temperature sensors among the source's components. Which
+//! labels to look for depends on the hwmon driver, so it is detected once at
+//! startup. `sampler.sensors` can name them instead, for hardware the
+//! detection does not know.
+
+use std::sync::Once;
+
+use serde::Serialize;
+use sysinfo::{CpuRefreshKind, RefreshKind};
+
+use super::source::{MetricsSource, SysinfoSource};
+use crate::config::{CoreIndex, SensorMapping};
+
+#[cfg(feature = "core_temp")]
+use crate::types::CpuCore;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    /// Intel: "coretemp Package id 0", "coretemp Core N".
+    Coretemp,
+    /// AMD: "k10temp Tctl", "k10temp Tdie", "k10temp TccdN".
+    K10temp,
+    /// AMD through the out-of-tree zenpower driver, same labels as k10temp.
+    Zenpower,
+    /// Anything else, e.g. "cpu_thermal" on ARM boards.
+    Other,
+}
+
+pub struct CpuSensors {
+    driver: Driver,
+    /// What was configured, over what was detected.
+    mapping: SensorMapping,
+    /// The per-core sensor each logical CPU reads: its physical core number
+    /// on Intel, its CCD (0-based, as in Tccd1 = 0) on AMD, whatever
+    /// `core_index` says when configured.
+    sensor_of_cpu: Vec<Option<usize>>,
+    map: ComponentMap,
+    missing_package: Once,
+}
+
+/// The `/debug/sensors` response.
+#[derive(Serialize, Debug)]
+pub struct SensorsReport {
+    driver: Driver,
+    /// Whether `sampler.sensors` picks any of the sensors, rather than the
+    /// detection.
+    configured: bool,
+    /// Whether per-core temperatures are reported, i.e. the server was built
+    /// with the `core_temp` feature. They are mapped either way.
+    per_core: bool,
+    /// Every component sysinfo found, in its order.
+    components: Vec<ComponentReport>,
+    /// Logical CPUs without a temperature sensor.
+    unmapped_cpus: Vec<usize>,
+    /// Why no sensor can be read at all, e.g. on Windows without
+    /// administrator rights.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unavailable: Option<String>,
+    /// Which RAPL energy counters CPU power is read from.
+    #[cfg(feature = "power")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power: Option<super::power::PowerReport>,
+}
+
+#[derive(Serialize, Debug)]
+struct ComponentReport {
+    label: String,
+    temperature: f32,
+    /// Whether it counts towards the package temperature.
+    package: bool,
+    /// The logical CPUs that read it.
+    cpus: Vec<usize>,
+}
+
+/// Detects the sensors from scratch, as at startup, and reports the result.
+/// Blocks.
+pub fn diagnose(mapping: &SensorMapping) -> SensorsReport {
+    let source = SysinfoSource::with_specifics(
+        RefreshKind::new()
+            .with_cpu(CpuRefreshKind::new())
+            .with_components_list(),
+    );
+    let mut report = CpuSensors::with_mapping(&source, mapping).report(&source);
+    report.unavailable = source.sensors_unavailable();
+    #[cfg(feature = "power")]
+    {
+        report.power = Some(super::power::diagnose());
+    }
+    report
+}
+
+/// Where in the source's component list the readings are, so that a tick
+/// only has to index into it. The list keeps its order as long as its length
+/// stays the same.
+#[derive(Debug, Default)]
+struct ComponentMap {
+    /// How many components there were when the map was built.
+    components: usize,
+    /// The package temperature is the hottest of these.
+    package: Vec<usize>,
+    /// The component of each logical CPU.
+    cpus: Vec<Option<usize>>,
+}
+
+impl CpuSensors {
+    /// Detects every sensor.
+    pub fn detect(source: &impl MetricsSource) -> Self {
+        Self::with_mapping(source, &SensorMapping::default())
+    }
+
+    /// Reads the sensors `mapping` names, and detects the others.
+    pub fn with_mapping(source: &impl MetricsSource, mapping: &SensorMapping) -> Self {
+        let driver = source
+            .components()
+            .iter()
+            .find_map(|component| driver_of(&component.label))
+            .unwrap_or(Driver::Other);
+
+        let ccds = source
+            .components()
+            .iter()
+            .filter_map(|component| ccd_index(&component.label))
+            .max()
+            .map_or(0, |max| max + 1);
+        let cpus = source.cpu_cores().len();
+        let sensor_of_cpu = match (&mapping.core, driver) {
+            (Some(_), _) => match mapping.core_index {
+                CoreIndex::Core => core_topology(source, cpus),
+                CoreIndex::Cpu => (0..cpus).map(Some).collect(),
+            },
+            (None, Driver::Coretemp) => core_topology(source, cpus),
+            (None, Driver::K10temp | Driver::Zenpower) if ccds > 0 => {
+                ccd_topology(source, cpus, ccds)
+            }
+            _ => vec![None; cpus],
+        };
+
+        let mut sensors = Self {
+            driver,
+            mapping: mapping.clone(),
+            sensor_of_cpu,
+            map: ComponentMap::default(),
+            missing_package: Once::new(),
+        };
+        sensors.map = sensors.map_components(source);
+        let package: Vec<&str> = sensors
+            .map
+            .package
+            .iter()
+            .map(|&index| source.components()[index].label.as_str())
+            .collect();
+        tracing::info!(
+            ?driver,
+            ccds,
+            configured = sensors.is_configured(),
+            "mapped {}/{cpus} cpu core sensors, package sensor: {}",
+            sensors.map.cpus.iter().flatten().count(),
+            if package.is_empty() {
+                "none".to_string()
+            } else {
+                package.join(", ")
+            },
+        );
+        sensors
+    }
+
+    /// What the sensors were picked with.
+    pub fn mapping(&self) -> &SensorMapping {
+        &self.mapping
+    }
+
+    fn is_configured(&self) -> bool {
+        !self.mapping.package.is_empty() || self.mapping.core.is_some()
+    }
+
+    /// How every component was mapped, for `/debug/sensors`.
+    pub fn report(&self, source: &impl MetricsSource) -> SensorsReport {
+        let components = source
+            .components()
+            .iter()
+            .enumerate()
+            .map(|(index, component)| ComponentReport {
+                label: component.label.clone(),
+                temperature: component.temperature,
+                package: self.map.package.contains(&index),
+                cpus: (0..self.map.cpus.len())
+                    .filter(|&cpu| self.map.cpus[cpu] == Some(index))
+                    .collect(),
+            })
+            .collect();
+        SensorsReport {
+            driver: self.driver,
+            configured: self.is_configured(),
+            per_core: cfg!(feature = "core_temp"),
+            components,
+            unmapped_cpus: (0..self.map.cpus.len())
+                .filter(|&cpu| self.map.cpus[cpu].is_none())
+                .collect(),
+            unavailable: None,
+            #[cfg(feature = "power")]
+            power: None,
+        }
+    }
+
+    /// Maps the components again if their number changed since they were
+    /// last mapped.
+    pub fn update(&mut self, source: &impl MetricsSource) {
+        if source.components().len() != self.map.components {
+            self.map = self.map_components(source);
+            tracing::debug!(
+                components = self.map.components,
+                "cpu temperature sensors remapped"
+            );
+        }
+    }
+
+    fn map_components(&self, source: &impl MetricsSource) -> ComponentMap {
+        let components = source.components();
+        let labels = components.iter().map(|component| component.label.as_str());
+
+        let patterns = &self.mapping.package;
+        let package = match self.driver {
+            _ if !patterns.is_empty() => labels
+                .clone()
+                .enumerate()
+                .filter(|(_, label)| patterns.iter().any(|pattern| pattern.is_match(label)))
+                .map(|(index, _)| index)
+                .collect(),
+            Driver::Coretemp | Driver::Other => package_sensors(labels.clone()),
+            Driver::K10temp | Driver::Zenpower => {
+                let find = |sensor: &str| {
+                    labels
+                        .clone()
+                        .position(|label| amd_sensor(label) == Some(sensor))
+                };
+                find("Tdie").or_else(|| find("Tctl")).into_iter().collect()
+            }
+        };
+        if package.is_empty() {
+            self.missing_package.call_once(|| {
+                let labels: Vec<&str> = labels.clone().collect();
+                tracing::warn!(?labels, "no cpu package temperature sensor among these");
+            });
+        }
+
+        let detected: fn(&str) -> Option<usize> = match self.driver {
+            #[cfg(feature = "core_temp")]
+            Driver::Coretemp => coretemp_core_index,
+            Driver::K10temp | Driver::Zenpower => ccd_index,
+            _ => |_| None,
+        };
+        let sensor_index = |label| match &self.mapping.core {
+            Some(pattern) => pattern.captured_number(label),
+            None => detected(label),
+        };
+        let mut sensor_components = vec![];
+        for (component, label) in labels.enumerate() {
+            let Some(sensor) = sensor_index(label) else {
+                continue;
+            };
+            if sensor_components.len() <= sensor {
+                sensor_components.resize(sensor + 1, None);
+            }
+            // On multi-socket machines core numbers repeat; keep the first.
+            sensor_components[sensor].get_or_insert(component);
+        }
+        let cpus = self
+            .sensor_of_cpu
+            .iter()
+            .map(|sensor| sensor_components.get((*sensor)?).copied().flatten())
+            .collect();
+
+        ComponentMap {
+            components: components.len(),
+            package,
+            cpus,
+        }
+    }
+
+    /// The package temperature, if there is a suitable sensor.
+    pub fn package_temp(&self, source: &impl MetricsSource) -> Option<f32> {
+        let components = source.components();
+        self.map
+            .package
+            .iter()
+            .filter_map(|&index| components.get(index))
+            .map(|component| component.temperature)
+            .reduce(f32::max)
+    }
+
+    /// Sets the temperature of each logical CPU: the sensor of its physical
+    /// core on Intel, of its CCD on AMD, `None` when there is no matching
+    /// sensor.
+    #[cfg(feature = "core_temp")]
+    pub fn core_temps(&self, source: &impl MetricsSource, cores: &mut [CpuCore]) {
+        let components = source.components();
+        for core in cores {
+            core.temp = self
+                .map
+                .cpus
+                .get(core.id)
+                .copied()
+                .flatten()
+                .and_then(|index| components.get(index))
+                .map(|component| component.temperature);
+        }
+    }
+}
+
+/// Finds the package temperature sensors among the component `labels` on
+/// everything but AMD, returning their indices. Tries the sensor kinds in
+/// this order:
+///
+/// - Linux: the last "coretemp Package id N", or "cpu_thermal" or
+///   "soc_thermal" (ARM boards).
+/// - Intel Macs: "PECI CPU", else "CPU Proximity".
+/// - Apple Silicon: every "PMU tdie" die sensor, else every performance core
+///   cluster sensor, of which the hottest counts.
+/// - Windows: every ACPI thermal zone, e.g. `ACPI\ThermalZone\TZ00_0`,
+///   of which the hottest counts.
+fn package_sensors<'a>(labels: impl Iterator<Item = &'a str> + Clone) -> Vec<usize> {
+    let indexed = || labels.clone().enumerate();
+    let last = |matches: &dyn Fn(&str) -> bool| -> Vec<usize> {
+        indexed()
+            .filter(|(_, label)| matches(label))
+            .map(|(index, _)| index)
+            .last()
+            .into_iter()
+            .collect()
+    };
+    let all = |prefix: &str| -> Vec<usize> {
+        indexed()
+            .filter(|(_, label)| label.starts_with(prefix))
+            .map(|(index, _)| index)
+            .collect()
+    };
+
+    [
+        last(&|label| {
+            label.contains("coretemp Package")
+                || label.contains("cpu_thermal")
+                || label.contains("soc_thermal")
+        }),
+        last(&|label| label == "PECI CPU"),
+        last(&|label| label == "CPU Proximity"),
+        all("PMU tdie"),
+        all("pACC MTR Temp Sensor"),
+        all("ACPI\\ThermalZone\\"),
+    ]
+    .into_iter()
+    .find(|sensors| !sensors.is_empty())
+    .unwrap_or_default()
+}
+
+fn driver_of(label: &str) -> Option<Driver> {
+    match label.split_once(' ')?.0 {
+        "coretemp" => Some(Driver::Coretemp),
+        "k10temp" => Some(Driver::K10temp),
+        "zenpower" => Some(Driver::Zenpower),
+        _ => None,
+    }
+}
+
+/// The sensor name of a k10temp/zenpower label, e.g. "Tctl".
+fn amd_sensor(label: &str) -> Option<&str> {
+    label
+        .strip_prefix("k10temp ")
+        .or_else(|| label.strip_prefix("zenpower "))
+}
+
+/// Parses "k10temp Tccd2" into CCD index 1.
+fn ccd_index(label: &str) -> Option<usize> {
+    let number: usize = amd_sensor(label)?.strip_prefix("Tccd")?.parse().ok()?;
+    number.checked_sub(1)
+}
+
+/// Parses the core number out of a label like "coretemp Core 10". The whole
+/// suffix has to be the number, so "Core 1" never matches "Core 10".
+#[cfg(feature = "core_temp")]
+fn coretemp_core_index(label: &str) -> Option<usize> {
+    label.strip_prefix("coretemp Core ")?.trim().parse().ok()
+}
+
+/// The physical core of each logical CPU, so that SMT siblings share their
+/// core's sensor. Falls back to the CPU index where the OS does not say.
+fn core_topology(source: &impl MetricsSource, cpus: usize) -> Vec<Option<usize>> {
+    (0..cpus)
+        .map(|cpu| Some(source.topology(cpu).core_id.unwrap_or(cpu)))
+        .collect()
+}
+
+/// Assigns logical CPUs to CCDs by their shared L3 cache. Each CCD has one
+/// (Zen 3 and later) or two (Zen 2) L3 caches, numbered in CCD order. Gives
+/// up, leaving every CPU unassigned, if the caches do not divide evenly.
+fn ccd_topology(source: &impl MetricsSource, cpus: usize, ccds: usize) -> Vec<Option<usize>> {
+    let l3_ids: Vec<Option<u32>> = (0..cpus)
+        .map(|cpu| source.topology(cpu).l3_cache_id)
+        .collect();
+
+    let mut caches: Vec<u32> = l3_ids.iter().flatten().copied().collect();
+    caches.sort_unstable();
+    caches.dedup();
+    if caches.is_empty() || !caches.len().is_multiple_of(ccds) {
+        return vec![None; cpus];
+    }
+    let caches_per_ccd = caches.len() / ccds;
+
+    l3_ids
+        .into_iter()
+        .map(|id| {
+            let cache = caches.binary_search(&id?).ok()?;
+            Some(cache / caches_per_ccd)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::source::{fake::FakeSource, Topology};
+
+    #[cfg(feature = "core_temp")]
+    #[test]
+    fn coretemp_labels_parse_to_their_whole_core_number() {
+        assert_eq!(coretemp_core_index("coretemp Core 1"), Some(1));
+        assert_eq!(coretemp_core_index("coretemp Core 10"), Some(10));
+        assert_eq!(coretemp_core_index("coretemp Core 1x"), None);
+        assert_eq!(coretemp_core_index("coretemp Core "), None);
+        assert_eq!(coretemp_core_index("coretemp Package id 0"), None);
+    }
+
+    #[cfg(feature = "core_temp")]
+    #[test]
+    fn coretemp_core_10_does_not_collide_with_core_1() {
+        let mut source = FakeSource::with_cpus(&[0.; 12]);
+        source.add_component("coretemp Package id 0", 70.);
+        // Listed with "Core 10" and "Core 11" first, as hwmon can.
+        for core in [10, 11, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9] {
+            source.add_component(&format!("coretemp Core {core}"), 30. + core as f32);
+        }
+        let sensors = CpuSensors::detect(&source);
+        let mut cores = crate::collectors::cpu::state(&source, 12).cores;
+        sensors.core_temps(&source, &mut cores);
+        for core in &cores {
+            assert_eq!(core.temp, Some(30. + core.id as f32), "cpu{}", core.id);
+        }
+    }
+
+    #[cfg(feature = "core_temp")]
+    #[test]
+    fn smt_siblings_share_their_core_sensor() {
+        let mut source = FakeSource::with_cpus(&[0.; 4]);
+        source.topology = [0, 1, 0, 1]
+            .map(|core| Topology {
+                core_id: Some(core),
+                l3_cache_id: None,
+            })
+            .to_vec();
+        source.add_component("coretemp Core 0", 40.);
+        source.add_component("coretemp Core 1", 50.);
+        let sensors = CpuSensors::detect(&source);
+        let mut cores = crate::collectors::cpu::state(&source, 4).cores;
+        sensors.core_temps(&source, &mut cores);
+        let temps: Vec<_> = cores.iter().map(|core| core.temp).collect();
+        assert_eq!(temps, [Some(40.), Some(50.), Some(40.), Some(50.)]);
+    }
+
+    #[test]
+    fn missing_package_sensor_gives_no_package_temp() {
+        let mut source = FakeSource::with_cpus(&[0.; 2]);
+        source.add_component("coretemp Core 0", 45.);
+        source.add_component("coretemp Core 1", 46.);
+        source.add_component("nvme Composite", 38.);
+        let sensors = CpuSensors::detect(&source);
+        assert_eq!(sensors.package_temp(&source), None);
+        assert_eq!(sensors.report(&source).components.len(), 3);
+    }
+
+    #[test]
+    fn amd_package_falls_back_to_tctl() {
+        let mut source = FakeSource::with_cpus(&[0.; 2]);
+        source.add_component("k10temp Tctl", 61.5);
+        source.add_component("k10temp Tccd1", 55.);
+        let sensors = CpuSensors::detect(&source);
+        assert_eq!(sensors.driver, Driver::K10temp);
+        assert_eq!(sensors.package_temp(&source), Some(61.5));
+    }
+
+    #[test]
+    fn package_sensor_appearing_later_is_mapped() {
+        let mut source = FakeSource::with_cpus(&[0.; 2]);
+        source.add_component("coretemp Core 0", 45.);
+        let mut sensors = CpuSensors::detect(&source);
+        assert_eq!(sensors.package_temp(&source), None);
+
+        source.add_component("coretemp Package id 0", 52.);
+        sensors.update(&source);
+        assert_eq!(sensors.package_temp(&source), Some(52.));
+    }
+
+    #[test]
+    fn hottest_acpi_thermal_zone_is_the_package() {
+        let mut source = FakeSource::with_cpus(&[0.; 2]);
+        source.add_component("ACPI\\ThermalZone\\TZ00_0", 27.8);
+        source.add_component("ACPI\\ThermalZone\\CPUZ_0", 51.);
+        let sensors = CpuSensors::detect(&source);
+        assert_eq!(sensors.package_temp(&source), Some(51.));
+        assert_eq!(sensors.report(&source).unmapped_cpus, [0, 1]);
+    }
+
+    #[test]
+    fn configured_package_sensors_replace_the_detected_ones() {
+        let mut source = FakeSource::with_cpus(&[0.; 2]);
+        source.add_component("coretemp Package id 0", 70.);
+        source.add_component("acpitz temp1", 48.);
+        source.add_component("acpitz temp2", 55.);
+        let mapping: SensorMapping = toml::from_str(r#"package = ["^acpitz"]"#).unwrap();
+        let sensors = CpuSensors::with_mapping(&source, &mapping);
+        assert_eq!(sensors.package_temp(&source), Some(55.));
+        assert!(sensors.report(&source).configured);
+    }
+
+    #[cfg(feature = "core_temp")]
+    #[test]
+    fn configured_core_sensors_by_logical_cpu() {
+        let mut source = FakeSource::with_cpus(&[0.; 3]);
+        // SMT siblings, which must not share when numbered by CPU.
+        source.topology = [0, 0, 1]
+            .map(|core| Topology {
+                core_id: Some(core),
+                l3_cache_id: None,
+            })
+            .to_vec();
+        source.add_component("coretemp Core 0", 99.);
+        for cpu in [2, 0, 1] {
+            source.add_component(&format!("cpu{cpu}_thermal"), 40. + cpu as f32);
+        }
+        let mapping: SensorMapping = toml::from_str(
+            r#"
+            core = '^cpu(\d+)_thermal'
+            core_index = "cpu"
+            "#,
+        )
+        .unwrap();
+        let sensors = CpuSensors::with_mapping(&source, &mapping);
+        let mut cores = crate::collectors::cpu::state(&source, 3).cores;
+        sensors.core_temps(&source, &mut cores);
+        let temps: Vec<_> = cores.iter().map(|core| core.temp).collect();
+        assert_eq!(temps, [Some(40.), Some(41.), Some(42.)]);
+    }
+
+    #[test]
+    fn ccds_follow_the_l3_caches() {
+        let mut source = FakeSource::with_cpus(&[0.; 4]);
+        source.topology = [7, 7, 9, 9]
+            .map(|cache| Topology {
+                core_id: None,
+                l3_cache_id: Some(cache),
+            })
+            .to_vec();
+        assert_eq!(
+            ccd_topology(&source, 4, 2),
+            [Some(0), Some(0), Some(1), Some(1)]
+        );
+        // Three caches cannot be split over two CCDs.
+        source.topology[3].l3_cache_id = Some(11);
+        assert_eq!(ccd_topology(&source, 4, 2), [None; 4]);
+    }
+}
@@ -0,0 +1,665 @@
+//! What the collectors read from the system. [`SysinfoSource`] reads it;
+//! tests drive the collectors and the sampler with a `FakeSource` instead.
+#![cfg_attr(
+    not(all(
+        feature = "cpu",
+        feature = "mem",
+        feature = "processes",
+        feature = "temps"
+    )),
+    allow(dead_code)
+)]
+
+use sysinfo::{CpuExt, Pid, ProcessExt, RefreshKind, System, SystemExt, UserExt};
+
+use crate::types::SensorKind;
+
+/// sysinfo has reported memory in KiB in some releases and in bytes in
+/// others; this is the factor from what the pinned version returns to bytes.
+/// Since 0.26 it is bytes.
+const SYSINFO_MEMORY_UNIT: u64 = 1;
+
+/// One logical CPU.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cpu {
+    /// The name the OS gives it, e.g. "cpu3" on Linux.
+    pub name: String,
+    pub usage: f32,
+}
+
+/// Which caches and core a logical CPU shares with others, where the OS
+/// says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Topology {
+    /// The physical core, shared by SMT siblings.
+    pub core_id: Option<usize>,
+    pub l3_cache_id: Option<u32>,
+}
+
+/// A temperature sensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Component {
+    pub label: String,
+    /// In °C.
+    pub temperature: f32,
+    /// The highest reading since the source was created.
+    pub max: Option<f32>,
+    /// Where the hardware starts protecting itself, if it says.
+    pub critical: Option<f32>,
+}
+
+/// A fan or voltage sensor hwmon lists besides the temperatures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HwmonSensor {
+    pub kind: SensorKind,
+    /// The chip and the sensor's label, e.g. "nct6798 CPU FAN", like the
+    /// components are labelled.
+    pub label: String,
+    /// In RPM or V.
+    pub value: f32,
+    pub max: Option<f32>,
+    pub critical: Option<f32>,
+}
+
+/// A RAPL energy counter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnergyZone {
+    /// The powercap zone, e.g. "intel-rapl:0:0".
+    pub id: String,
+    /// What it measures, e.g. "package-0" or "dram".
+    pub name: String,
+    /// Counts up in µJ and wraps around past `max_energy_uj`.
+    pub energy_uj: u64,
+    pub max_energy_uj: u64,
+}
+
+/// A mounted filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disk {
+    /// The device it is on, e.g. "/dev/sda1".
+    pub name: String,
+    pub mount_point: String,
+    /// e.g. "ext4".
+    pub file_system: String,
+    /// In bytes.
+    pub total: u64,
+    pub available: u64,
+    /// The I/O counters of the device, where the OS has them.
+    pub io: Option<DiskIo>,
+}
+
+/// Bytes read from and written to a device since boot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskIo {
+    pub read: u64,
+    pub written: u64,
+}
+
+/// A network interface and its counters since boot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    /// e.g. "eth0".
+    pub name: String,
+    pub received: NetCounters,
+    pub transmitted: NetCounters,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetCounters {
+    pub bytes: u64,
+    pub packets: u64,
+    pub errors: u64,
+}
+
+/// A graphics card. Readings its driver does not report are `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gpu {
+    /// The model, or for AMD cards the DRM card, e.g. "card0".
+    pub name: String,
+    /// What it was read through: "nvml" or "amdgpu".
+    pub driver: &'static str,
+    /// Percent.
+    pub utilization: Option<f32>,
+    /// In bytes.
+    pub memory_used: Option<u64>,
+    pub memory_total: Option<u64>,
+    /// In °C.
+    pub temp: Option<f32>,
+    pub power_watts: Option<f32>,
+    /// The processes using it, as the driver accounts them; only NVML
+    /// does.
+    pub processes: Vec<GpuProcess>,
+}
+
+/// A process using a GPU.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpuProcess {
+    pub pid: u32,
+    /// In bytes, `None` where the driver does not account it.
+    pub memory_used: Option<u64>,
+    /// Percent of the streaming multiprocessors' time, `None` where the
+    /// driver cannot tell.
+    pub sm_util: Option<f32>,
+}
+
+/// In bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Memory {
+    pub total: u64,
+    pub free: u64,
+    pub available: u64,
+    pub swap_total: u64,
+    pub swap_free: u64,
+}
+
+/// The load averages over 1, 5 and 15 minutes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Load {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Process<'a> {
+    pub pid: Pid,
+    pub name: &'a str,
+    /// Percent of one CPU.
+    pub cpu_usage: f32,
+    /// Resident memory in bytes.
+    pub memory: u64,
+    /// In bytes.
+    pub virtual_memory: u64,
+    /// The name of the user it runs as, if it could be told.
+    pub user: Option<&'a str>,
+    /// The command line, empty where it cannot be read.
+    pub cmd: &'a [String],
+    /// How long it has been running, in seconds.
+    pub run_time: u64,
+}
+
+/// The readings the sampler needs. They only change when refreshed, and
+/// usages are deltas between two refreshes, as with sysinfo.
+pub trait MetricsSource {
+    fn refresh_cpus(&mut self);
+    fn refresh_memory(&mut self);
+    /// Reads every process again, dropping the ones that exited.
+    fn refresh_processes(&mut self);
+    /// Reads `pid` alone again.
+    fn refresh_process(&mut self, pid: Pid);
+    fn refresh_components(&mut self);
+    fn refresh_energy(&mut self);
+    /// Lists the mounted filesystems again, with their space and I/O.
+    fn refresh_disks(&mut self);
+    /// Reads the counters of every network interface, picking up new ones.
+    fn refresh_networks(&mut self);
+    fn refresh_gpus(&mut self);
+    /// Reads the fans and voltages, on Linux.
+    fn refresh_hwmon(&mut self);
+
+    /// Every logical CPU, by index. How many there are can change.
+    fn cpu_cores(&self) -> &[Cpu];
+    fn topology(&self, cpu: usize) -> Topology;
+    /// The temperature sensors, in an order that only changes with their
+    /// number.
+    fn components(&self) -> &[Component];
+    /// The energy counters that could be read when the source was created.
+    fn energy_zones(&self) -> &[EnergyZone];
+    /// The filesystems as of the last refresh, in mount order.
+    fn disks(&self) -> &[Disk];
+    /// The network interfaces as of the last refresh, by name.
+    fn networks(&self) -> &[Interface];
+    /// The GPUs as of the last refresh.
+    fn gpus(&self) -> &[Gpu];
+    /// The fans and voltages as of the last refresh.
+    fn hwmon(&self) -> &[HwmonSensor];
+    fn memory(&self) -> Memory;
+    /// Read when called; Windows has none of its own, sysinfo emulates it.
+    fn load_average(&self) -> Load;
+    /// Read when called.
+    fn uptime_secs(&self) -> u64;
+    fn processes(&self) -> impl Iterator<Item = Process<'_>>;
+    fn process(&self, pid: Pid) -> Option<Process<'_>>;
+}
+
+/// The real system. CPUs and components are copied out of sysinfo on every
+/// refresh, so that they can be handed out as slices; processes are not, as
+/// there are too many of them.
+pub struct SysinfoSource {
+    sys: System,
+    cpus: Vec<Cpu>,
+    components: Vec<Component>,
+    energy: Vec<EnergyZone>,
+    disks: Vec<Disk>,
+    networks: Vec<Interface>,
+    gpus: Vec<Gpu>,
+    hwmon: Vec<HwmonSensor>,
+    #[cfg(feature = "gpu")]
+    gpu_reader: super::gpu::GpuReader,
+    /// Where temperatures are read from on Windows, chosen when the source
+    /// is created; `None` if it was not asked for components.
+    #[cfg(all(windows, feature = "temps"))]
+    thermal_zones:
+        Option<Result<super::thermal_zones::ThermalZones, super::thermal_zones::Unavailable>>,
+}
+
+impl Default for SysinfoSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SysinfoSource {
+    /// Reads what the compiled in collectors need.
+    pub fn new() -> Self {
+        #[cfg_attr(not(any(feature = "power", feature = "gpu")), allow(unused_mut))]
+        let mut source = Self::with_specifics(super::refresh_kind());
+        #[cfg(feature = "power")]
+        {
+            source.energy = super::power::readable_zones();
+        }
+        #[cfg(feature = "gpu")]
+        {
+            source.gpu_reader = super::gpu::GpuReader::open();
+        }
+        source
+    }
+
+    pub fn with_specifics(refresh: RefreshKind) -> Self {
+        #[cfg(all(windows, feature = "temps"))]
+        let thermal_zones = refresh.components_list().then(|| {
+            super::thermal_zones::ThermalZones::connect()
+                .inspect_err(|err| tracing::warn!(%err, "cpu temperatures cannot be read"))
+        });
+        #[cfg(all(windows, feature = "temps"))]
+        let refresh = refresh.without_components_list();
+        let mut source = Self {
+            sys: System::new_with_specifics(refresh),
+            cpus: vec![],
+            components: vec![],
+            energy: vec![],
+            disks: vec![],
+            networks: vec![],
+            gpus: vec![],
+            hwmon: vec![],
+            #[cfg(feature = "gpu")]
+            gpu_reader: super::gpu::GpuReader::default(),
+            #[cfg(all(windows, feature = "temps"))]
+            thermal_zones,
+        };
+        source.copy_cpus();
+        source.copy_components();
+        source
+    }
+
+    fn copy_cpus(&mut self) {
+        let cpus = self.sys.cpus();
+        if cpus.len() != self.cpus.len() {
+            self.cpus = cpus
+                .iter()
+                .map(|cpu| Cpu {
+                    name: cpu.name().to_string(),
+                    usage: cpu.cpu_usage(),
+                })
+                .collect();
+            return;
+        }
+        for (copy, cpu) in self.cpus.iter_mut().zip(cpus) {
+            copy.usage = cpu.cpu_usage();
+        }
+    }
+
+    /// Why no temperature sensor can be read at all, where the platform can
+    /// tell.
+    pub fn sensors_unavailable(&self) -> Option<String> {
+        #[cfg(all(windows, feature = "temps"))]
+        if let Some(Err(err)) = &self.thermal_zones {
+            return Some(err.to_string());
+        }
+        None
+    }
+
+    #[cfg(all(windows, feature = "temps"))]
+    fn copy_components(&mut self) {
+        let Some(Ok(zones)) = &self.thermal_zones else {
+            return;
+        };
+        match zones.read() {
+            Ok(components) => self.components = components,
+            Err(err) => {
+                tracing::debug!(%err, "cannot read the thermal zones");
+                self.components.clear();
+            }
+        }
+    }
+
+    #[cfg(not(all(windows, feature = "temps")))]
+    fn copy_components(&mut self) {
+        use sysinfo::ComponentExt;
+
+        let components = self.sys.components();
+        if components.len() != self.components.len() {
+            self.components = components
+                .iter()
+                .map(|component| Component {
+                    label: component.label().to_string(),
+                    temperature: component.temperature(),
+                    // NaN until there is a reading.
+                    max: Some(component.max()).filter(|max| !max.is_nan()),
+                    critical: component.critical(),
+                })
+                .collect();
+            return;
+        }
+        for (copy, component) in self.components.iter_mut().zip(components) {
+            copy.temperature = component.temperature();
+            copy.max = Some(component.max()).filter(|max| !max.is_nan());
+        }
+    }
+}
+
+impl MetricsSource for SysinfoSource {
+    fn refresh_cpus(&mut self) {
+        self.sys.refresh_cpu_specifics(super::cpu_refresh_kind());
+        self.copy_cpus();
+    }
+
+    fn refresh_memory(&mut self) {
+        self.sys.refresh_memory();
+    }
+
+    fn refresh_processes(&mut self) {
+        self.sys
+            .refresh_processes_specifics(super::process_refresh_kind());
+    }
+
+    fn refresh_process(&mut self, pid: Pid) {
+        self.sys
+            .refresh_process_specifics(pid, super::process_refresh_kind());
+    }
+
+    fn refresh_components(&mut self) {
+        self.sys.refresh_components();
+        self.copy_components();
+    }
+
+    fn refresh_energy(&mut self) {
+        #[cfg(feature = "power")]
+        super::power::refresh(&mut self.energy);
+    }
+
+    fn refresh_disks(&mut self) {
+        #[cfg(feature = "disks")]
+        {
+            // Rather than `refresh_disks`, to pick up what was mounted since.
+            self.sys.refresh_disks_list();
+            self.disks = super::disks::read(self.sys.disks());
+        }
+    }
+
+    fn refresh_networks(&mut self) {
+        #[cfg(feature = "network")]
+        {
+            self.sys.refresh_networks_list();
+            self.networks = super::network::read(self.sys.networks());
+        }
+    }
+
+    fn refresh_gpus(&mut self) {
+        #[cfg(feature = "gpu")]
+        {
+            self.gpus = self.gpu_reader.read();
+        }
+    }
+
+    fn refresh_hwmon(&mut self) {
+        #[cfg(feature = "sensors")]
+        {
+            self.hwmon = super::hwmon::read();
+        }
+    }
+
+    fn cpu_cores(&self) -> &[Cpu] {
+        &self.cpus
+    }
+
+    fn topology(&self, cpu: usize) -> Topology {
+        let read = |path: &str| {
+            std::fs::read_to_string(format!("/sys/devices/system/cpu/cpu{cpu}/{path}")).ok()
+        };
+        Topology {
+            core_id: read("topology/core_id").and_then(|id| id.trim().parse().ok()),
+            l3_cache_id: read("cache/index3/id").and_then(|id| id.trim().parse().ok()),
+        }
+    }
+
+    fn components(&self) -> &[Component] {
+        &self.components
+    }
+
+    fn energy_zones(&self) -> &[EnergyZone] {
+        &self.energy
+    }
+
+    fn disks(&self) -> &[Disk] {
+        &self.disks
+    }
+
+    fn networks(&self) -> &[Interface] {
+        &self.networks
+    }
+
+    fn gpus(&self) -> &[Gpu] {
+        &self.gpus
+    }
+
+    fn hwmon(&self) -> &[HwmonSensor] {
+        &self.hwmon
+    }
+
+    fn memory(&self) -> Memory {
+        Memory {
+            total: self.sys.total_memory() * SYSINFO_MEMORY_UNIT,
+            free: self.sys.free_memory() * SYSINFO_MEMORY_UNIT,
+            available: self.sys.available_memory() * SYSINFO_MEMORY_UNIT,
+            swap_total: self.sys.total_swap() * SYSINFO_MEMORY_UNIT,
+            swap_free: self.sys.free_swap() * SYSINFO_MEMORY_UNIT,
+        }
+    }
+
+    fn load_average(&self) -> Load {
+        let load = self.sys.load_average();
+        Load {
+            one: load.one,
+            five: load.five,
+            fifteen: load.fifteen,
+        }
+    }
+
+    fn uptime_secs(&self) -> u64 {
+        self.sys.uptime()
+    }
+
+    fn processes(&self) -> impl Iterator<Item = Process<'_>> {
+        self.sys
+            .processes()
+            .values()
+            .map(|proc| process(&self.sys, proc))
+    }
+
+    fn process(&self, pid: Pid) -> Option<Process<'_>> {
+        self.sys.process(pid).map(|proc| process(&self.sys, proc))
+    }
+}
+
+fn process<'a>(sys: &'a System, process: &'a sysinfo::Process) -> Process<'a> {
+    Process {
+        pid: process.pid(),
+        name: process.name(),
+        cpu_usage: process.cpu_usage(),
+        memory: process.memory(),
+        virtual_memory: process.virtual_memory(),
+        user: process
+            .user_id()
+            .and_then(|uid| sys.get_user_by_id(uid))
+            .map(|user| user.name()),
+        cmd: process.cmd(),
+        run_time: process.run_time(),
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use sysinfo::{Pid, PidExt};
+
+    use super::{
+        Component, Cpu, Disk, EnergyZone, Gpu, HwmonSensor, Interface, Load, Memory, MetricsSource,
+        Process, Topology,
+    };
+
+    /// Readings set by the test; refreshing changes nothing.
+    #[derive(Debug, Default)]
+    pub struct FakeSource {
+        pub cpus: Vec<Cpu>,
+        /// By CPU index; CPUs past its end have no known topology.
+        pub topology: Vec<Topology>,
+        pub components: Vec<Component>,
+        pub energy: Vec<EnergyZone>,
+        pub disks: Vec<Disk>,
+        pub networks: Vec<Interface>,
+        pub gpus: Vec<Gpu>,
+        pub hwmon: Vec<HwmonSensor>,
+        pub memory: Memory,
+        pub load: Load,
+        pub uptime_secs: u64,
+        pub processes: Vec<FakeProcess>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct FakeProcess {
+        pub pid: u32,
+        pub name: String,
+        pub cpu_usage: f32,
+        pub memory: u64,
+        pub user: Option<String>,
+        pub cmd: Vec<String>,
+        /// Still listed but no longer found by pid, like a process that
+        /// exits between the two.
+        pub exited: bool,
+    }
+
+    impl FakeSource {
+        /// `usages.len()` CPUs named "cpuN" with these usages.
+        pub fn with_cpus(usages: &[f32]) -> Self {
+            Self {
+                cpus: usages
+                    .iter()
+                    .enumerate()
+                    .map(|(id, &usage)| Cpu {
+                        name: format!("cpu{id}"),
+                        usage,
+                    })
+                    .collect(),
+                ..Self::default()
+            }
+        }
+
+        pub fn add_component(&mut self, label: &str, temperature: f32) {
+            self.components.push(Component {
+                label: label.to_string(),
+                temperature,
+                max: None,
+                critical: None,
+            });
+        }
+
+        pub fn add_process(&mut self, pid: u32, name: &str, cpu_usage: f32) {
+            self.processes.push(FakeProcess {
+                pid,
+                name: name.to_string(),
+                cpu_usage,
+                memory: u64::from(pid) << 20,
+                user: None,
+                cmd: vec![],
+                exited: false,
+            });
+        }
+    }
+
+    impl MetricsSource for FakeSource {
+        fn refresh_cpus(&mut self) {}
+        fn refresh_memory(&mut self) {}
+        fn refresh_processes(&mut self) {}
+        fn refresh_process(&mut self, _pid: Pid) {}
+        fn refresh_components(&mut self) {}
+        fn refresh_energy(&mut self) {}
+        fn refresh_disks(&mut self) {}
+        fn refresh_networks(&mut self) {}
+        fn refresh_gpus(&mut self) {}
+        fn refresh_hwmon(&mut self) {}
+
+        fn cpu_cores(&self) -> &[Cpu] {
+            &self.cpus
+        }
+
+        fn topology(&self, cpu: usize) -> Topology {
+            self.topology.get(cpu).copied().unwrap_or_default()
+        }
+
+        fn components(&self) -> &[Component] {
+            &self.components
+        }
+
+        fn energy_zones(&self) -> &[EnergyZone] {
+            &self.energy
+        }
+
+        fn disks(&self) -> &[Disk] {
+            &self.disks
+        }
+
+        fn networks(&self) -> &[Interface] {
+            &self.networks
+        }
+
+        fn gpus(&self) -> &[Gpu] {
+            &self.gpus
+        }
+
+        fn hwmon(&self) -> &[HwmonSensor] {
+            &self.hwmon
+        }
+
+        fn memory(&self) -> Memory {
+            self.memory
+        }
+
+        fn load_average(&self) -> Load {
+            self.load
+        }
+
+        fn uptime_secs(&self) -> u64 {
+            self.uptime_secs
+        }
+
+        fn processes(&self) -> impl Iterator<Item = Process<'_>> {
+            self.processes.iter().map(|process| Process {
+                pid: Pid::from_u32(process.pid),
+                name: &process.name,
+                cpu_usage: process.cpu_usage,
+                memory: process.memory,
+                virtual_memory: process.memory * 4,
+                user: process.user.as_deref(),
+                cmd: &process.cmd,
+                run_time: u64::from(process.pid),
+            })
+        }
+
+        fn process(&self, pid: Pid) -> Option<Process<'_>> {
+            self.processes()
+                .zip(&self.processes)
+                .find(|(process, fake)| process.pid == pid && !fake.exited)
+                .map(|(process, _)| process)
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! The load averages and the uptime. Both are read when sampled, so there
+//! is nothing to refresh.
+
+use std::time::{Duration, Instant};
+
+use super::{source::MetricsSource, Collector, Refresh};
+use crate::{
+    config::SamplerConfig,
+    types::{LoadAverage, SystemState},
+};
+
+pub fn sample(source: &impl MetricsSource) -> SystemState {
+    let load = source.load_average();
+    SystemState {
+        load_average: LoadAverage {
+            one: load.one as f32,
+            five: load.five as f32,
+            fifteen: load.fifteen as f32,
+        },
+        uptime_secs: source.uptime_secs(),
+    }
+}
+
+/// The system stream.
+#[derive(Debug, Default)]
+pub struct SystemCollector;
+
+impl<S: MetricsSource> Collector<S> for SystemCollector {
+    type State = SystemState;
+
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
+    fn period(&self, config: &SamplerConfig) -> Duration {
+        config.system_period()
+    }
+
+    fn refresh(&self, _tick: Instant, _config: &SamplerConfig) -> Refresh {
+        Refresh::default()
+    }
+
+    fn collect(
+        &mut self,
+        source: &S,
+        _tick: Instant,
+        _config: &SamplerConfig,
+    ) -> Option<SystemState> {
+        Some(sample(source))
+    }
+}
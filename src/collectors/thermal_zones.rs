@@ -0,0 +1,319 @@
+//! Windows temperatures: the ACPI thermal zones, read through WMI's
+//! `MSAcpi_ThermalZoneTemperature` class. sysinfo only reads the first zone
+//! and reports 0 °C for it when it cannot, so it is not asked for components
+//! on Windows; these zones take their place in [`super::source::SysinfoSource`].
+//!
+//! The class is only readable by administrators. Whether it can be read is
+//! found out once, when connecting, and reported on `/debug/sensors`.
+
+use std::{cell::Cell, fmt, ops::Deref, ptr::null_mut};
+
+use winapi::{
+    shared::{
+        rpcdce::{
+            RPC_C_AUTHN_LEVEL_CALL, RPC_C_AUTHN_LEVEL_DEFAULT, RPC_C_AUTHN_WINNT, RPC_C_AUTHZ_NONE,
+            RPC_C_IMP_LEVEL_IMPERSONATE,
+        },
+        winerror::{FAILED, HRESULT, RPC_E_CHANGED_MODE, RPC_E_TOO_LATE},
+        wtypes::{BSTR, VT_BSTR, VT_I4},
+        wtypesbase::CLSCTX_INPROC_SERVER,
+    },
+    um::{
+        combaseapi::{CoCreateInstance, CoInitializeEx, CoInitializeSecurity, CoSetProxyBlanket},
+        oaidl::VARIANT,
+        objbase::COINIT_MULTITHREADED,
+        objidl::EOAC_NONE,
+        oleauto::{SysAllocString, SysFreeString, SysStringLen, VariantClear},
+        unknwnbase::IUnknown,
+        wbemcli::{
+            CLSID_WbemLocator, IEnumWbemClassObject, IID_IWbemLocator, IWbemClassObject,
+            IWbemLocator, IWbemServices, WBEM_E_ACCESS_DENIED, WBEM_E_INVALID_CLASS,
+            WBEM_E_NOT_FOUND, WBEM_E_NOT_SUPPORTED, WBEM_FLAG_FORWARD_ONLY,
+            WBEM_FLAG_RETURN_IMMEDIATELY, WBEM_INFINITE,
+        },
+    },
+};
+
+use super::source::Component;
+
+const QUERY: &str = "SELECT InstanceName, CurrentTemperature FROM MSAcpi_ThermalZoneTemperature";
+
+/// A connection to the `ROOT\WMI` namespace, through which the zones are
+/// queried on every refresh.
+pub struct ThermalZones {
+    services: Com<IWbemServices>,
+}
+
+// The WMI objects live in the multithreaded apartment, which every thread
+// joins before using them, see `init_com`.
+unsafe impl Send for ThermalZones {}
+
+/// Why no thermal zone can be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    /// Not running as administrator.
+    AccessDenied,
+    /// The firmware has no thermal zone, or none that reports a temperature,
+    /// as in most virtual machines.
+    NoZones,
+    Wmi {
+        what: &'static str,
+        code: u32,
+    },
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unavailable::AccessDenied => write!(
+                f,
+                "access to MSAcpi_ThermalZoneTemperature denied, run as administrator to read temperatures"
+            ),
+            Unavailable::NoZones => write!(f, "the firmware reports no ACPI thermal zone"),
+            Unavailable::Wmi { what, code } => write!(f, "cannot {what}: WMI error {code:#010x}"),
+        }
+    }
+}
+
+impl ThermalZones {
+    /// Connects to WMI and reads the zones once, so that a missing
+    /// permission shows up at startup rather than on every tick.
+    pub fn connect() -> Result<Self, Unavailable> {
+        init_com()?;
+        let services = unsafe {
+            let mut locator: *mut IWbemLocator = null_mut();
+            check(
+                "create the WMI locator",
+                CoCreateInstance(
+                    &CLSID_WbemLocator,
+                    null_mut(),
+                    CLSCTX_INPROC_SERVER,
+                    &IID_IWbemLocator,
+                    &mut locator as *mut _ as *mut _,
+                ),
+            )?;
+            let locator = Com(locator);
+            let namespace = Bstr::new("ROOT\\WMI");
+            let mut services: *mut IWbemServices = null_mut();
+            check(
+                "connect to ROOT\\WMI",
+                locator.ConnectServer(
+                    namespace.0,
+                    null_mut(),
+                    null_mut(),
+                    null_mut(),
+                    0,
+                    null_mut(),
+                    null_mut(),
+                    &mut services,
+                ),
+            )?;
+            let services = Com(services);
+            check(
+                "set the WMI proxy blanket",
+                CoSetProxyBlanket(
+                    services.0 as *mut IUnknown,
+                    RPC_C_AUTHN_WINNT,
+                    RPC_C_AUTHZ_NONE,
+                    null_mut(),
+                    RPC_C_AUTHN_LEVEL_CALL,
+                    RPC_C_IMP_LEVEL_IMPERSONATE,
+                    null_mut(),
+                    EOAC_NONE,
+                ),
+            )?;
+            services
+        };
+        let zones = Self { services };
+        zones.read()?;
+        Ok(zones)
+    }
+
+    /// Every zone reporting a temperature, labelled with its instance name,
+    /// e.g. `ACPI\ThermalZone\TZ00_0`, in the order WMI lists them.
+    pub fn read(&self) -> Result<Vec<Component>, Unavailable> {
+        init_com()?;
+        let mut zones = vec![];
+        unsafe {
+            let language = Bstr::new("WQL");
+            let query = Bstr::new(QUERY);
+            let mut enumerator: *mut IEnumWbemClassObject = null_mut();
+            check(
+                "query the thermal zones",
+                self.services.ExecQuery(
+                    language.0,
+                    query.0,
+                    (WBEM_FLAG_FORWARD_ONLY | WBEM_FLAG_RETURN_IMMEDIATELY) as _,
+                    null_mut(),
+                    &mut enumerator,
+                ),
+            )?;
+            let enumerator = Com(enumerator);
+            loop {
+                let mut object: *mut IWbemClassObject = null_mut();
+                let mut returned = 0;
+                check(
+                    "read the thermal zones",
+                    enumerator.Next(WBEM_INFINITE as _, 1, &mut object, &mut returned),
+                )?;
+                if returned == 0 {
+                    break;
+                }
+                let object = Com(object);
+                let label = property(&object, "InstanceName").and_then(|value| value.string());
+                let tenths = property(&object, "CurrentTemperature").and_then(|value| value.int());
+                // Zones whose firmware does not implement _TMP report 0 K.
+                if let (Some(label), Some(tenths @ 1..)) = (label, tenths) {
+                    zones.push(Component {
+                        label,
+                        temperature: celsius(tenths),
+                        max: None,
+                        critical: None,
+                    });
+                }
+            }
+        }
+        if zones.is_empty() {
+            return Err(Unavailable::NoZones);
+        }
+        Ok(zones)
+    }
+}
+
+/// ACPI reports temperatures in tenths of a kelvin.
+pub fn celsius(tenths_of_kelvin: u32) -> f32 {
+    tenths_of_kelvin as f32 / 10. - 273.15
+}
+
+thread_local! {
+    static COM_INITIALIZED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Joins the current thread to the multithreaded apartment, once per thread.
+/// The threads are never uninitialized, which keeps the apartment alive for
+/// as long as the process runs.
+fn init_com() -> Result<(), Unavailable> {
+    if COM_INITIALIZED.with(Cell::get) {
+        return Ok(());
+    }
+    unsafe {
+        let result = CoInitializeEx(null_mut(), COINIT_MULTITHREADED);
+        // A thread already in a single-threaded apartment can still call in.
+        if result != RPC_E_CHANGED_MODE {
+            check("initialize COM", result)?;
+        }
+        // Process wide; only the first call succeeds.
+        let result = CoInitializeSecurity(
+            null_mut(),
+            -1,
+            null_mut(),
+            null_mut(),
+            RPC_C_AUTHN_LEVEL_DEFAULT,
+            RPC_C_IMP_LEVEL_IMPERSONATE,
+            null_mut(),
+            EOAC_NONE,
+            null_mut(),
+        );
+        if result != RPC_E_TOO_LATE {
+            check("initialize COM security", result)?;
+        }
+    }
+    COM_INITIALIZED.with(|initialized| initialized.set(true));
+    Ok(())
+}
+
+fn check(what: &'static str, result: HRESULT) -> Result<(), Unavailable> {
+    if !FAILED(result) {
+        return Ok(());
+    }
+    Err(match result as u32 {
+        WBEM_E_ACCESS_DENIED => Unavailable::AccessDenied,
+        WBEM_E_NOT_FOUND | WBEM_E_NOT_SUPPORTED | WBEM_E_INVALID_CLASS => Unavailable::NoZones,
+        code => Unavailable::Wmi { what, code },
+    })
+}
+
+/// A COM interface pointer, released when dropped.
+struct Com<T: Deref<Target = IUnknown>>(*mut T);
+
+impl<T: Deref<Target = IUnknown>> Deref for Com<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.0 }
+    }
+}
+
+impl<T: Deref<Target = IUnknown>> Drop for Com<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.Release();
+        }
+    }
+}
+
+struct Bstr(BSTR);
+
+impl Bstr {
+    fn new(text: &str) -> Self {
+        let wide = wide(text);
+        Self(unsafe { SysAllocString(wide.as_ptr()) })
+    }
+}
+
+impl Drop for Bstr {
+    fn drop(&mut self) {
+        unsafe { SysFreeString(self.0) }
+    }
+}
+
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(Some(0)).collect()
+}
+
+struct Variant(VARIANT);
+
+impl Variant {
+    fn string(&self) -> Option<String> {
+        unsafe {
+            if self.0.n1.n2().vt as u32 != VT_BSTR {
+                return None;
+            }
+            let text = *self.0.n1.n2().n3.bstrVal();
+            let len = SysStringLen(text) as usize;
+            Some(String::from_utf16_lossy(std::slice::from_raw_parts(
+                text, len,
+            )))
+        }
+    }
+
+    /// CIM `uint32` properties come as signed `VT_I4`.
+    fn int(&self) -> Option<u32> {
+        unsafe { (self.0.n1.n2().vt as u32 == VT_I4).then(|| *self.0.n1.n2().n3.lVal() as u32) }
+    }
+}
+
+impl Drop for Variant {
+    fn drop(&mut self) {
+        unsafe {
+            VariantClear(&mut self.0);
+        }
+    }
+}
+
+unsafe fn property(object: &Com<IWbemClassObject>, name: &str) -> Option<Variant> {
+    let mut value = Variant(std::mem::zeroed());
+    let result = object.Get(wide(name).as_ptr(), 0, &mut value.0, null_mut(), null_mut());
+    (!FAILED(result)).then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenths_of_kelvin_become_celsius() {
+        assert!(celsius(2732).abs() < 0.1);
+        assert!((celsius(3132) - 40.).abs() < 0.1);
+    }
+}
@@ -0,0 +1,1445 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use sysinfo::{System, SystemExt};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    alerts::AlertRule,
+    hub::{is_host_name, Remote},
+    types::{Instance, MemMode},
+};
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file to read settings from; re-read on SIGHUP.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, may be given several times. Overrides `bind` from
+    /// the config file.
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Vec<SocketAddr>,
+    /// Listen on this port instead, on every address that is bound.
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// What to do when one of several addresses cannot be bound.
+    #[arg(long, value_enum)]
+    pub bind_failure: Option<BindFailure>,
+    /// If a port is taken, try up to this many following ports instead.
+    #[arg(long, value_name = "N")]
+    pub port_retry: Option<u16>,
+    /// Keep retrying a bind that fails because the address is in use for this
+    /// long, e.g. `10s` or `500ms`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub bind_retry: Option<Duration>,
+    /// How often CPU usage is sampled, e.g. `1s`. Overrides
+    /// `sampler.cpu_interval_ms`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub cpu_interval: Option<Duration>,
+    /// How often memory is sampled. Overrides `sampler.mem_interval_ms`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub mem_interval: Option<Duration>,
+    /// How often processes are sampled. Overrides
+    /// `sampler.process_interval_ms`.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub process_interval: Option<Duration>,
+    /// How many processes the process stream reports. Overrides
+    /// `sampler.top_processes`.
+    #[arg(long, value_name = "N")]
+    pub top_processes: Option<usize>,
+    /// What the process stream picks the top processes by. Overrides
+    /// `sampler.top_processes_by`.
+    #[arg(long, value_name = "KEY")]
+    pub top_processes_by: Option<TopBy>,
+    /// Push every sample to the `/ingest` endpoint of a hub at this URL, e.g.
+    /// `ws://hub:7032/ingest?name=garage-pi`. Overrides `upstream.url`.
+    #[arg(long, value_name = "URL")]
+    pub upstream: Option<String>,
+    /// Allow signalling processes and changing their priority through the
+    /// admin API.
+    #[arg(long)]
+    pub enable_process_control: bool,
+    /// Serve synthetic readings instead of reading the system, for frontend
+    /// development and tests.
+    #[arg(long)]
+    pub simulate: bool,
+    /// Seed the synthetic readings, so that every run streams the same
+    /// samples. Random by default.
+    #[arg(long, value_name = "N", requires = "simulate")]
+    pub simulate_seed: Option<u64>,
+    /// Print one JSON snapshot of these streams to stdout and exit instead of
+    /// serving, e.g. `--once=cpus,ram`. Every stream by default.
+    #[arg(
+        long,
+        value_name = "STREAMS",
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ',',
+        conflicts_with = "simulate"
+    )]
+    pub once: Option<Vec<Stream>>,
+    /// Append every stream message to this file as JSON lines. Overrides
+    /// `metrics_log.path`.
+    #[arg(long, value_name = "PATH")]
+    pub log_metrics: Option<PathBuf>,
+    /// Keep samples in this SQLite database, for `GET /history`. Overrides
+    /// `db.path`.
+    #[arg(long, value_name = "PATH")]
+    pub db: Option<PathBuf>,
+    /// Serve the messages recorded in these `--log-metrics` files, gzipped
+    /// or not, at the pace they were recorded, instead of reading the
+    /// system. Give rotated files oldest first.
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 1..,
+        conflicts_with_all = ["simulate", "once"]
+    )]
+    pub replay: Vec<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Show a live summary of a running server in the terminal.
+    #[cfg(feature = "client")]
+    Client(crate::client::ClientArgs),
+    /// Show a full-screen dashboard of a server, or of this machine without
+    /// one.
+    #[cfg(feature = "tui")]
+    Tui(crate::tui::TuiArgs),
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BindFailure {
+    /// Exit if any address cannot be bound.
+    #[default]
+    Fail,
+    /// Keep serving on the addresses that could be bound.
+    Continue,
+}
+
+/// Everything that can be set in the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<SocketAddr>,
+    pub bind_failure: BindFailure,
+    /// How many following ports to try when a port is already in use.
+    pub port_retry: u16,
+    /// For how long to keep retrying a bind that fails because the address
+    /// is in use or not available yet.
+    pub bind_retry_ms: u64,
+    pub tls: TlsConfig,
+    /// A tracing filter directive such as `info` or `axact=debug`. When unset,
+    /// `RUST_LOG` is used.
+    pub log_level: Option<String>,
+    /// What this instance is called in every stream message and snapshot,
+    /// the host name when unset.
+    pub instance_name: Option<String>,
+    /// Sent along with `instance_name`, to tell instances apart by more
+    /// than their name, e.g. `{ site = "garage", role = "nas" }`.
+    pub labels: BTreeMap<String, String>,
+    /// An admin token, in addition to the ones in `tokens`. When unset,
+    /// `AXACT_ADMIN_TOKEN` is used.
+    pub admin_token: Option<String>,
+    /// Bearer tokens and what they may do. Once one of them is a `read`
+    /// token, reading metrics needs a token as well; otherwise only the
+    /// admin API does.
+    pub tokens: Vec<ApiToken>,
+    /// Whether the admin API may signal processes and change their priority,
+    /// see `POST /processes/:pid/signal` and `POST /processes/:pid/priority`.
+    #[serde(alias = "allow_process_control")]
+    pub process_control: bool,
+    /// The bearer token agents need to push to `/ingest`. When unset,
+    /// `AXACT_INGEST_TOKEN` is used, and without either ingest is disabled.
+    pub ingest_token: Option<String>,
+    /// How many samples each stream buffers for clients that fall behind.
+    /// When a client is further behind than that, it skips the oldest ones
+    /// and continues with the newest; memory use stays bounded either way.
+    pub channel_capacity: usize,
+    pub sampler: SamplerConfig,
+    pub websocket: WebSocketConfig,
+    /// Evaluated by the server, reported on `/alerts` and
+    /// `/realtime/alerts`.
+    pub alerts: Vec<AlertRule>,
+    /// Instances whose streams to re-broadcast, see [`crate::hub`].
+    pub remotes: Vec<Remote>,
+    pub upstream: UpstreamConfig,
+    pub mdns: MdnsConfig,
+    pub access_log: AccessLogConfig,
+    pub smart: SmartConfig,
+    pub kafka: KafkaConfig,
+    pub metrics_log: MetricsLogConfig,
+    pub history: HistoryConfig,
+    pub db: DbConfig,
+    pub share: ShareConfig,
+}
+
+/// A bearer token clients present in the `Authorization` header. On the
+/// read endpoints, a read token may also be given as the `token` query
+/// parameter, or as a WebSocket's first message.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiToken {
+    pub token: String,
+    pub role: Role,
+}
+
+/// Leaves the token out, so that it never ends up in a log.
+impl fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("role", &self.role)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What a token may do; an admin token may do everything a read token may.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// The streams, process lists, alerts and stats.
+    Read,
+    /// Also `/admin/config` and process control.
+    Admin,
+}
+
+/// Signed, expiring URLs for the streams, minted through the admin API;
+/// see [`crate::share`]. Re-read on SIGHUP.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShareConfig {
+    /// The key share URLs are signed with. When unset,
+    /// `AXACT_SHARE_SECRET` is used, and without either sharing is
+    /// disabled. Changing it revokes every share, after `grace_mins`.
+    pub secret: Option<String>,
+    /// A secret being rotated out, to accept for `grace_mins` after it is
+    /// loaded. A secret replaced on SIGHUP is kept that long without it.
+    pub previous_secret: Option<String>,
+    pub grace_mins: u64,
+    /// The longest a share can be minted for.
+    pub max_ttl_mins: u64,
+}
+
+/// Leaves the secrets out, so that they never end up in a log.
+impl fmt::Debug for ShareConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShareConfig")
+            .field("grace_mins", &self.grace_mins)
+            .field("max_ttl_mins", &self.max_ttl_mins)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Settings of the sampler loop that can be changed while it is running.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SamplerConfig {
+    /// How often CPU usage is sampled. Usages are deltas between two
+    /// refreshes, so this cannot be shorter than sysinfo's
+    /// `MINIMUM_CPU_UPDATE_INTERVAL` (200 ms on Linux).
+    pub cpu_interval_ms: u64,
+    pub mem_interval_ms: u64,
+    pub process_interval_ms: u64,
+    /// How often disk space and I/O are sampled.
+    pub disk_interval_ms: u64,
+    /// How often network throughput is sampled.
+    pub network_interval_ms: u64,
+    /// How often the GPUs are read.
+    pub gpu_interval_ms: u64,
+    /// How often every sensor is read for the sensors stream.
+    pub sensor_interval_ms: u64,
+    /// How often the load averages and the uptime are sampled.
+    pub system_interval_ms: u64,
+    /// How often the temperature sensors are read, on the CPU ticks. Raise it
+    /// when lowering `cpu_interval_ms`; temperatures do not change that fast.
+    pub temp_interval_ms: u64,
+    /// Which temperature sensors are the CPU's, where detection gets it
+    /// wrong.
+    pub sensors: SensorMapping,
+    /// How the reported used memory is computed.
+    pub mem_mode: MemMode,
+    pub top_processes: usize,
+    /// What the top processes are picked by. Connections to the process
+    /// stream can ask for the other key with `?sort=`.
+    pub top_processes_by: TopBy,
+    /// The most processes a connection to the process stream can ask for
+    /// with `?top=`.
+    pub max_top_processes: usize,
+    /// Report processes with the same name as one entry, summing their usage,
+    /// before picking the top ones.
+    pub group_processes: bool,
+    /// Leave the server's own process out of the top processes. Its usage is
+    /// still reported on `/stats`.
+    pub exclude_self: bool,
+    /// Only report processes whose name matches one of these regexes, if any
+    /// are given. Applies to every stream and endpoint.
+    pub process_allow: Vec<NamePattern>,
+    /// Never report processes whose name matches one of these regexes, e.g.
+    /// `"^(ssh|gpg)-agent$"`.
+    pub process_deny: Vec<NamePattern>,
+    /// Stop reading the system while no client is subscribed to any stream,
+    /// resuming as soon as one subscribes. `/stats` then shows the server's
+    /// own usage as of the pause.
+    pub pause_when_idle: bool,
+    /// Stretch the sampling interval, from `cpu_interval_ms` up to
+    /// `adaptive_max_interval_ms`, while the server uses more CPU than
+    /// `adaptive_cpu_budget` or has fewer than `adaptive_min_clients`
+    /// subscribers. The interval in use is shown on `/stats`.
+    pub adaptive: bool,
+    pub adaptive_max_interval_ms: u64,
+    /// CPU usage of the server's own process, in percent of one core.
+    pub adaptive_cpu_budget: f32,
+    pub adaptive_min_clients: usize,
+    /// Streams that skip samples equal to the last one they broadcast, e.g.
+    /// `["ram", "processes"]`. They still send one at least every
+    /// `max_silence_ms`, and as soon as more clients are subscribed than at
+    /// their last broadcast.
+    pub skip_unchanged: Vec<Stream>,
+    pub max_silence_ms: u64,
+}
+
+/// One of the realtime streams.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Cpus,
+    Ram,
+    Processes,
+}
+
+impl Stream {
+    /// The last segment of its `/realtime/` endpoint.
+    pub fn name(self) -> &'static str {
+        match self {
+            Stream::Cpus => "cpus",
+            Stream::Ram => "ram",
+            Stream::Processes => "processes",
+        }
+    }
+}
+
+/// What the top processes are the busiest by. Equal ones are ordered by pid,
+/// so that idle processes keep their places from one sample to the next.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TopBy {
+    /// CPU usage.
+    #[default]
+    Cpu,
+    /// Resident memory.
+    Memory,
+}
+
+/// A regex matched against process names or sensor labels, unanchored like
+/// `grep`. Invalid ones are rejected when the config is read.
+#[derive(Debug, Clone)]
+pub struct NamePattern(Regex);
+
+impl NamePattern {
+    pub fn is_match(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+
+    /// The number the first capture group matches in `name`.
+    pub fn captured_number(&self, name: &str) -> Option<usize> {
+        self.0.captures(name)?.get(1)?.as_str().parse().ok()
+    }
+}
+
+impl PartialEq for NamePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Serialize for NamePattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for NamePattern {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Regex::new(&text)
+            .map(NamePattern)
+            .map_err(|err| serde::de::Error::custom(format!("invalid regex {text:?}: {err}")))
+    }
+}
+
+/// The CPU temperature sensors to read instead of the detected ones, matched
+/// against component labels as `/debug/sensors` lists them, e.g.
+/// "k10temp Tctl". Whatever is left unset is detected.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorMapping {
+    /// The package temperature is the hottest of the sensors matching any of
+    /// these, e.g. `["^soc_thermal"]`.
+    pub package: Vec<NamePattern>,
+    /// Per-core sensors. The first capture group is the number of the core
+    /// the sensor is for, e.g. `'^cpu(\d+)_thermal'`.
+    pub core: Option<NamePattern>,
+    /// What that number counts.
+    pub core_index: CoreIndex,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CoreIndex {
+    /// Physical cores, whose SMT siblings share the sensor.
+    #[default]
+    Core,
+    /// Logical CPUs, as in `cores[].id`.
+    Cpu,
+}
+
+/// Keep-alive and encoding settings for the realtime WebSocket sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    /// How often to ping clients.
+    pub ping_interval_ms: u64,
+    /// How long to wait for any frame from the client after a ping.
+    pub pong_timeout_ms: u64,
+    /// How long a single send may block before the client is dropped.
+    pub send_timeout_ms: u64,
+    /// Drop a client that falls behind this many times in a row without
+    /// catching up in between; 0 never drops lagging clients.
+    pub max_lag_streak: u32,
+    /// With delta encoding, send a full message after this many deltas; 0
+    /// only sends the first one in full.
+    pub delta_keyframe_every: u32,
+}
+
+/// Pushing our samples to a hub, see [`crate::upstream`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The hub's `/ingest` endpoint. When unset, nothing is pushed.
+    pub url: Option<String>,
+    /// Sent as a bearer token. When unset, `AXACT_UPSTREAM_TOKEN` is used.
+    pub token: Option<String>,
+    /// The name the hub re-broadcasts us as, unless the URL has a `name`
+    /// parameter. Defaults to the host name.
+    pub name: Option<String>,
+    /// How many messages to hold while the hub cannot be reached. Beyond
+    /// that, the oldest are dropped.
+    pub queue: usize,
+}
+
+/// Serving HTTPS and WSS instead of plain HTTP and WS on every bound
+/// address, with the `tls` feature.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The PEM certificate chain, the server's own certificate first.
+    pub cert_path: Option<PathBuf>,
+    /// The PEM private key of the certificate.
+    pub key_path: Option<PathBuf>,
+}
+
+/// Zeroconf advertisement, with the `mdns` feature; see [`crate::mdns`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MdnsConfig {
+    /// Advertise the server, unless it only listens on loopback addresses.
+    pub advertise: bool,
+    /// The instance name to advertise, by default the host name.
+    pub name: Option<String>,
+}
+
+/// Disk health polling, with the `smart` feature; see [`crate::smart`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmartConfig {
+    /// How often the disks are read, at least a minute. Disks in standby
+    /// are skipped rather than woken up.
+    pub interval_ms: u64,
+    /// The disks to read, e.g. `["/dev/sda", "/dev/nvme0"]`. By default
+    /// every one `smartctl --scan` lists.
+    pub devices: Vec<String>,
+    /// The smartctl program, looked up in `PATH` unless it is a path.
+    pub smartctl: PathBuf,
+}
+
+/// Records of completed WebSocket sessions, see [`crate::access_log`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// Record every session, and log REST requests at the info rather than
+    /// the debug level.
+    pub enabled: bool,
+    /// Append the records to this file as JSON lines instead of logging
+    /// them.
+    pub path: Option<PathBuf>,
+    /// Rotate the file before it grows past this size.
+    pub max_size_mb: u64,
+    /// How many rotated files to keep, `path.1` being the newest.
+    pub keep: u32,
+}
+
+/// Appending every stream message to a local file, see
+/// [`crate::metrics_log`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsLogConfig {
+    /// The file to append to. When unset, nothing is written.
+    pub path: Option<PathBuf>,
+    /// Rotate the file before it grows past this size.
+    pub max_size_mb: u64,
+    /// Also rotate it once it has been written to for this long, e.g. 1440
+    /// for a file a day.
+    pub max_age_mins: Option<u64>,
+    /// How many rotated files to keep, `path.1` being the newest.
+    pub keep: u32,
+    /// Compress rotated files, to `path.1.gz` and so on.
+    pub gzip: bool,
+    pub fsync: Fsync,
+    /// How often `fsync = "interval"` flushes to disk.
+    pub fsync_interval_ms: u64,
+    /// How many lines to hold while the disk is slower than the streams.
+    /// Beyond that, new ones are dropped.
+    pub queue: usize,
+}
+
+/// Recent CPU and memory samples kept for `GET /history/*`, see
+/// [`crate::history`].
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// How far back to keep samples. When 0, none are kept. Otherwise the
+    /// CPU and memory streams are sampled even while no client is
+    /// connected.
+    pub retention_mins: u64,
+}
+
+/// Keeping samples in SQLite for `GET /history`, with the `sqlite` feature;
+/// see [`crate::db`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    /// The database file, created if needed. When unset, nothing is kept.
+    pub path: Option<PathBuf>,
+    /// How often a row is written, averaging the samples taken since the
+    /// last one.
+    pub interval_ms: u64,
+    /// Rows older than this are deleted. When 0, they are kept forever.
+    pub retention_days: u64,
+    /// How many of the top processes each row keeps.
+    pub top_processes: usize,
+}
+
+/// When the metrics log is flushed to disk, rather than whenever the OS
+/// gets to it.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Fsync {
+    /// Leave it to the OS.
+    Never,
+    /// Every `fsync_interval_ms`, on the next line written.
+    #[default]
+    Interval,
+    /// After every line.
+    Always,
+}
+
+/// Producing every stream message to Kafka, with the `kafka` feature; see
+/// [`crate::kafka`].
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaConfig {
+    /// Bootstrap brokers, e.g. `["kafka1:9092"]`. When empty, nothing is
+    /// produced.
+    pub brokers: Vec<String>,
+    /// One topic for every stream, each message with a `kind` field naming
+    /// its stream.
+    pub topic: Option<String>,
+    /// A topic per stream instead, e.g. `{ cpus = "cpu", ram = "ram" }`;
+    /// streams not listed are not produced.
+    pub topics: BTreeMap<String, String>,
+    pub acks: KafkaAcks,
+    pub compression: KafkaCompression,
+    /// How many messages to hold while the brokers cannot take them.
+    /// Beyond that, the oldest are dropped.
+    pub queue: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaAcks {
+    /// Do not wait for the broker.
+    None,
+    /// Wait for the partition leader.
+    Leader,
+    /// Wait for every in-sync replica.
+    #[default]
+    All,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaCompression {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+/// A partial update of a [`SamplerConfig`], as accepted by `PATCH /admin/config`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SamplerConfigPatch {
+    cpu_interval_ms: Option<u64>,
+    mem_interval_ms: Option<u64>,
+    process_interval_ms: Option<u64>,
+    disk_interval_ms: Option<u64>,
+    network_interval_ms: Option<u64>,
+    gpu_interval_ms: Option<u64>,
+    sensor_interval_ms: Option<u64>,
+    system_interval_ms: Option<u64>,
+    temp_interval_ms: Option<u64>,
+    mem_mode: Option<MemMode>,
+    top_processes: Option<usize>,
+    top_processes_by: Option<TopBy>,
+    max_top_processes: Option<usize>,
+    group_processes: Option<bool>,
+    exclude_self: Option<bool>,
+    process_allow: Option<Vec<NamePattern>>,
+    process_deny: Option<Vec<NamePattern>>,
+    pause_when_idle: Option<bool>,
+    adaptive: Option<bool>,
+    adaptive_max_interval_ms: Option<u64>,
+    adaptive_cpu_budget: Option<f32>,
+    adaptive_min_clients: Option<usize>,
+    skip_unchanged: Option<Vec<Stream>>,
+    max_silence_ms: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 7032))],
+            bind_failure: BindFailure::default(),
+            port_retry: 0,
+            bind_retry_ms: 0,
+            tls: TlsConfig::default(),
+            log_level: None,
+            instance_name: None,
+            labels: BTreeMap::new(),
+            admin_token: None,
+            tokens: vec![],
+            process_control: false,
+            ingest_token: None,
+            channel_capacity: 16,
+            sampler: SamplerConfig::default(),
+            websocket: WebSocketConfig::default(),
+            alerts: vec![],
+            remotes: vec![],
+            upstream: UpstreamConfig::default(),
+            mdns: MdnsConfig::default(),
+            access_log: AccessLogConfig::default(),
+            smart: SmartConfig::default(),
+            kafka: KafkaConfig::default(),
+            metrics_log: MetricsLogConfig::default(),
+            history: HistoryConfig::default(),
+            db: DbConfig::default(),
+            share: ShareConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads and validates a config file; nothing is applied if this fails.
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|err| format!("cannot parse {}: {err}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Overrides file settings with the ones given on the command line, which
+    /// can make them invalid: validate afterwards.
+    pub fn apply_args(&mut self, args: &Args) {
+        if !args.bind.is_empty() {
+            self.bind = args.bind.clone();
+        }
+        if let Some(port) = args.port {
+            for addr in &mut self.bind {
+                addr.set_port(port);
+            }
+        }
+        if let Some(bind_failure) = args.bind_failure {
+            self.bind_failure = bind_failure;
+        }
+        if let Some(port_retry) = args.port_retry {
+            self.port_retry = port_retry;
+        }
+        if let Some(bind_retry) = args.bind_retry {
+            self.bind_retry_ms = bind_retry.as_millis() as u64;
+        }
+        if let Some(interval) = args.cpu_interval {
+            self.sampler.cpu_interval_ms = interval.as_millis() as u64;
+            // The adaptive interval starts from it.
+            self.sampler.adaptive_max_interval_ms = self
+                .sampler
+                .adaptive_max_interval_ms
+                .max(self.sampler.cpu_interval_ms);
+        }
+        if let Some(interval) = args.mem_interval {
+            self.sampler.mem_interval_ms = interval.as_millis() as u64;
+        }
+        if let Some(interval) = args.process_interval {
+            self.sampler.process_interval_ms = interval.as_millis() as u64;
+        }
+        if let Some(top_processes) = args.top_processes {
+            self.sampler.top_processes = top_processes;
+        }
+        if let Some(top_processes_by) = args.top_processes_by {
+            self.sampler.top_processes_by = top_processes_by;
+        }
+        if args.enable_process_control {
+            self.process_control = true;
+        }
+        if let Some(upstream) = &args.upstream {
+            self.upstream.url = Some(upstream.clone());
+        }
+        if let Some(path) = &args.log_metrics {
+            self.metrics_log.path = Some(path.clone());
+        }
+        if let Some(path) = &args.db {
+            self.db.path = Some(path.clone());
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bind.is_empty() {
+            return Err("bind must list at least one address".into());
+        }
+        if self.channel_capacity == 0 {
+            return Err("channel_capacity must be greater than 0".into());
+        }
+        if let Some(level) = &self.log_level {
+            EnvFilter::try_new(level)
+                .map_err(|err| format!("invalid log_level {level:?}: {err}"))?;
+        }
+        if self
+            .instance_name
+            .as_ref()
+            .is_some_and(|name| name.is_empty())
+        {
+            return Err("instance_name must not be empty".into());
+        }
+        if let Some(name) = self.labels.keys().find(|name| !is_label_name(name)) {
+            return Err(format!(
+                "label name {name:?} must be letters, digits and underscores, not starting with a digit"
+            ));
+        }
+        for (i, token) in self.tokens.iter().enumerate() {
+            if token.token.is_empty() {
+                return Err(format!("tokens[{i}].token must not be empty"));
+            }
+            if let Some(first) = self.tokens[..i]
+                .iter()
+                .position(|other| other.token == token.token)
+            {
+                return Err(format!("tokens[{i}] is the same token as tokens[{first}]"));
+            }
+        }
+        self.websocket.validate()?;
+        self.sampler.validate()?;
+        for (i, rule) in self.alerts.iter().enumerate() {
+            rule.validate()?;
+            if self.alerts[..i].iter().any(|other| other.name == rule.name) {
+                return Err(format!("alert {:?} is defined twice", rule.name));
+            }
+        }
+        if !self.remotes.is_empty() && !cfg!(feature = "hub") {
+            return Err("remotes need the 'hub' feature, which was compiled out".into());
+        }
+        for (i, remote) in self.remotes.iter().enumerate() {
+            remote.validate()?;
+            if self.remotes[..i]
+                .iter()
+                .any(|other| other.name == remote.name)
+            {
+                return Err(format!("remote {:?} is defined twice", remote.name));
+            }
+        }
+        self.tls.validate()?;
+        self.upstream.validate()?;
+        self.mdns.validate()?;
+        self.access_log.validate()?;
+        self.smart.validate()?;
+        self.kafka.validate()?;
+        self.metrics_log.validate()?;
+        self.history.validate()?;
+        self.db.validate()?;
+        self.share.validate()?;
+        Ok(())
+    }
+
+    pub fn bind_retry(&self) -> Duration {
+        Duration::from_millis(self.bind_retry_ms)
+    }
+
+    /// `instance_name` and `labels`, with the host name for a missing name.
+    pub fn instance(&self) -> Instance {
+        Instance {
+            name: self
+                .instance_name
+                .clone()
+                .or_else(|| System::new().host_name())
+                .unwrap_or_else(|| "axact".into()),
+            labels: self.labels.clone(),
+        }
+    }
+
+    pub fn log_filter(&self) -> EnvFilter {
+        match &self.log_level {
+            Some(level) => EnvFilter::new(level),
+            None => EnvFilter::from_default_env(),
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_ms: 30_000,
+            pong_timeout_ms: 10_000,
+            send_timeout_ms: 10_000,
+            max_lag_streak: 5,
+            delta_keyframe_every: 30,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_millis(self.ping_interval_ms)
+    }
+
+    pub fn pong_timeout(&self) -> Duration {
+        Duration::from_millis(self.pong_timeout_ms)
+    }
+
+    pub fn send_timeout(&self) -> Duration {
+        Duration::from_millis(self.send_timeout_ms)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("websocket.ping_interval_ms", self.ping_interval_ms),
+            ("websocket.pong_timeout_ms", self.pong_timeout_ms),
+            ("websocket.send_timeout_ms", self.send_timeout_ms),
+        ] {
+            if value == 0 {
+                return Err(format!("{name} must be greater than 0"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            advertise: true,
+            name: None,
+        }
+    }
+}
+
+impl MdnsConfig {
+    fn validate(&self) -> Result<(), String> {
+        match &self.name {
+            Some(name) if !is_host_name(name) || name.contains('.') => Err(format!(
+                "mdns.name {name:?} may only contain letters, digits, '-' and '_'"
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TlsConfig {
+    /// The certificate and key paths, `None` to serve plain HTTP.
+    pub fn paths(&self) -> Option<(&Path, &Path)> {
+        Some((self.cert_path.as_deref()?, self.key_path.as_deref()?))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match (&self.cert_path, &self.key_path) {
+            (None, None) => Ok(()),
+            (Some(_), Some(_)) if !cfg!(feature = "tls") => {
+                Err("tls needs the 'tls' feature, which was compiled out".into())
+            }
+            (Some(_), Some(_)) => Ok(()),
+            _ => Err("tls.cert_path and tls.key_path must be set together".into()),
+        }
+    }
+}
+
+impl Default for SmartConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 10 * 60_000,
+            devices: vec![],
+            smartctl: "smartctl".into(),
+        }
+    }
+}
+
+impl SmartConfig {
+    /// SMART reads can wake disks and slow them down, so they are kept
+    /// rare.
+    const MIN_INTERVAL_MS: u64 = 60_000;
+
+    #[cfg(feature = "smart")]
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.interval_ms < Self::MIN_INTERVAL_MS {
+            return Err(format!(
+                "smart.interval_ms must be at least {}",
+                Self::MIN_INTERVAL_MS
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            max_size_mb: 10,
+            keep: 3,
+        }
+    }
+}
+
+impl AccessLogConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.max_size_mb == 0 {
+            return Err("access_log.max_size_mb must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for ShareConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            previous_secret: None,
+            grace_mins: 60,
+            max_ttl_mins: 7 * 24 * 60,
+        }
+    }
+}
+
+impl ShareConfig {
+    /// Shorter secrets would be easier to guess than the signatures.
+    const MIN_SECRET_LEN: usize = 16;
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, secret) in [
+            ("share.secret", &self.secret),
+            ("share.previous_secret", &self.previous_secret),
+        ] {
+            if secret
+                .as_ref()
+                .is_some_and(|secret| secret.len() < Self::MIN_SECRET_LEN)
+            {
+                return Err(format!(
+                    "{name} must be at least {} bytes long",
+                    Self::MIN_SECRET_LEN
+                ));
+            }
+        }
+        if self.max_ttl_mins == 0 {
+            return Err("share.max_ttl_mins must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for MetricsLogConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            max_size_mb: 100,
+            max_age_mins: None,
+            keep: 5,
+            gzip: false,
+            fsync: Fsync::default(),
+            fsync_interval_ms: 5_000,
+            queue: 10_000,
+        }
+    }
+}
+
+impl HistoryConfig {
+    /// The longest retention, so that the samples kept stay in the tens of
+    /// megabytes even on machines with many CPUs.
+    const MAX_RETENTION_MINS: u64 = 24 * 60;
+
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_mins * 60)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.retention_mins > Self::MAX_RETENTION_MINS {
+            return Err(format!(
+                "history.retention_mins must be at most {}",
+                Self::MAX_RETENTION_MINS
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval_ms: 10_000,
+            retention_days: 30,
+            top_processes: 5,
+        }
+    }
+}
+
+impl DbConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    /// `None` to keep rows forever.
+    pub fn retention(&self) -> Option<Duration> {
+        (self.retention_days > 0).then(|| Duration::from_secs(self.retention_days * 24 * 60 * 60))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.path.is_some() && !cfg!(feature = "sqlite") {
+            return Err("db needs the 'sqlite' feature, which was compiled out".into());
+        }
+        if self.interval_ms < 1_000 {
+            return Err("db.interval_ms must be at least 1000".into());
+        }
+        Ok(())
+    }
+}
+
+impl MetricsLogConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.max_size_mb == 0 {
+            return Err("metrics_log.max_size_mb must be greater than 0".into());
+        }
+        if self.max_age_mins == Some(0) {
+            return Err("metrics_log.max_age_mins must be greater than 0".into());
+        }
+        if self.fsync_interval_ms == 0 {
+            return Err("metrics_log.fsync_interval_ms must be greater than 0".into());
+        }
+        if self.queue == 0 {
+            return Err("metrics_log.queue must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            brokers: vec![],
+            topic: None,
+            topics: BTreeMap::new(),
+            acks: KafkaAcks::default(),
+            compression: KafkaCompression::default(),
+            queue: 10_000,
+        }
+    }
+}
+
+impl KafkaConfig {
+    /// What can be produced: the realtime streams and the alert events.
+    pub const STREAMS: [&'static str; 10] = [
+        "cpus",
+        "ram",
+        "processes",
+        "power",
+        "disks",
+        "network",
+        "gpu",
+        "sensors",
+        "system",
+        "alerts",
+    ];
+
+    /// The topic `stream` goes to, `None` if it is not produced.
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    pub fn topic_of(&self, stream: &str) -> Option<&str> {
+        match &self.topic {
+            Some(topic) => Some(topic),
+            None => self.topics.get(stream).map(String::as_str),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.brokers.is_empty() {
+            return Ok(());
+        }
+        if !cfg!(feature = "kafka") {
+            return Err("kafka needs the 'kafka' feature, which was compiled out".into());
+        }
+        match (&self.topic, self.topics.is_empty()) {
+            (Some(_), false) => return Err("kafka.topic and kafka.topics are exclusive".into()),
+            (None, true) => return Err("kafka needs a topic or topics".into()),
+            _ => {}
+        }
+        if let Some(stream) = self
+            .topics
+            .keys()
+            .find(|stream| !Self::STREAMS.contains(&stream.as_str()))
+        {
+            return Err(format!(
+                "kafka.topics: unknown stream {stream:?}, expected one of {}",
+                Self::STREAMS.join(", ")
+            ));
+        }
+        if self
+            .topic
+            .iter()
+            .chain(self.topics.values())
+            .any(String::is_empty)
+        {
+            return Err("kafka topics must not be empty".into());
+        }
+        if self.queue == 0 {
+            return Err("kafka.queue must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            token: None,
+            name: None,
+            queue: 1024,
+        }
+    }
+}
+
+impl UpstreamConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let Some(url) = &self.url else {
+            return Ok(());
+        };
+        if !cfg!(feature = "upstream") {
+            return Err("upstream needs the 'upstream' feature, which was compiled out".into());
+        }
+        if url.starts_with("wss://") {
+            return Err("upstream.url: wss:// is not supported, this build has no TLS".into());
+        }
+        if !url.starts_with("ws://") {
+            return Err("upstream.url must start with ws://".into());
+        }
+        if let Some(name) = &self.name {
+            if !is_host_name(name) {
+                return Err(format!(
+                    "upstream.name {name:?} may only contain letters, digits, '-', '_' and '.'"
+                ));
+            }
+        }
+        if self.queue == 0 {
+            return Err("upstream.queue must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        let cpu_interval = System::MINIMUM_CPU_UPDATE_INTERVAL * 3;
+        Self {
+            cpu_interval_ms: cpu_interval.as_millis() as u64,
+            mem_interval_ms: (cpu_interval * 5).as_millis() as u64,
+            process_interval_ms: (cpu_interval * 5).as_millis() as u64,
+            disk_interval_ms: (cpu_interval * 5).as_millis() as u64,
+            network_interval_ms: (cpu_interval * 2).as_millis() as u64,
+            gpu_interval_ms: (cpu_interval * 2).as_millis() as u64,
+            sensor_interval_ms: (cpu_interval * 2).as_millis() as u64,
+            system_interval_ms: (cpu_interval * 5).as_millis() as u64,
+            temp_interval_ms: cpu_interval.as_millis() as u64,
+            sensors: SensorMapping::default(),
+            mem_mode: MemMode::default(),
+            top_processes: 4,
+            top_processes_by: TopBy::default(),
+            max_top_processes: 100,
+            group_processes: false,
+            exclude_self: false,
+            process_allow: vec![],
+            process_deny: vec![],
+            pause_when_idle: true,
+            adaptive: false,
+            adaptive_max_interval_ms: (cpu_interval * 10).as_millis() as u64,
+            adaptive_cpu_budget: 2.,
+            adaptive_min_clients: 1,
+            skip_unchanged: vec![],
+            max_silence_ms: 30_000,
+        }
+    }
+}
+
+impl SamplerConfig {
+    /// Whether `process_allow` and `process_deny` let the process called
+    /// `name` be seen at all.
+    pub fn reports_process(&self, name: &str) -> bool {
+        let allowed = self.process_allow.is_empty()
+            || self.process_allow.iter().any(|allow| allow.is_match(name));
+        allowed && !self.process_deny.iter().any(|deny| deny.is_match(name))
+    }
+
+    pub fn cpu_interval(&self) -> Duration {
+        Duration::from_millis(self.cpu_interval_ms)
+    }
+
+    pub fn adaptive_max_interval(&self) -> Duration {
+        Duration::from_millis(self.adaptive_max_interval_ms)
+    }
+
+    /// How often `stream` is sampled at most; its own interval only counts
+    /// on the CPU ticks.
+    pub fn period(&self, stream: Stream) -> Duration {
+        let interval_ms = match stream {
+            Stream::Cpus => self.cpu_interval_ms,
+            Stream::Ram => self.mem_interval_ms,
+            Stream::Processes => self.process_interval_ms,
+        };
+        Duration::from_millis(interval_ms).max(self.cpu_interval())
+    }
+
+    /// [`Self::period`] of the disk stream, which is not one of the
+    /// [`Stream`]s as it is not relayed from remotes.
+    pub fn disk_period(&self) -> Duration {
+        Duration::from_millis(self.disk_interval_ms).max(self.cpu_interval())
+    }
+
+    /// [`Self::period`] of the network stream, which is not relayed from
+    /// remotes either.
+    pub fn network_period(&self) -> Duration {
+        Duration::from_millis(self.network_interval_ms).max(self.cpu_interval())
+    }
+
+    /// [`Self::period`] of the GPU stream, which is not relayed from remotes
+    /// either.
+    pub fn gpu_period(&self) -> Duration {
+        Duration::from_millis(self.gpu_interval_ms).max(self.cpu_interval())
+    }
+
+    /// [`Self::period`] of the sensors stream, which is not relayed from
+    /// remotes either.
+    pub fn sensor_period(&self) -> Duration {
+        Duration::from_millis(self.sensor_interval_ms).max(self.cpu_interval())
+    }
+
+    /// [`Self::period`] of the system stream, which is not relayed from
+    /// remotes either.
+    pub fn system_period(&self) -> Duration {
+        Duration::from_millis(self.system_interval_ms).max(self.cpu_interval())
+    }
+
+    /// How long `stream` may go without a broadcast while its samples do not
+    /// change, or `None` if it sends every sample.
+    pub fn max_silence(&self, stream: Stream) -> Option<Duration> {
+        self.skip_unchanged
+            .contains(&stream)
+            .then(|| Duration::from_millis(self.max_silence_ms))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("cpu_interval_ms", self.cpu_interval_ms),
+            ("mem_interval_ms", self.mem_interval_ms),
+            ("process_interval_ms", self.process_interval_ms),
+            ("disk_interval_ms", self.disk_interval_ms),
+            ("network_interval_ms", self.network_interval_ms),
+            ("gpu_interval_ms", self.gpu_interval_ms),
+            ("sensor_interval_ms", self.sensor_interval_ms),
+            ("system_interval_ms", self.system_interval_ms),
+            ("temp_interval_ms", self.temp_interval_ms),
+            ("max_silence_ms", self.max_silence_ms),
+        ] {
+            if value == 0 {
+                return Err(format!("{name} must be greater than 0"));
+            }
+        }
+        if self.cpu_interval() < System::MINIMUM_CPU_UPDATE_INTERVAL {
+            return Err(format!(
+                "cpu_interval_ms must be at least {}, the shortest interval sysinfo can compute usages over",
+                System::MINIMUM_CPU_UPDATE_INTERVAL.as_millis()
+            ));
+        }
+        if self.adaptive_max_interval_ms < self.cpu_interval_ms {
+            return Err("adaptive_max_interval_ms must not be less than cpu_interval_ms".into());
+        }
+        if self.adaptive_cpu_budget.is_nan() || self.adaptive_cpu_budget <= 0. {
+            return Err("adaptive_cpu_budget must be greater than 0".into());
+        }
+        if let Some(core) = &self.sensors.core {
+            if core.0.captures_len() < 2 {
+                return Err("sensors.core needs a capture group for the core number".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a copy with `patch` applied, or why the result is not valid.
+    pub fn patched(&self, patch: SamplerConfigPatch) -> Result<Self, String> {
+        let mut config = self.clone();
+        if let Some(value) = patch.cpu_interval_ms {
+            config.cpu_interval_ms = value;
+        }
+        if let Some(value) = patch.mem_interval_ms {
+            config.mem_interval_ms = value;
+        }
+        if let Some(value) = patch.process_interval_ms {
+            config.process_interval_ms = value;
+        }
+        if let Some(value) = patch.disk_interval_ms {
+            config.disk_interval_ms = value;
+        }
+        if let Some(value) = patch.network_interval_ms {
+            config.network_interval_ms = value;
+        }
+        if let Some(value) = patch.gpu_interval_ms {
+            config.gpu_interval_ms = value;
+        }
+        if let Some(value) = patch.sensor_interval_ms {
+            config.sensor_interval_ms = value;
+        }
+        if let Some(value) = patch.system_interval_ms {
+            config.system_interval_ms = value;
+        }
+        if let Some(value) = patch.temp_interval_ms {
+            config.temp_interval_ms = value;
+        }
+        if let Some(value) = patch.mem_mode {
+            config.mem_mode = value;
+        }
+        if let Some(value) = patch.top_processes {
+            config.top_processes = value;
+        }
+        if let Some(value) = patch.top_processes_by {
+            config.top_processes_by = value;
+        }
+        if let Some(value) = patch.max_top_processes {
+            config.max_top_processes = value;
+        }
+        if let Some(value) = patch.group_processes {
+            config.group_processes = value;
+        }
+        if let Some(value) = patch.exclude_self {
+            config.exclude_self = value;
+        }
+        if let Some(value) = patch.process_allow {
+            config.process_allow = value;
+        }
+        if let Some(value) = patch.process_deny {
+            config.process_deny = value;
+        }
+        if let Some(value) = patch.pause_when_idle {
+            config.pause_when_idle = value;
+        }
+        if let Some(value) = patch.adaptive {
+            config.adaptive = value;
+        }
+        if let Some(value) = patch.adaptive_max_interval_ms {
+            config.adaptive_max_interval_ms = value;
+        }
+        if let Some(value) = patch.adaptive_cpu_budget {
+            config.adaptive_cpu_budget = value;
+        }
+        if let Some(value) = patch.adaptive_min_clients {
+            config.adaptive_min_clients = value;
+        }
+        if let Some(value) = patch.skip_unchanged {
+            config.skip_unchanged = value;
+        }
+        if let Some(value) = patch.max_silence_ms {
+            config.max_silence_ms = value;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Parses a duration with an `ms`, `s`, `m`, `h` or `d` suffix.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit) = text
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or((text, ""), |split| text.split_at(split));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {text:?}, expected e.g. 10s or 500ms"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 60 * 60)),
+        "d" => Ok(Duration::from_secs(number * 24 * 60 * 60)),
+        _ => Err(format!(
+            "invalid duration {text:?}, expected a unit of ms, s, m, h or d"
+        )),
+    }
+}
+
+/// Whether `name` can be used as a label wherever labels end up, which is
+/// narrowest for Prometheus: `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn is_label_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Accepts either a single address or a list of them.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SocketAddr),
+        Many(Vec<SocketAddr>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
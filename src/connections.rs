@@ -0,0 +1,314 @@
+//! The open TCP and UDP sockets, as listed in `/proc/net`, with the process
+//! that owns each. Sockets are matched to processes by inode, through the
+//! `socket:[inode]` links in `/proc/<pid>/fd`; only root can read those of
+//! other users' processes, so their sockets have no pid otherwise. Neither
+//! do the sockets of processes `process_allow` and `process_deny` hide.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::SamplerConfig;
+
+/// The `GET /connections` query.
+#[derive(Deserialize, Debug, Default)]
+pub struct Filter {
+    pub state: Option<SocketState>,
+    pub pid: Option<u32>,
+}
+
+/// The `GET /connections` response.
+#[derive(Serialize, Debug)]
+pub struct ConnectionsReport {
+    pub timestamp_ms: u64,
+    /// How many sockets the filter matched in each state.
+    pub summary: BTreeMap<SocketState, usize>,
+    pub connections: Vec<Socket>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Socket {
+    pub protocol: Protocol,
+    pub local_address: IpAddr,
+    pub local_port: u16,
+    pub remote_address: IpAddr,
+    pub remote_port: u16,
+    pub state: SocketState,
+    pub pid: Option<u32>,
+    /// `None` without a pid, or if the process exited while being read.
+    pub process: Option<String>,
+    /// What `/proc/<pid>/fd` links to, `socket:[inode]`.
+    #[serde(skip)]
+    inode: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// The kernel's TCP states. A UDP socket is `established` once connected
+/// and `close` otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum SocketState {
+    Established,
+    SynSent,
+    SynRecv,
+    FinWait1,
+    FinWait2,
+    TimeWait,
+    Close,
+    CloseWait,
+    LastAck,
+    Listen,
+    Closing,
+    NewSynRecv,
+}
+
+impl SocketState {
+    /// From the `st` column.
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            0x01 => Self::Established,
+            0x02 => Self::SynSent,
+            0x03 => Self::SynRecv,
+            0x04 => Self::FinWait1,
+            0x05 => Self::FinWait2,
+            0x06 => Self::TimeWait,
+            0x07 => Self::Close,
+            0x08 => Self::CloseWait,
+            0x09 => Self::LastAck,
+            0x0a => Self::Listen,
+            0x0b => Self::Closing,
+            0x0c => Self::NewSynRecv,
+            _ => return None,
+        })
+    }
+}
+
+/// Lists the sockets `filter` matches. Reads a file per open descriptor of
+/// every process, so blocks for a while on a busy machine.
+#[cfg(target_os = "linux")]
+pub fn collect(filter: &Filter, config: &SamplerConfig) -> Result<ConnectionsReport, String> {
+    use std::{fs, io, path::Path};
+
+    let mut sockets = vec![];
+    for (file, protocol) in [
+        ("tcp", Protocol::Tcp),
+        ("tcp6", Protocol::Tcp),
+        ("udp", Protocol::Udp),
+        ("udp6", Protocol::Udp),
+    ] {
+        let path = Path::new("/proc/net").join(file);
+        match fs::read_to_string(&path) {
+            Ok(table) => sockets.extend(table.lines().skip(1).filter_map(|line| {
+                parse_line(line, protocol)
+                    .filter(|socket| filter.state.is_none_or(|state| socket.state == state))
+            })),
+            // Without IPv6 there are no tcp6 and udp6.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(format!("cannot read {}: {err}", path.display())),
+        }
+    }
+
+    attribute(&mut sockets, &owners(filter.pid), filter.pid, config);
+
+    let mut summary = BTreeMap::new();
+    for socket in &sockets {
+        *summary.entry(socket.state).or_insert(0) += 1;
+    }
+    Ok(ConnectionsReport {
+        timestamp_ms: crate::sampler::unix_millis(std::time::SystemTime::now()),
+        summary,
+        connections: sockets,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn collect(_: &Filter, _: &SamplerConfig) -> Result<ConnectionsReport, String> {
+    Err("connections can only be listed on Linux".into())
+}
+
+/// Gives each socket the pid and name of its owner in `owners`, unless
+/// `config` hides the owner, then keeps those of `pid` only, if given. A
+/// process whose name could not be read is only shown while no process is
+/// hidden.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn attribute(
+    sockets: &mut Vec<Socket>,
+    owners: &HashMap<u64, (u32, Option<String>)>,
+    pid: Option<u32>,
+    config: &SamplerConfig,
+) {
+    let hides_any = !config.process_allow.is_empty() || !config.process_deny.is_empty();
+    for socket in sockets.iter_mut() {
+        let Some((owner, name)) = owners.get(&socket.inode) else {
+            continue;
+        };
+        let reported = match name {
+            Some(name) => config.reports_process(name),
+            None => !hides_any,
+        };
+        if reported {
+            socket.pid = Some(*owner);
+            socket.process = name.clone();
+        }
+    }
+    if pid.is_some() {
+        sockets.retain(|socket| socket.pid == pid);
+    }
+}
+
+/// The pid and name of the process holding each socket inode, of `pid`
+/// only if given. Processes that cannot be read are left out.
+#[cfg(target_os = "linux")]
+fn owners(pid: Option<u32>) -> HashMap<u64, (u32, Option<String>)> {
+    use std::fs;
+
+    let pids: Vec<u32> = match pid {
+        Some(pid) => vec![pid],
+        None => fs::read_dir("/proc")
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+    };
+    let mut owners = HashMap::new();
+    for pid in pids {
+        let Ok(fds) = fs::read_dir(format!("/proc/{pid}/fd")) else {
+            continue;
+        };
+        let mut name = None;
+        for fd in fds.flatten() {
+            let Some(inode) = fs::read_link(fd.path())
+                .ok()
+                .and_then(|target| socket_inode(target.to_str()?))
+            else {
+                continue;
+            };
+            let name = name.get_or_insert_with(|| {
+                fs::read_to_string(format!("/proc/{pid}/comm"))
+                    .ok()
+                    .map(|comm| comm.trim_end().to_string())
+            });
+            // A socket shared by several processes, e.g. across a fork,
+            // goes to the first.
+            owners.entry(inode).or_insert_with(|| (pid, name.clone()));
+        }
+    }
+    owners
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn socket_inode(link: &str) -> Option<u64> {
+    link.strip_prefix("socket:[")?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+}
+
+/// One line of `/proc/net/{tcp,tcp6,udp,udp6}`:
+/// `sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ...`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_line(line: &str, protocol: Protocol) -> Option<Socket> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (local_address, local_port) = parse_address(fields.get(1)?)?;
+    let (remote_address, remote_port) = parse_address(fields.get(2)?)?;
+    let state = SocketState::from_code(u8::from_str_radix(fields.get(3)?, 16).ok()?)?;
+    Some(Socket {
+        protocol,
+        local_address,
+        local_port,
+        remote_address,
+        remote_port,
+        state,
+        pid: None,
+        process: None,
+        inode: fields.get(9)?.parse().ok()?,
+    })
+}
+
+/// `0100007F:0050` is 127.0.0.1:80: the address is printed as 32-bit words
+/// in the kernel's byte order, the port in hex.
+fn parse_address(field: &str) -> Option<(IpAddr, u16)> {
+    let (address, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for word in 0..address.len() / 8 {
+        let word = u32::from_str_radix(address.get(word * 8..word * 8 + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let address = match address.len() {
+        8 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        32 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+    Some((address, port))
+}
+
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_proc_net_lines() {
+        let tcp = "   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 23456 1 0000000000000000 100 0 0 10 0";
+        let socket = parse_line(tcp, Protocol::Tcp).unwrap();
+        assert_eq!(socket.local_address, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(socket.local_port, 8080);
+        assert_eq!(socket.remote_address, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(socket.state, SocketState::Listen);
+        assert_eq!(socket.inode, 23456);
+
+        let tcp6 = "   1: 00000000000000000000000001000000:0016 0000000000000000FFFF00000201A8C0:D431 01 00000000:00000000 02:000A7D8A 00000000     0        0 34567 2 0000000000000000 20 4 29 10 -1";
+        let socket = parse_line(tcp6, Protocol::Tcp).unwrap();
+        assert_eq!(socket.local_address, "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(socket.local_port, 22);
+        assert_eq!(
+            socket.remote_address,
+            "::ffff:192.168.1.2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(socket.remote_port, 54321);
+        assert_eq!(socket.state, SocketState::Established);
+
+        assert_eq!(socket_inode("socket:[34567]"), Some(34567));
+        assert_eq!(socket_inode("pipe:[34567]"), None);
+    }
+
+    #[test]
+    fn hidden_processes_are_not_shown_or_found() {
+        let line = |inode| {
+            format!("   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 {inode} 1 0000000000000000 100 0 0 10 0")
+        };
+        let sockets: Vec<Socket> = [1, 2]
+            .into_iter()
+            .map(|inode| parse_line(&line(inode), Protocol::Tcp).unwrap())
+            .collect();
+        let owners = HashMap::from([
+            (1, (100, Some("nginx".to_string()))),
+            (2, (200, Some("ssh-agent".to_string()))),
+        ]);
+        let config = SamplerConfig {
+            process_deny: vec![serde_json::from_str(r#""^(ssh|gpg)-agent$""#).unwrap()],
+            ..SamplerConfig::default()
+        };
+
+        let mut all = sockets.clone();
+        attribute(&mut all, &owners, None, &config);
+        let shown: Vec<_> = all
+            .iter()
+            .map(|socket| (socket.pid, socket.process.as_deref()))
+            .collect();
+        assert_eq!(shown, [(Some(100), Some("nginx")), (None, None)]);
+
+        let mut by_pid = sockets;
+        attribute(&mut by_pid, &owners, Some(200), &config);
+        assert!(by_pid.is_empty());
+    }
+}
@@ -0,0 +1,691 @@
+//! Samples kept in SQLite, with the `sqlite` feature, so that `GET /history`
+//! can chart days of them; [`crate::history`] keeps the last minutes in
+//! memory instead.
+//!
+//! Every `db.interval_ms` a row is written of the CPU and memory samples
+//! taken since the last one, averaged, and of the top processes as last
+//! sampled. Rows are written on a thread of their own, as with the metrics
+//! log, and queries read the file on connections of their own. Keeping rows
+//! subscribes to the streams, which are then sampled even while no client is
+//! connected.
+//!
+//! `GET /history` takes `since` and `until`, each how long ago (e.g. `2d`) or
+//! a time in ms since the Unix epoch, by default the last day; `step`, the
+//! length of the buckets the rows are aggregated over; and `agg`, `avg` (the
+//! default) or `max`. Without a `step`, the shortest one that gives at most
+//! [`MAX_BUCKETS`] buckets is used, in whole seconds and no shorter than
+//! `db.interval_ms`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    config::DbConfig,
+    history::{mean, parse_step, parse_time},
+    sampler::{unix_millis, Channels},
+    server::{error_response, AppState},
+    types::{CpuState, MemState, ProcessInfo, Sample},
+    ws::{Frame, Protocol},
+};
+
+/// The most buckets a query picks its own `step` for.
+pub const MAX_BUCKETS: u64 = 1_000;
+
+/// How far back a query goes without a `since`.
+const DEFAULT_SPAN: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often the rows past the retention are deleted.
+const PRUNE_EVERY: Duration = Duration::from_secs(60 * 60);
+
+/// How many rows to hold while the disk is slower than the interval. Beyond
+/// that, new ones are dropped.
+const QUEUE: usize = 64;
+
+/// Timestamps are those of the rows, in ms since the Unix epoch. Cores and
+/// processes are in tables of their own, so that they can be aggregated like
+/// the rest.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS samples (
+    timestamp_ms INTEGER PRIMARY KEY,
+    cpu_usage REAL NOT NULL,
+    cpu_temp REAL,
+    mem_used INTEGER NOT NULL,
+    mem_total INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS cores (
+    timestamp_ms INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    usage REAL NOT NULL,
+    temp REAL,
+    PRIMARY KEY (timestamp_ms, id)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS processes (
+    timestamp_ms INTEGER NOT NULL,
+    pid INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    cpu_usage REAL NOT NULL,
+    memory INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS processes_by_time ON processes (timestamp_ms);
+";
+
+/// Where the rows are, for the queries.
+pub struct Db {
+    path: PathBuf,
+    interval_ms: u64,
+    top_processes: usize,
+}
+
+impl Db {
+    /// Opens the database `config` names and writes a row of the samples
+    /// `channels` publish every interval until the process exits; `None` if
+    /// it names none.
+    pub fn start(config: &DbConfig, channels: &Channels) -> Result<Option<Arc<Self>>, String> {
+        let Some(path) = config.path.clone() else {
+            return Ok(None);
+        };
+        let conn = open(&path).map_err(|err| format!("cannot open {}: {err}", path.display()))?;
+        let (tx, rx) = mpsc::channel(QUEUE);
+        let retention = config.retention();
+        std::thread::Builder::new()
+            .name("db".into())
+            .spawn(move || write(conn, rx, retention))
+            .map_err(|err| format!("cannot start the db writer: {err}"))?;
+
+        #[cfg(feature = "processes")]
+        let processes = Some(channels.processes.broadcast().subscribe());
+        #[cfg(not(feature = "processes"))]
+        let processes = None;
+        tokio::spawn(collect(
+            channels.cpus.broadcast().subscribe(),
+            channels.ram.broadcast().subscribe(),
+            processes,
+            config.interval(),
+            config.top_processes,
+            tx,
+        ));
+        tracing::info!(path = %path.display(), "keeping samples in the db");
+        Ok(Some(Arc::new(Self {
+            path,
+            interval_ms: config.interval_ms,
+            top_processes: config.top_processes,
+        })))
+    }
+}
+
+fn open(path: &Path) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    // Lets the queries read while rows are written.
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+/// One row of every table, for one interval.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    timestamp_ms: u64,
+    /// The mean over all cores.
+    cpu_usage: f32,
+    cpu_temp: Option<f32>,
+    mem_used: u64,
+    mem_total: u64,
+    /// The usage and temperature of each core, by id.
+    cores: Vec<(f32, Option<f32>)>,
+    processes: Vec<ProcessInfo>,
+}
+
+/// The samples taken since the last row.
+#[derive(Debug, Default)]
+struct Window {
+    cpus: Vec<CpuState>,
+    ram: Vec<MemState>,
+    /// The last samples of the previous interval, which stand for a stream
+    /// that sent nothing since, having skipped its unchanged samples.
+    last_cpus: Option<CpuState>,
+    last_ram: Option<MemState>,
+    /// As last sampled.
+    processes: Vec<ProcessInfo>,
+}
+
+impl Window {
+    /// The row of the samples taken since the last one, which then start
+    /// over; `None` until the CPU and memory streams have both sent one.
+    fn row(&mut self, timestamp_ms: u64, top_processes: usize) -> Option<Row> {
+        if self.cpus.is_empty() {
+            self.cpus.extend(self.last_cpus.take());
+        }
+        if self.ram.is_empty() {
+            self.ram.extend(self.last_ram.take());
+        }
+        let (Some(cpu), Some(ram)) = (self.cpus.last(), self.ram.last()) else {
+            return None;
+        };
+        let count = self.cpus.iter().map(|cpu| cpu.cores.len()).min();
+        let cores: Vec<_> = (0..count.unwrap_or(0))
+            .map(|id| {
+                let usage = mean(self.cpus.iter().map(|cpu| Some(cpu.cores[id].usage)));
+                let temp = mean(self.cpus.iter().map(|cpu| cpu.cores[id].temp));
+                (usage.unwrap_or(0.), temp)
+            })
+            .collect();
+        let used: u64 = self.ram.iter().map(|ram| ram.used).sum();
+        let row = Row {
+            timestamp_ms,
+            cpu_usage: mean(cores.iter().map(|(usage, _)| Some(*usage))).unwrap_or(0.),
+            cpu_temp: mean(self.cpus.iter().map(|cpu| cpu.temp)),
+            mem_used: used / self.ram.len() as u64,
+            mem_total: ram.total,
+            cores,
+            processes: self.processes.iter().take(top_processes).cloned().collect(),
+        };
+        // Keeps the last ones, for the next interval.
+        self.last_cpus = Some(cpu.clone());
+        self.last_ram = Some(ram.clone());
+        self.cpus.clear();
+        self.ram.clear();
+        Some(row)
+    }
+}
+
+/// Gathers the samples of the streams and queues a row of them every
+/// `interval`, until a stream or the writer is gone.
+async fn collect(
+    mut cpus: broadcast::Receiver<Frame>,
+    mut ram: broadcast::Receiver<Frame>,
+    mut processes: Option<broadcast::Receiver<Frame>>,
+    interval: Duration,
+    top_processes: usize,
+    tx: mpsc::Sender<Row>,
+) {
+    let mut window = Window::default();
+    let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            received = cpus.recv() => match decode(received) {
+                Received::Sample(cpu) => window.cpus.push(cpu),
+                Received::Nothing => {}
+                Received::Closed => return,
+            },
+            received = ram.recv() => match decode(received) {
+                Received::Sample(ram) => window.ram.push(ram),
+                Received::Nothing => {}
+                Received::Closed => return,
+            },
+            received = recv(&mut processes) => match decode(received) {
+                Received::Sample(processes) => window.processes = processes,
+                Received::Nothing => {}
+                Received::Closed => processes = None,
+            },
+            _ = ticks.tick() => {
+                let Some(row) = window.row(unix_millis(SystemTime::now()), top_processes) else {
+                    continue;
+                };
+                match tx.try_send(row) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        tracing::warn!("the db is behind, dropping a row");
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => return,
+                }
+            }
+        }
+    }
+}
+
+/// What a stream sent.
+enum Received<T> {
+    Sample(T),
+    /// Samples were skipped, or one could not be decoded.
+    Nothing,
+    Closed,
+}
+
+fn decode<T: DeserializeOwned>(received: Result<Frame, RecvError>) -> Received<T> {
+    match received {
+        Ok(frame) => match serde_json::from_str::<Sample<T>>(frame.text(Protocol::V2)) {
+            Ok(sample) => Received::Sample(sample.data),
+            Err(err) => {
+                tracing::error!(%err, "cannot decode sample for the db");
+                Received::Nothing
+            }
+        },
+        // The interval averages whatever samples it got.
+        Err(RecvError::Lagged(_)) => Received::Nothing,
+        Err(RecvError::Closed) => Received::Closed,
+    }
+}
+
+/// Receives from `rx`, or never if there is none.
+async fn recv(rx: &mut Option<broadcast::Receiver<Frame>>) -> Result<Frame, RecvError> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Writes the queued rows, and deletes the ones past `retention` now and
+/// then, until the collector is gone.
+fn write(mut conn: Connection, mut rx: mpsc::Receiver<Row>, retention: Option<Duration>) {
+    let mut pruned_at: Option<Instant> = None;
+    while let Some(row) = rx.blocking_recv() {
+        if let Err(err) = insert(&mut conn, &row) {
+            tracing::warn!(%err, "cannot write to the db, dropping a row");
+        }
+        let Some(retention) = retention else {
+            continue;
+        };
+        if pruned_at.is_some_and(|at| at.elapsed() < PRUNE_EVERY) {
+            continue;
+        }
+        pruned_at = Some(Instant::now());
+        let oldest_ms = row
+            .timestamp_ms
+            .saturating_sub(retention.as_millis() as u64);
+        if let Err(err) = prune(&conn, oldest_ms) {
+            tracing::warn!(%err, "cannot delete old rows from the db");
+        }
+    }
+}
+
+fn insert(conn: &mut Connection, row: &Row) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT OR REPLACE INTO samples (timestamp_ms, cpu_usage, cpu_temp, mem_used, mem_total)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            row.timestamp_ms,
+            row.cpu_usage,
+            row.cpu_temp,
+            row.mem_used,
+            row.mem_total
+        ],
+    )?;
+    {
+        let mut core = tx.prepare_cached(
+            "INSERT OR REPLACE INTO cores (timestamp_ms, id, usage, temp) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (id, (usage, temp)) in row.cores.iter().enumerate() {
+            core.execute(params![row.timestamp_ms, id, usage, temp])?;
+        }
+        let mut process = tx.prepare_cached(
+            "INSERT INTO processes (timestamp_ms, pid, name, cpu_usage, memory)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for proc in &row.processes {
+            process.execute(params![
+                row.timestamp_ms,
+                proc.pid,
+                proc.name,
+                proc.cpu_usage,
+                proc.memory
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+fn prune(conn: &Connection, oldest_ms: u64) -> rusqlite::Result<()> {
+    for table in ["samples", "cores", "processes"] {
+        conn.execute(
+            &format!("DELETE FROM {table} WHERE timestamp_ms < ?1"),
+            [oldest_ms],
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HistoryParams {
+    since: Option<String>,
+    until: Option<String>,
+    step: Option<String>,
+    #[serde(default)]
+    agg: Agg,
+}
+
+/// How the rows of a bucket are aggregated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Agg {
+    #[default]
+    Avg,
+    Max,
+}
+
+impl Agg {
+    /// The SQL aggregate function.
+    fn function(self) -> &'static str {
+        match self {
+            Agg::Avg => "avg",
+            Agg::Max => "max",
+        }
+    }
+}
+
+/// The rows a query covers, in ms since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    since_ms: u64,
+    until_ms: u64,
+    step_ms: u64,
+}
+
+impl HistoryParams {
+    fn range(&self, now_ms: u64, interval_ms: u64) -> Result<Range, String> {
+        let since_ms = match &self.since {
+            Some(since) => parse_time(since, now_ms).map_err(|err| format!("since: {err}"))?,
+            None => now_ms.saturating_sub(DEFAULT_SPAN.as_millis() as u64),
+        };
+        let until_ms = match &self.until {
+            Some(until) => parse_time(until, now_ms).map_err(|err| format!("until: {err}"))?,
+            None => now_ms,
+        };
+        if since_ms >= until_ms {
+            return Err("since must be before until".into());
+        }
+        let step_ms = match &self.step {
+            Some(step) => parse_step(step)?,
+            None => {
+                let seconds = (until_ms - since_ms).div_ceil(MAX_BUCKETS).div_ceil(1_000);
+                (seconds * 1_000).max(interval_ms)
+            }
+        };
+        Ok(Range {
+            since_ms,
+            until_ms,
+            step_ms,
+        })
+    }
+}
+
+/// The buckets of a query, as one array per value, all of the same length.
+#[derive(Serialize, Debug, Default)]
+pub struct Buckets {
+    step_ms: u64,
+    agg: Agg,
+    /// When each bucket starts. Those without rows are left out.
+    timestamp_ms: Vec<u64>,
+    /// The mean over all cores.
+    cpu_usage: Vec<f32>,
+    /// Package temperature in °C.
+    cpu_temp: Vec<Option<f32>>,
+    /// In bytes.
+    mem_used: Vec<u64>,
+    mem_total: Vec<u64>,
+    /// The usage of each core, by id.
+    cores: Vec<Vec<Option<f32>>>,
+    core_temps: Vec<Vec<Option<f32>>>,
+    /// The busiest processes of each bucket, by name, among the ones its
+    /// rows kept.
+    processes: Vec<Vec<ProcessUsage>>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ProcessUsage {
+    name: String,
+    cpu_usage: f32,
+    memory: u64,
+}
+
+impl Db {
+    /// Aggregates the rows in `range`, on a connection of its own.
+    fn query(&self, range: Range, agg: Agg) -> rusqlite::Result<Buckets> {
+        let conn = Connection::open_with_flags(
+            &self.path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        query(&conn, range, agg, self.top_processes)
+    }
+}
+
+fn query(
+    conn: &Connection,
+    range: Range,
+    agg: Agg,
+    top_processes: usize,
+) -> rusqlite::Result<Buckets> {
+    let agg_fn = agg.function();
+    let bounds = params![range.step_ms, range.since_ms, range.until_ms];
+    let mut buckets = Buckets {
+        step_ms: range.step_ms,
+        agg,
+        ..Buckets::default()
+    };
+
+    let mut samples = conn.prepare(&format!(
+        "SELECT timestamp_ms / ?1 * ?1 AS bucket, {agg_fn}(cpu_usage), {agg_fn}(cpu_temp),
+                {agg_fn}(mem_used), max(mem_total)
+         FROM samples WHERE timestamp_ms >= ?2 AND timestamp_ms < ?3
+         GROUP BY bucket ORDER BY bucket"
+    ))?;
+    let mut rows = samples.query(bounds)?;
+    while let Some(row) = rows.next()? {
+        buckets.timestamp_ms.push(row.get(0)?);
+        buckets.cpu_usage.push(row.get::<_, f64>(1)? as f32);
+        buckets
+            .cpu_temp
+            .push(row.get::<_, Option<f64>>(2)?.map(|temp| temp as f32));
+        buckets.mem_used.push(row.get::<_, f64>(3)? as u64);
+        buckets.mem_total.push(row.get(4)?);
+    }
+    let count = buckets.timestamp_ms.len();
+    let index: HashMap<u64, usize> = buckets
+        .timestamp_ms
+        .iter()
+        .enumerate()
+        .map(|(i, at)| (*at, i))
+        .collect();
+
+    let mut cores = conn.prepare(&format!(
+        "SELECT timestamp_ms / ?1 * ?1 AS bucket, id, {agg_fn}(usage), {agg_fn}(temp)
+         FROM cores WHERE timestamp_ms >= ?2 AND timestamp_ms < ?3
+         GROUP BY bucket, id"
+    ))?;
+    let mut rows = cores.query(bounds)?;
+    while let Some(row) = rows.next()? {
+        let (Some(&i), id) = (index.get(&row.get(0)?), row.get::<_, usize>(1)?) else {
+            continue;
+        };
+        if buckets.cores.len() <= id {
+            buckets.cores.resize(id + 1, vec![None; count]);
+            buckets.core_temps.resize(id + 1, vec![None; count]);
+        }
+        buckets.cores[id][i] = Some(row.get::<_, f64>(2)? as f32);
+        buckets.core_temps[id][i] = row.get::<_, Option<f64>>(3)?.map(|temp| temp as f32);
+    }
+
+    buckets.processes = vec![vec![]; count];
+    let mut processes = conn.prepare(&format!(
+        "SELECT timestamp_ms / ?1 * ?1 AS bucket, name, {agg_fn}(cpu_usage) AS cpu,
+                {agg_fn}(memory)
+         FROM processes WHERE timestamp_ms >= ?2 AND timestamp_ms < ?3
+         GROUP BY bucket, name ORDER BY bucket, cpu DESC"
+    ))?;
+    let mut rows = processes.query(bounds)?;
+    while let Some(row) = rows.next()? {
+        let Some(&i) = index.get(&row.get(0)?) else {
+            continue;
+        };
+        if buckets.processes[i].len() < top_processes {
+            buckets.processes[i].push(ProcessUsage {
+                name: row.get(1)?,
+                cpu_usage: row.get::<_, f64>(2)? as f32,
+                memory: row.get::<_, f64>(3)? as u64,
+            });
+        }
+    }
+    Ok(buckets)
+}
+
+#[axum::debug_handler]
+pub async fn history_get(
+    Query(params): Query<HistoryParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let Some(db) = state.db.clone() else {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            "the db is disabled, set db.path or --db to enable it",
+        );
+    };
+    let range = match params.range(unix_millis(SystemTime::now()), db.interval_ms) {
+        Ok(range) => range,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    match tokio::task::spawn_blocking(move || db.query(range, params.agg)).await {
+        Ok(Ok(buckets)) => Json(buckets).into_response(),
+        Ok(Err(err)) => {
+            tracing::error!(%err, "cannot query the db");
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("cannot query the db: {err}"),
+            )
+        }
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CpuCore, MemMode, MemUnit};
+
+    fn cpu(usages: &[f32], temp: Option<f32>) -> CpuState {
+        CpuState {
+            cores: usages
+                .iter()
+                .enumerate()
+                .map(|(id, &usage)| CpuCore {
+                    id,
+                    name: format!("cpu{id}"),
+                    usage,
+                    temp,
+                })
+                .collect(),
+            temp,
+            core_temp: false,
+            power_watts: None,
+        }
+    }
+
+    fn ram(used: u64) -> MemState {
+        MemState {
+            total: 1000,
+            used,
+            free: 1000 - used,
+            available: 1000 - used,
+            mode: MemMode::default(),
+            unit: MemUnit::Bytes,
+            total_mib: 0,
+            used_mib: 0,
+            swap_total: 0,
+            swap_used: 0,
+        }
+    }
+
+    #[test]
+    fn rows_average_the_interval_and_carry_quiet_streams_over() {
+        let mut window = Window::default();
+        assert_eq!(window.row(0, 5), None);
+
+        window.cpus = vec![cpu(&[10., 30.], Some(50.)), cpu(&[30., 50.], None)];
+        window.ram = vec![ram(100), ram(300)];
+        let row = window.row(1_000, 5).unwrap();
+        assert_eq!(row.cores, [(20., Some(50.)), (40., Some(50.))]);
+        assert_eq!((row.cpu_usage, row.cpu_temp), (30., Some(50.)));
+        assert_eq!((row.mem_used, row.mem_total), (200, 1000));
+
+        // Memory skipped its unchanged samples since.
+        window.cpus = vec![cpu(&[0., 0.], None)];
+        let row = window.row(2_000, 5).unwrap();
+        assert_eq!((row.cpu_usage, row.mem_used), (0., 300));
+    }
+
+    #[test]
+    fn buckets_aggregate_rows_cores_and_processes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        let process = |name: &str, cpu_usage| ProcessInfo {
+            pid: 1,
+            name: name.into(),
+            cpu_usage,
+            memory: 100,
+            virtual_memory: 0,
+            user: None,
+            cmd: vec![],
+            run_time: 0,
+            instances: None,
+            self_process: false,
+        };
+        for (timestamp_ms, usage, processes) in [
+            (10_000, 10., vec![process("make", 50.), process("sh", 1.)]),
+            (15_000, 30., vec![process("sh", 90.)]),
+            (20_000, 70., vec![]),
+        ] {
+            let row = Row {
+                timestamp_ms,
+                cpu_usage: usage,
+                cpu_temp: None,
+                mem_used: 100,
+                mem_total: 1000,
+                cores: vec![(usage, None)],
+                processes,
+            };
+            insert(&mut conn, &row).unwrap();
+        }
+        let range = Range {
+            since_ms: 0,
+            until_ms: 30_000,
+            step_ms: 10_000,
+        };
+
+        let avg = query(&conn, range, Agg::Avg, 1).unwrap();
+        assert_eq!(avg.timestamp_ms, [10_000, 20_000]);
+        assert_eq!(avg.cpu_usage, [20., 70.]);
+        assert_eq!(avg.cores, [[Some(20.), Some(70.)]]);
+        assert_eq!(avg.processes[0][0].name, "make");
+        assert!(avg.processes[1].is_empty());
+
+        let max = query(&conn, range, Agg::Max, 1).unwrap();
+        assert_eq!(max.cpu_usage, [30., 70.]);
+        assert_eq!(max.processes[0][0].name, "sh");
+    }
+
+    #[test]
+    fn step_defaults_to_at_most_max_buckets() {
+        let params = |since: &str| HistoryParams {
+            since: Some(since.into()),
+            ..HistoryParams::default()
+        };
+        let now_ms = 100 * 24 * 60 * 60 * 1000;
+        assert_eq!(params("1h").range(now_ms, 10_000).unwrap().step_ms, 10_000);
+        assert_eq!(params("7d").range(now_ms, 10_000).unwrap().step_ms, 605_000);
+        let backwards = HistoryParams {
+            until: Some("2h".into()),
+            ..params("1h")
+        };
+        assert!(backwards.range(now_ms, 10_000).is_err());
+    }
+}
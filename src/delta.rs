@@ -0,0 +1,222 @@
+//! Delta encoding of stream messages, for clients that ask for it with
+//! `delta`: after a full message, the keyframe, only what changed is sent, as
+//! `{"delta":patch}`. The patch is a JSON Merge Patch (RFC 7396), except that
+//! an array that kept its length is patched element by element, with an
+//! object keyed by the indices that changed, e.g. `{"cores":{"3":{"usage":
+//! 41.5}}}`. A full message is sent again every `delta_keyframe_every`
+//! messages, so that a client that lost track can recover. A null in a merge
+//! patch removes the key, so a change that would have to carry a null, a
+//! temperature that went missing say, is sent as a full message instead.
+//!
+//! Fractional values, usages and temperatures, only count as changed once
+//! they moved by more than the client's epsilon from what it was last sent,
+//! so that it never drifts further than that. Integers, counts and sizes in
+//! bytes, are sent whenever they change.
+
+use serde_json::{Map, Value};
+
+/// One session's encoder.
+#[derive(Debug)]
+pub struct Delta {
+    /// `None` when the session sends every message in full.
+    epsilon: Option<f64>,
+    keyframe_every: u32,
+    /// What the client holds, as it applied our patches.
+    last: Option<Value>,
+    since_keyframe: u32,
+}
+
+impl Delta {
+    pub fn new(epsilon: Option<f64>, keyframe_every: u32) -> Self {
+        Self {
+            epsilon,
+            keyframe_every,
+            last: None,
+            since_keyframe: 0,
+        }
+    }
+
+    /// The message to send for `text`, a full message; `None` if nothing
+    /// changed.
+    pub fn encode(&mut self, text: String) -> Option<String> {
+        let Some(epsilon) = self.epsilon else {
+            return Some(text);
+        };
+        let Ok(value) = serde_json::from_str::<Value>(&text) else {
+            return Some(text);
+        };
+        let keyframe_due = self.keyframe_every != 0 && self.since_keyframe >= self.keyframe_every;
+        if let Some(last) = self.last.as_mut().filter(|_| !keyframe_due) {
+            if let Ok(patch) = diff(last, &value, epsilon) {
+                let patch = patch?;
+                self.since_keyframe += 1;
+                return Some(serde_json::json!({ "delta": patch }).to_string());
+            }
+        }
+        self.last = Some(value);
+        self.since_keyframe = 0;
+        Some(text)
+    }
+}
+
+/// A change a merge patch cannot carry, see [`carries_null`].
+#[derive(Debug)]
+struct NeedsKeyframe;
+
+/// The patch taking `last` to `new`, or `None` if they are the same within
+/// `epsilon`. `last` is patched along, so that it stays what the client
+/// holds, and must be replaced by `new` when this fails.
+fn diff(last: &mut Value, new: &Value, epsilon: f64) -> Result<Option<Value>, NeedsKeyframe> {
+    match (last, new) {
+        (Value::Object(last), Value::Object(new)) => {
+            let mut patch = Map::new();
+            for (key, value) in new {
+                match last.get_mut(key) {
+                    Some(old) => {
+                        if let Some(changed) = diff(old, value, epsilon)? {
+                            patch.insert(key.clone(), changed);
+                        }
+                    }
+                    None => {
+                        if carries_null(value) {
+                            return Err(NeedsKeyframe);
+                        }
+                        last.insert(key.clone(), value.clone());
+                        patch.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            let removed: Vec<String> = last
+                .keys()
+                .filter(|key| !new.contains_key(*key))
+                .cloned()
+                .collect();
+            for key in removed {
+                last.remove(&key);
+                patch.insert(key, Value::Null);
+            }
+            Ok((!patch.is_empty()).then_some(Value::Object(patch)))
+        }
+        (Value::Array(last), Value::Array(new)) if last.len() == new.len() => {
+            let mut patch = Map::new();
+            for (index, (old, value)) in last.iter_mut().zip(new).enumerate() {
+                if let Some(changed) = diff(old, value, epsilon)? {
+                    patch.insert(index.to_string(), changed);
+                }
+            }
+            Ok((!patch.is_empty()).then_some(Value::Object(patch)))
+        }
+        (Value::Number(old), Value::Number(number)) if old.is_f64() && number.is_f64() => {
+            let (Some(from), Some(to)) = (old.as_f64(), number.as_f64()) else {
+                return Ok(None);
+            };
+            if (to - from).abs() <= epsilon {
+                return Ok(None);
+            }
+            *old = number.clone();
+            Ok(Some(new.clone()))
+        }
+        (last, new) => {
+            if last == new {
+                return Ok(None);
+            }
+            if carries_null(new) {
+                return Err(NeedsKeyframe);
+            }
+            *last = new.clone();
+            Ok(Some(new.clone()))
+        }
+    }
+}
+
+/// Whether `value` would lose a null when applied as a merge patch: it is
+/// null, or an object with a null member. Arrays are replaced whole, nulls
+/// and all.
+fn carries_null(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Object(map) => map.values().any(carries_null),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(delta: &mut Delta, value: Value) -> Option<Value> {
+        let text = delta.encode(value.to_string())?;
+        Some(serde_json::from_str(&text).unwrap())
+    }
+
+    #[test]
+    fn only_changes_beyond_epsilon_are_sent() {
+        let mut delta = Delta::new(Some(0.5), 0);
+        let first =
+            serde_json::json!({ "used": 100, "cores": [{ "usage": 10.0 }, { "usage": 20.0 }] });
+        assert_eq!(encode(&mut delta, first.clone()), Some(first));
+
+        let drifted =
+            serde_json::json!({ "used": 100, "cores": [{ "usage": 10.25 }, { "usage": 20.0 }] });
+        assert_eq!(encode(&mut delta, drifted), None);
+
+        // 10.75 is 0.75 from the 10.0 the client holds, not 0.5 from 10.25.
+        let moved =
+            serde_json::json!({ "used": 101, "cores": [{ "usage": 10.75 }, { "usage": 20.0 }] });
+        assert_eq!(
+            encode(&mut delta, moved),
+            Some(
+                serde_json::json!({ "delta": { "used": 101, "cores": { "0": { "usage": 10.75 } } } })
+            )
+        );
+
+        let resized = serde_json::json!({ "used": 101, "cores": [{ "usage": 10.75 }] });
+        assert_eq!(
+            encode(&mut delta, resized),
+            Some(serde_json::json!({ "delta": { "cores": [{ "usage": 10.75 }] } }))
+        );
+    }
+
+    #[test]
+    fn keyframes_are_sent_periodically() {
+        let mut delta = Delta::new(Some(0.), 2);
+        let sample = |seq: u64| serde_json::json!({ "seq": seq, "usage": 1.5 });
+        assert_eq!(encode(&mut delta, sample(1)), Some(sample(1)));
+        assert_eq!(
+            encode(&mut delta, sample(2)),
+            Some(serde_json::json!({ "delta": { "seq": 2 } }))
+        );
+        assert_eq!(
+            encode(&mut delta, sample(3)),
+            Some(serde_json::json!({ "delta": { "seq": 3 } }))
+        );
+        assert_eq!(encode(&mut delta, sample(4)), Some(sample(4)));
+    }
+
+    #[test]
+    fn changes_to_null_are_sent_in_full() {
+        let mut delta = Delta::new(Some(0.), 0);
+        let sample = |temp: Value| serde_json::json!({ "used": 1, "temp": temp });
+        assert_eq!(
+            encode(&mut delta, sample(40.5.into())),
+            Some(sample(40.5.into()))
+        );
+        // `{"delta":{"temp":null}}` would remove temp rather than null it.
+        assert_eq!(
+            encode(&mut delta, sample(Value::Null)),
+            Some(sample(Value::Null))
+        );
+        assert_eq!(
+            encode(&mut delta, sample(41.5.into())),
+            Some(serde_json::json!({ "delta": { "temp": 41.5 } }))
+        );
+
+        let added = serde_json::json!({ "used": 1, "temp": 41.5, "gpu": { "temp": null } });
+        assert_eq!(encode(&mut delta, added.clone()), Some(added));
+        let removed = sample(41.5.into());
+        assert_eq!(
+            encode(&mut delta, removed),
+            Some(serde_json::json!({ "delta": { "gpu": null } }))
+        );
+    }
+}
@@ -0,0 +1,439 @@
+//! Recent samples of the CPU and memory streams, kept in memory for
+//! `GET /history/*`, so that dashboards can draw a chart as soon as they
+//! connect instead of starting from an empty graph.
+//!
+//! Only what a chart needs of each sample is kept, for
+//! `history.retention_mins`. Keeping them subscribes to the streams, which
+//! are then sampled even while no client is connected.
+//!
+//! Queries take `since`, how long ago (e.g. `5m`) or a time in ms since the
+//! Unix epoch, and `step`, e.g. `10s`, to average the samples over buckets
+//! of that length instead of returning each one. Buckets start at multiples
+//! of `step` since the epoch, so that polling gives the same buckets again;
+//! the ones without samples are left out.
+//!
+//! Responses are compressed with zstd or gzip as `Accept-Encoding` asks, or
+//! `compress` for clients that cannot set it, e.g. `?compress=zstd`.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    config::{parse_duration, HistoryConfig},
+    sampler::{unix_millis, Channels},
+    server::{error_response, AppState},
+    types::Sample,
+    ws::{Frame, Protocol},
+};
+
+/// The samples kept of every stream that has a history.
+pub struct History {
+    #[cfg(feature = "cpu")]
+    cpus: Ring<CpuPoint>,
+    #[cfg(feature = "mem")]
+    ram: Ring<MemPoint>,
+}
+
+impl History {
+    /// Starts keeping the samples `channels` publish, `None` if `config`
+    /// keeps none.
+    pub fn start(config: &HistoryConfig, channels: &Channels) -> Option<Arc<Self>> {
+        if config.retention_mins == 0 {
+            return None;
+        }
+        let retention = config.retention();
+        let history = Arc::new(Self {
+            #[cfg(feature = "cpu")]
+            cpus: Ring::new(retention),
+            #[cfg(feature = "mem")]
+            ram: Ring::new(retention),
+        });
+        #[cfg(feature = "cpu")]
+        tokio::spawn(record(
+            channels.cpus.broadcast().subscribe(),
+            history.clone(),
+            |history| &history.cpus,
+            CpuPoint::new,
+        ));
+        #[cfg(feature = "mem")]
+        tokio::spawn(record(
+            channels.ram.broadcast().subscribe(),
+            history.clone(),
+            |history| &history.ram,
+            MemPoint::new,
+        ));
+        tracing::info!(retention_mins = config.retention_mins, "keeping history");
+        Some(history)
+    }
+}
+
+/// Adds every sample on `rx` to a ring of `history` until the stream ends.
+async fn record<T: DeserializeOwned, P: Point>(
+    mut rx: broadcast::Receiver<Frame>,
+    history: Arc<History>,
+    ring: fn(&History) -> &Ring<P>,
+    point: fn(&T) -> P,
+) {
+    loop {
+        match rx.recv().await {
+            Ok(frame) => match serde_json::from_str::<Sample<T>>(frame.text(Protocol::V2)) {
+                Ok(sample) => ring(&history).push(sample.timestamp_ms, point(&sample.data)),
+                Err(err) => tracing::error!(%err, "cannot decode sample for the history"),
+            },
+            // Leaves a gap in the history rather than falling further behind.
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// The samples of one stream, oldest first, by their timestamp in ms.
+struct Ring<P> {
+    retention_ms: u64,
+    points: Mutex<VecDeque<(u64, P)>>,
+}
+
+impl<P: Point> Ring<P> {
+    fn new(retention: Duration) -> Self {
+        Self {
+            retention_ms: retention.as_millis() as u64,
+            points: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Adds a sample, dropping the ones that are now past the retention.
+    fn push(&self, timestamp_ms: u64, point: P) {
+        let mut points = self.points.lock().unwrap();
+        points.push_back((timestamp_ms, point));
+        let oldest = timestamp_ms.saturating_sub(self.retention_ms);
+        while points.front().is_some_and(|(at, _)| *at < oldest) {
+            points.pop_front();
+        }
+    }
+
+    /// The samples taken from `since_ms` on, each one or, with `step_ms`,
+    /// averaged over the buckets they fall in, by the time they or their
+    /// bucket start at.
+    fn query(&self, since_ms: u64, step_ms: Option<u64>) -> Vec<(u64, P)> {
+        let points = self.points.lock().unwrap();
+        let first = points.partition_point(|(at, _)| *at < since_ms);
+        let points = points.range(first..);
+        let Some(step_ms) = step_ms else {
+            return points.cloned().collect();
+        };
+        let mut buckets = vec![];
+        let mut bucket: Vec<&P> = vec![];
+        let mut start = None;
+        for (at, point) in points {
+            let at = at - at % step_ms;
+            if start.is_some_and(|start| start != at) {
+                buckets.extend(start.map(|start| (start, P::mean(&bucket))));
+                bucket.clear();
+            }
+            start = Some(at);
+            bucket.push(point);
+        }
+        buckets.extend(start.map(|start| (start, P::mean(&bucket))));
+        buckets
+    }
+}
+
+/// What is kept of a sample.
+trait Point: Clone {
+    /// The average of `points`, which are never empty.
+    fn mean(points: &[&Self]) -> Self;
+}
+
+/// The mean of the values that are there, `None` if none are.
+#[cfg(feature = "cpu")]
+pub(crate) fn mean(values: impl Iterator<Item = Option<f32>>) -> Option<f32> {
+    let (sum, count) = values
+        .flatten()
+        .fold((0., 0), |(sum, count), value| (sum + value, count + 1));
+    (count > 0).then(|| sum / count as f32)
+}
+
+#[cfg(feature = "cpu")]
+#[derive(Debug, Clone, PartialEq)]
+struct CpuPoint {
+    /// By core id.
+    usage: Vec<f32>,
+    temp: Option<f32>,
+}
+
+#[cfg(feature = "cpu")]
+impl CpuPoint {
+    fn new(state: &crate::types::CpuState) -> Self {
+        Self {
+            usage: state.cores.iter().map(|core| core.usage).collect(),
+            temp: state.temp,
+        }
+    }
+}
+
+#[cfg(feature = "cpu")]
+impl Point for CpuPoint {
+    fn mean(points: &[&Self]) -> Self {
+        let cores = points.iter().map(|point| point.usage.len()).min();
+        Self {
+            usage: (0..cores.unwrap_or(0))
+                .map(|core| mean(points.iter().map(|point| Some(point.usage[core]))).unwrap_or(0.))
+                .collect(),
+            temp: mean(points.iter().map(|point| point.temp)),
+        }
+    }
+}
+
+#[cfg(feature = "mem")]
+#[derive(Debug, Clone, PartialEq)]
+struct MemPoint {
+    total: u64,
+    used: u64,
+    available: u64,
+}
+
+#[cfg(feature = "mem")]
+impl MemPoint {
+    fn new(state: &crate::types::MemState) -> Self {
+        // The streams broadcast bytes, connections convert them.
+        Self {
+            total: state.total,
+            used: state.used,
+            available: state.available,
+        }
+    }
+}
+
+#[cfg(feature = "mem")]
+impl Point for MemPoint {
+    fn mean(points: &[&Self]) -> Self {
+        let mean = |value: fn(&MemPoint) -> u64| {
+            let sum: u128 = points.iter().map(|point| u128::from(value(point))).sum();
+            (sum / points.len() as u128) as u64
+        };
+        Self {
+            total: mean(|point| point.total),
+            used: mean(|point| point.used),
+            available: mean(|point| point.available),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct HistoryParams {
+    since: Option<String>,
+    step: Option<String>,
+}
+
+impl HistoryParams {
+    /// From when on samples are wanted, in ms since the Unix epoch; the
+    /// oldest kept when not given.
+    fn since_ms(&self, now_ms: u64) -> Result<u64, String> {
+        match &self.since {
+            Some(since) => parse_time(since, now_ms).map_err(|err| format!("since: {err}")),
+            None => Ok(0),
+        }
+    }
+
+    fn step_ms(&self) -> Result<Option<u64>, String> {
+        self.step.as_deref().map(parse_step).transpose()
+    }
+}
+
+/// In ms since the Unix epoch, a time given either as how long ago it was,
+/// e.g. `5m` or `2d`, or already in ms since the epoch.
+pub(crate) fn parse_time(text: &str, now_ms: u64) -> Result<u64, String> {
+    if !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit()) {
+        return text.parse().map_err(|_| format!("invalid time {text:?}"));
+    }
+    let ago = parse_duration(text).map_err(|_| {
+        format!("invalid time {text:?}, expected e.g. 5m or a time in ms since the Unix epoch")
+    })?;
+    Ok(now_ms.saturating_sub(ago.as_millis() as u64))
+}
+
+/// A `step` of at least a ms.
+pub(crate) fn parse_step(text: &str) -> Result<u64, String> {
+    match parse_duration(text)?.as_millis() as u64 {
+        0 => Err("step must be longer than 0".into()),
+        step_ms => Ok(step_ms),
+    }
+}
+
+/// The CPU usage samples as one array per value, all of the same length.
+#[cfg(feature = "cpu")]
+#[derive(Serialize, Debug)]
+pub struct CpuHistory {
+    /// `null` when every sample is returned.
+    step_ms: Option<u64>,
+    /// When the samples or their buckets start.
+    timestamp_ms: Vec<u64>,
+    /// The mean over all cores.
+    usage: Vec<f32>,
+    /// The usage of each core, by id.
+    cores: Vec<Vec<f32>>,
+    /// Package temperature in °C.
+    temp: Vec<Option<f32>>,
+}
+
+/// The memory samples as one array per value, in bytes.
+#[cfg(feature = "mem")]
+#[derive(Serialize, Debug)]
+pub struct MemHistory {
+    step_ms: Option<u64>,
+    timestamp_ms: Vec<u64>,
+    total: Vec<u64>,
+    /// As `sampler.mem_mode` computes it.
+    used: Vec<u64>,
+    available: Vec<u64>,
+}
+
+#[cfg(feature = "cpu")]
+#[axum::debug_handler]
+pub async fn cpus_get(
+    Query(params): Query<HistoryParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let (history, since_ms, step_ms) = match query(&state, &params) {
+        Ok(query) => query,
+        Err((status, err)) => return error_response(status, err),
+    };
+    let points = history.cpus.query(since_ms, step_ms);
+    let cores = points.iter().map(|(_, point)| point.usage.len()).min();
+    Json(CpuHistory {
+        step_ms,
+        timestamp_ms: points.iter().map(|(at, _)| *at).collect(),
+        usage: points
+            .iter()
+            .map(|(_, point)| mean(point.usage.iter().copied().map(Some)).unwrap_or(0.))
+            .collect(),
+        cores: (0..cores.unwrap_or(0))
+            .map(|core| points.iter().map(|(_, point)| point.usage[core]).collect())
+            .collect(),
+        temp: points.iter().map(|(_, point)| point.temp).collect(),
+    })
+    .into_response()
+}
+
+#[cfg(feature = "mem")]
+#[axum::debug_handler]
+pub async fn ram_get(
+    Query(params): Query<HistoryParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let (history, since_ms, step_ms) = match query(&state, &params) {
+        Ok(query) => query,
+        Err((status, err)) => return error_response(status, err),
+    };
+    let points = history.ram.query(since_ms, step_ms);
+    Json(MemHistory {
+        step_ms,
+        timestamp_ms: points.iter().map(|(at, _)| *at).collect(),
+        total: points.iter().map(|(_, point)| point.total).collect(),
+        used: points.iter().map(|(_, point)| point.used).collect(),
+        available: points.iter().map(|(_, point)| point.available).collect(),
+    })
+    .into_response()
+}
+
+/// The history, and the `since` and `step` asked for.
+#[cfg_attr(not(any(feature = "cpu", feature = "mem")), allow(dead_code))]
+fn query<'a>(
+    state: &'a AppState,
+    params: &HistoryParams,
+) -> Result<(&'a History, u64, Option<u64>), (StatusCode, String)> {
+    let Some(history) = state.history.as_deref() else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "history is disabled, set history.retention_mins to enable it".into(),
+        ));
+    };
+    let bad_request = |err| (StatusCode::BAD_REQUEST, err);
+    let since_ms = params
+        .since_ms(unix_millis(SystemTime::now()))
+        .map_err(bad_request)?;
+    let step_ms = params.step_ms().map_err(bad_request)?;
+    Ok((history, since_ms, step_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "mem")]
+    fn memory(used: u64) -> MemPoint {
+        MemPoint {
+            total: 100,
+            used,
+            available: 100 - used,
+        }
+    }
+
+    #[cfg(feature = "mem")]
+    #[test]
+    fn samples_past_the_retention_are_dropped() {
+        let ring = Ring::new(Duration::from_secs(10));
+        for at in [1_000, 5_000, 11_000, 12_000] {
+            ring.push(at, memory(at / 1_000));
+        }
+        let kept: Vec<u64> = ring.query(0, None).iter().map(|(at, _)| *at).collect();
+        assert_eq!(kept, [5_000, 11_000, 12_000]);
+        assert_eq!(ring.query(11_000, None).len(), 2);
+    }
+
+    #[cfg(feature = "mem")]
+    #[test]
+    fn buckets_average_their_samples_and_empty_ones_are_left_out() {
+        let ring = Ring::new(Duration::from_secs(60));
+        for (at, used) in [(10_200, 10), (10_700, 20), (11_100, 30), (14_500, 40)] {
+            ring.push(at, memory(used));
+        }
+        let buckets = ring.query(0, Some(1_000));
+        assert_eq!(
+            buckets,
+            [
+                (10_000, memory(15)),
+                (11_000, memory(30)),
+                (14_000, memory(40))
+            ]
+        );
+        // Since a time inside a bucket, it only averages what follows.
+        assert_eq!(ring.query(10_500, Some(1_000))[0], (10_000, memory(20)));
+    }
+
+    #[cfg(feature = "cpu")]
+    #[test]
+    fn cpu_buckets_average_each_core_and_the_readable_temps() {
+        let point = |usage: Vec<f32>, temp| CpuPoint { usage, temp };
+        let mean = CpuPoint::mean(&[
+            &point(vec![10., 50.], Some(40.)),
+            &point(vec![30., 70.], None),
+        ]);
+        assert_eq!(mean, point(vec![20., 60.], Some(40.)));
+    }
+
+    #[test]
+    fn since_is_a_time_ago_or_a_timestamp() {
+        let params = |since: &str| HistoryParams {
+            since: Some(since.into()),
+            ..HistoryParams::default()
+        };
+        assert_eq!(params("5m").since_ms(600_000), Ok(300_000));
+        assert_eq!(params("1h").since_ms(7_200_000), Ok(3_600_000));
+        assert_eq!(params("1700000000000").since_ms(0), Ok(1_700_000_000_000));
+        assert!(params("yesterday").since_ms(0).is_err());
+        assert_eq!(HistoryParams::default().since_ms(600_000), Ok(0));
+    }
+}
@@ -0,0 +1,424 @@
+//! Hub mode: follows the streams of other instances, or takes the samples
+//! agents push to `/ingest`, and re-broadcasts them, tagged with the
+//! instance's name, on `/realtime/<stream>?host=<name>`.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use axum::extract::ws::{Message, WebSocket};
+#[cfg(feature = "hub")]
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue;
+use tokio::{sync::broadcast, time::timeout};
+#[cfg(feature = "hub")]
+use tokio_tungstenite::connect_async;
+
+use crate::{
+    config::Stream,
+    sampler::unix_millis,
+    stats::Stats,
+    types::{CpuState, MemState, Payload, ProcessInfo, Sample},
+    ws::Frame,
+};
+
+/// Another instance whose streams the hub re-broadcasts.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Remote {
+    /// What clients pass as `?host=`.
+    pub name: String,
+    /// Base URL of the instance, e.g. `ws://nas:7032`.
+    pub url: String,
+}
+
+impl Remote {
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_host_name(&self.name) {
+            return Err(format!(
+                "remote {:?}: names may only contain letters, digits, '-', '_' and '.'",
+                self.name
+            ));
+        }
+        if self.url.starts_with("wss://") {
+            return Err(format!(
+                "remote {:?}: wss:// is not supported, this build has no TLS",
+                self.name
+            ));
+        }
+        if !self.url.starts_with("ws://") {
+            return Err(format!("remote {:?}: url must start with ws://", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` can be used as a host name, which is passed unescaped in
+/// query strings.
+pub fn is_host_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Why a host stream cannot be subscribed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeError {
+    UnknownHost,
+    /// Not connected to the remote's stream right now.
+    Offline,
+}
+
+/// Why an agent cannot push as a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError {
+    /// The name belongs to a configured remote.
+    Remote,
+    /// Another agent is pushing as that host.
+    Pushing,
+}
+
+/// What an agent sends to `/ingest` for every sample it broadcasts.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Pushed<'a> {
+    pub stream: Stream,
+    /// The sample as its v2 clients get it.
+    #[serde(borrow)]
+    pub sample: &'a RawValue,
+}
+
+pub struct Hub {
+    /// The configured remotes, then the hosts agents have pushed as, in the
+    /// order they first connected.
+    hosts: Mutex<Vec<Arc<Host>>>,
+    capacity: usize,
+}
+
+struct Host {
+    name: String,
+    /// `None` for hosts that push to `/ingest`.
+    url: Option<String>,
+    /// Set while an agent is pushing as this host.
+    pushing: AtomicBool,
+    cpus: Mutex<Link>,
+    ram: Mutex<Link>,
+    processes: Mutex<Link>,
+}
+
+/// The connection to one stream of a remote.
+#[derive(Default)]
+struct Link {
+    /// Set while connected. Dropping it ends the sessions that follow the
+    /// stream, so that their clients learn the host went away instead of
+    /// waiting on a silent stream.
+    tx: Option<broadcast::Sender<Frame>>,
+    /// When it last connected or disconnected, in milliseconds since the
+    /// Unix epoch.
+    since: Option<u64>,
+    /// Why it last disconnected or failed to connect.
+    error: Option<String>,
+}
+
+/// Lets an agent push as a host; the host is free again once dropped.
+pub struct PushGuard(Arc<Host>);
+
+/// A host on `GET /hosts`.
+#[derive(Serialize, Debug)]
+pub struct HostReport {
+    name: String,
+    /// `null` for hosts that push to `/ingest`.
+    url: Option<String>,
+    state: HostState,
+    cpus: LinkReport,
+    ram: LinkReport,
+    processes: LinkReport,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum HostState {
+    /// Every stream is connected.
+    Online,
+    /// Some streams are connected.
+    Degraded,
+    Offline,
+}
+
+#[derive(Serialize, Debug)]
+struct LinkReport {
+    online: bool,
+    since: Option<u64>,
+    error: Option<String>,
+}
+
+impl Hub {
+    /// A hub for `remotes`, which buffers `capacity` samples per host stream.
+    /// Nothing is connected until [`connect`].
+    pub fn new(remotes: &[Remote], capacity: usize) -> Self {
+        Self {
+            hosts: Mutex::new(
+                remotes
+                    .iter()
+                    .map(|remote| {
+                        Arc::new(Host::new(remote.name.clone(), Some(remote.url.clone())))
+                    })
+                    .collect(),
+            ),
+            capacity,
+        }
+    }
+
+    fn host(&self, name: &str) -> Option<Arc<Host>> {
+        let hosts = self.hosts.lock().unwrap();
+        hosts.iter().find(|host| host.name == name).cloned()
+    }
+
+    pub fn subscribe(
+        &self,
+        host: &str,
+        stream: Stream,
+    ) -> Result<broadcast::Receiver<Frame>, SubscribeError> {
+        let host = self.host(host).ok_or(SubscribeError::UnknownHost)?;
+        let link = host.link(stream).lock().unwrap();
+        link.tx
+            .as_ref()
+            .map(broadcast::Sender::subscribe)
+            .ok_or(SubscribeError::Offline)
+    }
+
+    /// Claims `name` for an agent, adding the host on its first push.
+    pub fn start_push(&self, name: &str) -> Result<PushGuard, PushError> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = match hosts.iter().find(|host| host.name == name) {
+            Some(host) if host.url.is_some() => return Err(PushError::Remote),
+            Some(host) => host.clone(),
+            None => {
+                let host = Arc::new(Host::new(name.to_string(), None));
+                hosts.push(host.clone());
+                host
+            }
+        };
+        if host.pushing.swap(true, Ordering::AcqRel) {
+            return Err(PushError::Pushing);
+        }
+        Ok(PushGuard(host))
+    }
+
+    pub fn report(&self) -> Vec<HostReport> {
+        let hosts = self.hosts.lock().unwrap().clone();
+        hosts.iter().map(|host| host.report()).collect()
+    }
+}
+
+impl Drop for PushGuard {
+    fn drop(&mut self) {
+        self.0.pushing.store(false, Ordering::Release);
+    }
+}
+
+impl Host {
+    fn new(name: String, url: Option<String>) -> Self {
+        Self {
+            name,
+            url,
+            pushing: AtomicBool::new(false),
+            cpus: Mutex::default(),
+            ram: Mutex::default(),
+            processes: Mutex::default(),
+        }
+    }
+
+    fn link(&self, stream: Stream) -> &Mutex<Link> {
+        match stream {
+            Stream::Cpus => &self.cpus,
+            Stream::Ram => &self.ram,
+            Stream::Processes => &self.processes,
+        }
+    }
+
+    /// Marks `stream` as connected and returns the sender to re-broadcast
+    /// its samples on.
+    fn go_online(&self, stream: Stream, capacity: usize) -> broadcast::Sender<Frame> {
+        let tx = broadcast::channel(capacity).0;
+        let mut link = self.link(stream).lock().unwrap();
+        link.tx = Some(tx.clone());
+        link.since = Some(unix_millis(SystemTime::now()));
+        link.error = None;
+        tracing::info!(host = self.name, ?stream, "remote stream connected");
+        tx
+    }
+
+    /// Marks `stream` as disconnected, or as still unreachable.
+    fn go_offline(&self, stream: Stream, error: String) {
+        let mut link = self.link(stream).lock().unwrap();
+        if link.tx.take().is_some() || link.since.is_none() {
+            link.since = Some(unix_millis(SystemTime::now()));
+            tracing::warn!(host = self.name, ?stream, %error, "remote stream offline");
+        }
+        link.error = Some(error);
+    }
+
+    fn report(&self) -> HostReport {
+        let report = |stream| {
+            let link = self.link(stream).lock().unwrap();
+            LinkReport {
+                online: link.tx.is_some(),
+                since: link.since,
+                error: link.error.clone(),
+            }
+        };
+        let (cpus, ram, processes) = (
+            report(Stream::Cpus),
+            report(Stream::Ram),
+            report(Stream::Processes),
+        );
+        let online = [&cpus, &ram, &processes]
+            .iter()
+            .filter(|link| link.online)
+            .count();
+        HostReport {
+            name: self.name.clone(),
+            url: self.url.clone(),
+            state: match online {
+                0 => HostState::Offline,
+                3 => HostState::Online,
+                _ => HostState::Degraded,
+            },
+            cpus,
+            ram,
+            processes,
+        }
+    }
+
+    /// Decodes a v2 sample of `stream` and re-broadcasts it tagged with our
+    /// name. Decoding the payload lets v1 clients of the hub get the v1
+    /// shape. The remote's `seq` and `timestamp_ms` are kept.
+    fn relay(&self, stream: Stream, text: &str, tx: &broadcast::Sender<Frame>, stats: &Stats) {
+        let frame = match stream {
+            Stream::Cpus => self.encode::<CpuState>(text),
+            Stream::Ram => self.encode::<MemState>(text),
+            Stream::Processes => self.encode::<Vec<ProcessInfo>>(text),
+        };
+        match frame {
+            Ok(frame) => {
+                stats.hosts.record_encoded(Frame::ENCODINGS);
+                let _ = tx.send(frame);
+                stats.hosts.record_broadcast();
+            }
+            Err(err) => {
+                tracing::debug!(host = self.name, ?stream, %err, "cannot relay remote sample, skipping it")
+            }
+        }
+    }
+
+    fn encode<T: Payload + DeserializeOwned>(&self, text: &str) -> serde_json::Result<Frame> {
+        let sample: Sample<T> = serde_json::from_str(text)?;
+        Frame::encode(&Sample {
+            seq: sample.seq,
+            timestamp_ms: sample.timestamp_ms,
+            host: Some(self.name.clone()),
+            top: None,
+            format: None,
+            instance: sample.instance,
+            data: &sample.data,
+            replayed: false,
+        })
+    }
+}
+
+/// Re-broadcasts what an agent pushes over `ws` until it disconnects or
+/// goes quiet for `idle_timeout`. Each stream counts as connected from its
+/// first sample on.
+pub async fn ingest(
+    hub: Arc<Hub>,
+    guard: PushGuard,
+    stats: Arc<Stats>,
+    idle_timeout: Duration,
+    mut ws: WebSocket,
+) {
+    let host = &guard.0;
+    let mut streams: Vec<(Stream, broadcast::Sender<Frame>)> = vec![];
+    let error: String = loop {
+        let text = match timeout(idle_timeout, ws.recv()).await {
+            Err(_) => break "agent went quiet".into(),
+            Ok(Some(Ok(Message::Text(text)))) => text,
+            Ok(Some(Ok(Message::Close(_))) | None) => break "connection closed".into(),
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(err))) => break err.to_string(),
+        };
+        let pushed: Pushed = match serde_json::from_str(&text) {
+            Ok(pushed) => pushed,
+            Err(err) => {
+                tracing::debug!(host = host.name, %err, "cannot decode pushed message, skipping it");
+                continue;
+            }
+        };
+        let tx = match streams.iter().find(|(stream, _)| *stream == pushed.stream) {
+            Some((_, tx)) => tx,
+            None => {
+                let tx = host.go_online(pushed.stream, hub.capacity);
+                streams.push((pushed.stream, tx));
+                &streams[streams.len() - 1].1
+            }
+        };
+        host.relay(pushed.stream, pushed.sample.get(), tx, &stats);
+    };
+    for (stream, _) in streams {
+        host.go_offline(stream, error.clone());
+    }
+}
+
+#[cfg(feature = "hub")]
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Follows every stream of every configured remote until the process exits.
+#[cfg(feature = "hub")]
+pub fn connect(hub: Arc<Hub>, stats: Arc<Stats>) {
+    let hosts = hub.hosts.lock().unwrap().clone();
+    for host in hosts {
+        for stream in [Stream::Cpus, Stream::Ram, Stream::Processes] {
+            tokio::spawn(follow(host.clone(), stream, hub.capacity, stats.clone()));
+        }
+    }
+}
+
+/// Keeps one stream of a remote connected, reconnecting with exponential
+/// backoff.
+#[cfg(feature = "hub")]
+async fn follow(host: Arc<Host>, stream: Stream, capacity: usize, stats: Arc<Stats>) {
+    use tokio_tungstenite::tungstenite::Message;
+
+    let Some(base) = &host.url else { return };
+    let url = format!(
+        "{}/realtime/{}?v=2",
+        base.trim_end_matches('/'),
+        stream.name()
+    );
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let error = match connect_async(url.as_str()).await {
+            Ok((mut socket, _)) => {
+                backoff = Duration::from_secs(1);
+                let tx = host.go_online(stream, capacity);
+                loop {
+                    match socket.next().await {
+                        Some(Ok(Message::Text(text))) => host.relay(stream, &text, &tx, &stats),
+                        Some(Ok(Message::Close(_))) | None => break "connection closed".into(),
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => break err.to_string(),
+                    }
+                }
+            }
+            Err(err) => err.to_string(),
+        };
+        host.go_offline(stream, error);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,53 @@
+//! `GET /info`: what the machine is, for clients to label their dashboards
+//! with. None of it changes while the server runs, so it is read once at
+//! startup rather than streamed.
+
+use serde::Serialize;
+use sysinfo::{CpuExt, CpuRefreshKind, RefreshKind, System, SystemExt};
+
+/// Whatever the OS does not say is `None`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SystemInfo {
+    pub hostname: Option<String>,
+    /// E.g. "Ubuntu", "Darwin" or "Windows".
+    pub os_name: Option<String>,
+    /// E.g. "22.04" or "13.4.1".
+    pub os_version: Option<String>,
+    pub kernel: Option<String>,
+    /// E.g. "AMD Ryzen 9 7950X 16-Core Processor".
+    pub cpu_brand: Option<String>,
+    /// E.g. "AuthenticAMD" or "GenuineIntel".
+    pub cpu_vendor: Option<String>,
+    pub physical_cores: Option<usize>,
+    pub logical_cores: usize,
+    /// In bytes.
+    pub total_memory: u64,
+    /// Unix seconds.
+    pub boot_time: u64,
+    /// The server's.
+    pub version: &'static str,
+}
+
+/// Blocks.
+pub fn read() -> SystemInfo {
+    let sys = System::new_with_specifics(
+        RefreshKind::new()
+            .with_cpu(CpuRefreshKind::new())
+            .with_memory(),
+    );
+    let cpu = sys.cpus().first();
+    let non_empty = |text: &str| Some(text.trim().to_owned()).filter(|text| !text.is_empty());
+    SystemInfo {
+        hostname: sys.host_name(),
+        os_name: sys.name(),
+        os_version: sys.os_version(),
+        kernel: sys.kernel_version(),
+        cpu_brand: cpu.and_then(|cpu| non_empty(cpu.brand())),
+        cpu_vendor: cpu.and_then(|cpu| non_empty(cpu.vendor_id())),
+        physical_cores: sys.physical_core_count(),
+        logical_cores: sys.cpus().len(),
+        total_memory: sys.total_memory(),
+        boot_time: sys.boot_time(),
+        version: env!("CARGO_PKG_VERSION"),
+    }
+}
@@ -0,0 +1,262 @@
+//! Kafka sink: produces every message of the configured streams, as their v2
+//! clients get it, keyed by the instance name so that each host's messages
+//! stay in order within a partition.
+//!
+//! Messages go through a bounded queue of our own, the oldest dropped once it
+//! is full, so that brokers being down never holds up the sampler: it only
+//! ever sends to a broadcast channel.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    config::{ClientConfig, RDKafkaLogLevel},
+    error::{KafkaError, RDKafkaErrorCode},
+    producer::{BaseRecord, DeliveryResult, ProducerContext, ThreadedProducer},
+    ClientContext,
+};
+use tokio::sync::{broadcast, watch, Notify};
+
+use crate::{
+    config::{KafkaAcks, KafkaCompression, KafkaConfig},
+    metrics_log::with_kind,
+    stats::Stats,
+    types::Instance,
+    ws::{Frame, Protocol},
+};
+
+/// How often at most to warn about messages dropped from the queue, or
+/// about the brokers being unreachable.
+const WARNING_EVERY: Duration = Duration::from_secs(60);
+
+/// How many messages librdkafka may hold, sent or waiting to be batched.
+/// Kept small, so that the queue whose oldest messages are dropped is ours.
+const IN_FLIGHT: usize = 1000;
+
+/// How long to wait when librdkafka's own queue is full.
+const FULL_RETRY: Duration = Duration::from_millis(100);
+
+pub type Producer = ThreadedProducer<Context>;
+
+/// Counts deliveries on `/stats`.
+pub struct Context {
+    stats: Arc<Stats>,
+    warned_down_at: Mutex<Option<Instant>>,
+}
+
+impl ClientContext for Context {
+    fn log(&self, level: RDKafkaLogLevel, facility: &str, message: &str) {
+        match level {
+            RDKafkaLogLevel::Emerg
+            | RDKafkaLogLevel::Alert
+            | RDKafkaLogLevel::Critical
+            | RDKafkaLogLevel::Error => {
+                tracing::error!(target: "librdkafka", facility, "{message}")
+            }
+            RDKafkaLogLevel::Warning => tracing::warn!(target: "librdkafka", facility, "{message}"),
+            RDKafkaLogLevel::Notice | RDKafkaLogLevel::Info => {
+                tracing::info!(target: "librdkafka", facility, "{message}")
+            }
+            RDKafkaLogLevel::Debug => tracing::debug!(target: "librdkafka", facility, "{message}"),
+        }
+    }
+
+    /// Failed connections are retried every few seconds at most and
+    /// reported every time; losing every broker is worth a warning now and
+    /// then.
+    fn error(&self, error: KafkaError, reason: &str) {
+        if error.rdkafka_error_code() == Some(RDKafkaErrorCode::AllBrokersDown) {
+            let mut warned_at = self.warned_down_at.lock().unwrap();
+            if warned_at.is_none_or(|at| at.elapsed() >= WARNING_EVERY) {
+                tracing::warn!(reason, "kafka unreachable");
+                *warned_at = Some(Instant::now());
+                return;
+            }
+        }
+        tracing::debug!(%error, reason, "kafka");
+    }
+}
+
+impl ProducerContext for Context {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        match result {
+            Ok(_) => self.stats.kafka.record_delivered(),
+            Err((err, _)) => {
+                self.stats.kafka.record_failed();
+                tracing::debug!(%err, "kafka message not delivered");
+            }
+        }
+    }
+}
+
+/// Creates the producer; it connects to the brokers in the background.
+pub fn producer(config: &KafkaConfig, stats: Arc<Stats>) -> Result<Producer, String> {
+    let acks = match config.acks {
+        KafkaAcks::None => "0",
+        KafkaAcks::Leader => "1",
+        KafkaAcks::All => "all",
+    };
+    let compression = match config.compression {
+        KafkaCompression::None => "none",
+        KafkaCompression::Gzip => "gzip",
+        KafkaCompression::Snappy => "snappy",
+        KafkaCompression::Lz4 => "lz4",
+        KafkaCompression::Zstd => "zstd",
+    };
+    ClientConfig::new()
+        .set("bootstrap.servers", config.brokers.join(","))
+        .set("client.id", "axact")
+        .set("acks", acks)
+        .set("compression.type", compression)
+        .set("queue.buffering.max.messages", IN_FLIGHT.to_string())
+        .create_with_context(Context {
+            stats,
+            warned_down_at: Mutex::new(None),
+        })
+        .map_err(|err| format!("cannot create the kafka producer: {err}"))
+}
+
+/// A message waiting for the producer.
+struct Message {
+    topic: Arc<str>,
+    payload: String,
+}
+
+/// Messages waiting for the producer, the oldest dropped once it is full.
+struct Queue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    ready: Notify,
+}
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    /// Dropped since the last warning about it.
+    dropped: u64,
+    warned_at: Option<Instant>,
+}
+
+impl Queue {
+    fn push(&self, message: Message, stats: &Stats) {
+        let mut state = self.state.lock().unwrap();
+        if state.messages.len() == self.capacity {
+            state.messages.pop_front();
+            state.record_dropped(1, stats);
+        }
+        state.messages.push_back(message);
+        drop(state);
+        self.ready.notify_one();
+    }
+}
+
+impl QueueState {
+    fn record_dropped(&mut self, count: u64, stats: &Stats) {
+        stats.kafka.record_dropped(count);
+        self.dropped += count;
+        if self
+            .warned_at
+            .is_none_or(|at| at.elapsed() >= WARNING_EVERY)
+        {
+            tracing::warn!(
+                dropped = self.dropped,
+                "kafka queue full, dropping the oldest messages"
+            );
+            self.dropped = 0;
+            self.warned_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Produces every message of `streams` whose stream has a topic, until the
+/// process exits.
+pub async fn produce(
+    producer: Producer,
+    config: KafkaConfig,
+    instance: watch::Receiver<Arc<Instance>>,
+    streams: Vec<(&'static str, broadcast::Receiver<Frame>)>,
+    stats: Arc<Stats>,
+) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState {
+            messages: VecDeque::new(),
+            dropped: 0,
+            warned_at: None,
+        }),
+        capacity: config.queue,
+        ready: Notify::new(),
+    });
+    for (stream, rx) in streams {
+        let Some(topic) = config.topic_of(stream) else {
+            continue;
+        };
+        // On a shared topic, a message says which stream it is from.
+        let kind = config.topic.is_some().then_some(stream);
+        tokio::spawn(collect(
+            rx,
+            topic.into(),
+            kind,
+            queue.clone(),
+            stats.clone(),
+        ));
+    }
+
+    loop {
+        let next = queue.state.lock().unwrap().messages.pop_front();
+        let Some(message) = next else {
+            queue.ready.notified().await;
+            continue;
+        };
+        let key = instance.borrow().name.clone();
+        let record = BaseRecord::to(&message.topic)
+            .key(&key)
+            .payload(&message.payload);
+        match producer.send(record) {
+            Ok(()) => {}
+            Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) => {
+                queue.state.lock().unwrap().messages.push_front(message);
+                tokio::time::sleep(FULL_RETRY).await;
+            }
+            Err((err, _)) => {
+                stats.kafka.record_failed();
+                tracing::debug!(topic = %message.topic, %err, "cannot produce kafka message");
+            }
+        }
+    }
+}
+
+/// Queues every message of one stream.
+async fn collect(
+    mut rx: broadcast::Receiver<Frame>,
+    topic: Arc<str>,
+    kind: Option<&'static str>,
+    queue: Arc<Queue>,
+    stats: Arc<Stats>,
+) {
+    loop {
+        let frame = match rx.recv().await {
+            Ok(frame) => frame,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                queue.state.lock().unwrap().record_dropped(skipped, &stats);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let text = frame.text(Protocol::V2);
+        let payload = match kind {
+            Some(kind) => with_kind(text, kind),
+            None => text.to_owned(),
+        };
+        queue.push(
+            Message {
+                topic: topic.clone(),
+                payload,
+            },
+            &stats,
+        );
+    }
+}
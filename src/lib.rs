@@ -0,0 +1,55 @@
+//! Samples system metrics and streams them to WebSocket clients.
+//! [`server::run`] is all the `axact` binary does; the sampler and the
+//! collectors it runs can be embedded in other programs as well.
+
+pub mod access_log;
+pub mod admin;
+pub mod alerts;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod collectors;
+pub mod config;
+pub mod connections;
+#[cfg(feature = "sqlite")]
+pub mod db;
+pub mod delta;
+#[cfg(any(feature = "cpu", feature = "mem"))]
+pub mod history;
+pub mod hub;
+pub mod info;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod metrics_log;
+pub mod multiplex;
+pub mod once;
+#[cfg(unix)]
+pub mod reload;
+pub mod replay;
+pub mod sampler;
+pub mod server;
+pub mod share;
+pub mod shutdown;
+pub mod simulate;
+pub mod smart;
+pub mod snapshot;
+#[cfg(any(
+    feature = "cpu",
+    feature = "mem",
+    feature = "processes",
+    feature = "power",
+    feature = "disks",
+    feature = "network",
+    feature = "gpu",
+    feature = "sensors",
+    feature = "system"
+))]
+pub mod sse;
+pub mod stats;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod types;
+#[cfg(feature = "upstream")]
+pub mod upstream;
+pub mod ws;
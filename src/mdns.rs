@@ -0,0 +1,616 @@
+//! Zeroconf: advertises the server as `_axact._tcp.local` over multicast DNS
+//! and browses for other instances for `GET /discover`. Speaks just enough
+//! DNS for that: there is no probing for name conflicts, no known-answer
+//! suppression and no IPv6 records.
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use sysinfo::{System, SystemExt};
+use tokio::{net::UdpSocket, task::JoinHandle, time};
+
+use crate::config::MdnsConfig;
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+const SERVICE: &str = "_axact._tcp.local";
+/// Asked for by browsers that enumerate every service type.
+const SERVICE_TYPES: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the class of records that replace every other record of their
+/// name, and of questions that ask for a unicast reply.
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+/// Answers to a legacy unicast query may not be cached for longer.
+const LEGACY_TTL: u32 = 10;
+
+/// A resource record, with its data already encoded.
+struct Record {
+    name: String,
+    kind: u16,
+    /// Whether it replaces every cached record of its name and type.
+    unique: bool,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+/// Our records, grouped by the question they answer.
+struct Records {
+    instance: String,
+    host: String,
+    ptr: Record,
+    service_type: Record,
+    srv: Record,
+    txt: Record,
+    a: Vec<Record>,
+}
+
+/// The running advertisement; see [`Advertisement::withdraw`].
+pub struct Advertisement {
+    socket: Arc<UdpSocket>,
+    records: Arc<Records>,
+    responder: JoinHandle<()>,
+}
+
+/// An instance `GET /discover` found.
+#[derive(Serialize, Debug, Default)]
+pub struct Instance {
+    name: String,
+    host: Option<String>,
+    port: Option<u16>,
+    addresses: Vec<IpAddr>,
+    version: Option<String>,
+    streams: Vec<String>,
+}
+
+impl Advertisement {
+    /// Advertises the server on the addresses it is listening on, unless it
+    /// only listens on loopback addresses. Logs and gives up if the mDNS
+    /// port cannot be joined.
+    pub async fn start(config: &MdnsConfig, bound: &[SocketAddr]) -> Option<Self> {
+        if !config.advertise {
+            return None;
+        }
+        let Some(port) = bound
+            .iter()
+            .find(|addr| !addr.ip().is_loopback())
+            .map(SocketAddr::port)
+        else {
+            tracing::info!("only listening on loopback, not advertising over mDNS");
+            return None;
+        };
+        let addresses = advertised_addresses(bound);
+        if addresses.is_empty() {
+            tracing::warn!("no IPv4 address to advertise over mDNS");
+            return None;
+        }
+
+        let host_name = System::new().host_name();
+        let host_label = host_name
+            .as_deref()
+            .and_then(|name| name.split('.').next())
+            .filter(|label| !label.is_empty())
+            .unwrap_or("axact");
+        let instance = config.name.as_deref().unwrap_or(host_label);
+        let records = Arc::new(Records::new(instance, host_label, port, &addresses));
+
+        let socket = match responder_socket() {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                tracing::warn!(%err, "cannot join mDNS, not advertising");
+                return None;
+            }
+        };
+        tracing::info!(
+            instance = records.instance,
+            host = records.host,
+            port,
+            ?addresses,
+            "advertising over mDNS"
+        );
+        let responder = tokio::spawn(respond(socket.clone(), records.clone()));
+        Some(Self {
+            socket,
+            records,
+            responder,
+        })
+    }
+
+    /// Tells everyone on the network to forget us.
+    pub async fn withdraw(self) {
+        self.responder.abort();
+        let packet = self.records.announcement(Some(0));
+        if let Err(err) = self.socket.send_to(&packet, (GROUP, PORT)).await {
+            tracing::warn!(%err, "cannot withdraw mDNS advertisement");
+        } else {
+            tracing::info!(
+                instance = self.records.instance,
+                "mDNS advertisement withdrawn"
+            );
+        }
+    }
+}
+
+/// The IPv4 addresses we are reachable on. A wildcard bind is reachable on
+/// every address, of which the one of the default multicast route is used.
+fn advertised_addresses(bound: &[SocketAddr]) -> Vec<Ipv4Addr> {
+    let mut addresses = vec![];
+    for addr in bound {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => match primary_address() {
+                Some(ip) => ip,
+                None => continue,
+            },
+            IpAddr::V4(ip) if !ip.is_loopback() => ip,
+            _ => continue,
+        };
+        if !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+    }
+    addresses
+}
+
+/// The address the system would send multicast from. Connecting a UDP
+/// socket sends nothing.
+fn primary_address() -> Option<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((GROUP, PORT)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+/// A socket on the mDNS port that shares it with other responders, such as
+/// Avahi.
+fn responder_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Announces our records twice, a second apart, then answers queries.
+async fn respond(socket: Arc<UdpSocket>, records: Arc<Records>) {
+    let group = SocketAddr::from((GROUP, PORT));
+    let announcement = records.announcement(None);
+    let mut announce_again = Some(time::Instant::now() + Duration::from_secs(1));
+    let _ = socket.send_to(&announcement, group).await;
+
+    let mut buf = [0; 9000];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = time::sleep_until(announce_again.unwrap_or_else(time::Instant::now)),
+                if announce_again.is_some() =>
+            {
+                announce_again = None;
+                let _ = socket.send_to(&announcement, group).await;
+                continue;
+            }
+        };
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                tracing::debug!(%err, "mDNS receive failed");
+                continue;
+            }
+        };
+        let Some(reply) = records.reply(&buf[..len], peer.port() != PORT) else {
+            continue;
+        };
+        // Queries from another port are legacy unicast ones, which want the
+        // answer sent back to them alone.
+        let to = if peer.port() == PORT { group } else { peer };
+        if let Err(err) = socket.send_to(&reply, to).await {
+            tracing::debug!(%err, %to, "mDNS reply failed");
+        }
+    }
+}
+
+impl Records {
+    fn new(instance: &str, host: &str, port: u16, addresses: &[Ipv4Addr]) -> Self {
+        let instance = format!("{instance}.{SERVICE}");
+        let host = format!("{host}.local");
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&port.to_be_bytes());
+        encode_name(&mut srv, &host);
+        let streams: Vec<&str> = [
+            (cfg!(feature = "cpu"), "cpus"),
+            (cfg!(feature = "mem"), "ram"),
+            (cfg!(feature = "processes"), "processes"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, stream)| enabled.then_some(stream))
+        .collect();
+        let mut txt = vec![];
+        for entry in [
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            format!("streams={}", streams.join(",")),
+        ] {
+            txt.push(entry.len() as u8);
+            txt.extend_from_slice(entry.as_bytes());
+        }
+
+        let name_data = |name: &str| {
+            let mut data = vec![];
+            encode_name(&mut data, name);
+            data
+        };
+        Self {
+            ptr: Record {
+                name: SERVICE.into(),
+                kind: TYPE_PTR,
+                unique: false,
+                ttl: 4500,
+                data: name_data(&instance),
+            },
+            service_type: Record {
+                name: SERVICE_TYPES.into(),
+                kind: TYPE_PTR,
+                unique: false,
+                ttl: 4500,
+                data: name_data(SERVICE),
+            },
+            srv: Record {
+                name: instance.clone(),
+                kind: TYPE_SRV,
+                unique: true,
+                ttl: 120,
+                data: srv,
+            },
+            txt: Record {
+                name: instance.clone(),
+                kind: TYPE_TXT,
+                unique: true,
+                ttl: 4500,
+                data: txt,
+            },
+            a: addresses
+                .iter()
+                .map(|ip| Record {
+                    name: host.clone(),
+                    kind: TYPE_A,
+                    unique: true,
+                    ttl: 120,
+                    data: ip.octets().to_vec(),
+                })
+                .collect(),
+            instance,
+            host,
+        }
+    }
+
+    /// Every record, unsolicited; with a `ttl` of 0 it is a goodbye.
+    fn announcement(&self, ttl: Option<u32>) -> Vec<u8> {
+        let mut answers = vec![&self.ptr, &self.srv, &self.txt];
+        answers.extend(&self.a);
+        encode_response(0, &[], &answers, &[], ttl)
+    }
+
+    /// The reply to `query`, if it asks about us.
+    fn reply(&self, query: &[u8], legacy: bool) -> Option<Vec<u8>> {
+        let mut reader = Reader::new(query);
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        if flags & 0x8000 != 0 {
+            // A response, not a query.
+            return None;
+        }
+        let questions = reader.u16()?;
+        reader.skip(6)?;
+
+        let mut asked = vec![];
+        let mut answers: Vec<&Record> = vec![];
+        let mut additionals: Vec<&Record> = vec![];
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let kind = reader.u16()?;
+            let class = reader.u16()? & !CLASS_TOP_BIT;
+            if class != CLASS_IN {
+                continue;
+            }
+            let matches = |wanted: u16| kind == wanted || kind == TYPE_ANY;
+            let before = answers.len();
+            if name.eq_ignore_ascii_case(SERVICE) && matches(TYPE_PTR) {
+                answers.push(&self.ptr);
+                additionals.extend([&self.srv, &self.txt]);
+                additionals.extend(&self.a);
+            } else if name.eq_ignore_ascii_case(SERVICE_TYPES) && matches(TYPE_PTR) {
+                answers.push(&self.service_type);
+            } else if name.eq_ignore_ascii_case(&self.instance) {
+                if matches(TYPE_SRV) {
+                    answers.push(&self.srv);
+                    additionals.extend(&self.a);
+                }
+                if matches(TYPE_TXT) {
+                    answers.push(&self.txt);
+                }
+            } else if name.eq_ignore_ascii_case(&self.host) && matches(TYPE_A) {
+                answers.extend(&self.a);
+            }
+            if answers.len() > before {
+                asked.push((name, kind));
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        dedup(&mut answers);
+        additionals.retain(|record| !answers.iter().any(|answer| std::ptr::eq(*answer, *record)));
+        dedup(&mut additionals);
+
+        // Legacy resolvers expect their query echoed, and do not know about
+        // the cache-flush bit.
+        if legacy {
+            Some(encode_response(
+                id,
+                &asked,
+                &answers,
+                &additionals,
+                Some(LEGACY_TTL),
+            ))
+        } else {
+            Some(encode_response(0, &[], &answers, &additionals, None))
+        }
+    }
+}
+
+fn dedup(records: &mut Vec<&Record>) {
+    let mut kept: Vec<&Record> = vec![];
+    for record in records.drain(..) {
+        if !kept.iter().any(|other| std::ptr::eq(*other, record)) {
+            kept.push(record);
+        }
+    }
+    *records = kept;
+}
+
+/// An authoritative response; `ttl` overrides every record's. With
+/// `questions`, it is a reply to a legacy query and sets no cache-flush
+/// bits.
+fn encode_response(
+    id: u16,
+    questions: &[(String, u16)],
+    answers: &[&Record],
+    additionals: &[&Record],
+    ttl: Option<u32>,
+) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&0x8400u16.to_be_bytes());
+    for count in [questions.len(), answers.len(), 0, additionals.len()] {
+        out.extend_from_slice(&(count as u16).to_be_bytes());
+    }
+    for (name, kind) in questions {
+        encode_name(&mut out, name);
+        out.extend_from_slice(&kind.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for record in answers.iter().chain(additionals) {
+        encode_name(&mut out, &record.name);
+        out.extend_from_slice(&record.kind.to_be_bytes());
+        let class = if record.unique && questions.is_empty() {
+            CLASS_IN | CLASS_TOP_BIT
+        } else {
+            CLASS_IN
+        };
+        out.extend_from_slice(&class.to_be_bytes());
+        out.extend_from_slice(&ttl.unwrap_or(record.ttl).to_be_bytes());
+        out.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&record.data);
+    }
+    out
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+/// Asks for every instance on the network and collects the answers that
+/// arrive within `wait`.
+pub async fn discover(wait: Duration) -> std::io::Result<Vec<Instance>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_ttl_v4(255)?;
+    let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    encode_name(&mut query, SERVICE);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    socket
+        .send_to(&query, SocketAddrV4::new(GROUP, PORT))
+        .await?;
+
+    let mut found = Found::default();
+    let deadline = time::Instant::now() + wait;
+    let mut buf = [0; 9000];
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, _) = received?;
+        found.read(&buf[..len]);
+    }
+    Ok(found.instances())
+}
+
+/// What the responses to a browse said, by record name.
+#[derive(Default)]
+struct Found {
+    instances: Vec<String>,
+    services: BTreeMap<String, (u16, String)>,
+    texts: BTreeMap<String, Vec<String>>,
+    addresses: BTreeMap<String, Vec<IpAddr>>,
+}
+
+impl Found {
+    fn read(&mut self, packet: &[u8]) {
+        let _ = self.try_read(packet);
+    }
+
+    fn try_read(&mut self, packet: &[u8]) -> Option<()> {
+        let mut reader = Reader::new(packet);
+        reader.skip(4)?;
+        let questions = reader.u16()?;
+        let records = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+        for _ in 0..questions {
+            reader.name()?;
+            reader.skip(4)?;
+        }
+        for _ in 0..records {
+            let name = reader.name()?.to_ascii_lowercase();
+            let kind = reader.u16()?;
+            reader.skip(6)?;
+            let len = reader.u16()? as usize;
+            let start = reader.pos;
+            let mut data = reader.clone();
+            reader.skip(len)?;
+            match kind {
+                TYPE_PTR if name == SERVICE => {
+                    let instance = data.name()?.to_ascii_lowercase();
+                    if !self.instances.contains(&instance) {
+                        self.instances.push(instance);
+                    }
+                }
+                TYPE_SRV => {
+                    data.skip(4)?;
+                    let port = data.u16()?;
+                    let target = data.name()?.to_ascii_lowercase();
+                    self.services.insert(name, (port, target));
+                }
+                TYPE_TXT => {
+                    let mut entries = vec![];
+                    while data.pos < start + len {
+                        let entry_len = data.u8()? as usize;
+                        let entry = data.bytes(entry_len)?;
+                        entries.push(String::from_utf8_lossy(entry).into_owned());
+                    }
+                    self.texts.insert(name, entries);
+                }
+                TYPE_A if len == 4 => {
+                    let ip = IpAddr::from(<[u8; 4]>::try_from(data.bytes(4)?).ok()?);
+                    let addresses = self.addresses.entry(name).or_default();
+                    if !addresses.contains(&ip) {
+                        addresses.push(ip);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(())
+    }
+
+    fn instances(self) -> Vec<Instance> {
+        self.instances
+            .iter()
+            .map(|full_name| {
+                let mut instance = Instance {
+                    name: full_name
+                        .strip_suffix(SERVICE)
+                        .unwrap_or(full_name)
+                        .trim_end_matches('.')
+                        .to_string(),
+                    ..Instance::default()
+                };
+                if let Some((port, target)) = self.services.get(full_name) {
+                    instance.port = Some(*port);
+                    instance.addresses = self.addresses.get(target).cloned().unwrap_or_default();
+                    instance.host = Some(target.clone());
+                }
+                for entry in self.texts.get(full_name).into_iter().flatten() {
+                    match entry.split_once('=') {
+                        Some(("version", version)) => instance.version = Some(version.into()),
+                        Some(("streams", streams)) => {
+                            instance.streams = streams
+                                .split(',')
+                                .filter(|stream| !stream.is_empty())
+                                .map(Into::into)
+                                .collect()
+                        }
+                        _ => {}
+                    }
+                }
+                instance
+            })
+            .collect()
+    }
+}
+
+/// Reads a DNS message, following name compression pointers.
+#[derive(Clone)]
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(drop)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let mut labels = vec![];
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // Bounds the pointers a malicious packet could loop through.
+        for _ in 0..128 {
+            let len = *self.buf.get(pos)? as usize;
+            if len == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Some(labels.join("."));
+            }
+            if len & 0xc0 == 0xc0 {
+                let target = (len & 0x3f) << 8 | *self.buf.get(pos + 1)? as usize;
+                if !jumped {
+                    self.pos = pos + 2;
+                }
+                jumped = true;
+                pos = target;
+                continue;
+            }
+            let label = self.buf.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+        None
+    }
+}
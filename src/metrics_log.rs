@@ -0,0 +1,186 @@
+//! The metrics log: every message of the realtime streams and the alert
+//! events, as their v2 clients get it plus a `kind` field naming its stream,
+//! appended as one JSON line to a file that is rotated by size or age.
+//! `--replay` serves such files again, see [`crate::replay`].
+//!
+//! Lines are written on a thread of their own, behind a bounded queue, so
+//! that a slow or full disk never holds up the streams: lines that do not
+//! fit in the queue or cannot be written are dropped, with a warning now
+//! and then.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    access_log::{RotatingFile, Rotation},
+    config::{Fsync, MetricsLogConfig},
+    stats::Stats,
+    ws::{Frame, Protocol},
+};
+
+/// How often at most to warn about lines that were dropped or could not be
+/// written.
+const WARNING_EVERY: Duration = Duration::from_secs(60);
+
+/// Opens the file `config` names and writes every message of `streams` to
+/// it until the process exits.
+pub fn start(
+    config: &MetricsLogConfig,
+    streams: Vec<(&'static str, broadcast::Receiver<Frame>)>,
+    stats: Arc<Stats>,
+) -> Result<(), String> {
+    let Some(path) = config.path.clone() else {
+        return Ok(());
+    };
+    let rotation = Rotation {
+        max_size: config.max_size_mb * 1024 * 1024,
+        max_age: config
+            .max_age_mins
+            .map(|mins| Duration::from_secs(mins * 60)),
+        keep: config.keep,
+        gzip: config.gzip,
+    };
+    let file = RotatingFile::open(path.clone(), rotation)
+        .map_err(|err| format!("cannot open {}: {err}", path.display()))?;
+
+    let (tx, rx) = mpsc::channel(config.queue);
+    let dropped = Arc::new(Mutex::new(Throttled::default()));
+    for (kind, rx) in streams {
+        tokio::spawn(collect(
+            rx,
+            kind,
+            tx.clone(),
+            dropped.clone(),
+            stats.clone(),
+        ));
+    }
+    let fsync = match config.fsync {
+        Fsync::Never => None,
+        Fsync::Interval => Some(Duration::from_millis(config.fsync_interval_ms)),
+        Fsync::Always => Some(Duration::ZERO),
+    };
+    std::thread::Builder::new()
+        .name("metrics-log".into())
+        .spawn(move || write(file, rx, fsync, &stats))
+        .map_err(|err| format!("cannot start the metrics log writer: {err}"))?;
+    tracing::info!(path = %path.display(), "logging metrics");
+    Ok(())
+}
+
+/// Queues every message of one stream.
+async fn collect(
+    mut rx: broadcast::Receiver<Frame>,
+    kind: &'static str,
+    tx: mpsc::Sender<String>,
+    dropped: Arc<Mutex<Throttled>>,
+    stats: Arc<Stats>,
+) {
+    loop {
+        let skipped = match rx.recv().await {
+            Ok(frame) => match tx.try_send(with_kind(frame.text(Protocol::V2), kind)) {
+                Ok(()) => continue,
+                Err(mpsc::error::TrySendError::Full(_)) => 1,
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+            },
+            Err(broadcast::error::RecvError::Lagged(skipped)) => skipped,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        stats.metrics_log.record_dropped(skipped);
+        if let Some(count) = dropped.lock().unwrap().record(skipped) {
+            tracing::warn!(dropped = count, "metrics log queue full, dropping lines");
+        }
+    }
+}
+
+/// Writes the queued lines, flushing them to disk at most every `fsync`,
+/// until every collector is gone.
+fn write(
+    mut file: RotatingFile,
+    mut rx: mpsc::Receiver<String>,
+    fsync: Option<Duration>,
+    stats: &Stats,
+) {
+    let mut failed = Throttled::default();
+    let mut synced_at = Instant::now();
+    while let Some(line) = rx.blocking_recv() {
+        let mut result = file.write_line(&line);
+        if let Some(fsync) = fsync {
+            if result.is_ok() && synced_at.elapsed() >= fsync {
+                result = file.sync();
+                synced_at = Instant::now();
+            }
+        }
+        match result {
+            Ok(()) => stats.metrics_log.record_written(),
+            Err(err) => {
+                stats.metrics_log.record_failed();
+                if let Some(count) = failed.record(1) {
+                    tracing::warn!(
+                        path = %file.path.display(),
+                        %err,
+                        failed = count,
+                        "cannot write metrics log, dropping lines"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Counts something to warn about at most once per `WARNING_EVERY`.
+#[derive(Default)]
+struct Throttled {
+    /// Since the last warning.
+    count: u64,
+    warned_at: Option<Instant>,
+}
+
+impl Throttled {
+    /// The count to warn about, if it is time to.
+    fn record(&mut self, count: u64) -> Option<u64> {
+        self.count += count;
+        if self
+            .warned_at
+            .is_some_and(|at| at.elapsed() < WARNING_EVERY)
+        {
+            return None;
+        }
+        self.warned_at = Some(Instant::now());
+        Some(std::mem::take(&mut self.count))
+    }
+}
+
+/// `sample`, a JSON object, with a `kind` field in front.
+pub fn with_kind(sample: &str, kind: &str) -> String {
+    match sample.strip_prefix('{') {
+        Some(rest) => format!("{{\"kind\":\"{kind}\",{rest}"),
+        None => sample.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kind_goes_first() {
+        let tagged = with_kind(r#"{"seq":1,"data":{}}"#, "ram");
+        assert_eq!(tagged, r#"{"kind":"ram","seq":1,"data":{}}"#);
+        let value: serde_json::Value = serde_json::from_str(&tagged).unwrap();
+        assert_eq!(value["kind"], "ram");
+    }
+
+    #[test]
+    fn warnings_are_throttled() {
+        let mut dropped = Throttled::default();
+        assert_eq!(dropped.record(3), Some(3));
+        assert_eq!(dropped.record(2), None);
+        assert_eq!(dropped.record(1), None);
+        dropped.warned_at = Some(Instant::now() - WARNING_EVERY);
+        assert_eq!(dropped.record(1), Some(4));
+    }
+}
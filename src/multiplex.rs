@@ -0,0 +1,650 @@
+//! `/realtime/all`: several streams over one WebSocket. The client picks
+//! its topics with `?only=` or `?exclude=`, and changes them at any time by
+//! sending `{"subscribe": [...]}` or `{"unsubscribe": [...]}`. Each message
+//! comes wrapped as `{"topic": "cpus", "data": ...}`, `data` being what the
+//! topic's own endpoint would send. The effective topics are echoed as
+//! `{"subscribed": [...]}` on connecting and after every request.
+//!
+//! Every subscription is a task of its own, forwarding its broadcast channel
+//! into the connection's queue; a slow client lags on each topic apart.
+
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize, Serializer};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    config::SamplerConfig,
+    sampler::Broadcast,
+    stats::{ChannelStats, Stats},
+    types::Format,
+    ws::{self, Encoding, Frame, Protocol},
+};
+
+/// How many messages of every topic together wait for the client before
+/// the topics start lagging.
+const QUEUE: usize = 64;
+
+/// One of the streams that can be multiplexed, named after its endpoint.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String")]
+pub enum Topic {
+    #[cfg(feature = "cpu")]
+    Cpus,
+    #[cfg(feature = "power")]
+    Power,
+    #[cfg(feature = "disks")]
+    Disks,
+    #[cfg(feature = "network")]
+    Network,
+    #[cfg(feature = "gpu")]
+    Gpu,
+    #[cfg(feature = "sensors")]
+    Sensors,
+    #[cfg(feature = "system")]
+    System,
+    #[cfg(feature = "mem")]
+    Ram,
+    #[cfg(feature = "processes")]
+    Processes,
+    Alerts,
+}
+
+impl Topic {
+    pub const ALL: &'static [Topic] = &[
+        #[cfg(feature = "cpu")]
+        Topic::Cpus,
+        #[cfg(feature = "power")]
+        Topic::Power,
+        #[cfg(feature = "disks")]
+        Topic::Disks,
+        #[cfg(feature = "network")]
+        Topic::Network,
+        #[cfg(feature = "gpu")]
+        Topic::Gpu,
+        #[cfg(feature = "sensors")]
+        Topic::Sensors,
+        #[cfg(feature = "system")]
+        Topic::System,
+        #[cfg(feature = "mem")]
+        Topic::Ram,
+        #[cfg(feature = "processes")]
+        Topic::Processes,
+        Topic::Alerts,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "cpu")]
+            Topic::Cpus => "cpus",
+            #[cfg(feature = "power")]
+            Topic::Power => "power",
+            #[cfg(feature = "disks")]
+            Topic::Disks => "disks",
+            #[cfg(feature = "network")]
+            Topic::Network => "network",
+            #[cfg(feature = "gpu")]
+            Topic::Gpu => "gpu",
+            #[cfg(feature = "sensors")]
+            Topic::Sensors => "sensors",
+            #[cfg(feature = "system")]
+            Topic::System => "system",
+            #[cfg(feature = "mem")]
+            Topic::Ram => "ram",
+            #[cfg(feature = "processes")]
+            Topic::Processes => "processes",
+            Topic::Alerts => "alerts",
+        }
+    }
+
+    /// The sampling period its interval cannot be shorter than, `None` for
+    /// events, which cannot be coalesced.
+    #[cfg_attr(
+        not(any(
+            feature = "cpu",
+            feature = "mem",
+            feature = "processes",
+            feature = "power",
+            feature = "disks",
+            feature = "network",
+            feature = "gpu",
+            feature = "sensors",
+            feature = "system"
+        )),
+        allow(unused_variables)
+    )]
+    fn period(self, config: &SamplerConfig) -> Option<Duration> {
+        match self {
+            #[cfg(feature = "cpu")]
+            Topic::Cpus => Some(config.period(crate::config::Stream::Cpus)),
+            #[cfg(feature = "power")]
+            Topic::Power => Some(config.period(crate::config::Stream::Cpus)),
+            #[cfg(feature = "disks")]
+            Topic::Disks => Some(config.disk_period()),
+            #[cfg(feature = "network")]
+            Topic::Network => Some(config.network_period()),
+            #[cfg(feature = "gpu")]
+            Topic::Gpu => Some(config.gpu_period()),
+            #[cfg(feature = "sensors")]
+            Topic::Sensors => Some(config.sensor_period()),
+            #[cfg(feature = "system")]
+            Topic::System => Some(config.system_period()),
+            #[cfg(feature = "mem")]
+            Topic::Ram => Some(config.period(crate::config::Stream::Ram)),
+            #[cfg(feature = "processes")]
+            Topic::Processes => Some(config.period(crate::config::Stream::Processes)),
+            Topic::Alerts => None,
+        }
+    }
+
+    fn stats(self, stats: &Stats) -> &ChannelStats {
+        match self {
+            #[cfg(feature = "cpu")]
+            Topic::Cpus => &stats.cpus,
+            #[cfg(feature = "power")]
+            Topic::Power => &stats.power,
+            #[cfg(feature = "disks")]
+            Topic::Disks => &stats.disks,
+            #[cfg(feature = "network")]
+            Topic::Network => &stats.network,
+            #[cfg(feature = "gpu")]
+            Topic::Gpu => &stats.gpu,
+            #[cfg(feature = "sensors")]
+            Topic::Sensors => &stats.sensors,
+            #[cfg(feature = "system")]
+            Topic::System => &stats.system,
+            #[cfg(feature = "mem")]
+            Topic::Ram => &stats.ram,
+            #[cfg(feature = "processes")]
+            Topic::Processes => &stats.processes,
+            Topic::Alerts => &stats.alerts,
+        }
+    }
+
+    /// `frame` as a connection with `protocol` and `format` gets it.
+    fn encode(self, frame: &Frame, protocol: Protocol, format: Format) -> Option<String> {
+        if format.is_raw() {
+            return Some(frame.text(protocol).to_owned());
+        }
+        match self {
+            #[cfg(feature = "cpu")]
+            Topic::Cpus => ws::reformat::<crate::types::CpuState>(frame, protocol, format),
+            #[cfg(feature = "power")]
+            Topic::Power => ws::reformat::<crate::types::PowerState>(frame, protocol, format),
+            #[cfg(feature = "disks")]
+            Topic::Disks => ws::reformat::<crate::types::DiskState>(frame, protocol, format),
+            #[cfg(feature = "network")]
+            Topic::Network => ws::reformat::<crate::types::NetState>(frame, protocol, format),
+            #[cfg(feature = "gpu")]
+            Topic::Gpu => ws::reformat::<crate::types::GpuState>(frame, protocol, format),
+            #[cfg(feature = "sensors")]
+            Topic::Sensors => ws::reformat::<crate::types::SensorState>(frame, protocol, format),
+            #[cfg(feature = "system")]
+            Topic::System => ws::reformat::<crate::types::SystemState>(frame, protocol, format),
+            #[cfg(feature = "mem")]
+            Topic::Ram => ws::reformat::<crate::types::MemState>(frame, protocol, format),
+            #[cfg(feature = "processes")]
+            Topic::Processes => {
+                ws::reformat::<Vec<crate::types::ProcessInfo>>(frame, protocol, format)
+            }
+            // Events have no values to convert.
+            Topic::Alerts => Some(frame.text(protocol).to_owned()),
+        }
+    }
+}
+
+impl TryFrom<String> for Topic {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, String> {
+        name.parse()
+    }
+}
+
+impl std::str::FromStr for Topic {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        // "cpu" as well, after the feature.
+        let name = if name == "cpu" { "cpus" } else { name };
+        Topic::ALL
+            .iter()
+            .copied()
+            .find(|topic| topic.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Topic::ALL.iter().map(|topic| topic.name()).collect();
+                format!(
+                    "unknown topic {name:?}, expected one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Serialize for Topic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// The broadcast channel of every topic, subscribed to by `/realtime/all`
+/// connections.
+pub struct Topics {
+    broadcasts: Vec<(Topic, Arc<Broadcast>)>,
+}
+
+impl Topics {
+    pub fn new(broadcasts: Vec<(Topic, Arc<Broadcast>)>) -> Self {
+        Self { broadcasts }
+    }
+
+    fn broadcast(&self, topic: Topic) -> Option<&Broadcast> {
+        self.broadcasts
+            .iter()
+            .find(|(candidate, _)| *candidate == topic)
+            .map(|(_, broadcast)| &**broadcast)
+    }
+}
+
+/// Query parameters `/realtime/all` takes on top of [`ws::StreamParams`].
+#[derive(Deserialize, Debug, Default)]
+pub struct AllParams {
+    /// The topics to start with, comma-separated.
+    only: Option<String>,
+    /// Every topic but these to start with, comma-separated.
+    exclude: Option<String>,
+    /// Per-topic versions of `interval`.
+    #[cfg(feature = "cpu")]
+    cpus_interval: Option<String>,
+    #[cfg(feature = "power")]
+    power_interval: Option<String>,
+    #[cfg(feature = "disks")]
+    disks_interval: Option<String>,
+    #[cfg(feature = "network")]
+    network_interval: Option<String>,
+    #[cfg(feature = "gpu")]
+    gpu_interval: Option<String>,
+    #[cfg(feature = "sensors")]
+    sensors_interval: Option<String>,
+    #[cfg(feature = "system")]
+    system_interval: Option<String>,
+    #[cfg(feature = "mem")]
+    ram_interval: Option<String>,
+    #[cfg(feature = "processes")]
+    processes_interval: Option<String>,
+}
+
+impl AllParams {
+    /// The topics to subscribe to on connecting: none unless `only` or
+    /// `exclude` says otherwise.
+    pub fn topics(&self) -> Result<Vec<Topic>, String> {
+        let parse = |list: &str| -> Result<Vec<Topic>, String> {
+            list.split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::parse)
+                .collect()
+        };
+        match (&self.only, &self.exclude) {
+            (Some(_), Some(_)) => Err("only and exclude cannot be combined".into()),
+            (Some(only), None) => parse(only),
+            (None, Some(exclude)) => {
+                let excluded = parse(exclude)?;
+                Ok(Topic::ALL
+                    .iter()
+                    .copied()
+                    .filter(|topic| !excluded.contains(topic))
+                    .collect())
+            }
+            (None, None) => Ok(vec![]),
+        }
+    }
+
+    /// The interval asked for each topic, which cannot be shorter than the
+    /// interval its stream is sampled at.
+    pub fn intervals(&self, config: &SamplerConfig) -> Result<BTreeMap<Topic, Duration>, String> {
+        let asked: &[(Topic, &Option<String>)] = &[
+            #[cfg(feature = "cpu")]
+            (Topic::Cpus, &self.cpus_interval),
+            #[cfg(feature = "power")]
+            (Topic::Power, &self.power_interval),
+            #[cfg(feature = "disks")]
+            (Topic::Disks, &self.disks_interval),
+            #[cfg(feature = "network")]
+            (Topic::Network, &self.network_interval),
+            #[cfg(feature = "gpu")]
+            (Topic::Gpu, &self.gpu_interval),
+            #[cfg(feature = "sensors")]
+            (Topic::Sensors, &self.sensors_interval),
+            #[cfg(feature = "system")]
+            (Topic::System, &self.system_interval),
+            #[cfg(feature = "mem")]
+            (Topic::Ram, &self.ram_interval),
+            #[cfg(feature = "processes")]
+            (Topic::Processes, &self.processes_interval),
+        ];
+        let mut intervals = BTreeMap::new();
+        for (topic, text) in asked {
+            let (Some(text), Some(period)) = (text, topic.period(config)) else {
+                continue;
+            };
+            let interval =
+                ws::parse_interval(text, period).map_err(|err| format!("{topic}_{err}"))?;
+            intervals.insert(*topic, interval);
+        }
+        Ok(intervals)
+    }
+}
+
+/// A message from the client.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    #[serde(default)]
+    subscribe: Vec<Topic>,
+    #[serde(default)]
+    unsubscribe: Vec<Topic>,
+}
+
+/// What a subscription task hands the connection.
+pub enum Event {
+    /// `caught_up` when no message of the topic was waiting behind it.
+    Message {
+        topic: Topic,
+        message: Message,
+        caught_up: bool,
+    },
+    Lagged {
+        topic: Topic,
+        skipped: u64,
+    },
+}
+
+/// The topics one connection is subscribed to, each forwarded by a task
+/// into a queue the connection reads from.
+pub struct Subscriptions {
+    topics: Arc<Topics>,
+    stats: Arc<Stats>,
+    protocol: Protocol,
+    format: Format,
+    encoding: Encoding,
+    intervals: BTreeMap<Topic, Duration>,
+    tasks: BTreeMap<Topic, JoinHandle<()>>,
+    tx: mpsc::Sender<Event>,
+    rx: mpsc::Receiver<Event>,
+}
+
+impl Subscriptions {
+    pub fn new(
+        topics: Arc<Topics>,
+        stats: Arc<Stats>,
+        protocol: Protocol,
+        format: Format,
+        encoding: Encoding,
+        intervals: BTreeMap<Topic, Duration>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE);
+        Self {
+            topics,
+            stats,
+            protocol,
+            format,
+            encoding,
+            intervals,
+            tasks: BTreeMap::new(),
+            tx,
+            rx,
+        }
+    }
+
+    pub fn subscribe(&mut self, topic: Topic) {
+        if self.tasks.contains_key(&topic) {
+            return;
+        }
+        let Some(broadcast) = self.topics.broadcast(topic) else {
+            return;
+        };
+        let task = tokio::spawn(forward(
+            topic,
+            broadcast.subscribe(),
+            self.intervals.get(&topic).copied(),
+            (self.protocol, self.format, self.encoding),
+            self.tx.clone(),
+            self.stats.clone(),
+        ));
+        self.tasks.insert(topic, task);
+    }
+
+    pub fn unsubscribe(&mut self, topic: Topic) {
+        if let Some(task) = self.tasks.remove(&topic) {
+            task.abort();
+        }
+    }
+
+    /// Applies a request from the client, returning the reply to send it.
+    pub fn handle(&mut self, text: &str) -> String {
+        match serde_json::from_str::<Request>(text) {
+            Ok(request) => {
+                for topic in request.subscribe {
+                    self.subscribe(topic);
+                }
+                for topic in request.unsubscribe {
+                    self.unsubscribe(topic);
+                }
+                self.subscribed()
+            }
+            Err(err) => serde_json::json!({ "error": err.to_string() }).to_string(),
+        }
+    }
+
+    /// The `subscribed` message, listing the topics in effect.
+    pub fn subscribed(&self) -> String {
+        let topics: Vec<_> = self.tasks.keys().collect();
+        serde_json::json!({ "subscribed": topics }).to_string()
+    }
+
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.rx.recv().await
+    }
+
+    pub fn stats(&self, topic: Topic) -> &ChannelStats {
+        topic.stats(&self.stats)
+    }
+
+    /// Counts the client as kicked on every topic it is subscribed to.
+    pub fn record_kicked(&self) {
+        for topic in self.tasks.keys() {
+            self.stats(*topic).record_kicked();
+        }
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for task in self.tasks.values() {
+            task.abort();
+        }
+    }
+}
+
+/// Forwards one topic's messages, wrapped, to the connection's queue,
+/// sending at most one per `interval`, the latest.
+async fn forward(
+    topic: Topic,
+    mut rx: tokio::sync::broadcast::Receiver<Frame>,
+    interval: Option<Duration>,
+    (protocol, format, encoding): (Protocol, Format, Encoding),
+    tx: mpsc::Sender<Event>,
+    stats: Arc<Stats>,
+) {
+    let _client = topic.stats(&stats).connect();
+    let mut next_send = time::Instant::now();
+    let mut pending: Option<Frame> = None;
+    loop {
+        let frame = tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(frame) => {
+                    if let Some(interval) = interval {
+                        let now = time::Instant::now();
+                        if now < next_send {
+                            pending = Some(frame);
+                            continue;
+                        }
+                        next_send = now + interval;
+                        // Older than this one, it must not follow it.
+                        pending = None;
+                    }
+                    frame
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    topic.stats(&stats).record_dropped(skipped);
+                    if tx.send(Event::Lagged { topic, skipped }).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = time::sleep_until(next_send), if pending.is_some() => {
+                next_send = time::Instant::now() + interval.unwrap_or_default();
+                let Some(frame) = pending.take() else { continue };
+                frame
+            }
+        };
+        let message = if encoding == Encoding::Msgpack && format.is_raw() {
+            // The frame's own MessagePack, shared with the other
+            // connections, rather than a transcoding of our own.
+            let Some(data) = frame.msgpack(protocol) else {
+                continue;
+            };
+            Message::Binary(msgpack_envelope(topic, data))
+        } else {
+            let Some(data) = topic.encode(&frame, protocol, format) else {
+                continue;
+            };
+            let text = format!(r#"{{"topic":"{topic}","data":{data}}}"#);
+            let Some(message) = encoding.message(text) else {
+                continue;
+            };
+            message
+        };
+        let caught_up = rx.is_empty();
+        if tx
+            .send(Event::Message {
+                topic,
+                message,
+                caught_up,
+            })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// `{"topic": topic, "data": data}` in MessagePack, `data` being encoded
+/// already.
+fn msgpack_envelope(topic: Topic, data: &[u8]) -> Vec<u8> {
+    // A map of two entries.
+    let mut bytes = vec![0x82];
+    for text in ["topic", topic.name(), "data"] {
+        // Short enough to be a fixstr, whose length is in its marker.
+        bytes.push(0xa0 | text.len() as u8);
+        bytes.extend_from_slice(text.as_bytes());
+    }
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "cpu", feature = "mem", feature = "processes"))]
+    #[test]
+    fn only_and_exclude_pick_the_starting_topics() {
+        let params = |query: &str| -> AllParams { serde_json::from_str(query).unwrap() };
+        assert_eq!(
+            params(r#"{"only":"cpu,ram"}"#).topics().unwrap(),
+            [Topic::Cpus, Topic::Ram]
+        );
+        let excluded = params(r#"{"exclude":"processes"}"#).topics().unwrap();
+        assert!(excluded.contains(&Topic::Alerts) && !excluded.contains(&Topic::Processes));
+        assert!(params(r#"{"only":"gpus"}"#).topics().is_err());
+        assert!(params(r#"{"only":"ram","exclude":"cpus"}"#)
+            .topics()
+            .is_err());
+        assert!(params("{}").topics().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribing_wraps_messages_in_their_topic() {
+        let mut publisher =
+            crate::sampler::Publisher::new(8, tokio::sync::watch::channel(Arc::default()).1);
+        let broadcast = publisher.broadcast();
+        let topics = Arc::new(Topics::new(vec![(Topic::Alerts, broadcast.clone())]));
+        let mut subscriptions = Subscriptions::new(
+            topics,
+            Arc::new(Stats::new()),
+            Protocol::V1,
+            Format::default(),
+            Encoding::Json,
+            BTreeMap::new(),
+        );
+        assert_eq!(
+            subscriptions.handle(r#"{"subscribe":["alerts"]}"#),
+            r#"{"subscribed":["alerts"]}"#
+        );
+        assert!(subscriptions
+            .handle(r#"{"subscribe":["gpus"]}"#)
+            .contains("unknown topic"));
+        // Let the task subscribe before publishing.
+        while broadcast.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let event = crate::alerts::AlertEvent {
+            rule: "hot".into(),
+            severity: crate::alerts::Severity::Warning,
+            state: crate::alerts::AlertState::Firing,
+            value: 90.,
+            since: 0,
+        };
+        publisher.publish(&event, None, &ChannelStats::default());
+        let Some(Event::Message {
+            topic,
+            message: Message::Text(text),
+            ..
+        }) = subscriptions.recv().await
+        else {
+            panic!("no message");
+        };
+        assert_eq!(topic, Topic::Alerts);
+        assert!(text.starts_with(r#"{"topic":"alerts","data":{"rule":"hot""#));
+
+        subscriptions.handle(r#"{"unsubscribe":["alerts"]}"#);
+        assert_eq!(subscriptions.subscribed(), r#"{"subscribed":[]}"#);
+    }
+
+    #[test]
+    fn msgpack_envelopes_read_like_the_json_ones() {
+        let data = serde_json::json!({ "rule": "hot", "value": 90.5 });
+        let bytes = msgpack_envelope(Topic::Alerts, &rmp_serde::to_vec(&data).unwrap());
+        let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(
+            decoded,
+            serde_json::json!({ "topic": "alerts", "data": data })
+        );
+    }
+}
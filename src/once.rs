@@ -0,0 +1,135 @@
+//! `--once`: prints a single JSON snapshot of the streams to stdout instead
+//! of serving them, for shell scripts and cron jobs.
+
+use std::io::Write;
+
+use serde::Serialize;
+use sysinfo::{System, SystemExt};
+
+#[cfg(feature = "temps")]
+use crate::collectors::sensors::CpuSensors;
+#[cfg(any(feature = "cpu", feature = "mem", feature = "processes"))]
+use crate::collectors::source::MetricsSource;
+#[cfg(feature = "cpu")]
+use crate::{collectors::cpu, types::CpuState};
+#[cfg(feature = "mem")]
+use crate::{collectors::mem, types::MemState};
+#[cfg(feature = "processes")]
+use crate::{collectors::processes, types::ProcessInfo};
+use crate::{
+    collectors::source::SysinfoSource,
+    config::{SamplerConfig, Stream},
+    types::Instance,
+};
+
+/// What `--once` prints: each stream's payload as it would be streamed, for
+/// the streams asked for.
+#[derive(Serialize, Debug)]
+struct Snapshot {
+    timestamp_ms: u64,
+    instance: Instance,
+    #[cfg(feature = "cpu")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpus: Option<CpuState>,
+    #[cfg(feature = "mem")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ram: Option<MemState>,
+    #[cfg(feature = "processes")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    processes: Option<Vec<ProcessInfo>>,
+}
+
+/// Samples `streams`, every compiled in one when empty, as `config` says and
+/// prints them on one line.
+#[cfg_attr(
+    not(all(feature = "cpu", feature = "mem", feature = "processes")),
+    allow(unused_mut, unused_variables)
+)]
+pub async fn run(
+    config: &SamplerConfig,
+    instance: Instance,
+    streams: &[Stream],
+) -> Result<(), String> {
+    if let Some(feature) = streams.iter().find_map(|&stream| compiled_without(stream)) {
+        return Err(format!("compiled without the `{feature}` feature"));
+    }
+    let wanted = |stream| {
+        compiled_without(stream).is_none() && (streams.is_empty() || streams.contains(&stream))
+    };
+
+    let mut source = SysinfoSource::new();
+    // Usages are deltas between two refreshes, and creating the source was
+    // the first.
+    if wanted(Stream::Cpus) || wanted(Stream::Processes) {
+        tokio::time::sleep(System::MINIMUM_CPU_UPDATE_INTERVAL).await;
+    }
+    let mut snapshot = Snapshot {
+        timestamp_ms: crate::sampler::unix_millis(std::time::SystemTime::now()),
+        instance,
+        #[cfg(feature = "cpu")]
+        cpus: None,
+        #[cfg(feature = "mem")]
+        ram: None,
+        #[cfg(feature = "processes")]
+        processes: None,
+    };
+
+    #[cfg(feature = "cpu")]
+    if wanted(Stream::Cpus) {
+        source.refresh_cpus();
+        if source.cpu_cores().is_empty() {
+            return Err("no cpus could be read".into());
+        }
+        #[cfg(feature = "temps")]
+        let sensors = {
+            source.refresh_components();
+            CpuSensors::with_mapping(&source, &config.sensors)
+        };
+        let mut cpu_state = cpu::state(&source, source.cpu_cores().len());
+        cpu::sample(
+            &mut cpu_state,
+            &source,
+            #[cfg(feature = "temps")]
+            Some(&sensors),
+        );
+        snapshot.cpus = Some(cpu_state);
+    }
+
+    #[cfg(feature = "mem")]
+    if wanted(Stream::Ram) {
+        source.refresh_memory();
+        if source.memory().total == 0 {
+            return Err("memory usage could not be read".into());
+        }
+        snapshot.ram = Some(mem::sample(&source, config.mem_mode));
+    }
+
+    #[cfg(feature = "processes")]
+    if wanted(Stream::Processes) {
+        source.refresh_processes();
+        if source.processes().next().is_none() {
+            return Err("no processes could be read".into());
+        }
+        snapshot.processes = Some(processes::sample(
+            &source,
+            config,
+            sysinfo::get_current_pid().ok(),
+            config.top_processes,
+            &mut processes::Buffers::default(),
+        ));
+    }
+
+    let json = serde_json::to_string(&snapshot).map_err(|err| err.to_string())?;
+    // Unlike `println!`, does not panic when the reader has gone away.
+    writeln!(std::io::stdout().lock(), "{json}")
+        .map_err(|err| format!("cannot write to stdout: {err}"))
+}
+
+/// The feature `stream` needs, if it was left out of the build.
+fn compiled_without(stream: Stream) -> Option<&'static str> {
+    match stream {
+        Stream::Cpus => (!cfg!(feature = "cpu")).then_some("cpu"),
+        Stream::Ram => (!cfg!(feature = "mem")).then_some("mem"),
+        Stream::Processes => (!cfg!(feature = "processes")).then_some("processes"),
+    }
+}
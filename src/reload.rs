@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::{
+    config::{Args, Config},
+    server::AppState,
+};
+
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Re-reads the config file on every SIGHUP and applies the settings that can
+/// change at runtime. Settings that need a restart are only reported.
+pub async fn reload_on_sighup(
+    path: PathBuf,
+    args: Args,
+    mut current: Config,
+    state: AppState,
+    log_handle: LogHandle,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            tracing::error!(%err, "cannot listen for SIGHUP, config reload disabled");
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        let new = Config::load(&path).and_then(|mut new| {
+            new.apply_args(&args);
+            new.validate()?;
+            Ok(new)
+        });
+        let new = match new {
+            Ok(new) => new,
+            Err(err) => {
+                tracing::error!(%err, "config reload failed, keeping the previous config");
+                continue;
+            }
+        };
+        tracing::info!(path = %path.display(), "config file reloaded");
+        if *state.sampler_config.borrow() != new.sampler {
+            tracing::info!(config = ?new.sampler, "sampler config reloaded");
+            state.sampler_config.send_replace(new.sampler.clone());
+        }
+        let instance = new.instance();
+        if **state.instance.borrow() != instance {
+            tracing::info!(?instance, "instance name and labels reloaded");
+            state.instance.send_replace(std::sync::Arc::new(instance));
+        }
+        if new.share != current.share {
+            state.shares.reload(&new.share, crate::share::unix_secs());
+        }
+        if new.log_level != current.log_level {
+            match log_handle.reload(new.log_filter()) {
+                Ok(()) => tracing::info!(log_level = ?new.log_level, "log level reloaded"),
+                Err(err) => tracing::error!(%err, "cannot change the log level"),
+            }
+        }
+        if new.bind != current.bind
+            || new.port_retry != current.port_retry
+            || new.bind_retry_ms != current.bind_retry_ms
+        {
+            tracing::warn!(bind = ?new.bind, "bind addresses changed, restart to apply");
+        }
+        if new.tls != current.tls {
+            tracing::warn!("tls changed, restart to apply");
+        }
+        if new.websocket != current.websocket {
+            tracing::warn!("websocket settings changed, restart to apply");
+        }
+        if new.channel_capacity != current.channel_capacity {
+            tracing::warn!("channel_capacity changed, restart to apply");
+        }
+        if new.alerts != current.alerts {
+            tracing::warn!("alerts changed, restart to apply");
+        }
+        if new.remotes != current.remotes {
+            tracing::warn!("remotes changed, restart to apply");
+        }
+        if new.upstream != current.upstream {
+            tracing::warn!("upstream changed, restart to apply");
+        }
+        if new.mdns != current.mdns {
+            tracing::warn!("mdns changed, restart to apply");
+        }
+        if new.smart != current.smart {
+            tracing::warn!("smart changed, restart to apply");
+        }
+        if new.kafka != current.kafka {
+            tracing::warn!("kafka changed, restart to apply");
+        }
+        if new.metrics_log != current.metrics_log {
+            tracing::warn!("metrics_log changed, restart to apply");
+        }
+        if new.history != current.history {
+            tracing::warn!("history changed, restart to apply");
+        }
+        if new.db != current.db {
+            tracing::warn!("db changed, restart to apply");
+        }
+        if new.access_log != current.access_log {
+            tracing::warn!("access_log changed, restart to apply");
+        }
+        if new.process_control != current.process_control {
+            tracing::warn!("process_control changed, restart to apply");
+        }
+        if new.ingest_token != current.ingest_token {
+            tracing::warn!("ingest_token changed, restart to apply");
+        }
+        if new.admin_token != current.admin_token {
+            tracing::warn!("admin_token changed, restart to apply");
+        }
+        if new.tokens != current.tokens {
+            tracing::warn!("tokens changed, restart to apply");
+        }
+        // Settings that need a restart keep describing the running server.
+        current.sampler = new.sampler;
+        current.log_level = new.log_level;
+    }
+}
@@ -0,0 +1,237 @@
+//! `--replay`: serves the messages recorded in metrics log files (see
+//! [`crate::metrics_log`]) instead of reading the system. They are
+//! published to the sampler's channels, at the pace they were recorded, so
+//! that the streams, alerts and pushing upstream work on top of them
+//! unchanged. Recorded alert events are skipped: the alerts are evaluated
+//! again, on the replayed samples.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use tokio::sync::{mpsc, watch};
+
+use crate::{sampler::Channels, stats::Stats};
+
+/// Gaps longer than this, while the server was down, are cut short.
+const MAX_GAP: Duration = Duration::from_secs(10);
+
+/// A metrics log file, opened.
+pub struct Recording {
+    path: PathBuf,
+    reader: Box<dyn BufRead + Send>,
+}
+
+/// One line of a metrics log, its sample's data left unparsed until its
+/// kind is known.
+#[derive(Deserialize)]
+struct Record {
+    kind: String,
+    timestamp_ms: u64,
+    data: Box<RawValue>,
+}
+
+/// Opens every file, uncompressing those named `*.gz`.
+pub fn open(paths: &[PathBuf]) -> Result<Vec<Recording>, String> {
+    paths
+        .iter()
+        .map(|path| {
+            let file =
+                File::open(path).map_err(|err| format!("cannot open {}: {err}", path.display()))?;
+            let reader: Box<dyn BufRead + Send> =
+                match path.extension().is_some_and(|ext| ext == "gz") {
+                    true => Box::new(BufReader::new(GzDecoder::new(file))),
+                    false => Box::new(BufReader::new(file)),
+                };
+            Ok(Recording {
+                path: path.clone(),
+                reader,
+            })
+        })
+        .collect()
+}
+
+/// Publishes the recorded samples, one file after the other, until they run
+/// out or `shutdown` changes or its sender is dropped.
+pub async fn run(
+    recordings: Vec<Recording>,
+    mut channels: Channels,
+    stats: Arc<Stats>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    tracing::info!("replaying recorded samples, the system is not read");
+    stats.cpus.set_active(cfg!(feature = "cpu"));
+    stats.ram.set_active(cfg!(feature = "mem"));
+    stats.processes.set_active(cfg!(feature = "processes"));
+
+    let (tx, mut rx) = mpsc::channel(64);
+    tokio::task::spawn_blocking(move || read(recordings, tx));
+    let mut last_ms = None;
+    loop {
+        let record = tokio::select! {
+            record = rx.recv() => match record {
+                Some(record) => record,
+                None => break,
+            },
+            _ = shutdown.changed() => return,
+        };
+        if let Some(last_ms) = last_ms {
+            let gap = Duration::from_millis(record.timestamp_ms.saturating_sub(last_ms));
+            tokio::select! {
+                _ = tokio::time::sleep(gap.min(MAX_GAP)) => {}
+                _ = shutdown.changed() => return,
+            }
+        }
+        last_ms = Some(record.timestamp_ms);
+        if let Err(err) = publish(&mut channels, &stats, &record) {
+            tracing::debug!(kind = record.kind, %err, "cannot replay sample");
+        }
+    }
+    tracing::info!("replay finished");
+}
+
+/// Reads the records of every file in turn, skipping the lines that are not
+/// one, such as the last of a file that was being written when the disk
+/// filled up.
+fn read(recordings: Vec<Recording>, tx: mpsc::Sender<Record>) {
+    for recording in recordings {
+        let mut skipped = 0;
+        for line in recording.reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    tracing::warn!(path = %recording.path.display(), %err, "cannot read recording");
+                    break;
+                }
+            };
+            match serde_json::from_str(&line) {
+                Ok(record) => {
+                    if tx.blocking_send(record).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            tracing::warn!(path = %recording.path.display(), skipped, "skipped unreadable lines");
+        }
+    }
+}
+
+/// Publishes `record` to its stream's channel. Streams that were compiled
+/// out, and alert events, are skipped.
+#[cfg_attr(
+    not(any(
+        feature = "cpu",
+        feature = "mem",
+        feature = "processes",
+        feature = "power",
+        feature = "disks",
+        feature = "network",
+        feature = "gpu",
+        feature = "sensors",
+        feature = "system"
+    )),
+    allow(unused_variables)
+)]
+fn publish(channels: &mut Channels, stats: &Stats, record: &Record) -> serde_json::Result<()> {
+    let data = record.data.get();
+    match record.kind.as_str() {
+        #[cfg(feature = "cpu")]
+        "cpus" => {
+            channels
+                .cpus
+                .publish(&serde_json::from_str(data)?, None, &stats.cpus);
+            stats.record_tick();
+        }
+        #[cfg(feature = "mem")]
+        "ram" => channels
+            .ram
+            .publish(&serde_json::from_str(data)?, None, &stats.ram),
+        #[cfg(feature = "power")]
+        "power" => channels
+            .power
+            .publish(&serde_json::from_str(data)?, None, &stats.power),
+        #[cfg(feature = "disks")]
+        "disks" => channels
+            .disks
+            .publish(&serde_json::from_str(data)?, None, &stats.disks),
+        #[cfg(feature = "network")]
+        "network" => channels
+            .network
+            .publish(&serde_json::from_str(data)?, None, &stats.network),
+        #[cfg(feature = "gpu")]
+        "gpu" => channels
+            .gpu
+            .publish(&serde_json::from_str(data)?, None, &stats.gpu),
+        #[cfg(feature = "sensors")]
+        "sensors" => channels
+            .sensors
+            .publish(&serde_json::from_str(data)?, None, &stats.sensors),
+        #[cfg(feature = "system")]
+        "system" => channels
+            .system
+            .publish(&serde_json::from_str(data)?, None, &stats.system),
+        #[cfg(feature = "processes")]
+        "processes" => {
+            let processes: Vec<crate::types::ProcessInfo> = serde_json::from_str(data)?;
+            if channels.process_table.wanted() {
+                channels
+                    .process_table
+                    .publish(crate::collectors::processes::Table {
+                        timestamp_ms: crate::sampler::unix_millis(std::time::SystemTime::now()),
+                        processes: processes.clone(),
+                    });
+            }
+            if channels.process_list.receiver_count() > 0 {
+                channels.process_list.publish(processes.clone());
+            }
+            channels
+                .processes
+                .publish(&processes, None, &stats.processes);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_gzipped_recordings_and_skips_partial_lines() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("axact-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.jsonl.1.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        writeln!(
+            encoder,
+            r#"{{"kind":"ram","seq":0,"timestamp_ms":1000,"data":{{"used":1}}}}"#
+        )
+        .unwrap();
+        write!(encoder, r#"{{"kind":"ram","seq":1,"times"#).unwrap();
+        encoder.finish().unwrap();
+
+        let recordings = open(&[path]).unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        read(recordings, tx);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let record = rx.try_recv().unwrap();
+        assert_eq!((record.kind.as_str(), record.timestamp_ms), ("ram", 1000));
+        assert_eq!(record.data.get(), r#"{"used":1}"#);
+        assert!(rx.try_recv().is_err());
+    }
+}
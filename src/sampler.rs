@@ -0,0 +1,1245 @@
+#[cfg(feature = "processes")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{
+    any::Any,
+    collections::VecDeque,
+    fmt::Debug,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::FutureExt;
+
+use sysinfo::{Pid, System, SystemExt};
+use tokio::{
+    sync::{broadcast, watch, Notify},
+    task::block_in_place,
+    time::{self, MissedTickBehavior},
+};
+
+#[cfg(feature = "disks")]
+use crate::collectors::disks::DiskMeter;
+#[cfg(feature = "gpu")]
+use crate::collectors::gpu::GpuCollector;
+#[cfg(feature = "sensors")]
+use crate::collectors::hwmon::SensorCollector;
+#[cfg(feature = "network")]
+use crate::collectors::network::NetMeter;
+#[cfg(feature = "power")]
+use crate::collectors::power::PowerMeter;
+#[cfg(feature = "system")]
+use crate::collectors::system::SystemCollector;
+#[cfg(feature = "disks")]
+use crate::types::DiskState;
+#[cfg(feature = "gpu")]
+use crate::types::GpuState;
+#[cfg(feature = "network")]
+use crate::types::NetState;
+#[cfg(feature = "power")]
+use crate::types::PowerState;
+#[cfg(feature = "sensors")]
+use crate::types::SensorState;
+#[cfg(feature = "system")]
+use crate::types::SystemState;
+#[cfg(feature = "cpu")]
+use crate::{collectors::cpu, types::CpuState};
+#[cfg(feature = "mem")]
+use crate::{collectors::mem, types::MemState};
+#[cfg(feature = "processes")]
+use crate::{collectors::processes, types::ProcessInfo};
+use crate::{
+    collectors::{
+        source::{MetricsSource, SysinfoSource},
+        Collector, Refresh,
+    },
+    config::SamplerConfig,
+    stats::{ChannelStats, Stats},
+    types::{Instance, Payload, Sample},
+    ws::Frame,
+};
+
+/// The sending side of every stream the sampler feeds.
+pub struct Channels {
+    #[cfg(feature = "cpu")]
+    pub cpus: Publisher<CpuState>,
+    /// How many CPUs the first sampler run saw; kept across restarts so the
+    /// length of `cores` never changes.
+    #[cfg(feature = "cpu")]
+    cpu_count: Option<usize>,
+    #[cfg(feature = "power")]
+    pub power: Publisher<PowerState>,
+    #[cfg(feature = "disks")]
+    pub disks: Publisher<DiskState>,
+    #[cfg(feature = "network")]
+    pub network: Publisher<NetState>,
+    #[cfg(feature = "gpu")]
+    pub gpu: Publisher<GpuState>,
+    #[cfg(feature = "sensors")]
+    pub sensors: Publisher<SensorState>,
+    #[cfg(feature = "system")]
+    pub system: Publisher<SystemState>,
+    #[cfg(feature = "mem")]
+    pub ram: Publisher<MemState>,
+    #[cfg(feature = "processes")]
+    pub processes: Publisher<Vec<ProcessInfo>>,
+    #[cfg(feature = "processes")]
+    pub process_list: ProcessList,
+    #[cfg(feature = "processes")]
+    pub process_table: ProcessTable,
+    /// Notified whenever a client subscribes to any stream, so that a paused
+    /// sampler resumes right away.
+    subscribed: Arc<Notify>,
+}
+
+impl Channels {
+    /// Creates every stream's broadcast channel, each buffering `capacity`
+    /// samples, whose samples say they come from the latest `instance`.
+    #[cfg_attr(
+        not(any(feature = "cpu", feature = "mem", feature = "processes")),
+        allow(unused_variables)
+    )]
+    pub fn new(capacity: usize, instance: watch::Receiver<Arc<Instance>>) -> Self {
+        let subscribed = Arc::new(Notify::new());
+        Self {
+            #[cfg(feature = "cpu")]
+            cpus: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "cpu")]
+            cpu_count: None,
+            #[cfg(feature = "power")]
+            power: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "disks")]
+            disks: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "network")]
+            network: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "gpu")]
+            gpu: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "sensors")]
+            sensors: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "system")]
+            system: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "mem")]
+            ram: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "processes")]
+            processes: Publisher::waking(capacity, instance.clone(), &subscribed),
+            #[cfg(feature = "processes")]
+            process_list: ProcessList::new(capacity, instance, &subscribed),
+            #[cfg(feature = "processes")]
+            process_table: ProcessTable::new(),
+            subscribed,
+        }
+    }
+}
+
+impl Channels {
+    /// How many clients are subscribed, summed over all streams.
+    #[cfg_attr(
+        not(any(feature = "cpu", feature = "mem", feature = "processes")),
+        allow(unused_mut)
+    )]
+    fn subscribers(&self) -> usize {
+        let mut subscribers = 0;
+        #[cfg(feature = "cpu")]
+        {
+            subscribers += self.cpus.broadcast.receiver_count();
+        }
+        #[cfg(feature = "power")]
+        {
+            subscribers += self.power.broadcast.receiver_count();
+        }
+        #[cfg(feature = "disks")]
+        {
+            subscribers += self.disks.broadcast.receiver_count();
+        }
+        #[cfg(feature = "network")]
+        {
+            subscribers += self.network.broadcast.receiver_count();
+        }
+        #[cfg(feature = "gpu")]
+        {
+            subscribers += self.gpu.broadcast.receiver_count();
+        }
+        #[cfg(feature = "sensors")]
+        {
+            subscribers += self.sensors.broadcast.receiver_count();
+        }
+        #[cfg(feature = "system")]
+        {
+            subscribers += self.system.broadcast.receiver_count();
+        }
+        #[cfg(feature = "mem")]
+        {
+            subscribers += self.ram.broadcast.receiver_count();
+        }
+        #[cfg(feature = "processes")]
+        {
+            subscribers += self.processes.broadcast.receiver_count();
+            subscribers += self.process_list.receiver_count();
+            subscribers += usize::from(self.process_table.wanted());
+        }
+        subscribers
+    }
+}
+
+/// Every reported process, grouped like the process stream but not cut to
+/// `top_processes`, for the connections that filter it themselves.
+#[cfg(feature = "processes")]
+#[derive(Clone)]
+pub struct ProcessList {
+    tx: broadcast::Sender<Arc<Sample<Vec<ProcessInfo>>>>,
+    next_seq: Arc<AtomicU64>,
+    instance: watch::Receiver<Arc<Instance>>,
+    subscribed: Arc<Notify>,
+}
+
+#[cfg(feature = "processes")]
+impl ProcessList {
+    fn new(
+        capacity: usize,
+        instance: watch::Receiver<Arc<Instance>>,
+        subscribed: &Arc<Notify>,
+    ) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+            next_seq: Arc::default(),
+            instance,
+            subscribed: subscribed.clone(),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Sample<Vec<ProcessInfo>>>> {
+        let rx = self.tx.subscribe();
+        // After subscribing, so that the sampler counts this subscriber.
+        self.subscribed.notify_one();
+        rx
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    /// Sends every process to the connections that filter the process
+    /// stream. Unlike the stream, unchanged lists are sent again.
+    pub fn publish(&self, data: Vec<ProcessInfo>) {
+        let sample = Sample {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            timestamp_ms: unix_millis(SystemTime::now()),
+            host: None,
+            top: None,
+            format: None,
+            instance: Some(self.instance.borrow().clone()),
+            data,
+            replayed: false,
+        };
+        let _ = self.tx.send(Arc::new(sample));
+    }
+}
+
+/// How long the process table keeps being refreshed after `GET /processes`
+/// last read it, so that paging through it does not find it gone stale.
+#[cfg(feature = "processes")]
+const PROCESS_TABLE_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Every process as of the last full refresh, shared with the handlers that
+/// page through it. The sampler only keeps it up to date while it is read.
+#[cfg(feature = "processes")]
+#[derive(Clone)]
+pub struct ProcessTable {
+    tx: Arc<watch::Sender<Option<Arc<processes::Table>>>>,
+    /// When it was last read, in ms since the Unix epoch.
+    read_at: Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[cfg(feature = "processes")]
+impl ProcessTable {
+    fn new() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(None).0),
+            read_at: Arc::default(),
+        }
+    }
+
+    pub fn publish(&self, table: processes::Table) {
+        self.tx.send_replace(Some(Arc::new(table)));
+    }
+
+    /// The last table, `None` until the first full refresh after it was
+    /// first asked for.
+    pub fn latest(&self) -> Option<Arc<processes::Table>> {
+        self.read_at.store(
+            unix_millis(SystemTime::now()),
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        self.tx.borrow().clone()
+    }
+
+    /// Whether it was read recently enough to be kept up to date.
+    pub fn wanted(&self) -> bool {
+        let read_at = self.read_at.load(std::sync::atomic::Ordering::Relaxed);
+        read_at > 0
+            && unix_millis(SystemTime::now()).saturating_sub(read_at)
+                < PROCESS_TABLE_KEEPALIVE.as_millis() as u64
+    }
+}
+
+/// A stream's broadcast channel, which also retains the last frames sent
+/// for clients resuming after a reconnect, see [`Broadcast::resume`].
+pub struct Broadcast {
+    tx: broadcast::Sender<Frame>,
+    /// The last `capacity` frames, oldest first.
+    history: Mutex<VecDeque<Frame>>,
+    capacity: usize,
+    /// Notified on every subscription.
+    subscribed: Arc<Notify>,
+}
+
+/// What a client resuming after `since_seq` missed.
+#[derive(Debug, Default)]
+pub struct Backlog {
+    /// The first and last sequence numbers it missed that are no longer
+    /// retained, if any.
+    pub gap: Option<(u64, u64)>,
+    /// The retained frames numbered after `since_seq`, in order.
+    pub frames: Vec<Frame>,
+}
+
+impl Broadcast {
+    pub fn new(capacity: usize) -> Self {
+        Self::waking(capacity, Arc::default())
+    }
+
+    fn waking(capacity: usize, subscribed: Arc<Notify>) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+            history: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            subscribed,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Frame> {
+        let rx = self.tx.subscribe();
+        // After subscribing, so that the sampler counts this subscriber.
+        self.subscribed.notify_one();
+        rx
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    /// The last frame sent, if any is retained.
+    pub fn latest(&self) -> Option<Frame> {
+        self.history.lock().unwrap().back().cloned()
+    }
+
+    /// Subscribes along with what was sent after `since_seq`. Frames are
+    /// retained and sent under the same lock, so that none is missed or
+    /// received twice.
+    pub fn resume(&self, since_seq: u64) -> (broadcast::Receiver<Frame>, Backlog) {
+        let history = self.history.lock().unwrap();
+        let rx = self.tx.subscribe();
+        self.subscribed.notify_one();
+        let gap = history
+            .front()
+            .map(Frame::seq)
+            .filter(|oldest| *oldest > since_seq.saturating_add(1))
+            .map(|oldest| (since_seq + 1, oldest - 1));
+        let frames = history
+            .iter()
+            .filter(|frame| frame.seq() > since_seq)
+            .cloned()
+            .collect();
+        (rx, Backlog { gap, frames })
+    }
+
+    fn send(&self, frame: Frame) {
+        let mut history = self.history.lock().unwrap();
+        if history.len() == self.capacity {
+            history.pop_front();
+        }
+        history.push_back(frame.clone());
+        let _ = self.tx.send(frame);
+    }
+}
+
+/// Numbers the samples of one stream, encodes them and broadcasts the
+/// resulting frames. Numbers keep counting up for as long as the process
+/// runs, across sampler restarts.
+pub struct Publisher<T> {
+    broadcast: Arc<Broadcast>,
+    /// Read on every sample, so that a reload applies from the next one.
+    instance: watch::Receiver<Arc<Instance>>,
+    next_seq: u64,
+    /// The last broadcast, kept while unchanged samples are skipped.
+    last: Option<LastBroadcast<T>>,
+}
+
+struct LastBroadcast<T> {
+    data: T,
+    at: Instant,
+    receivers: usize,
+}
+
+impl<T: Payload + Debug> Publisher<T> {
+    /// Broadcasts to a new channel buffering `capacity` frames.
+    pub fn new(capacity: usize, instance: watch::Receiver<Arc<Instance>>) -> Self {
+        Self::waking(capacity, instance, &Arc::default())
+    }
+
+    /// Like [`Self::new`], notifying `subscribed` whenever a client
+    /// subscribes.
+    fn waking(
+        capacity: usize,
+        instance: watch::Receiver<Arc<Instance>>,
+        subscribed: &Arc<Notify>,
+    ) -> Self {
+        Self {
+            broadcast: Arc::new(Broadcast::waking(capacity, subscribed.clone())),
+            instance,
+            next_seq: 0,
+            last: None,
+        }
+    }
+
+    /// A handle for subscribing clients to this stream.
+    pub fn broadcast(&self) -> Arc<Broadcast> {
+        self.broadcast.clone()
+    }
+
+    fn has_subscribers(&self) -> bool {
+        self.broadcast.receiver_count() > 0
+    }
+
+    /// Broadcasts `data`, unless it equals the last broadcast and that was
+    /// less than `max_silence` ago; `None` broadcasts every sample.
+    pub fn publish(&mut self, data: &T, max_silence: Option<Duration>, stats: &ChannelStats) {
+        let receivers = self.broadcast.receiver_count();
+        match (max_silence, &self.last) {
+            (Some(max_silence), Some(last))
+                if last.data == *data
+                    && receivers <= last.receivers
+                    && last.at.elapsed() < max_silence =>
+            {
+                stats.record_suppressed();
+                return;
+            }
+            (None, Some(_)) => self.last = None,
+            _ => {}
+        }
+
+        if cfg!(debug_assertions) {
+            dbg!(data);
+        }
+        let sample = Sample {
+            seq: self.next_seq,
+            timestamp_ms: unix_millis(SystemTime::now()),
+            host: None,
+            top: None,
+            format: None,
+            instance: Some(self.instance.borrow().clone()),
+            data,
+            replayed: false,
+        };
+        self.next_seq += 1;
+        match Frame::encode(&sample) {
+            Ok(frame) => {
+                stats.record_encoded(Frame::ENCODINGS);
+                self.broadcast.send(frame);
+                stats.record_broadcast();
+            }
+            Err(err) => {
+                tracing::error!(seq = sample.seq, %err, "cannot serialize sample, skipping it")
+            }
+        }
+
+        if max_silence.is_some() {
+            let now = Instant::now();
+            match &mut self.last {
+                Some(last) => {
+                    last.data.clone_from(data);
+                    last.at = now;
+                    last.receivers = receivers;
+                }
+                None => {
+                    self.last = Some(LastBroadcast {
+                        data: data.clone(),
+                        at: now,
+                        receivers,
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// How often at most to warn about ticks that take longer than the interval.
+const SLOW_TICK_WARNING_EVERY: Duration = Duration::from_secs(60);
+
+/// How long to wait before restarting a sampler that panicked.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Runs the sampling loop until `shutdown` changes or its sender is dropped,
+/// restarting it with fresh sysinfo state whenever it panics. Sequence
+/// numbers carry on across restarts.
+///
+/// The sysinfo calls block, so they run in `block_in_place`, which needs the
+/// multi-threaded runtime.
+pub async fn supervise(
+    mut config_rx: watch::Receiver<SamplerConfig>,
+    mut channels: Channels,
+    stats: Arc<Stats>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let result = AssertUnwindSafe(run(
+            SysinfoSource::new,
+            &mut config_rx,
+            &mut channels,
+            &stats,
+            &mut shutdown,
+        ))
+        .catch_unwind()
+        .await;
+        let Err(payload) = result else { return };
+        stats.record_sampler_restart();
+        tracing::error!(
+            panic = panic_message(&*payload),
+            restart_in_ms = RESTART_DELAY.as_millis() as u64,
+            "sampler panicked, restarting it"
+        );
+        tokio::select! {
+            _ = time::sleep(RESTART_DELAY) => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic payload"
+    }
+}
+
+/// The sampling loop; returns on shutdown. The source is created by
+/// `new_source` inside the loop, so that a restart starts from scratch.
+async fn run<S: MetricsSource>(
+    new_source: fn() -> S,
+    config_rx: &mut watch::Receiver<SamplerConfig>,
+    channels: &mut Channels,
+    stats: &Stats,
+    shutdown: &mut watch::Receiver<bool>,
+) {
+    let mut sampler = block_in_place(|| Sampler::new(new_source(), channels));
+    let mut config = config_rx.borrow_and_update().clone();
+    sampler.prime(&config, channels, stats).await;
+    let mut pacer = Pacer::new(&config);
+    let mut ticks = ticker(pacer.interval(&config, channels.subscribers()));
+    stats.set_sampler_interval(ticks.period());
+    let mut idle = false;
+    stats.set_sampler_idle(false);
+    let mut last_slow_warning: Option<Instant> = None;
+
+    loop {
+        let tick = tokio::select! {
+            tick = ticks.tick() => tick.into_std(),
+            changed = config_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                config = config_rx.borrow_and_update().clone();
+                tracing::debug!(?config, "sampler picked up new config");
+                pacer.clamp(&config);
+                let interval = pacer.interval(&config, channels.subscribers());
+                if interval != ticks.period() {
+                    ticks = ticker(interval);
+                    stats.set_sampler_interval(interval);
+                }
+                continue;
+            }
+            // Picks up returning subscribers without waiting out an interval
+            // that was stretched for lack of them.
+            _ = time::sleep(config.cpu_interval()), if ticks.period() > config.cpu_interval() => {
+                let interval = pacer.interval(&config, channels.subscribers());
+                if interval < ticks.period() {
+                    tracing::debug!(
+                        from_ms = ticks.period().as_millis() as u64,
+                        to_ms = interval.as_millis() as u64,
+                        "subscribers returned, sampling interval changed"
+                    );
+                    ticks = ticker(interval);
+                    stats.set_sampler_interval(interval);
+                }
+                continue;
+            }
+            // Resumes as soon as a client subscribes, rather than on the next
+            // tick.
+            _ = channels.subscribed.notified(), if idle => Instant::now(),
+            _ = shutdown.changed() => return,
+        };
+
+        if config.pause_when_idle && channels.subscribers() == 0 {
+            if !idle {
+                idle = true;
+                sampler.pause(stats);
+                stats.set_sampler_idle(true);
+                tracing::debug!("no subscribers, sampler paused");
+            }
+            stats.record_tick();
+            continue;
+        }
+        if idle {
+            idle = false;
+            stats.set_sampler_idle(false);
+            tracing::debug!("sampler resumed");
+            // The readings from before the pause are stale baselines.
+            sampler.prime(&config, channels, stats).await;
+            ticks.reset();
+        }
+
+        let started = Instant::now();
+        let own_cpu_usage = block_in_place(|| sampler.sample(tick, &config, channels, stats));
+        let took = started.elapsed();
+        stats.refresh.tick.record(took);
+        stats.record_tick();
+
+        if tick.elapsed() > ticks.period() {
+            // The interval skips the ticks we missed instead of bunching
+            // them up.
+            stats.record_overrun();
+            if took > ticks.period()
+                && last_slow_warning.is_none_or(|at| at.elapsed() >= SLOW_TICK_WARNING_EVERY)
+            {
+                last_slow_warning = Some(Instant::now());
+                tracing::warn!(
+                    took_ms = took.as_millis() as u64,
+                    interval_ms = ticks.period().as_millis() as u64,
+                    "sampler tick took longer than the sampling interval, see /stats for where the time goes"
+                );
+            }
+        }
+
+        if let Some(usage) = own_cpu_usage {
+            pacer.observe(&config, usage);
+        }
+        let interval = pacer.interval(&config, channels.subscribers());
+        if interval != ticks.period() {
+            tracing::debug!(
+                from_ms = ticks.period().as_millis() as u64,
+                to_ms = interval.as_millis() as u64,
+                "sampling interval changed"
+            );
+            ticks = ticker(interval);
+            stats.set_sampler_interval(interval);
+        }
+    }
+}
+
+/// Picks the sampling interval in adaptive mode: it doubles while the
+/// server is over its CPU budget and halves again once it is well under it,
+/// and is at its longest while there are too few subscribers.
+struct Pacer {
+    /// The interval the CPU budget allows.
+    within_budget: Duration,
+}
+
+impl Pacer {
+    fn new(config: &SamplerConfig) -> Self {
+        Self {
+            within_budget: config.cpu_interval(),
+        }
+    }
+
+    /// Keeps the interval within the bounds of a changed config.
+    fn clamp(&mut self, config: &SamplerConfig) {
+        self.within_budget = self
+            .within_budget
+            .clamp(config.cpu_interval(), config.adaptive_max_interval());
+    }
+
+    /// Takes a new reading of the server's own CPU usage into account.
+    fn observe(&mut self, config: &SamplerConfig, own_cpu_usage: f32) {
+        if !config.adaptive {
+            return;
+        }
+        let budget = config.adaptive_cpu_budget;
+        let within_budget = if own_cpu_usage > budget {
+            (self.within_budget * 2).min(config.adaptive_max_interval())
+        } else if own_cpu_usage < budget / 2. {
+            (self.within_budget / 2).max(config.cpu_interval())
+        } else {
+            self.within_budget
+        };
+        if within_budget != self.within_budget {
+            tracing::debug!(
+                own_cpu_usage,
+                budget,
+                interval_ms = within_budget.as_millis() as u64,
+                "adapting sampling interval to the cpu budget"
+            );
+            self.within_budget = within_budget;
+        }
+    }
+
+    fn interval(&self, config: &SamplerConfig, subscribers: usize) -> Duration {
+        if !config.adaptive {
+            config.cpu_interval()
+        } else if subscribers < config.adaptive_min_clients {
+            config.adaptive_max_interval()
+        } else {
+            self.within_budget
+        }
+    }
+}
+
+/// Ticks on absolute deadlines, so the time spent refreshing does not
+/// stretch the period.
+fn ticker(period: Duration) -> time::Interval {
+    let mut ticks = time::interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    ticks
+}
+
+/// The state of one sampler run.
+struct Sampler<S> {
+    source: S,
+    self_pid: Option<Pid>,
+    collectors: Vec<Box<dyn Entry<S>>>,
+    /// When our own usage is next read, for `/stats` and the adaptive
+    /// interval.
+    next_own: Instant,
+}
+
+impl<S: MetricsSource> Sampler<S> {
+    fn new(source: S, channels: &mut Channels) -> Self {
+        let self_pid = sysinfo::get_current_pid().ok();
+        Self {
+            collectors: collectors(&source, self_pid, channels),
+            source,
+            self_pid,
+            next_own: Instant::now(),
+        }
+    }
+
+    /// Usage is computed from the difference between two refreshes, so a
+    /// refresh after a while without one only gives a baseline. This takes
+    /// that baseline for the streams that have subscribers and waits long
+    /// enough for the next refresh to be meaningful. Every stream with
+    /// subscribers then samples on the next tick.
+    async fn prime(&mut self, config: &SamplerConfig, channels: &mut Channels, stats: &Stats) {
+        let now = Instant::now();
+        let mut refresh = Refresh::default();
+        for entry in &mut self.collectors {
+            refresh |= entry.prime(now, config, channels, stats);
+        }
+        block_in_place(|| {
+            self.refresh(refresh, stats);
+            if !refresh.processes {
+                self.refresh_own();
+            }
+            for entry in &mut self.collectors {
+                entry.run(&self.source, now, config, channels, stats);
+            }
+        });
+        time::sleep(System::MINIMUM_CPU_UPDATE_INTERVAL).await;
+        self.next_own = Instant::now();
+    }
+
+    /// Marks every stream inactive, so that they are primed again once they
+    /// have subscribers.
+    fn pause(&mut self, stats: &Stats) {
+        for entry in &mut self.collectors {
+            entry.pause(stats);
+        }
+    }
+
+    /// Refreshes each of `refresh` once, however many collectors read it.
+    fn refresh(&mut self, refresh: Refresh, stats: &Stats) {
+        let source = &mut self.source;
+        if refresh.cpus {
+            stats.refresh.cpu.time(|| source.refresh_cpus());
+        }
+        if refresh.energy {
+            stats.refresh.energy.time(|| source.refresh_energy());
+        }
+        if refresh.memory {
+            stats.refresh.memory.time(|| source.refresh_memory());
+        }
+        if refresh.disks {
+            stats.refresh.disks.time(|| source.refresh_disks());
+        }
+        if refresh.networks {
+            stats.refresh.network.time(|| source.refresh_networks());
+        }
+        if refresh.gpus {
+            stats.refresh.gpus.time(|| source.refresh_gpus());
+        }
+        if refresh.hwmon {
+            stats.refresh.hwmon.time(|| source.refresh_hwmon());
+        }
+        if refresh.processes {
+            stats.refresh.processes.time(|| source.refresh_processes());
+        }
+        if refresh.components {
+            stats
+                .refresh
+                .components
+                .time(|| source.refresh_components());
+        }
+    }
+
+    /// Refreshes our own process alone, for when no stream reads them all.
+    fn refresh_own(&mut self) {
+        if let Some(pid) = self.self_pid {
+            self.source.refresh_process(pid);
+        }
+    }
+
+    /// Refreshes what is due at `tick` and publishes it, returning our own CPU
+    /// usage if it was refreshed. Blocks.
+    ///
+    /// Streams without subscribers are skipped. One that gains a subscriber
+    /// takes a baseline on this tick and, if its readings are deltas,
+    /// publishes from the next one.
+    fn sample(
+        &mut self,
+        tick: Instant,
+        config: &SamplerConfig,
+        channels: &mut Channels,
+        stats: &Stats,
+    ) -> Option<f32> {
+        let mut refresh = Refresh::default();
+        let mut reads = Refresh::default();
+        for entry in &mut self.collectors {
+            refresh |= entry.plan(tick, config, channels, stats);
+            reads |= entry.reads(tick, config);
+        }
+        self.refresh(refresh, stats);
+
+        // Refreshing our own process in between would skew the usages of
+        // the others, so while every process is read, ours is read with them.
+        let mut own_cpu_usage = None;
+        let own_due = refresh.processes || (!reads.processes && tick >= self.next_own);
+        if own_due {
+            if !refresh.processes {
+                stats.refresh.processes.time(|| self.refresh_own());
+            }
+            self.next_own = tick + Duration::from_millis(config.process_interval_ms);
+            if let Some(own) = self.self_pid.and_then(|pid| self.source.process(pid)) {
+                stats.set_self_usage(own.cpu_usage, own.memory);
+                own_cpu_usage = Some(own.cpu_usage);
+            }
+        }
+
+        for entry in &mut self.collectors {
+            entry.run(&self.source, tick, config, channels, stats);
+        }
+        own_cpu_usage
+    }
+}
+
+/// The collectors of the streams that are compiled in, each publishing to
+/// its stream in `channels`. A new stream only needs registering here.
+#[cfg_attr(
+    not(all(feature = "cpu", feature = "processes")),
+    allow(unused_variables)
+)]
+fn collectors<S: MetricsSource>(
+    source: &S,
+    self_pid: Option<Pid>,
+    channels: &mut Channels,
+) -> Vec<Box<dyn Entry<S>>> {
+    vec![
+        #[cfg(feature = "cpu")]
+        {
+            let cpu_count = *channels.cpu_count.get_or_insert(source.cpu_cores().len());
+            register(
+                cpu::CpuCollector::new(source, cpu_count),
+                |channels| &mut channels.cpus,
+                |stats| &stats.cpus,
+            )
+        },
+        #[cfg(feature = "power")]
+        register(
+            PowerMeter::default(),
+            |channels| &mut channels.power,
+            |stats| &stats.power,
+        ),
+        #[cfg(feature = "disks")]
+        register(
+            DiskMeter::default(),
+            |channels| &mut channels.disks,
+            |stats| &stats.disks,
+        ),
+        #[cfg(feature = "network")]
+        register(
+            NetMeter::default(),
+            |channels| &mut channels.network,
+            |stats| &stats.network,
+        ),
+        #[cfg(feature = "gpu")]
+        register(
+            GpuCollector::default(),
+            |channels| &mut channels.gpu,
+            |stats| &stats.gpu,
+        ),
+        #[cfg(feature = "sensors")]
+        register(
+            SensorCollector,
+            |channels| &mut channels.sensors,
+            |stats| &stats.sensors,
+        ),
+        #[cfg(feature = "system")]
+        register(
+            SystemCollector,
+            |channels| &mut channels.system,
+            |stats| &stats.system,
+        ),
+        #[cfg(feature = "mem")]
+        register(
+            mem::MemCollector,
+            |channels| &mut channels.ram,
+            |stats| &stats.ram,
+        ),
+        #[cfg(feature = "processes")]
+        register(
+            processes::ProcessCollector::new(
+                self_pid,
+                channels.processes.broadcast(),
+                channels.process_list.clone(),
+                channels.process_table.clone(),
+            ),
+            |channels| &mut channels.processes,
+            |stats| &stats.processes,
+        ),
+    ]
+}
+
+#[cfg_attr(
+    not(any(
+        feature = "cpu",
+        feature = "mem",
+        feature = "processes",
+        feature = "power",
+        feature = "disks",
+        feature = "network",
+        feature = "gpu",
+        feature = "sensors",
+        feature = "system"
+    )),
+    allow(dead_code)
+)]
+fn register<S, C>(
+    collector: C,
+    publisher: fn(&mut Channels) -> &mut Publisher<C::State>,
+    stats: fn(&Stats) -> &ChannelStats,
+) -> Box<dyn Entry<S>>
+where
+    S: MetricsSource,
+    C: Collector<S> + 'static,
+{
+    Box::new(Registered {
+        collector,
+        publisher,
+        stats,
+        active: false,
+        next: Instant::now(),
+        planned: None,
+    })
+}
+
+/// A [`Registered`] collector, whatever its stream publishes.
+trait Entry<S>: Send {
+    /// Marks the stream active if it has subscribers, planning a baseline
+    /// if it takes one. Returns what that needs refreshed.
+    fn prime(
+        &mut self,
+        now: Instant,
+        config: &SamplerConfig,
+        channels: &mut Channels,
+        stats: &Stats,
+    ) -> Refresh;
+
+    /// Plans what to do at `tick`, returning what that needs refreshed.
+    fn plan(
+        &mut self,
+        tick: Instant,
+        config: &SamplerConfig,
+        channels: &mut Channels,
+        stats: &Stats,
+    ) -> Refresh;
+
+    /// What the stream reads while it is active, whether due or not.
+    fn reads(&self, tick: Instant, config: &SamplerConfig) -> Refresh;
+
+    /// Does what was planned, from a refreshed `source`.
+    fn run(
+        &mut self,
+        source: &S,
+        tick: Instant,
+        config: &SamplerConfig,
+        channels: &mut Channels,
+        stats: &Stats,
+    );
+
+    fn pause(&mut self, stats: &Stats);
+}
+
+/// A collector and the stream it feeds.
+struct Registered<C, T> {
+    collector: C,
+    publisher: fn(&mut Channels) -> &mut Publisher<T>,
+    stats: fn(&Stats) -> &ChannelStats,
+    /// Whether the stream was wanted on the last tick; idle streams are not
+    /// refreshed.
+    active: bool,
+    /// When the stream is next due.
+    next: Instant,
+    planned: Option<Step>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Baseline,
+    Collect,
+}
+
+impl<C, T: Payload + Debug> Registered<C, T> {
+    fn wanted<S: MetricsSource>(&self, channels: &mut Channels) -> bool
+    where
+        C: Collector<S>,
+    {
+        (self.publisher)(channels).has_subscribers() || self.collector.wanted()
+    }
+
+    fn planned_refresh<S: MetricsSource>(&self, tick: Instant, config: &SamplerConfig) -> Refresh
+    where
+        C: Collector<S>,
+    {
+        match self.planned {
+            Some(_) => self.collector.refresh(tick, config),
+            None => Refresh::default(),
+        }
+    }
+}
+
+impl<S, C, T> Entry<S> for Registered<C, T>
+where
+    S: MetricsSource,
+    C: Collector<S, State = T>,
+    T: Payload + Debug + Send,
+{
+    fn prime(
+        &mut self,
+        now: Instant,
+        config: &SamplerConfig,
+        channels: &mut Channels,
+        stats: &Stats,
+    ) -> Refresh {
+        self.active = self.wanted(channels);
+        (self.stats)(stats).set_active(self.active);
+        self.next = now;
+        self.planned = (self.active && self.collector.deltas()).then_some(Step::Baseline);
+        self.planned_refresh(now, config)
+    }
+
+    fn plan(
+        &mut self,
+        tick: Instant,
+        config: &SamplerConfig,
+        channels: &mut Channels,
+        stats: &Stats,
+    ) -> Refresh {
+        let wanted = self.wanted(channels);
+        self.planned = match demand(&mut self.active, wanted, (self.stats)(stats)) {
+            Demand::Idle => {
+                self.collector.reset();
+                None
+            }
+            // Rates need a baseline, so these streams publish from the tick
+            // after they start on rather than after a whole period.
+            Demand::Starting if self.collector.deltas() => {
+                tracing::debug!(stream = self.collector.name(), "stream started");
+                self.next = tick;
+                Some(Step::Baseline)
+            }
+            Demand::Starting | Demand::Active if tick >= self.next => {
+                self.next = tick + self.collector.period(config);
+                Some(Step::Collect)
+            }
+            Demand::Starting | Demand::Active => None,
+        };
+        self.planned_refresh(tick, config)
+    }
+
+    fn reads(&self, tick: Instant, config: &SamplerConfig) -> Refresh {
+        match self.active {
+            true => self.collector.refresh(tick, config),
+            false => Refresh::default(),
+        }
+    }
+
+    fn run(
+        &mut self,
+        source: &S,
+        tick: Instant,
+        config: &SamplerConfig,
+        channels: &mut Channels,
+        stats: &Stats,
+    ) {
+        match self.planned.take() {
+            Some(Step::Baseline) => self.collector.baseline(source, Instant::now()),
+            Some(Step::Collect) => {
+                if let Some(state) = self.collector.collect(source, tick, config) {
+                    let max_silence = self.collector.max_silence(config);
+                    (self.publisher)(channels).publish(&state, max_silence, (self.stats)(stats));
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn pause(&mut self, stats: &Stats) {
+        self.active = false;
+        self.planned = None;
+        (self.stats)(stats).set_active(false);
+        self.collector.reset();
+    }
+}
+
+/// Whether a stream is wanted on this tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Demand {
+    /// Nobody is subscribed.
+    Idle,
+    /// Subscribers appeared since the last tick.
+    Starting,
+    Active,
+}
+
+/// Updates `active` from whether the stream has subscribers now.
+fn demand(active: &mut bool, subscribed: bool, stats: &ChannelStats) -> Demand {
+    let was_active = std::mem::replace(active, subscribed);
+    if was_active != subscribed {
+        stats.set_active(subscribed);
+    }
+    match (was_active, subscribed) {
+        (_, false) => Demand::Idle,
+        (false, true) => Demand::Starting,
+        (true, true) => Demand::Active,
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "cpu", feature = "processes"))]
+    use crate::collectors::source::fake::FakeSource;
+    use crate::ws::Protocol;
+
+    #[cfg(any(feature = "cpu", feature = "processes"))]
+    fn received<T: serde::de::DeserializeOwned>(rx: &mut broadcast::Receiver<Frame>) -> Vec<T> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|frame| {
+                let sample: Sample<T> = serde_json::from_str(frame.text(Protocol::V2)).unwrap();
+                sample.data
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "cpu")]
+    #[test]
+    fn cpus_are_published_once_subscribed() {
+        let mut channels = Channels::new(8, watch::channel(Arc::default()).1);
+        let stats = Stats::new();
+        let config = SamplerConfig::default();
+        let mut sampler = Sampler::new(FakeSource::with_cpus(&[12.5, 40.]), &mut channels);
+        let mut rx = channels.cpus.broadcast().subscribe();
+
+        let tick = Instant::now();
+        sampler.sample(tick, &config, &mut channels, &stats);
+        // The first tick after subscribing only takes a baseline.
+        assert!(received::<CpuState>(&mut rx).is_empty());
+
+        sampler.source.cpus[1].usage = 60.;
+        sampler.sample(tick + config.cpu_interval(), &config, &mut channels, &stats);
+        let states = received::<CpuState>(&mut rx);
+        assert_eq!(states.len(), 1);
+        let usages: Vec<_> = states[0].cores.iter().map(|core| core.usage).collect();
+        assert_eq!(usages, [12.5, 60.]);
+    }
+
+    #[cfg(feature = "processes")]
+    #[test]
+    fn processes_are_cut_to_top_processes() {
+        let mut channels = Channels::new(8, watch::channel(Arc::default()).1);
+        let stats = Stats::new();
+        let config = SamplerConfig {
+            top_processes: 2,
+            ..SamplerConfig::default()
+        };
+        let mut source = FakeSource::default();
+        for pid in 1..=5 {
+            source.add_process(pid, &format!("p{pid}"), pid as f32);
+        }
+        let mut sampler = Sampler::new(source, &mut channels);
+        let mut rx = channels.processes.broadcast().subscribe();
+
+        let tick = Instant::now();
+        sampler.sample(tick, &config, &mut channels, &stats);
+        let interval = Duration::from_millis(config.process_interval_ms);
+        sampler.sample(tick + interval, &config, &mut channels, &stats);
+        let lists = received::<Vec<ProcessInfo>>(&mut rx);
+        assert_eq!(lists.len(), 1);
+        let pids: Vec<_> = lists[0].iter().map(|process| process.pid).collect();
+        assert_eq!(pids, [5, 4]);
+    }
+
+    #[test]
+    fn resuming_replays_retained_frames_after_a_gap() {
+        let mut publisher: Publisher<Vec<crate::types::ProcessInfo>> =
+            Publisher::new(3, watch::channel(Arc::default()).1);
+        let broadcast = publisher.broadcast();
+        let stats = ChannelStats::default();
+        for _ in 0..5 {
+            publisher.publish(&Vec::new(), None, &stats);
+        }
+
+        let (mut rx, backlog) = broadcast.resume(0);
+        assert_eq!(backlog.gap, Some((1, 1)));
+        let seqs: Vec<_> = backlog.frames.iter().map(Frame::seq).collect();
+        assert_eq!(seqs, [2, 3, 4]);
+        let replayed: Sample<Vec<crate::types::ProcessInfo>> =
+            serde_json::from_str(backlog.frames[0].replayed().text(Protocol::V2)).unwrap();
+        assert!(replayed.replayed);
+
+        let (_, backlog) = broadcast.resume(3);
+        assert_eq!(backlog.gap, None);
+        assert_eq!(backlog.frames.len(), 1);
+
+        publisher.publish(&Vec::new(), None, &stats);
+        assert_eq!(rx.try_recv().unwrap().seq(), 5);
+        assert_eq!(broadcast.latest().map(|frame| frame.seq()), Some(5));
+    }
+
+    #[tokio::test]
+    async fn subscribing_wakes_the_sampler() {
+        let subscribed = Arc::new(Notify::new());
+        let publisher: Publisher<Vec<crate::types::ProcessInfo>> =
+            Publisher::waking(3, watch::channel(Arc::default()).1, &subscribed);
+        let _rx = publisher.broadcast().subscribe();
+        time::timeout(Duration::from_secs(1), subscribed.notified())
+            .await
+            .expect("subscribing did not notify");
+    }
+}
@@ -0,0 +1,1903 @@
+use axum::{
+    extract::{ws::WebSocket, ConnectInfo, Query, State, WebSocketUpgrade},
+    http::{Request, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router, Server,
+};
+use std::{
+    fmt,
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::TcpSocket,
+    sync::{broadcast, watch},
+    task::JoinSet,
+    time,
+};
+
+#[cfg(feature = "client")]
+use crate::client;
+#[cfg(any(feature = "processes", feature = "temps"))]
+use crate::collectors;
+#[cfg(feature = "sqlite")]
+use crate::db;
+#[cfg(any(feature = "cpu", feature = "mem"))]
+use crate::history;
+#[cfg(feature = "kafka")]
+use crate::kafka;
+#[cfg(feature = "mdns")]
+use crate::mdns;
+#[cfg(unix)]
+use crate::reload;
+#[cfg(feature = "smart")]
+use crate::smart;
+#[cfg(feature = "tui")]
+use crate::tui;
+#[cfg(feature = "upstream")]
+use crate::upstream;
+use crate::{
+    access_log, admin, alerts, config, connections, hub, info, metrics_log, multiplex, once,
+    replay, sampler, share, shutdown, simulate, stats, types, ws,
+};
+#[cfg(any(
+    feature = "cpu",
+    feature = "mem",
+    feature = "processes",
+    feature = "power",
+    feature = "disks",
+    feature = "network",
+    feature = "gpu",
+    feature = "sensors",
+    feature = "system"
+))]
+use crate::{snapshot, sse};
+#[cfg(feature = "client")]
+use config::Command;
+use config::{Args, BindFailure, Config};
+use stats::Stats;
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    compression::CompressionLayer,
+    trace::{DefaultOnRequest, DefaultOnResponse, MakeSpan, TraceLayer},
+    LatencyUnit,
+};
+use tracing_subscriber::{layer::SubscriberExt, reload::Layer, util::SubscriberInitExt};
+use ws::{stream_channel, Caps, Connection, Frame, SessionOptions, StreamParams};
+
+/// What every handler shares. Only [`run`] builds one.
+#[derive(Clone)]
+pub struct AppState {
+    #[cfg(feature = "cpu")]
+    pub(crate) cpus_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "power")]
+    pub(crate) power_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "disks")]
+    pub(crate) disks_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "network")]
+    pub(crate) network_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "gpu")]
+    pub(crate) gpu_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "sensors")]
+    pub(crate) sensors_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "system")]
+    pub(crate) system_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "mem")]
+    pub(crate) ram_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "processes")]
+    pub(crate) process_broadcast: Arc<sampler::Broadcast>,
+    #[cfg(feature = "processes")]
+    pub(crate) process_list: sampler::ProcessList,
+    #[cfg(feature = "processes")]
+    pub(crate) process_table: sampler::ProcessTable,
+    pub(crate) alerts_broadcast: Arc<sampler::Broadcast>,
+    /// The recent samples, `None` unless `history.retention_mins` is set.
+    #[cfg(any(feature = "cpu", feature = "mem"))]
+    pub(crate) history: Option<Arc<history::History>>,
+    /// Where the samples are kept, `None` unless `db.path` is set.
+    #[cfg(feature = "sqlite")]
+    pub(crate) db: Option<Arc<db::Db>>,
+    /// Every stream `/realtime/all` can multiplex.
+    pub(crate) topics: Arc<multiplex::Topics>,
+    /// The rules that are firing.
+    pub(crate) alerts_firing: watch::Receiver<Vec<alerts::AlertEvent>>,
+    /// The latest disk health reading, `None` until the first one.
+    #[cfg(feature = "smart")]
+    pub(crate) smart: watch::Receiver<Option<smart::SmartReport>>,
+    pub(crate) hub: Arc<hub::Hub>,
+    /// What the machine is, read at startup.
+    pub(crate) info: Arc<info::SystemInfo>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) access_log: access_log::AccessLog,
+    pub(crate) sampler_config: Arc<watch::Sender<config::SamplerConfig>>,
+    /// Who every message and snapshot says it comes from.
+    pub(crate) instance: Arc<watch::Sender<Arc<types::Instance>>>,
+    pub(crate) websocket: config::WebSocketConfig,
+    pub(crate) tokens: Arc<admin::Tokens>,
+    pub(crate) shares: Arc<share::Shares>,
+    pub(crate) ingest_token: Option<Arc<str>>,
+    pub(crate) process_control: bool,
+    /// Tells the sessions when the server is shutting down.
+    pub(crate) shutdown: Arc<shutdown::Shutdown>,
+}
+
+impl AppState {
+    /// A receiver for `stream`, `None` if it was compiled out.
+    #[allow(unreachable_patterns)]
+    fn subscribe(&self, stream: config::Stream) -> Option<broadcast::Receiver<Frame>> {
+        match stream {
+            #[cfg(feature = "cpu")]
+            config::Stream::Cpus => Some(self.cpus_broadcast.subscribe()),
+            #[cfg(feature = "mem")]
+            config::Stream::Ram => Some(self.ram_broadcast.subscribe()),
+            #[cfg(feature = "processes")]
+            config::Stream::Processes => Some(self.process_broadcast.subscribe()),
+            _ => None,
+        }
+    }
+
+    /// Receivers for every stream a sink such as the metrics log writes out,
+    /// with their names.
+    fn sink_streams(&self) -> Vec<(&'static str, broadcast::Receiver<Frame>)> {
+        #[cfg_attr(
+            not(any(
+                feature = "power",
+                feature = "disks",
+                feature = "network",
+                feature = "gpu",
+                feature = "sensors",
+                feature = "system"
+            )),
+            allow(unused_mut)
+        )]
+        let mut streams: Vec<_> = [
+            config::Stream::Cpus,
+            config::Stream::Ram,
+            config::Stream::Processes,
+        ]
+        .into_iter()
+        .filter_map(|stream| Some((stream.name(), self.subscribe(stream)?)))
+        .collect();
+        #[cfg(feature = "power")]
+        streams.push(("power", self.power_broadcast.subscribe()));
+        #[cfg(feature = "disks")]
+        streams.push(("disks", self.disks_broadcast.subscribe()));
+        #[cfg(feature = "network")]
+        streams.push(("network", self.network_broadcast.subscribe()));
+        #[cfg(feature = "gpu")]
+        streams.push(("gpu", self.gpu_broadcast.subscribe()));
+        #[cfg(feature = "sensors")]
+        streams.push(("sensors", self.sensors_broadcast.subscribe()));
+        #[cfg(feature = "system")]
+        streams.push(("system", self.system_broadcast.subscribe()));
+        streams.push(("alerts", self.alerts_broadcast.subscribe()));
+        streams
+    }
+
+    /// A receiver for `stream` of the hub remote `params` asks for, `None`
+    /// if it asks for our own.
+    #[cfg_attr(
+        not(any(feature = "cpu", feature = "mem", feature = "processes")),
+        allow(dead_code)
+    )]
+    fn subscribe_host(
+        &self,
+        params: &StreamParams,
+        stream: config::Stream,
+    ) -> Result<Option<broadcast::Receiver<Frame>>, (StatusCode, String)> {
+        let Some(host) = params.host() else {
+            return Ok(None);
+        };
+        match self.hub.subscribe(host, stream) {
+            Ok(rx) => Ok(Some(rx)),
+            Err(hub::SubscribeError::UnknownHost) => {
+                Err((StatusCode::NOT_FOUND, format!("unknown host {host:?}")))
+            }
+            Err(hub::SubscribeError::Offline) => Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("host {host:?} is offline"),
+            )),
+        }
+    }
+}
+
+/// After how many missed CPU ticks `/healthz` reports the sampler as stalled.
+const HEALTH_STALE_TICKS: u32 = 10;
+const MAX_BIND_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How long a shutdown waits for requests to finish and sessions to close.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Why the server could not start. Each kind exits with its own code.
+#[derive(Debug)]
+pub enum StartupError {
+    Config(String),
+    Bind {
+        addr: SocketAddr,
+        err: std::io::Error,
+    },
+    NothingBound,
+    /// `--once` could not take its snapshot.
+    Snapshot(String),
+    /// The terminal `axact tui` draws on failed.
+    #[cfg(feature = "tui")]
+    Terminal(std::io::Error),
+}
+
+impl StartupError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Config(_) => 2,
+            StartupError::Bind { .. } | StartupError::NothingBound => 3,
+            StartupError::Snapshot(_) => 1,
+            #[cfg(feature = "tui")]
+            StartupError::Terminal(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::Config(err) => f.write_str(err),
+            StartupError::Bind { addr, err } => write!(f, "cannot bind {addr}: {err}"),
+            StartupError::NothingBound => {
+                f.write_str("none of the configured addresses could be bound")
+            }
+            StartupError::Snapshot(err) => write!(f, "cannot take a snapshot: {err}"),
+            #[cfg(feature = "tui")]
+            StartupError::Terminal(err) => write!(f, "terminal: {err}"),
+        }
+    }
+}
+
+/// Runs what `args` ask for: the server, unless a subcommand is given.
+pub async fn run(args: Args) -> Result<(), StartupError> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path).map_err(StartupError::Config)?,
+        None => Config::default(),
+    };
+    config.apply_args(&args);
+    config.validate().map_err(StartupError::Config)?;
+
+    match args.command.clone() {
+        #[cfg(feature = "client")]
+        Some(Command::Client(client_args)) => {
+            client::run(client_args).await;
+            return Ok(());
+        }
+        #[cfg(feature = "tui")]
+        Some(Command::Tui(tui_args)) => {
+            return tui::run(tui_args, config.sampler)
+                .await
+                .map_err(StartupError::Terminal);
+        }
+        None => {}
+    }
+
+    if let Some(streams) = &args.once {
+        return once::run(&config.sampler, config.instance(), streams)
+            .await
+            .map_err(StartupError::Snapshot);
+    }
+
+    let (instance, instance_rx) = watch::channel(Arc::new(config.instance()));
+    let channels = sampler::Channels::new(config.channel_capacity, instance_rx.clone());
+
+    let (log_filter, log_handle) = Layer::new(config.log_filter());
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let (sampler_config, config_rx) = watch::channel(config.sampler.clone());
+    let alerts_publisher = sampler::Publisher::new(config.channel_capacity, instance_rx);
+    let (alerts_firing_tx, alerts_firing) = watch::channel(vec![]);
+    let (smart_tx, smart_rx) = watch::channel(None);
+    let app_state = app_state(
+        &config,
+        &channels,
+        &alerts_publisher,
+        alerts_firing,
+        smart_rx.clone(),
+        sampler_config,
+        instance,
+    )?;
+    let stats = app_state.stats.clone();
+    let hub = app_state.hub.clone();
+    let router = router(&app_state, &config);
+
+    if !config.alerts.is_empty() {
+        let state = app_state.clone();
+        tokio::spawn(alerts::evaluate(
+            config.alerts.clone(),
+            move |stream| state.subscribe(stream),
+            smart_rx,
+            alerts_publisher,
+            alerts_firing_tx,
+            stats.clone(),
+        ));
+    }
+
+    // Command line arguments are not validated with the file.
+    config.upstream.validate().map_err(StartupError::Config)?;
+    #[cfg(feature = "upstream")]
+    if let Some((name, url)) = upstream::endpoint(&config.upstream).map_err(StartupError::Config)? {
+        let streams = [
+            config::Stream::Cpus,
+            config::Stream::Ram,
+            config::Stream::Processes,
+        ]
+        .into_iter()
+        .filter_map(|stream| Some((stream, app_state.subscribe(stream)?)))
+        .collect();
+        tracing::info!(name, "pushing samples upstream");
+        tokio::spawn(upstream::push(
+            url,
+            config
+                .upstream
+                .token
+                .clone()
+                .or_else(|| std::env::var("AXACT_UPSTREAM_TOKEN").ok()),
+            config.upstream.queue,
+            config.websocket,
+            streams,
+        ));
+    }
+
+    #[cfg(feature = "kafka")]
+    if !config.kafka.brokers.is_empty() {
+        let producer =
+            kafka::producer(&config.kafka, stats.clone()).map_err(StartupError::Config)?;
+        tracing::info!(brokers = ?config.kafka.brokers, "producing to kafka");
+        tokio::spawn(kafka::produce(
+            producer,
+            config.kafka.clone(),
+            app_state.instance.subscribe(),
+            app_state.sink_streams(),
+            stats.clone(),
+        ));
+    }
+
+    metrics_log::start(&config.metrics_log, app_state.sink_streams(), stats.clone())
+        .map_err(StartupError::Config)?;
+
+    #[cfg(feature = "smart")]
+    if !args.simulate && args.replay.is_empty() {
+        tokio::spawn(smart::poll(config.smart.clone(), smart_tx));
+    }
+    #[cfg(not(feature = "smart"))]
+    drop(smart_tx);
+
+    #[cfg(feature = "hub")]
+    hub::connect(hub, stats.clone());
+    #[cfg(not(feature = "hub"))]
+    drop(hub);
+
+    let shutdown = app_state.shutdown.clone();
+    let shutdown_rx = shutdown.subscribe();
+    if args.simulate {
+        let seed = args.simulate_seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        tokio::spawn(simulate::run(config_rx, channels, stats, shutdown_rx, seed));
+    } else if !args.replay.is_empty() {
+        let recordings = replay::open(&args.replay).map_err(StartupError::Config)?;
+        tokio::spawn(replay::run(recordings, channels, stats, shutdown_rx));
+    } else {
+        tokio::spawn(sampler::supervise(config_rx, channels, stats, shutdown_rx));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = args.config.clone() {
+        tokio::spawn(reload::reload_on_sighup(
+            path,
+            args,
+            config.clone(),
+            app_state.clone(),
+            log_handle,
+        ));
+    }
+    #[cfg(not(unix))]
+    drop(log_handle);
+
+    let result = serve(router, &config, &shutdown).await;
+    // Stops the sampler, if the listeners stopped on their own.
+    shutdown.begin();
+    result
+}
+
+/// Builds the state the handlers share, around the channels the sampler
+/// publishes to.
+#[cfg_attr(
+    not(any(
+        feature = "cpu",
+        feature = "mem",
+        feature = "processes",
+        feature = "power",
+        feature = "disks",
+        feature = "network",
+        feature = "gpu",
+        feature = "sensors",
+        feature = "system",
+        feature = "sqlite"
+    )),
+    allow(unused_variables)
+)]
+fn app_state(
+    config: &Config,
+    channels: &sampler::Channels,
+    alerts_publisher: &sampler::Publisher<alerts::AlertEvent>,
+    alerts_firing: watch::Receiver<Vec<alerts::AlertEvent>>,
+    smart: watch::Receiver<Option<crate::smart::SmartReport>>,
+    sampler_config: watch::Sender<config::SamplerConfig>,
+    instance: watch::Sender<Arc<types::Instance>>,
+) -> Result<AppState, StartupError> {
+    let stats = Arc::new(Stats::new());
+    let access_log =
+        access_log::AccessLog::open(&config.access_log).map_err(StartupError::Config)?;
+    let hub = Arc::new(hub::Hub::new(&config.remotes, config.channel_capacity));
+    #[cfg(feature = "sqlite")]
+    let db = db::Db::start(&config.db, channels).map_err(StartupError::Config)?;
+    #[cfg(not(feature = "smart"))]
+    drop(smart);
+    Ok(AppState {
+        #[cfg(feature = "cpu")]
+        cpus_broadcast: channels.cpus.broadcast(),
+        #[cfg(feature = "power")]
+        power_broadcast: channels.power.broadcast(),
+        #[cfg(feature = "disks")]
+        disks_broadcast: channels.disks.broadcast(),
+        #[cfg(feature = "network")]
+        network_broadcast: channels.network.broadcast(),
+        #[cfg(feature = "gpu")]
+        gpu_broadcast: channels.gpu.broadcast(),
+        #[cfg(feature = "sensors")]
+        sensors_broadcast: channels.sensors.broadcast(),
+        #[cfg(feature = "system")]
+        system_broadcast: channels.system.broadcast(),
+        #[cfg(feature = "mem")]
+        ram_broadcast: channels.ram.broadcast(),
+        #[cfg(feature = "processes")]
+        process_broadcast: channels.processes.broadcast(),
+        #[cfg(feature = "processes")]
+        process_list: channels.process_list.clone(),
+        #[cfg(feature = "processes")]
+        process_table: channels.process_table.clone(),
+        alerts_broadcast: alerts_publisher.broadcast(),
+        #[cfg(any(feature = "cpu", feature = "mem"))]
+        history: history::History::start(&config.history, channels),
+        #[cfg(feature = "sqlite")]
+        db,
+        topics: Arc::new(multiplex::Topics::new(vec![
+            #[cfg(feature = "cpu")]
+            (multiplex::Topic::Cpus, channels.cpus.broadcast()),
+            #[cfg(feature = "power")]
+            (multiplex::Topic::Power, channels.power.broadcast()),
+            #[cfg(feature = "disks")]
+            (multiplex::Topic::Disks, channels.disks.broadcast()),
+            #[cfg(feature = "network")]
+            (multiplex::Topic::Network, channels.network.broadcast()),
+            #[cfg(feature = "gpu")]
+            (multiplex::Topic::Gpu, channels.gpu.broadcast()),
+            #[cfg(feature = "sensors")]
+            (multiplex::Topic::Sensors, channels.sensors.broadcast()),
+            #[cfg(feature = "system")]
+            (multiplex::Topic::System, channels.system.broadcast()),
+            #[cfg(feature = "mem")]
+            (multiplex::Topic::Ram, channels.ram.broadcast()),
+            #[cfg(feature = "processes")]
+            (multiplex::Topic::Processes, channels.processes.broadcast()),
+            (multiplex::Topic::Alerts, alerts_publisher.broadcast()),
+        ])),
+        alerts_firing,
+        #[cfg(feature = "smart")]
+        smart,
+        hub,
+        info: Arc::new(info::read()),
+        stats,
+        access_log,
+        sampler_config: Arc::new(sampler_config),
+        instance: Arc::new(instance),
+        websocket: config.websocket,
+        tokens: Arc::new(admin::Tokens::new(config)),
+        shares: Arc::new(share::Shares::new(&config.share)),
+        ingest_token: config
+            .ingest_token
+            .clone()
+            .or_else(|| std::env::var("AXACT_INGEST_TOKEN").ok())
+            .map(Into::into),
+        process_control: config.process_control,
+        shutdown: shutdown::Shutdown::new(),
+    })
+}
+
+/// Routes every endpoint to its handler.
+fn router(app_state: &AppState, config: &Config) -> Router {
+    let router = Router::new();
+    #[cfg(feature = "cpu")]
+    let router = router.route("/realtime/cpus", get(realtime_cpus_get));
+    #[cfg(not(feature = "cpu"))]
+    let router = router.route("/realtime/cpus", get(|| compiled_without("cpu")));
+    #[cfg(feature = "cpu")]
+    let router = router.route("/snapshot/cpus", get(snapshot::cpus_get));
+    #[cfg(not(feature = "cpu"))]
+    let router = router.route("/snapshot/cpus", get(|| compiled_without("cpu")));
+    #[cfg(feature = "cpu")]
+    let router = router.route("/sse/cpus", get(sse::cpus_get));
+    #[cfg(not(feature = "cpu"))]
+    let router = router.route("/sse/cpus", get(|| compiled_without("cpu")));
+    #[cfg(feature = "power")]
+    let router = router.route("/realtime/power", get(realtime_power_get));
+    #[cfg(not(feature = "power"))]
+    let router = router.route("/realtime/power", get(|| compiled_without("power")));
+    #[cfg(feature = "power")]
+    let router = router.route("/snapshot/power", get(snapshot::power_get));
+    #[cfg(not(feature = "power"))]
+    let router = router.route("/snapshot/power", get(|| compiled_without("power")));
+    #[cfg(feature = "power")]
+    let router = router.route("/sse/power", get(sse::power_get));
+    #[cfg(not(feature = "power"))]
+    let router = router.route("/sse/power", get(|| compiled_without("power")));
+    #[cfg(feature = "disks")]
+    let router = router.route("/realtime/disks", get(realtime_disks_get));
+    #[cfg(not(feature = "disks"))]
+    let router = router.route("/realtime/disks", get(|| compiled_without("disks")));
+    #[cfg(feature = "disks")]
+    let router = router.route("/snapshot/disks", get(snapshot::disks_get));
+    #[cfg(not(feature = "disks"))]
+    let router = router.route("/snapshot/disks", get(|| compiled_without("disks")));
+    #[cfg(feature = "disks")]
+    let router = router.route("/sse/disks", get(sse::disks_get));
+    #[cfg(not(feature = "disks"))]
+    let router = router.route("/sse/disks", get(|| compiled_without("disks")));
+    #[cfg(feature = "network")]
+    let router = router.route("/realtime/network", get(realtime_network_get));
+    #[cfg(not(feature = "network"))]
+    let router = router.route("/realtime/network", get(|| compiled_without("network")));
+    #[cfg(feature = "network")]
+    let router = router.route("/snapshot/network", get(snapshot::network_get));
+    #[cfg(not(feature = "network"))]
+    let router = router.route("/snapshot/network", get(|| compiled_without("network")));
+    #[cfg(feature = "network")]
+    let router = router.route("/sse/network", get(sse::network_get));
+    #[cfg(not(feature = "network"))]
+    let router = router.route("/sse/network", get(|| compiled_without("network")));
+    #[cfg(feature = "gpu")]
+    let router = router.route("/realtime/gpu", get(realtime_gpu_get));
+    #[cfg(not(feature = "gpu"))]
+    let router = router.route("/realtime/gpu", get(|| compiled_without("gpu")));
+    #[cfg(feature = "gpu")]
+    let router = router.route("/snapshot/gpu", get(snapshot::gpu_get));
+    #[cfg(not(feature = "gpu"))]
+    let router = router.route("/snapshot/gpu", get(|| compiled_without("gpu")));
+    #[cfg(feature = "gpu")]
+    let router = router.route("/sse/gpu", get(sse::gpu_get));
+    #[cfg(not(feature = "gpu"))]
+    let router = router.route("/sse/gpu", get(|| compiled_without("gpu")));
+    #[cfg(feature = "sensors")]
+    let router = router.route("/realtime/sensors", get(realtime_sensors_get));
+    #[cfg(not(feature = "sensors"))]
+    let router = router.route("/realtime/sensors", get(|| compiled_without("sensors")));
+    #[cfg(feature = "sensors")]
+    let router = router.route("/snapshot/sensors", get(snapshot::sensors_get));
+    #[cfg(not(feature = "sensors"))]
+    let router = router.route("/snapshot/sensors", get(|| compiled_without("sensors")));
+    #[cfg(feature = "sensors")]
+    let router = router.route("/sse/sensors", get(sse::sensors_get));
+    #[cfg(not(feature = "sensors"))]
+    let router = router.route("/sse/sensors", get(|| compiled_without("sensors")));
+    #[cfg(feature = "system")]
+    let router = router.route("/realtime/system", get(realtime_system_get));
+    #[cfg(not(feature = "system"))]
+    let router = router.route("/realtime/system", get(|| compiled_without("system")));
+    #[cfg(feature = "system")]
+    let router = router.route("/snapshot/system", get(snapshot::system_get));
+    #[cfg(not(feature = "system"))]
+    let router = router.route("/snapshot/system", get(|| compiled_without("system")));
+    #[cfg(feature = "system")]
+    let router = router.route("/sse/system", get(sse::system_get));
+    #[cfg(not(feature = "system"))]
+    let router = router.route("/sse/system", get(|| compiled_without("system")));
+    #[cfg(feature = "mem")]
+    let router = router.route("/realtime/ram", get(realtime_ram_get));
+    #[cfg(not(feature = "mem"))]
+    let router = router.route("/realtime/ram", get(|| compiled_without("mem")));
+    #[cfg(feature = "mem")]
+    let router = router.route("/snapshot/ram", get(snapshot::ram_get));
+    #[cfg(not(feature = "mem"))]
+    let router = router.route("/snapshot/ram", get(|| compiled_without("mem")));
+    #[cfg(feature = "mem")]
+    let router = router.route("/sse/ram", get(sse::ram_get));
+    #[cfg(not(feature = "mem"))]
+    let router = router.route("/sse/ram", get(|| compiled_without("mem")));
+    #[cfg(feature = "processes")]
+    let router = router.route("/realtime/processes", get(realtime_process_get));
+    #[cfg(not(feature = "processes"))]
+    let router = router.route("/realtime/processes", get(|| compiled_without("processes")));
+    #[cfg(feature = "processes")]
+    let router = router.route("/snapshot/processes", get(snapshot::processes_get));
+    #[cfg(not(feature = "processes"))]
+    let router = router.route("/snapshot/processes", get(|| compiled_without("processes")));
+    #[cfg(feature = "processes")]
+    let router = router.route("/sse/processes", get(sse::processes_get));
+    #[cfg(not(feature = "processes"))]
+    let router = router.route("/sse/processes", get(|| compiled_without("processes")));
+    #[cfg(feature = "processes")]
+    let router = router
+        .route("/processes", get(processes_get))
+        .route("/processes/:pid", get(process_get));
+    #[cfg(not(feature = "processes"))]
+    let router = router
+        .route("/processes", get(|| compiled_without("processes")))
+        .route("/processes/:pid", get(|| compiled_without("processes")));
+    #[cfg(feature = "temps")]
+    let router = router.route("/debug/sensors", get(debug_sensors_get));
+    #[cfg(not(feature = "temps"))]
+    let router = router.route("/debug/sensors", get(|| compiled_without("temps")));
+    #[cfg(feature = "mdns")]
+    let router = router.route("/discover", get(discover_get));
+    #[cfg(not(feature = "mdns"))]
+    let router = router.route("/discover", get(|| compiled_without("mdns")));
+    #[cfg(feature = "smart")]
+    let router = router.route("/smart", get(smart_get));
+    #[cfg(not(feature = "smart"))]
+    let router = router.route("/smart", get(|| compiled_without("smart")));
+    // The history responses are the largest the server produces.
+    let history_router = Router::new();
+    #[cfg(feature = "cpu")]
+    let history_router = history_router.route("/history/cpus", get(history::cpus_get));
+    #[cfg(not(feature = "cpu"))]
+    let history_router = history_router.route("/history/cpus", get(|| compiled_without("cpu")));
+    #[cfg(feature = "mem")]
+    let history_router = history_router.route("/history/ram", get(history::ram_get));
+    #[cfg(not(feature = "mem"))]
+    let history_router = history_router.route("/history/ram", get(|| compiled_without("mem")));
+    #[cfg(feature = "sqlite")]
+    let history_router = history_router.route("/history", get(db::history_get));
+    #[cfg(not(feature = "sqlite"))]
+    let history_router = history_router.route("/history", get(|| compiled_without("sqlite")));
+    let history_router = history_router
+        .layer(CompressionLayer::new())
+        .layer(middleware::from_fn(compress_param));
+    router
+        .merge(history_router)
+        .route("/realtime/alerts", get(realtime_alerts_get))
+        .route("/realtime/all", get(realtime_all_get))
+        .route("/alerts", get(alerts_get))
+        .route("/hosts", get(hosts_get))
+        .route("/info", get(info_get))
+        .route("/stats", get(stats_get))
+        // The routes above are the read API.
+        .route_layer(middleware::from_fn_with_state(
+            admin::ReadGate::from(app_state),
+            admin::require_read,
+        ))
+        .route("/ingest", get(ingest_get))
+        .route("/healthz", get(healthz_get))
+        .route("/connections", get(connections_get))
+        .route(
+            "/admin/config",
+            get(admin::config_get).patch(admin::config_patch),
+        )
+        .route(
+            "/admin/share",
+            get(share::shares_get).post(share::share_post),
+        )
+        .route("/admin/share/:id", delete(share::share_delete))
+        .route("/processes/:pid/signal", post(admin::process_signal_post))
+        .route(
+            "/processes/:pid/priority",
+            post(admin::process_priority_post),
+        )
+        .with_state(app_state.clone())
+        .layer(request_log(&config.access_log))
+}
+
+/// Serves the router on every configured address until all listeners stop,
+/// or until a signal, after which they are given [`SHUTDOWN_GRACE`] to
+/// finish.
+async fn serve(
+    router: Router,
+    config: &Config,
+    shutdown: &Arc<shutdown::Shutdown>,
+) -> Result<(), StartupError> {
+    let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+    #[cfg(feature = "tls")]
+    let tls = match config.tls.paths() {
+        Some((cert, key)) => Some(
+            axum_server::tls_rustls::RustlsConfig::from_pem_file(cert, key)
+                .await
+                .map_err(|err| {
+                    StartupError::Config(format!("cannot load the TLS certificate: {err}"))
+                })?,
+        ),
+        None => None,
+    };
+    let mut servers = JoinSet::new();
+    let mut bound = vec![];
+
+    for &addr in &config.bind {
+        let listener = match bind(addr, config).await {
+            Ok(listener) => listener,
+            Err(err) if config.bind_failure == BindFailure::Continue => {
+                eprintln!("{err}");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let local_addr = listener
+            .local_addr()
+            .map_err(|err| StartupError::Bind { addr, err })?;
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &tls {
+            let handle = axum_server::Handle::new();
+            let server = axum_server::from_tcp_rustls(listener, tls.clone()).handle(handle.clone());
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                shutdown.requested().await;
+                handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+            });
+            println!("Listening on {local_addr} (TLS)");
+            bound.push(local_addr);
+            servers.spawn(server.serve(make_service.clone()));
+            continue;
+        }
+        let server = Server::from_tcp(listener)
+            .map_err(|err| StartupError::Bind {
+                addr,
+                err: std::io::Error::other(err),
+            })?
+            .serve(make_service.clone())
+            .with_graceful_shutdown({
+                let shutdown = shutdown.clone();
+                async move { shutdown.requested().await }
+            });
+        println!("Listening on {local_addr}");
+        bound.push(local_addr);
+        servers.spawn(async move { server.await.map_err(std::io::Error::other) });
+    }
+
+    if servers.is_empty() {
+        return Err(StartupError::NothingBound);
+    }
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            tracing::info!("shutting down");
+            shutdown.begin();
+        }
+    });
+
+    #[cfg(feature = "mdns")]
+    let advertisement = mdns::Advertisement::start(&config.mdns, &bound).await;
+    #[cfg(not(feature = "mdns"))]
+    drop(bound);
+
+    while let Some(result) = servers.join_next().await {
+        if let Ok(Err(err)) = result {
+            tracing::error!(%err, "listener failed");
+        }
+    }
+    // WebSockets are no longer the listeners' once upgraded, so they are
+    // waited for apart.
+    shutdown.begin();
+    if !shutdown.drained(SHUTDOWN_GRACE).await {
+        tracing::warn!("sessions still open after the shutdown grace period");
+    }
+    #[cfg(feature = "mdns")]
+    if let Some(advertisement) = advertisement {
+        advertisement.withdraw().await;
+    }
+    Ok(())
+}
+
+/// Binds `addr` (see [`bind_once`]), retrying with backoff for up to
+/// `bind_retry` while the address is in use or not available yet.
+async fn bind(addr: SocketAddr, config: &Config) -> Result<TcpListener, StartupError> {
+    let deadline = time::Instant::now() + config.bind_retry();
+    let mut backoff = Duration::from_millis(100);
+    let mut attempt = 1;
+    loop {
+        match bind_once(addr, config.port_retry) {
+            Err(StartupError::Bind { ref err, .. })
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::AddrInUse | std::io::ErrorKind::AddrNotAvailable
+                ) && time::Instant::now() + backoff <= deadline =>
+            {
+                tracing::warn!(
+                    %addr,
+                    %err,
+                    attempt,
+                    retry_in_ms = backoff.as_millis() as u64,
+                    "cannot bind, retrying"
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BIND_BACKOFF);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Binds `addr`, or with `port_retry` set and the port taken, the first free
+/// one of the next `port_retry` ports.
+fn bind_once(addr: SocketAddr, port_retry: u16) -> Result<TcpListener, StartupError> {
+    let mut candidate = addr;
+    loop {
+        match listen(candidate) {
+            Ok(listener) => {
+                if candidate != addr {
+                    tracing::info!(requested = %addr, bound = %candidate, "requested port was taken");
+                }
+                return Ok(listener);
+            }
+            Err(err)
+                if err.kind() == std::io::ErrorKind::AddrInUse
+                    && candidate.port() < addr.port().saturating_add(port_retry) =>
+            {
+                tracing::debug!(addr = %candidate, "port taken, trying the next one");
+                candidate.set_port(candidate.port() + 1);
+            }
+            // Report the address that was asked for, not the last one tried.
+            Err(err) => return Err(StartupError::Bind { addr, err }),
+        }
+    }
+}
+
+fn listen(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // Lets a restarted server bind while connections of the previous one
+    // linger in TIME_WAIT. On Windows the option would let two servers share
+    // a port, so it is left off there.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)?.into_std()
+}
+
+/// Logs every request with its status and latency, at the info level with
+/// the access log enabled and at the debug level otherwise.
+fn request_log(
+    config: &config::AccessLogConfig,
+) -> TraceLayer<
+    SharedClassifier<ServerErrorsAsFailures>,
+    RequestSpan,
+    DefaultOnRequest,
+    DefaultOnResponse,
+> {
+    let level = if config.enabled {
+        tracing::Level::INFO
+    } else {
+        tracing::Level::DEBUG
+    };
+    TraceLayer::new_for_http()
+        .make_span_with(RequestSpan { level })
+        .on_request(DefaultOnRequest::new().level(tracing::Level::DEBUG))
+        .on_response(
+            DefaultOnResponse::new()
+                .level(level)
+                .latency_unit(LatencyUnit::Millis),
+        )
+}
+
+/// The span of a request, as `DefaultMakeSpan` makes it but with the read
+/// token a browser passes in the query blanked out.
+#[derive(Clone)]
+struct RequestSpan {
+    level: tracing::Level,
+}
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> tracing::Span {
+        let uri = admin::redact_token(request.uri());
+        macro_rules! span {
+            ($level:expr) => {
+                tracing::span!(
+                    $level,
+                    "request",
+                    method = %request.method(),
+                    uri = %uri,
+                    version = ?request.version(),
+                )
+            };
+        }
+        match self.level {
+            tracing::Level::INFO => span!(tracing::Level::INFO),
+            _ => span!(tracing::Level::DEBUG),
+        }
+    }
+}
+
+/// The `compress` query parameter, for clients that cannot set
+/// `Accept-Encoding`.
+#[derive(serde::Deserialize, Debug)]
+struct CompressParams {
+    compress: Option<String>,
+}
+
+/// Makes `?compress=zstd` or `gzip` ask for that encoding in place of the
+/// `Accept-Encoding` header, for the `CompressionLayer` it runs before.
+async fn compress_param<B>(
+    Query(params): Query<CompressParams>,
+    mut request: Request<B>,
+    next: middleware::Next<B>,
+) -> Response {
+    if let Some(compress) = params.compress {
+        if !matches!(compress.as_str(), "zstd" | "gzip" | "identity") {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("compress must be zstd, gzip or identity, got {compress:?}"),
+            );
+        }
+        let value = axum::http::HeaderValue::from_str(&compress).expect("a valid header value");
+        request
+            .headers_mut()
+            .insert(axum::http::header::ACCEPT_ENCODING, value);
+    }
+    next.run(request).await
+}
+
+pub(crate) fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    let message: String = message.into();
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+/// Answers plain HTTP requests to a WebSocket endpoint, which people tend
+/// to send while exploring the API with curl.
+fn upgrade_required(endpoint: &str) -> Response {
+    let mut response = error_response(
+        StatusCode::UPGRADE_REQUIRED,
+        format!("{endpoint} is a WebSocket endpoint, connect with a WebSocket client"),
+    );
+    response.headers_mut().insert(
+        axum::http::header::UPGRADE,
+        axum::http::HeaderValue::from_static("websocket"),
+    );
+    response
+}
+
+/// Stands in for the routes of collectors that were compiled out, so
+/// clients get an explanation instead of a bare 404.
+#[allow(dead_code)]
+async fn compiled_without(feature: &'static str) -> impl IntoResponse {
+    error_response(
+        StatusCode::NOT_FOUND,
+        format!("compiled without '{feature}'"),
+    )
+}
+
+/// A stream of samples, with what [`serve_stream`] needs to serve it.
+struct RealtimeStream {
+    endpoint: &'static str,
+    broadcast: Arc<sampler::Broadcast>,
+    stats: fn(&Stats) -> &stats::ChannelStats,
+    /// The interval the stream is sampled at.
+    period: Duration,
+    /// The stream to subscribe to on a hub remote, for streams that are
+    /// relayed.
+    relayed: Option<config::Stream>,
+}
+
+/// Serves `stream` to a WebSocket client, converting samples of `T` as
+/// `params` asks.
+#[cfg_attr(
+    not(any(
+        feature = "cpu",
+        feature = "mem",
+        feature = "processes",
+        feature = "power",
+        feature = "disks",
+        feature = "network",
+        feature = "gpu",
+        feature = "sensors",
+        feature = "system"
+    )),
+    allow(dead_code)
+)]
+fn serve_stream<T: types::Payload + serde::de::DeserializeOwned>(
+    ws: Option<ws::Upgrade>,
+    peer: SocketAddr,
+    params: &StreamParams,
+    state: AppState,
+    stream: RealtimeStream,
+) -> Response {
+    let caps = Caps {
+        period: Some(stream.period),
+        host: stream.relayed.is_some(),
+        since_seq: true,
+        convert: true,
+        encoding: true,
+        delta: true,
+    };
+    let options = match params.validate(stream.endpoint, caps) {
+        Ok(options) => options,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    let host_rx = match stream.relayed {
+        Some(relayed) => match state.subscribe_host(params, relayed) {
+            Ok(rx) => rx,
+            Err((status, err)) => return error_response(status, err),
+        },
+        None => None,
+    };
+    let Some(ws) = ws else {
+        return upgrade_required(stream.endpoint);
+    };
+    ws.on_upgrade(move |ws: WebSocket| async move {
+        let format = options.format;
+        let mut conn = Connection::new(
+            &state.stats,
+            &state.access_log,
+            &state.shutdown,
+            stream.endpoint,
+            peer,
+            options,
+        );
+        let (rx, stats) = match host_rx {
+            Some(rx) => (rx, &state.stats.hosts),
+            None => (
+                conn.subscribe::<T>(&stream.broadcast),
+                (stream.stats)(&state.stats),
+            ),
+        };
+        if format.is_raw() {
+            stream_channel(conn, rx, stats, state.websocket, ws).await
+        } else {
+            let encode = |frame: &Frame, protocol| ws::reformat::<T>(frame, protocol, format);
+            ws::stream_with(conn, rx, encode, stats, state.websocket, ws).await
+        }
+    })
+    .into_response()
+}
+
+#[cfg(feature = "cpu")]
+#[axum::debug_handler]
+async fn realtime_cpus_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/cpus",
+        broadcast: state.cpus_broadcast.clone(),
+        stats: |stats| &stats.cpus,
+        period: state.sampler_config.borrow().period(config::Stream::Cpus),
+        relayed: Some(config::Stream::Cpus),
+    };
+    serve_stream::<types::CpuState>(ws, peer, &params, state, stream)
+}
+
+/// RAPL power per domain, sampled along with the CPUs.
+#[cfg(feature = "power")]
+#[axum::debug_handler]
+async fn realtime_power_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/power",
+        broadcast: state.power_broadcast.clone(),
+        stats: |stats| &stats.power,
+        period: state.sampler_config.borrow().period(config::Stream::Cpus),
+        relayed: None,
+    };
+    serve_stream::<types::PowerState>(ws, peer, &params, state, stream)
+}
+
+/// Space and I/O of every mounted filesystem, on its own interval.
+#[cfg(feature = "disks")]
+#[axum::debug_handler]
+async fn realtime_disks_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/disks",
+        broadcast: state.disks_broadcast.clone(),
+        stats: |stats| &stats.disks,
+        period: state.sampler_config.borrow().disk_period(),
+        relayed: None,
+    };
+    serve_stream::<types::DiskState>(ws, peer, &params, state, stream)
+}
+
+/// Throughput of every network interface, on its own interval.
+#[cfg(feature = "network")]
+#[axum::debug_handler]
+async fn realtime_network_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/network",
+        broadcast: state.network_broadcast.clone(),
+        stats: |stats| &stats.network,
+        period: state.sampler_config.borrow().network_period(),
+        relayed: None,
+    };
+    serve_stream::<types::NetState>(ws, peer, &params, state, stream)
+}
+
+/// Utilization, memory, temperature and power of every GPU, on its own
+/// interval.
+#[cfg(feature = "gpu")]
+#[axum::debug_handler]
+async fn realtime_gpu_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/gpu",
+        broadcast: state.gpu_broadcast.clone(),
+        stats: |stats| &stats.gpu,
+        period: state.sampler_config.borrow().gpu_period(),
+        relayed: None,
+    };
+    serve_stream::<types::GpuState>(ws, peer, &params, state, stream)
+}
+
+/// Every temperature, fan and voltage sensor, on its own interval.
+#[cfg(feature = "sensors")]
+#[axum::debug_handler]
+async fn realtime_sensors_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/sensors",
+        broadcast: state.sensors_broadcast.clone(),
+        stats: |stats| &stats.sensors,
+        period: state.sampler_config.borrow().sensor_period(),
+        relayed: None,
+    };
+    serve_stream::<types::SensorState>(ws, peer, &params, state, stream)
+}
+
+/// The load averages and the uptime, on their own interval.
+#[cfg(feature = "system")]
+#[axum::debug_handler]
+async fn realtime_system_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/system",
+        broadcast: state.system_broadcast.clone(),
+        stats: |stats| &stats.system,
+        period: state.sampler_config.borrow().system_period(),
+        relayed: None,
+    };
+    serve_stream::<types::SystemState>(ws, peer, &params, state, stream)
+}
+
+#[cfg(feature = "mem")]
+#[axum::debug_handler]
+async fn realtime_ram_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let stream = RealtimeStream {
+        endpoint: "/realtime/ram",
+        broadcast: state.ram_broadcast.clone(),
+        stats: |stats| &stats.ram,
+        period: state.sampler_config.borrow().period(config::Stream::Ram),
+        relayed: Some(config::Stream::Ram),
+    };
+    serve_stream::<types::MemState>(ws, peer, &params, state, stream)
+}
+
+#[cfg(feature = "processes")]
+#[derive(serde::Deserialize, Debug)]
+struct ProcessStreamParams {
+    /// Only send these processes, see [`collectors::processes::NameFilter`].
+    filter: Option<String>,
+    /// How many processes to send instead of `top_processes`, at most
+    /// `max_top_processes`.
+    top: Option<usize>,
+    /// What to pick them by instead of `top_processes_by`.
+    sort: Option<config::TopBy>,
+}
+
+#[cfg(feature = "processes")]
+#[axum::debug_handler]
+async fn realtime_process_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    Query(process_params): Query<ProcessStreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let filter = match process_params
+        .filter
+        .as_deref()
+        .map(collectors::processes::NameFilter::parse)
+        .transpose()
+    {
+        Ok(filter) => filter,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    let top = process_params
+        .top
+        .map(|top| top.min(state.sampler_config.borrow().max_top_processes));
+    let sort = process_params.sort;
+    let picks = filter.is_some() || top.is_some() || sort.is_some();
+    let period = state
+        .sampler_config
+        .borrow()
+        .period(config::Stream::Processes);
+    if !picks {
+        let stream = RealtimeStream {
+            endpoint: "/realtime/processes",
+            broadcast: state.process_broadcast.clone(),
+            stats: |stats| &stats.processes,
+            period,
+            relayed: Some(config::Stream::Processes),
+        };
+        return serve_stream::<Vec<types::ProcessInfo>>(ws, peer, &params, state, stream);
+    }
+    let caps = Caps {
+        period: Some(period),
+        host: true,
+        since_seq: true,
+        convert: true,
+        encoding: true,
+        delta: true,
+    };
+    let options = match params.validate("/realtime/processes", caps) {
+        Ok(options) => options,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    if options.since_seq.is_some() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "filter, top and sort cannot be used with since_seq, only the top processes are retained",
+        );
+    }
+    if options.host.is_some() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "filter, top and sort cannot be used with host, remotes only send their top processes",
+        );
+    }
+    let options = SessionOptions {
+        filter: process_params.filter,
+        top,
+        sort,
+        ..options
+    };
+    let Some(ws) = ws else {
+        return upgrade_required("/realtime/processes");
+    };
+    // Picks from every process rather than the top ones, and sends every
+    // sample, even when nothing matches.
+    ws.on_upgrade(move |ws: WebSocket| async move {
+        let rx = state.process_list.subscribe();
+        let format = options.format;
+        let conn = Connection::new(
+            &state.stats,
+            &state.access_log,
+            &state.shutdown,
+            "/realtime/processes",
+            peer,
+            options,
+        );
+        let encode = |sample: &Arc<types::Sample<Vec<types::ProcessInfo>>>,
+                      protocol: ws::Protocol| {
+            let mut picked: Vec<_> = sample
+                .data
+                .iter()
+                .filter(|process| {
+                    filter
+                        .as_ref()
+                        .is_none_or(|filter| filter.matches(&process.name))
+                })
+                .cloned()
+                .collect();
+            if let Some(sort) = sort {
+                collectors::processes::sort(&mut picked, sort);
+            }
+            picked.truncate(top.unwrap_or(usize::MAX));
+            types::Payload::apply_format(&mut picked, &format);
+            let picked = types::Sample {
+                seq: sample.seq,
+                timestamp_ms: sample.timestamp_ms,
+                host: None,
+                top,
+                format: (!format.is_raw()).then_some(format),
+                instance: sample.instance.clone(),
+                data: &picked,
+                replayed: false,
+            };
+            protocol
+                .encode(&picked)
+                .inspect_err(|err| {
+                    tracing::error!(
+                        seq = sample.seq,
+                        %err,
+                        "cannot serialize sample, skipping it"
+                    )
+                })
+                .ok()
+        };
+        ws::stream_with(
+            conn,
+            rx,
+            encode,
+            &state.stats.processes,
+            state.websocket,
+            ws,
+        )
+        .await
+    })
+    .into_response()
+}
+
+#[axum::debug_handler]
+async fn realtime_alerts_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let caps = Caps {
+        since_seq: true,
+        encoding: true,
+        ..Caps::default()
+    };
+    let options = match params.validate("/realtime/alerts", caps) {
+        Ok(options) => options,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    let Some(ws) = ws else {
+        return upgrade_required("/realtime/alerts");
+    };
+    ws.on_upgrade(move |ws: WebSocket| async move {
+        let mut conn = Connection::new(
+            &state.stats,
+            &state.access_log,
+            &state.shutdown,
+            "/realtime/alerts",
+            peer,
+            options,
+        );
+        let rx = conn.subscribe::<alerts::AlertEvent>(&state.alerts_broadcast);
+        stream_channel(conn, rx, &state.stats.alerts, state.websocket, ws).await
+    })
+    .into_response()
+}
+
+/// Several streams over one socket, see [`multiplex`].
+#[axum::debug_handler]
+async fn realtime_all_get(
+    ws: Option<ws::Upgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    Query(all_params): Query<multiplex::AllParams>,
+    State(state): State<AppState>,
+) -> Response {
+    if params.host().is_some() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "host is not supported on /realtime/all, connect to the remote's own streams",
+        );
+    }
+    if params.has_interval() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "interval is per topic on /realtime/all, e.g. cpus_interval",
+        );
+    }
+    let caps = Caps {
+        convert: true,
+        encoding: true,
+        ..Caps::default()
+    };
+    let options = match params.validate("/realtime/all", caps) {
+        Ok(options) => options,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    let topics = match all_params.topics() {
+        Ok(topics) => topics,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    let intervals = match all_params.intervals(&state.sampler_config.borrow()) {
+        Ok(intervals) => intervals,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    let Some(ws) = ws else {
+        return upgrade_required("/realtime/all");
+    };
+    ws.on_upgrade(move |ws: WebSocket| async move {
+        let mut subscriptions = multiplex::Subscriptions::new(
+            state.topics.clone(),
+            state.stats.clone(),
+            options.protocol,
+            options.format,
+            options.encoding,
+            intervals,
+        );
+        for topic in &topics {
+            subscriptions.subscribe(*topic);
+        }
+        let options = SessionOptions {
+            topics: Some(topics),
+            ..options
+        };
+        let conn = Connection::new(
+            &state.stats,
+            &state.access_log,
+            &state.shutdown,
+            "/realtime/all",
+            peer,
+            options,
+        );
+        ws::stream_topics(conn, subscriptions, state.websocket, ws).await
+    })
+    .into_response()
+}
+
+/// The rules that are firing, each with the event it fired with.
+#[axum::debug_handler]
+async fn alerts_get(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.alerts_firing.borrow().clone())
+}
+
+/// The configured hub remotes and the state of their connections.
+#[axum::debug_handler]
+async fn hosts_get(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.hub.report())
+}
+
+/// The hostname, OS, CPU and memory of the machine, and the server's
+/// version.
+#[axum::debug_handler]
+async fn info_get(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.info.clone())
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct IngestParams {
+    /// The host to re-broadcast the pushed samples as.
+    name: String,
+}
+
+/// Takes the samples an agent pushes, see [`hub::ingest`].
+#[axum::debug_handler]
+async fn ingest_get(
+    _: admin::IngestAuth,
+    ws: Option<WebSocketUpgrade>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Query(params): Query<IngestParams>,
+    State(state): State<AppState>,
+) -> Response {
+    if !hub::is_host_name(&params.name) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "name may only contain letters, digits, '-', '_' and '.'",
+        );
+    }
+    let Some(ws) = ws else {
+        return upgrade_required("/ingest");
+    };
+    let guard = match state.hub.start_push(&params.name) {
+        Ok(guard) => guard,
+        Err(hub::PushError::Remote) => {
+            return error_response(
+                StatusCode::CONFLICT,
+                format!("host {:?} is a configured remote", params.name),
+            )
+        }
+        Err(hub::PushError::Pushing) => {
+            return error_response(
+                StatusCode::CONFLICT,
+                format!("an agent is already pushing as {:?}", params.name),
+            )
+        }
+    };
+    ws.on_upgrade(move |ws: WebSocket| async move {
+        tracing::info!(host = params.name, %peer, "agent connected");
+        let idle_timeout = state.websocket.ping_interval() + state.websocket.pong_timeout();
+        hub::ingest(
+            state.hub.clone(),
+            guard,
+            state.stats.clone(),
+            idle_timeout,
+            ws,
+        )
+        .await;
+        tracing::info!(host = params.name, %peer, "agent disconnected");
+    })
+    .into_response()
+}
+
+#[axum::debug_handler]
+async fn stats_get(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.stats.report())
+}
+
+#[cfg(feature = "mdns")]
+#[derive(serde::Deserialize, Debug)]
+struct DiscoverParams {
+    /// How long to wait for answers, at most 10 s.
+    #[serde(default = "default_discover_timeout_ms")]
+    timeout_ms: u64,
+}
+
+#[cfg(feature = "mdns")]
+fn default_discover_timeout_ms() -> u64 {
+    1000
+}
+
+/// Browses the local network for other instances over mDNS.
+#[cfg(feature = "mdns")]
+#[axum::debug_handler]
+async fn discover_get(Query(params): Query<DiscoverParams>) -> Response {
+    let wait = Duration::from_millis(params.timeout_ms.min(10_000));
+    match mdns::discover(wait).await {
+        Ok(instances) => Json(instances).into_response(),
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("mDNS browse failed: {err}"),
+        ),
+    }
+}
+
+#[cfg(feature = "processes")]
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ProcessSort {
+    Cpu,
+    Memory,
+    Name,
+}
+
+#[cfg(feature = "processes")]
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[cfg(feature = "processes")]
+#[derive(serde::Deserialize, Debug)]
+struct ProcessPageParams {
+    #[serde(default = "default_process_sort")]
+    sort: ProcessSort,
+    /// Descending for usages and ascending for names by default.
+    order: Option<SortOrder>,
+    #[serde(default)]
+    offset: usize,
+    /// At most 1000.
+    #[serde(default = "default_process_limit")]
+    limit: usize,
+}
+
+#[cfg(feature = "processes")]
+fn default_process_sort() -> ProcessSort {
+    ProcessSort::Cpu
+}
+
+#[cfg(feature = "processes")]
+fn default_process_limit() -> usize {
+    100
+}
+
+#[cfg(feature = "processes")]
+#[derive(serde::Serialize, Debug)]
+struct ProcessPage<'a> {
+    /// When the table was taken; pages with different timestamps come from
+    /// different refreshes.
+    timestamp_ms: u64,
+    instance: Arc<types::Instance>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+    processes: Vec<&'a types::ProcessInfo>,
+}
+
+/// A page of every process as of the last refresh. Ties are broken by pid,
+/// so that pages of one table never overlap.
+#[cfg(feature = "processes")]
+#[axum::debug_handler]
+async fn processes_get(
+    State(state): State<AppState>,
+    Query(params): Query<ProcessPageParams>,
+) -> Response {
+    let Some(table) = state.process_table.latest() else {
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the process table is being taken, retry in a moment",
+        );
+    };
+    let order = params.order.unwrap_or(match params.sort {
+        ProcessSort::Name => SortOrder::Asc,
+        ProcessSort::Cpu | ProcessSort::Memory => SortOrder::Desc,
+    });
+    let mut processes: Vec<_> = table.processes.iter().collect();
+    processes.sort_unstable_by(|a, b| {
+        let by = match params.sort {
+            ProcessSort::Cpu => a.cpu_usage.total_cmp(&b.cpu_usage),
+            ProcessSort::Memory => a.memory.cmp(&b.memory),
+            ProcessSort::Name => a.name.cmp(&b.name),
+        };
+        let by = match order {
+            SortOrder::Asc => by,
+            SortOrder::Desc => by.reverse(),
+        };
+        by.then(a.pid.cmp(&b.pid))
+    });
+    let limit = params.limit.min(1000);
+    let total = processes.len();
+    let page = processes
+        .into_iter()
+        .skip(params.offset)
+        .take(limit)
+        .collect();
+    Json(ProcessPage {
+        timestamp_ms: table.timestamp_ms,
+        instance: state.instance.borrow().clone(),
+        total,
+        offset: params.offset,
+        limit,
+        processes: page,
+    })
+    .into_response()
+}
+
+#[cfg(feature = "processes")]
+#[derive(serde::Deserialize, Debug)]
+struct ProcessParams {
+    /// Include the environment, which needs the admin token.
+    #[serde(default)]
+    env: bool,
+}
+
+/// Everything about one process, refreshed for this request.
+#[cfg(feature = "processes")]
+#[axum::debug_handler]
+async fn process_get(
+    State(state): State<AppState>,
+    axum::extract::Path(pid): axum::extract::Path<u32>,
+    Query(params): Query<ProcessParams>,
+    headers: axum::http::HeaderMap,
+) -> Response {
+    if params.env {
+        if let Err((status, err)) = state.tokens.authorize_admin(
+            &headers,
+            "env=true needs the admin API, set admin_token, AXACT_ADMIN_TOKEN or an admin token in tokens to enable it",
+        ) {
+            return error_response(status, err);
+        }
+    }
+    let config = state.sampler_config.borrow().clone();
+    match tokio::task::spawn_blocking(move || {
+        collectors::processes::detail(pid, params.env, &config)
+    })
+    .await
+    {
+        Ok(Some(detail)) => Json(detail).into_response(),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, format!("no process {pid}")),
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("cannot read process {pid}: {err}"),
+        ),
+    }
+}
+
+/// Detects the CPU temperature sensors again and shows how every component
+/// was mapped.
+#[cfg(feature = "temps")]
+async fn debug_sensors_get(State(state): State<AppState>) -> Response {
+    let mapping = state.sampler_config.borrow().sensors.clone();
+    match tokio::task::spawn_blocking(move || collectors::sensors::diagnose(&mapping)).await {
+        Ok(report) => Json(report).into_response(),
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("sensor detection failed: {err}"),
+        ),
+    }
+}
+
+/// The open sockets and the processes they belong to. Needs a token even
+/// while the rest of the read API does not, as it tells who the machine
+/// talks to.
+#[axum::debug_handler(state = AppState)]
+async fn connections_get(
+    _: admin::ReadAuth,
+    Query(filter): Query<connections::Filter>,
+    State(state): State<AppState>,
+) -> Response {
+    let config = state.sampler_config.borrow().clone();
+    match tokio::task::spawn_blocking(move || connections::collect(&filter, &config)).await {
+        Ok(Ok(report)) => Json(report).into_response(),
+        Ok(Err(err)) => error_response(StatusCode::INTERNAL_SERVER_ERROR, err),
+        Err(err) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("listing connections failed: {err}"),
+        ),
+    }
+}
+
+/// The latest SMART reading of every disk.
+#[cfg(feature = "smart")]
+#[axum::debug_handler]
+async fn smart_get(State(state): State<AppState>) -> Response {
+    match state.smart.borrow().clone() {
+        Some(report) => Json(report).into_response(),
+        None => error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "the disks have not been read yet",
+        ),
+    }
+}
+
+/// 200 while the sampler is ticking, 503 once it has stalled or keeps
+/// crashing.
+#[axum::debug_handler]
+async fn healthz_get(State(state): State<AppState>) -> impl IntoResponse {
+    let interval = state
+        .sampler_config
+        .borrow()
+        .cpu_interval()
+        .max(state.stats.sampler_interval());
+    let health = state.stats.health(interval * HEALTH_STALE_TICKS);
+    let status = if health.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(health))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Serves the router for `config` on a local port.
+    fn spawn(config: &Config) -> SocketAddr {
+        let (instance, instance_rx) = watch::channel(Arc::new(config.instance()));
+        let channels = sampler::Channels::new(config.channel_capacity, instance_rx.clone());
+        let alerts_publisher = sampler::Publisher::new(config.channel_capacity, instance_rx);
+        let (_, alerts_firing) = watch::channel(vec![]);
+        let (_, smart) = watch::channel(None);
+        let (sampler_config, _) = watch::channel(config.sampler.clone());
+        let state = app_state(
+            config,
+            &channels,
+            &alerts_publisher,
+            alerts_firing,
+            smart,
+            sampler_config,
+            instance,
+        )
+        .unwrap();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(router(&state, config).into_make_service_with_connect_info::<SocketAddr>());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// Sends `request`, returning the status and the JSON body.
+    async fn send(request: Request<hyper::Body>) -> (StatusCode, serde_json::Value) {
+        let response = hyper::Client::new().request(request).await.unwrap();
+        let status = response.status();
+        if status == StatusCode::UPGRADE_REQUIRED {
+            assert_eq!(response.headers()[header::UPGRADE], "websocket");
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> (StatusCode, serde_json::Value) {
+        send(
+            Request::get(format!("http://{addr}{path}"))
+                .body(hyper::Body::empty())
+                .unwrap(),
+        )
+        .await
+    }
+
+    fn upgrade_required(endpoint: &str) -> (StatusCode, serde_json::Value) {
+        let error = format!("{endpoint} is a WebSocket endpoint, connect with a WebSocket client");
+        (StatusCode::UPGRADE_REQUIRED, json!({ "error": error }))
+    }
+
+    #[tokio::test]
+    async fn plain_gets_to_streams_are_told_to_upgrade() {
+        let addr = spawn(&Config::default());
+        #[cfg(feature = "mem")]
+        assert_eq!(
+            get(addr, "/realtime/ram").await,
+            upgrade_required("/realtime/ram")
+        );
+        #[cfg(feature = "processes")]
+        assert_eq!(
+            get(addr, "/realtime/processes").await,
+            upgrade_required("/realtime/processes")
+        );
+        assert_eq!(
+            get(addr, "/realtime/alerts").await,
+            upgrade_required("/realtime/alerts")
+        );
+    }
+
+    #[tokio::test]
+    async fn plain_gets_to_realtime_all_are_told_to_upgrade() {
+        let addr = spawn(&Config::default());
+        assert_eq!(
+            get(addr, "/realtime/all").await,
+            upgrade_required("/realtime/all")
+        );
+        assert_eq!(
+            get(addr, "/realtime/all?topics=alerts").await,
+            upgrade_required("/realtime/all")
+        );
+    }
+
+    /// A share lets the request through the read gate, to the handler.
+    #[tokio::test]
+    async fn plain_gets_with_a_share_are_told_to_upgrade() {
+        let addr = spawn(&Config {
+            admin_token: Some("root".into()),
+            tokens: vec![config::ApiToken {
+                token: "dashboard".into(),
+                role: config::Role::Read,
+            }],
+            share: config::ShareConfig {
+                secret: Some("0123456789abcdef".into()),
+                ..config::ShareConfig::default()
+            },
+            ..Config::default()
+        });
+        let (status, _) = get(addr, "/realtime/all").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, share) = send(
+            Request::post(format!("http://{addr}/admin/share"))
+                .header(header::AUTHORIZATION, "Bearer root")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(r#"{"path":"/realtime/all"}"#.into())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            get(addr, share["url"].as_str().unwrap()).await,
+            upgrade_required("/realtime/all")
+        );
+    }
+}
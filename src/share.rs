@@ -0,0 +1,439 @@
+//! Share URLs: read-only links to a single stream, such as
+//! `/realtime/cpus?expires=1700000000&sig=…`, for embedding a live view
+//! without handing out a token. The signature is an HMAC-SHA256 of the path
+//! and expiry under `share.secret`, so any instance with the same secret
+//! accepts it until it expires; no state is needed to check one.
+//!
+//! Minted shares are listed, and revoked, through the admin API. Both lists
+//! are kept in memory only: after a restart, shares minted before stay
+//! valid but are no longer listed and cannot be revoked one by one.
+//! Changing the secret revokes all of them.
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    admin::AdminAuth,
+    config::ShareConfig,
+    server::{error_response, AppState},
+};
+
+/// The endpoints a share can be for: the read-only streams, over WebSocket
+/// and server-sent events. Those of collectors that were compiled out are
+/// listed too, they answer 404.
+const SHAREABLE: [&str; 20] = [
+    "/realtime/cpus",
+    "/realtime/ram",
+    "/realtime/processes",
+    "/realtime/power",
+    "/realtime/disks",
+    "/realtime/network",
+    "/realtime/gpu",
+    "/realtime/sensors",
+    "/realtime/system",
+    "/realtime/alerts",
+    "/realtime/all",
+    "/sse/cpus",
+    "/sse/ram",
+    "/sse/processes",
+    "/sse/power",
+    "/sse/disks",
+    "/sse/network",
+    "/sse/gpu",
+    "/sse/sensors",
+    "/sse/system",
+];
+
+/// How long a share lasts when the request does not say.
+const DEFAULT_TTL_MINS: u64 = 60;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Share {
+    /// The start of the signature, which identifies it.
+    pub id: String,
+    pub path: String,
+    /// The path with the signature, to append to the server's address.
+    pub url: String,
+    pub label: Option<String>,
+    /// In seconds since the Unix epoch.
+    pub created: u64,
+    pub expires: u64,
+}
+
+/// The `POST /admin/share` body.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ShareRequest {
+    path: String,
+    ttl_mins: Option<u64>,
+    /// Shown in the list, e.g. where the share is embedded.
+    label: Option<String>,
+}
+
+/// The secrets shares are signed with, and the shares minted and revoked
+/// since startup.
+pub struct Shares(Mutex<Registry>);
+
+struct Registry {
+    secret: Option<Box<str>>,
+    /// Accepted as well until the given time, in seconds since the epoch.
+    previous: Option<(Box<str>, u64)>,
+    grace: Duration,
+    max_ttl: Duration,
+    minted: BTreeMap<String, Share>,
+    /// Until they expire, by id.
+    revoked: BTreeMap<String, u64>,
+}
+
+impl Shares {
+    pub fn new(config: &ShareConfig) -> Self {
+        let shares = Self(Mutex::new(Registry {
+            secret: None,
+            previous: None,
+            grace: Duration::ZERO,
+            max_ttl: Duration::ZERO,
+            minted: BTreeMap::new(),
+            revoked: BTreeMap::new(),
+        }));
+        shares.reload(config, unix_secs());
+        shares
+    }
+
+    /// Applies a reloaded config. A secret that is replaced is still
+    /// accepted for `grace_mins`, unless the config names a previous one.
+    pub fn reload(&self, config: &ShareConfig, now: u64) {
+        let mut state = self.0.lock().unwrap();
+        let secret = config
+            .secret
+            .clone()
+            .or_else(|| std::env::var("AXACT_SHARE_SECRET").ok())
+            .map(Into::<Box<str>>::into);
+        state.grace = Duration::from_secs(config.grace_mins * 60);
+        state.max_ttl = Duration::from_secs(config.max_ttl_mins * 60);
+        let until = now + state.grace.as_secs();
+        match &config.previous_secret {
+            Some(previous)
+                if state
+                    .previous
+                    .as_ref()
+                    .is_none_or(|(current, _)| **current != **previous) =>
+            {
+                state.previous = Some((previous.as_str().into(), until));
+            }
+            Some(_) => {}
+            None if state.secret.is_some() && state.secret != secret => {
+                state.previous = state.secret.take().map(|old| (old, until));
+                tracing::info!(grace_mins = config.grace_mins, "share secret rotated");
+            }
+            None => {}
+        }
+        state.secret = secret;
+    }
+
+    /// Signs a share of `path` for `ttl`.
+    fn mint(
+        &self,
+        path: String,
+        ttl: Option<Duration>,
+        label: Option<String>,
+        now: u64,
+    ) -> Result<Share, (StatusCode, String)> {
+        let mut state = self.0.lock().unwrap();
+        let Some(secret) = &state.secret else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "sharing is disabled, set share.secret or AXACT_SHARE_SECRET to enable it".into(),
+            ));
+        };
+        if !SHAREABLE.contains(&path.as_str()) {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("only {} can be shared", SHAREABLE.join(", ")),
+            ));
+        }
+        let ttl = ttl.unwrap_or(Duration::from_secs(DEFAULT_TTL_MINS * 60));
+        if ttl.is_zero() || ttl > state.max_ttl {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!(
+                    "ttl_mins must be between 1 and {}",
+                    state.max_ttl.as_secs() / 60
+                ),
+            ));
+        }
+        let expires = now + ttl.as_secs();
+        let sig = sign(secret, &path, expires);
+        let share = Share {
+            id: id_of(&sig).to_string(),
+            url: format!("{path}?expires={expires}&sig={sig}"),
+            path,
+            label,
+            created: now,
+            expires,
+        };
+        state.minted.insert(share.id.clone(), share.clone());
+        Ok(share)
+    }
+
+    /// The shares minted since startup that are still valid, soonest to
+    /// expire first.
+    fn list(&self, now: u64) -> Vec<Share> {
+        let mut state = self.0.lock().unwrap();
+        state.prune(now);
+        let mut shares: Vec<_> = state.minted.values().cloned().collect();
+        shares.sort_by_key(|share| share.expires);
+        shares
+    }
+
+    /// Revokes the share with this id, if it was minted and is still valid.
+    fn revoke(&self, id: &str, now: u64) -> Option<Share> {
+        let mut state = self.0.lock().unwrap();
+        state.prune(now);
+        let share = state.minted.remove(id)?;
+        state.revoked.insert(share.id.clone(), share.expires);
+        Some(share)
+    }
+
+    /// Checks the share signature in `uri`, `None` if it has none. Its
+    /// parameters are decimal and hex digits, so they need no decoding.
+    pub fn check(&self, uri: &Uri, now: u64) -> Option<Result<(), &'static str>> {
+        let (mut expires, mut sig) = (None, None);
+        for pair in uri.query().unwrap_or_default().split('&') {
+            match pair.split_once('=') {
+                Some(("expires", value)) => expires = Some(value),
+                Some(("sig", value)) => sig = Some(value),
+                _ => {}
+            }
+        }
+        match (expires, sig) {
+            (None, None) => None,
+            (Some(expires), Some(sig)) => Some(match expires.parse() {
+                Ok(expires) => self.verify(uri.path(), expires, sig, now),
+                Err(_) => Err("invalid share signature"),
+            }),
+            _ => Some(Err("a share needs both expires and sig")),
+        }
+    }
+
+    fn verify(&self, path: &str, expires: u64, sig: &str, now: u64) -> Result<(), &'static str> {
+        let state = self.0.lock().unwrap();
+        let valid = |secret: &str| {
+            crate::admin::constant_time_eq(sign(secret, path, expires).as_bytes(), sig.as_bytes())
+        };
+        let signed = state.secret.as_deref().is_some_and(valid)
+            || state
+                .previous
+                .as_ref()
+                .is_some_and(|(secret, until)| now < *until && valid(secret));
+        if !signed || !SHAREABLE.contains(&path) {
+            return Err("invalid share signature");
+        }
+        if now >= expires {
+            return Err("share expired");
+        }
+        if state.revoked.contains_key(id_of(sig)) {
+            return Err("share revoked");
+        }
+        Ok(())
+    }
+}
+
+impl Registry {
+    fn prune(&mut self, now: u64) {
+        self.minted.retain(|_, share| share.expires > now);
+        self.revoked.retain(|_, expires| *expires > now);
+    }
+}
+
+/// The hex HMAC-SHA256 of `path` and `expires`.
+fn sign(secret: &str, path: &str, expires: u64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{path}\n{expires}").as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn id_of(sig: &str) -> &str {
+    sig.get(..16).unwrap_or(sig)
+}
+
+pub fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Mints a share. Needs the admin token.
+#[axum::debug_handler]
+pub async fn share_post(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Json(request): Json<ShareRequest>,
+) -> Response {
+    let ttl = request
+        .ttl_mins
+        .map(|mins| Duration::from_secs(mins.saturating_mul(60)));
+    match state
+        .shares
+        .mint(request.path, ttl, request.label, unix_secs())
+    {
+        Ok(share) => {
+            tracing::info!(
+                id = share.id,
+                path = share.path,
+                expires = share.expires,
+                "share minted"
+            );
+            (StatusCode::CREATED, Json(share)).into_response()
+        }
+        Err((status, err)) => error_response(status, err),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn shares_get(_: AdminAuth, State(state): State<AppState>) -> Json<Vec<Share>> {
+    Json(state.shares.list(unix_secs()))
+}
+
+#[axum::debug_handler]
+pub async fn share_delete(
+    _: AdminAuth,
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.shares.revoke(&id, unix_secs()) {
+        Some(share) => {
+            tracing::info!(id, path = share.path, "share revoked");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => error_response(StatusCode::NOT_FOUND, format!("no share {id:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(secret: &str) -> ShareConfig {
+        ShareConfig {
+            secret: Some(secret.into()),
+            ..ShareConfig::default()
+        }
+    }
+
+    fn uri(url: &str) -> Uri {
+        url.parse().unwrap()
+    }
+
+    #[test]
+    fn shares_expire_and_are_bound_to_their_path() {
+        let shares = Shares::new(&config("0123456789abcdef"));
+        let share = shares
+            .mint("/realtime/cpus".into(), None, None, 1_000)
+            .unwrap();
+        assert_eq!(share.expires, 1_000 + 3_600);
+        assert_eq!(shares.check(&uri(&share.url), 1_000), Some(Ok(())));
+        assert_eq!(
+            shares.check(&uri(&format!("{}&v=2", share.url)), 1_000),
+            Some(Ok(()))
+        );
+        assert_eq!(shares.check(&uri("/realtime/cpus?v=2"), 1_000), None);
+        assert_eq!(
+            shares.check(&uri(&share.url), 4_600),
+            Some(Err("share expired"))
+        );
+        let tampered = share.url.replace("expires=4600", "expires=9600");
+        assert_eq!(
+            shares.check(&uri(&tampered), 1_000),
+            Some(Err("invalid share signature"))
+        );
+        let other_path = share.url.replace("/realtime/cpus", "/realtime/ram");
+        assert_eq!(
+            shares.check(&uri(&other_path), 1_000),
+            Some(Err("invalid share signature"))
+        );
+        assert!(shares
+            .mint("/stats".into(), None, None, 1_000)
+            .is_err_and(|(status, _)| status == StatusCode::UNPROCESSABLE_ENTITY));
+    }
+
+    #[test]
+    fn shares_can_be_revoked() {
+        let shares = Shares::new(&config("0123456789abcdef"));
+        let share = shares
+            .mint("/realtime/ram".into(), None, Some("wiki".into()), 1_000)
+            .unwrap();
+        assert_eq!(shares.list(1_000), vec![share.clone()]);
+        assert_eq!(shares.revoke(&share.id, 1_000), Some(share.clone()));
+        assert_eq!(shares.revoke(&share.id, 1_000), None);
+        assert_eq!(shares.list(1_000), vec![]);
+        assert_eq!(
+            shares.check(&uri(&share.url), 1_000),
+            Some(Err("share revoked"))
+        );
+    }
+
+    #[test]
+    fn previous_secret_is_accepted_during_the_grace_window() {
+        let shares = Shares::new(&config("0123456789abcdef"));
+        let share = shares
+            .mint("/realtime/cpus".into(), None, None, 1_000)
+            .unwrap();
+        shares.reload(&config("fedcba9876543210"), 1_000);
+        assert_eq!(shares.check(&uri(&share.url), 1_000), Some(Ok(())));
+        assert_eq!(
+            shares.check(&uri(&share.url), 1_000 + 3_600 - 1),
+            Some(Ok(()))
+        );
+        let share = shares
+            .mint(
+                "/realtime/cpus".into(),
+                Some(Duration::from_secs(7200)),
+                None,
+                1_000,
+            )
+            .unwrap();
+        shares.reload(&config("0000000000000000"), 2_000);
+        assert_eq!(
+            shares.check(&uri(&share.url), 2_000 + 3_600),
+            Some(Err("invalid share signature"))
+        );
+    }
+
+    #[test]
+    fn every_read_stream_is_shareable() {
+        use crate::multiplex::Topic;
+
+        let shares = Shares::new(&config("0123456789abcdef"));
+        let mut paths = vec!["/realtime/all".to_string()];
+        for topic in Topic::ALL {
+            paths.push(format!("/realtime/{}", topic.name()));
+            if *topic != Topic::Alerts {
+                paths.push(format!("/sse/{}", topic.name()));
+            }
+        }
+        for path in paths {
+            let share = shares.mint(path.clone(), None, None, 1_000);
+            let share = share.unwrap_or_else(|err| panic!("{path}: {err:?}"));
+            assert_eq!(shares.check(&uri(&share.url), 1_000), Some(Ok(())));
+        }
+    }
+}
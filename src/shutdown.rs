@@ -0,0 +1,115 @@
+//! Shutting down on SIGTERM or Ctrl-C: the sampler stops, the listeners stop
+//! accepting and finish their requests, and every WebSocket and SSE session
+//! is closed, WebSockets with a Close frame saying the server is going
+//! away, so that clients can tell a restart from a dropped connection.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::watch, time};
+
+/// Shared by everything that has to stop.
+#[derive(Debug)]
+pub struct Shutdown {
+    requested: watch::Sender<bool>,
+    /// Sessions that have not closed yet.
+    sessions: watch::Sender<usize>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            requested: watch::channel(false).0,
+            sessions: watch::channel(0).0,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Tells everything waiting in [`Self::requested`] to stop.
+    pub fn begin(&self) {
+        self.requested.send_replace(true);
+    }
+
+    /// A receiver that changes once [`Self::begin`] is called, as the
+    /// sampler and the replays take it.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.requested.subscribe()
+    }
+
+    /// Resolves once [`Self::begin`] is called.
+    pub async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        // Cannot fail: `self` holds the sender.
+        let _ = requested.wait_for(|requested| *requested).await;
+    }
+
+    /// Counts a session until the returned guard is dropped.
+    pub fn session(self: &Arc<Self>) -> Session {
+        self.sessions.send_modify(|sessions| *sessions += 1);
+        Session(self.clone())
+    }
+
+    /// Waits up to `grace` for the sessions to close; false if some are
+    /// still open.
+    pub async fn drained(&self, grace: Duration) -> bool {
+        let mut sessions = self.sessions.subscribe();
+        let drained = time::timeout(grace, sessions.wait_for(|sessions| *sessions == 0)).await;
+        drained.is_ok()
+    }
+}
+
+/// An open WebSocket or SSE session.
+#[derive(Debug)]
+pub struct Session(Arc<Shutdown>);
+
+impl Session {
+    /// Resolves once the server starts shutting down.
+    pub async fn ended(&self) {
+        self.0.requested().await
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.0.sessions.send_modify(|sessions| *sessions -= 1);
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("cannot listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sessions_end_on_shutdown_and_are_waited_for() {
+        let shutdown = Shutdown::new();
+        let session = shutdown.session();
+        assert!(!shutdown.drained(Duration::from_millis(10)).await);
+
+        let closed = tokio::spawn(async move {
+            session.ended().await;
+        });
+        shutdown.begin();
+        closed.await.unwrap();
+        assert!(shutdown.drained(Duration::from_millis(10)).await);
+    }
+}
@@ -0,0 +1,346 @@
+//! Synthetic readings for `--simulate`, for frontend development and tests on
+//! machines whose sensors cannot or should not be read. They are published
+//! to the sampler's channels, so that the streams, alerts and pushing
+//! upstream work on top of them unchanged. `/processes/:pid` and
+//! `/debug/sensors` still look at the real system.
+//!
+//! Everything is derived from the seed and the number of ticks, so a given
+//! seed always gives the same samples.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::watch,
+    time::{self, MissedTickBehavior},
+};
+
+#[cfg(any(feature = "cpu", feature = "mem", feature = "processes"))]
+use crate::config::Stream;
+#[cfg(feature = "cpu")]
+use crate::types::{CpuCore, CpuState};
+#[cfg(feature = "mem")]
+use crate::types::{MemMode, MemState, MemUnit};
+#[cfg(feature = "processes")]
+use crate::{
+    collectors::processes::{self, Table},
+    types::ProcessInfo,
+};
+use crate::{config::SamplerConfig, sampler::Channels, stats::Stats};
+
+const CORES: usize = 8;
+#[cfg(feature = "mem")]
+const TOTAL_MEMORY: u64 = 16 << 30;
+/// How long the used memory takes to swing up and back down.
+#[cfg(feature = "mem")]
+const MEMORY_PERIOD: Duration = Duration::from_secs(600);
+
+/// The pid and name of every simulated process; the last one stands for the
+/// server itself.
+const PROCESSES: [(u32, &str); 10] = [
+    (1, "systemd"),
+    (412, "dockerd"),
+    (880, "postgres"),
+    (912, "nginx"),
+    (1377, "node"),
+    (2045, "firefox"),
+    (2210, "code"),
+    (3301, "cargo"),
+    (3302, "rustc"),
+    (4096, "axact"),
+];
+
+/// xorshift64*: not for anything but noise, and reproducible from a seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // A zero state would stay zero.
+        Self((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn unit(&mut self) -> f32 {
+        (self.next() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Roughly normal, with a mean of 0 and a standard deviation of 1.
+    fn noise(&mut self) -> f32 {
+        (0..4).map(|_| self.unit()).sum::<f32>() * 1.732 - 3.464
+    }
+}
+
+/// A value drifting at random and pulled back towards `mean`.
+struct Walk {
+    value: f32,
+    mean: f32,
+    step: f32,
+    max: f32,
+}
+
+impl Walk {
+    fn new(rng: &mut Rng, mean: f32, step: f32, max: f32) -> Self {
+        Self {
+            value: (mean + rng.noise() * step * 3.).clamp(0., max),
+            mean,
+            step,
+            max,
+        }
+    }
+
+    fn advance(&mut self, rng: &mut Rng) -> f32 {
+        self.value += rng.noise() * self.step + (self.mean - self.value) * 0.05;
+        self.value = self.value.clamp(0., self.max);
+        self.value
+    }
+}
+
+struct Core {
+    usage: Walk,
+    /// Follows the usage with some lag, like a heat sink would.
+    temp: f32,
+}
+
+#[cfg_attr(not(feature = "processes"), allow(dead_code))]
+struct Process {
+    pid: u32,
+    name: &'static str,
+    cpu_usage: Walk,
+    /// In MiB.
+    memory: Walk,
+}
+
+struct Simulation {
+    rng: Rng,
+    /// How much simulated time has passed, one interval per tick.
+    elapsed: Duration,
+    cores: Vec<Core>,
+    processes: Vec<Process>,
+}
+
+impl Simulation {
+    fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let cores = (0..CORES)
+            .map(|_| {
+                let mean = 10. + rng.unit() * 40.;
+                Core {
+                    usage: Walk::new(&mut rng, mean, 4., 100.),
+                    temp: 40.,
+                }
+            })
+            .collect();
+        // Similar means, so that the busiest processes trade places.
+        let processes = PROCESSES
+            .iter()
+            .map(|&(pid, name)| {
+                let cpu_mean = 2. + rng.unit() * 20.;
+                let memory_mean = 50. + rng.unit() * 1500.;
+                Process {
+                    pid,
+                    name,
+                    cpu_usage: Walk::new(&mut rng, cpu_mean, 2., 400.),
+                    memory: Walk::new(&mut rng, memory_mean, 10., 8192.),
+                }
+            })
+            .collect();
+        Self {
+            rng,
+            elapsed: Duration::ZERO,
+            cores,
+            processes,
+        }
+    }
+
+    fn advance(&mut self, interval: Duration) {
+        self.elapsed += interval;
+        let rng = &mut self.rng;
+        for core in &mut self.cores {
+            let usage = core.usage.advance(rng);
+            let target = 35. + usage * 0.45 + rng.noise() * 0.5;
+            core.temp += (target - core.temp) * 0.2;
+        }
+        for process in &mut self.processes {
+            process.cpu_usage.advance(rng);
+            process.memory.advance(rng);
+        }
+    }
+
+    #[cfg(feature = "cpu")]
+    fn cpu_state(&self) -> CpuState {
+        let temps = cfg!(feature = "temps");
+        let core_temp = cfg!(feature = "core_temp");
+        CpuState {
+            cores: self
+                .cores
+                .iter()
+                .enumerate()
+                .map(|(id, core)| CpuCore {
+                    id,
+                    name: format!("cpu{id}"),
+                    usage: core.usage.value,
+                    temp: core_temp.then_some(core.temp),
+                })
+                .collect(),
+            temp: temps.then(|| self.cores.iter().map(|core| core.temp).fold(0., f32::max)),
+            core_temp,
+            power_watts: None,
+        }
+    }
+
+    #[cfg(feature = "mem")]
+    fn mem_state(&mut self, mode: MemMode) -> MemState {
+        let phase =
+            self.elapsed.as_secs_f32() / MEMORY_PERIOD.as_secs_f32() * std::f32::consts::TAU;
+        let used_fraction = 0.5 + 0.15 * phase.sin() + self.rng.noise() * 0.005;
+        let total = TOTAL_MEMORY;
+        let available = (total as f32 * (1. - used_fraction)) as u64;
+        // A third of what is available is caches.
+        let free = available / 3 * 2;
+        let used = match mode {
+            MemMode::Available => total - available,
+            MemMode::Strict => total - free,
+        };
+        MemState {
+            total,
+            used,
+            free,
+            available,
+            mode,
+            unit: MemUnit::Bytes,
+            total_mib: total >> 20,
+            used_mib: used >> 20,
+            swap_total: 0,
+            swap_used: 0,
+        }
+    }
+
+    /// Every process `config` reports, busiest by `top_processes_by` first.
+    /// They all started with the simulation and run as nobody in particular.
+    #[cfg(feature = "processes")]
+    fn processes(&self, config: &SamplerConfig) -> Vec<ProcessInfo> {
+        let self_pid = PROCESSES[PROCESSES.len() - 1].0;
+        let mut processes: Vec<_> = self
+            .processes
+            .iter()
+            .filter(|process| config.reports_process(process.name))
+            .filter(|process| !(config.exclude_self && process.pid == self_pid))
+            .map(|process| ProcessInfo {
+                pid: process.pid,
+                name: process.name.to_string(),
+                cpu_usage: process.cpu_usage.value,
+                memory: (process.memory.value as u64) << 20,
+                virtual_memory: (process.memory.value as u64) << 22,
+                user: None,
+                cmd: vec![process.name.to_string()],
+                run_time: self.elapsed.as_secs(),
+                // Every name is different, so every group has one process.
+                instances: config.group_processes.then_some(1),
+                self_process: process.pid == self_pid,
+            })
+            .collect();
+        processes::sort(&mut processes, config.top_processes_by);
+        processes
+    }
+}
+
+/// Publishes simulated samples until `shutdown` changes or its sender is
+/// dropped, on the same schedule as the sampler.
+#[cfg_attr(
+    not(any(feature = "cpu", feature = "mem", feature = "processes")),
+    allow(unused_variables, unused_mut)
+)]
+pub async fn run(
+    mut config_rx: watch::Receiver<SamplerConfig>,
+    mut channels: Channels,
+    stats: Arc<Stats>,
+    mut shutdown: watch::Receiver<bool>,
+    seed: u64,
+) {
+    tracing::info!(seed, "simulating readings, the system is not read");
+    let mut simulation = Simulation::new(seed);
+    let mut config = config_rx.borrow_and_update().clone();
+    let mut ticks = ticker(config.cpu_interval());
+    stats.set_sampler_interval(config.cpu_interval());
+    stats.cpus.set_active(cfg!(feature = "cpu"));
+    stats.ram.set_active(cfg!(feature = "mem"));
+    stats.processes.set_active(cfg!(feature = "processes"));
+    #[cfg(feature = "mem")]
+    let mut next_mem = Duration::ZERO;
+    #[cfg(feature = "processes")]
+    let mut next_processes = Duration::ZERO;
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            changed = config_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                config = config_rx.borrow_and_update().clone();
+                if config.cpu_interval() != ticks.period() {
+                    ticks = ticker(config.cpu_interval());
+                    stats.set_sampler_interval(config.cpu_interval());
+                }
+                continue;
+            }
+            _ = shutdown.changed() => return,
+        }
+
+        simulation.advance(config.cpu_interval());
+        #[cfg(any(feature = "mem", feature = "processes"))]
+        let now = simulation.elapsed;
+
+        #[cfg(feature = "cpu")]
+        channels.cpus.publish(
+            &simulation.cpu_state(),
+            config.max_silence(Stream::Cpus),
+            &stats.cpus,
+        );
+
+        #[cfg(feature = "mem")]
+        if now >= next_mem {
+            next_mem = now + Duration::from_millis(config.mem_interval_ms);
+            channels.ram.publish(
+                &simulation.mem_state(config.mem_mode),
+                config.max_silence(Stream::Ram),
+                &stats.ram,
+            );
+        }
+
+        #[cfg(feature = "processes")]
+        if now >= next_processes {
+            next_processes = now + Duration::from_millis(config.process_interval_ms);
+            let mut processes = simulation.processes(&config);
+            if channels.process_table.wanted() {
+                channels.process_table.publish(Table {
+                    timestamp_ms: crate::sampler::unix_millis(std::time::SystemTime::now()),
+                    processes: processes.clone(),
+                });
+            }
+            if channels.process_list.receiver_count() > 0 {
+                channels.process_list.publish(processes.clone());
+            }
+            processes.truncate(config.top_processes);
+            channels.processes.publish(
+                &processes,
+                config.max_silence(Stream::Processes),
+                &stats.processes,
+            );
+        }
+
+        stats.record_tick();
+    }
+}
+
+fn ticker(period: Duration) -> time::Interval {
+    let mut ticks = time::interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    ticks
+}
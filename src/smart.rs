@@ -0,0 +1,477 @@
+//! Disk health from SMART, with the `smart` feature: every `smart.interval_ms`
+//! each disk is read with `smartctl --json`, which needs root or the disk
+//! group on Linux. Served at `/smart` and readable by alert rules as
+//! `smart.*` metrics.
+#![cfg_attr(not(feature = "smart"), allow(dead_code))]
+
+use serde::{Deserialize, Serialize};
+
+/// The latest reading of every disk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SmartReport {
+    pub timestamp_ms: u64,
+    pub devices: Vec<DiskHealth>,
+    /// Why no disks could be listed, e.g. because smartctl is missing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiskHealth {
+    /// E.g. `/dev/sda` or `/dev/nvme0`.
+    pub device: String,
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub status: DiskStatus,
+}
+
+/// What could be read of a disk. Attributes a disk does not have, e.g. the
+/// NVMe ones of a SATA disk, are `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum DiskStatus {
+    Available {
+        /// The disk's own overall assessment.
+        passed: bool,
+        reallocated_sectors: Option<u64>,
+        power_on_hours: Option<u64>,
+        /// NVMe wear estimate; can exceed 100.
+        percentage_used: Option<u8>,
+        media_errors: Option<u64>,
+        /// When the disk was last read, as later reads skip it while it is
+        /// in standby.
+        read_at_ms: u64,
+    },
+    Unavailable {
+        reason: String,
+    },
+}
+
+impl SmartReport {
+    /// The disks that could be read whose name contains `device`, or all of
+    /// them.
+    pub fn available<'a>(
+        &'a self,
+        device: Option<&'a str>,
+    ) -> impl Iterator<Item = (&'a DiskHealth, SmartValues)> + 'a {
+        self.devices
+            .iter()
+            .filter(move |disk| device.is_none_or(|name| disk.device.contains(name)))
+            .filter_map(|disk| match disk.status {
+                DiskStatus::Available {
+                    passed,
+                    reallocated_sectors,
+                    power_on_hours,
+                    percentage_used,
+                    media_errors,
+                    ..
+                } => Some((
+                    disk,
+                    SmartValues {
+                        passed,
+                        reallocated_sectors,
+                        power_on_hours,
+                        percentage_used,
+                        media_errors,
+                    },
+                )),
+                DiskStatus::Unavailable { .. } => None,
+            })
+    }
+}
+
+/// The readings of an available disk.
+#[derive(Debug, Clone, Copy)]
+pub struct SmartValues {
+    pub passed: bool,
+    pub reallocated_sectors: Option<u64>,
+    pub power_on_hours: Option<u64>,
+    pub percentage_used: Option<u8>,
+    pub media_errors: Option<u64>,
+}
+
+#[cfg(feature = "smart")]
+pub use smartctl::poll;
+
+#[cfg(feature = "smart")]
+mod smartctl {
+    use std::{process::Stdio, time::SystemTime};
+
+    use serde::Deserialize;
+    use tokio::{process::Command, sync::watch, time};
+
+    use super::{DiskHealth, DiskStatus, SmartReport};
+    use crate::{config::SmartConfig, sampler::unix_millis};
+
+    /// The longest a single smartctl run may take; reading a disk that has
+    /// to spin up first can take a while.
+    const SMARTCTL_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+    /// The ATA attribute counting sectors remapped to spares.
+    const REALLOCATED_SECTOR_COUNT: u8 = 5;
+
+    /// smartctl exit status bits: 0 for a command line it could not parse,
+    /// 1 for a device it could not open or that is in a low-power mode, 2
+    /// for a failed SMART command. The higher bits describe the disk.
+    const EXIT_CANNOT_READ: i32 = 0b111;
+
+    /// Reads the disks every `config.interval_ms` into `report`, forever.
+    pub async fn poll(config: SmartConfig, report: watch::Sender<Option<SmartReport>>) {
+        let mut ticker = time::interval(config.interval());
+        ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let previous = report.borrow().clone();
+            let next = read(&config, previous.as_ref()).await;
+            for disk in &next.devices {
+                if let DiskStatus::Available { passed: false, .. } = disk.status {
+                    tracing::warn!(device = disk.device, "disk fails its SMART health check");
+                }
+            }
+            report.send_replace(Some(next));
+        }
+    }
+
+    /// One reading of every disk. Disks in standby keep their reading from
+    /// `previous`.
+    async fn read(config: &SmartConfig, previous: Option<&SmartReport>) -> SmartReport {
+        let timestamp_ms = unix_millis(SystemTime::now());
+        let devices = if config.devices.is_empty() {
+            match scan(config).await {
+                Ok(devices) => devices,
+                Err(err) => {
+                    tracing::warn!(%err, "cannot list disks for SMART");
+                    return SmartReport {
+                        timestamp_ms,
+                        devices: vec![],
+                        error: Some(err),
+                    };
+                }
+            }
+        } else {
+            config
+                .devices
+                .iter()
+                .map(|name| ScannedDevice {
+                    name: name.clone(),
+                    kind: None,
+                })
+                .collect()
+        };
+
+        let mut disks = Vec::with_capacity(devices.len());
+        for device in devices {
+            let earlier = previous.and_then(|report| {
+                report
+                    .devices
+                    .iter()
+                    .find(|disk| disk.device == device.name)
+            });
+            let disk = match smartctl(config, &device).await {
+                Ok(output) => output.health(&device.name, earlier, timestamp_ms),
+                Err(reason) => DiskHealth {
+                    device: device.name.clone(),
+                    model: None,
+                    status: DiskStatus::Unavailable { reason },
+                },
+            };
+            if let DiskStatus::Unavailable { reason } = &disk.status {
+                tracing::debug!(device = disk.device, reason, "cannot read SMART");
+            }
+            disks.push(disk);
+        }
+        SmartReport {
+            timestamp_ms,
+            devices: disks,
+            error: None,
+        }
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Scan {
+        #[serde(default)]
+        devices: Vec<ScannedDevice>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct ScannedDevice {
+        name: String,
+        /// The `-d` type smartctl picked, e.g. `sat` or `nvme`.
+        #[serde(rename = "type")]
+        kind: Option<String>,
+    }
+
+    async fn scan(config: &SmartConfig) -> Result<Vec<ScannedDevice>, String> {
+        let stdout = run(config, &["--scan", "--json"]).await?;
+        serde_json::from_slice::<Scan>(&stdout)
+            .map(|scan| scan.devices)
+            .map_err(|err| format!("cannot parse smartctl --scan output: {err}"))
+    }
+
+    async fn smartctl(config: &SmartConfig, device: &ScannedDevice) -> Result<Output, String> {
+        // `--nocheck=standby` leaves sleeping disks be instead of spinning
+        // them up.
+        let mut args = vec![
+            "--json",
+            "--info",
+            "--health",
+            "--attributes",
+            "--nocheck=standby",
+        ];
+        if let Some(kind) = &device.kind {
+            args.extend(["--device", kind]);
+        }
+        args.push(&device.name);
+        let stdout = run(config, &args).await?;
+        serde_json::from_slice(&stdout)
+            .map_err(|err| format!("cannot parse smartctl output: {err}"))
+    }
+
+    /// Runs smartctl and returns its output, whatever its exit status; its
+    /// JSON says what went wrong.
+    async fn run(config: &SmartConfig, args: &[&str]) -> Result<Vec<u8>, String> {
+        let smartctl = Command::new(&config.smartctl)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .output();
+        match time::timeout(SMARTCTL_TIMEOUT, smartctl).await {
+            Ok(Ok(output)) => Ok(output.stdout),
+            Ok(Err(err)) => Err(format!("cannot run {}: {err}", config.smartctl.display())),
+            Err(_) => Err(format!(
+                "{} did not finish within {} s",
+                config.smartctl.display(),
+                SMARTCTL_TIMEOUT.as_secs()
+            )),
+        }
+    }
+
+    /// What we read of `smartctl --json` output.
+    #[derive(Deserialize, Debug, Default)]
+    #[serde(default)]
+    pub(super) struct Output {
+        smartctl: Run,
+        model_name: Option<String>,
+        smart_status: Option<Status>,
+        power_on_time: Option<PowerOnTime>,
+        ata_smart_attributes: Option<AtaAttributes>,
+        nvme_smart_health_information_log: Option<NvmeLog>,
+    }
+
+    #[derive(Deserialize, Debug, Default)]
+    #[serde(default)]
+    struct Run {
+        exit_status: i32,
+        messages: Vec<RunMessage>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct RunMessage {
+        string: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Status {
+        passed: bool,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct PowerOnTime {
+        hours: u64,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct AtaAttributes {
+        table: Vec<AtaAttribute>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct AtaAttribute {
+        id: u8,
+        raw: RawValue,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct RawValue {
+        value: u64,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct NvmeLog {
+        percentage_used: Option<u8>,
+        media_errors: Option<u64>,
+        power_on_hours: Option<u64>,
+    }
+
+    impl Output {
+        /// The health of `device`; `earlier` is its previous reading, kept
+        /// while the disk is in standby.
+        pub(super) fn health(
+            self,
+            device: &str,
+            earlier: Option<&DiskHealth>,
+            now_ms: u64,
+        ) -> DiskHealth {
+            let reason = self
+                .smartctl
+                .messages
+                .into_iter()
+                .map(|message| message.string)
+                .next();
+            let in_standby = reason
+                .as_deref()
+                .is_some_and(|reason| reason.to_ascii_lowercase().contains("standby"));
+            if in_standby {
+                if let Some(
+                    earlier @ DiskHealth {
+                        status: DiskStatus::Available { .. },
+                        ..
+                    },
+                ) = earlier
+                {
+                    return earlier.clone();
+                }
+            }
+            let status = match self.smart_status {
+                Some(status) if self.smartctl.exit_status & EXIT_CANNOT_READ == 0 => {
+                    let nvme = self.nvme_smart_health_information_log;
+                    DiskStatus::Available {
+                        passed: status.passed,
+                        reallocated_sectors: self.ata_smart_attributes.and_then(|attributes| {
+                            attributes
+                                .table
+                                .iter()
+                                .find(|attribute| attribute.id == REALLOCATED_SECTOR_COUNT)
+                                .map(|attribute| attribute.raw.value)
+                        }),
+                        power_on_hours: self
+                            .power_on_time
+                            .map(|time| time.hours)
+                            .or(nvme.as_ref().and_then(|log| log.power_on_hours)),
+                        percentage_used: nvme.as_ref().and_then(|log| log.percentage_used),
+                        media_errors: nvme.as_ref().and_then(|log| log.media_errors),
+                        read_at_ms: now_ms,
+                    }
+                }
+                _ => DiskStatus::Unavailable {
+                    reason: reason.unwrap_or_else(|| {
+                        format!(
+                            "smartctl exited with status {} without a health assessment",
+                            self.smartctl.exit_status
+                        )
+                    }),
+                },
+            };
+            DiskHealth {
+                device: device.to_string(),
+                model: self.model_name,
+                status,
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "smart"))]
+mod tests {
+    use super::{smartctl::Output, *};
+
+    fn health(json: &str, earlier: Option<&DiskHealth>) -> DiskHealth {
+        let output: Output = serde_json::from_str(json).unwrap();
+        output.health("/dev/sda", earlier, 1000)
+    }
+
+    #[test]
+    fn reads_ata_attributes() {
+        let disk = health(
+            r#"{
+                "smartctl": {"exit_status": 0},
+                "model_name": "WDC WD40EFRX",
+                "smart_status": {"passed": true},
+                "power_on_time": {"hours": 31415},
+                "ata_smart_attributes": {"table": [
+                    {"id": 1, "name": "Raw_Read_Error_Rate", "raw": {"value": 0, "string": "0"}},
+                    {"id": 5, "name": "Reallocated_Sector_Ct", "raw": {"value": 8, "string": "8"}}
+                ]}
+            }"#,
+            None,
+        );
+        assert_eq!(disk.model.as_deref(), Some("WDC WD40EFRX"));
+        assert_eq!(
+            disk.status,
+            DiskStatus::Available {
+                passed: true,
+                reallocated_sectors: Some(8),
+                power_on_hours: Some(31415),
+                percentage_used: None,
+                media_errors: None,
+                read_at_ms: 1000,
+            }
+        );
+    }
+
+    #[test]
+    fn reads_nvme_health_log() {
+        let disk = health(
+            r#"{
+                "smartctl": {"exit_status": 8},
+                "smart_status": {"passed": false},
+                "nvme_smart_health_information_log": {
+                    "percentage_used": 103, "media_errors": 2, "power_on_hours": 900
+                }
+            }"#,
+            None,
+        );
+        assert_eq!(
+            disk.status,
+            DiskStatus::Available {
+                passed: false,
+                reallocated_sectors: None,
+                power_on_hours: Some(900),
+                percentage_used: Some(103),
+                media_errors: Some(2),
+                read_at_ms: 1000,
+            }
+        );
+    }
+
+    #[test]
+    fn unreadable_disks_are_unavailable_with_the_reason() {
+        let disk = health(
+            r#"{"smartctl": {"exit_status": 2, "messages": [
+                {"string": "Smartctl open device: /dev/sda failed: Permission denied", "severity": "error"}
+            ]}}"#,
+            None,
+        );
+        assert_eq!(
+            disk.status,
+            DiskStatus::Unavailable {
+                reason: "Smartctl open device: /dev/sda failed: Permission denied".into()
+            }
+        );
+    }
+
+    #[test]
+    fn disks_in_standby_keep_their_last_reading() {
+        let standby = r#"{"smartctl": {"exit_status": 2, "messages": [
+            {"string": "Device is in STANDBY mode, exit(2)", "severity": "information"}
+        ]}}"#;
+        assert!(matches!(
+            health(standby, None).status,
+            DiskStatus::Unavailable { .. }
+        ));
+        let earlier = DiskHealth {
+            device: "/dev/sda".into(),
+            model: None,
+            status: DiskStatus::Available {
+                passed: true,
+                reallocated_sectors: Some(0),
+                power_on_hours: Some(10),
+                percentage_used: None,
+                media_errors: None,
+                read_at_ms: 500,
+            },
+        };
+        assert_eq!(health(standby, Some(&earlier)), earlier);
+    }
+}
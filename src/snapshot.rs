@@ -0,0 +1,245 @@
+//! `GET /snapshot/*`: the latest sample of a stream as a plain JSON
+//! response, for scripts and integrations that poll instead of holding a
+//! WebSocket open. It takes the stream's `v`, `mem_unit` and `round`.
+//!
+//! While a stream has subscribers its latest sample is current and is
+//! answered from the broadcast's history. Otherwise the request subscribes
+//! itself, which starts the sampler, and waits for the next sample.
+
+use std::time::Duration;
+
+#[cfg(any(
+    feature = "cpu",
+    feature = "mem",
+    feature = "processes",
+    feature = "power",
+    feature = "disks",
+    feature = "network",
+    feature = "gpu",
+    feature = "sensors",
+    feature = "system"
+))]
+use axum::extract::{Query, State};
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::de::DeserializeOwned;
+use tokio::{sync::broadcast::error::RecvError, time::timeout};
+
+#[cfg(any(feature = "cpu", feature = "mem", feature = "processes"))]
+use crate::config;
+#[cfg(any(
+    feature = "cpu",
+    feature = "mem",
+    feature = "processes",
+    feature = "power",
+    feature = "disks",
+    feature = "network",
+    feature = "gpu",
+    feature = "sensors",
+    feature = "system"
+))]
+use crate::server::AppState;
+use crate::{
+    sampler::Broadcast,
+    server::error_response,
+    types::Payload,
+    ws::{self, Caps, Frame, StreamParams},
+};
+
+/// How much longer than a sampling period to wait for a sample of an idle
+/// stream: starting the sampler takes a baseline refresh first.
+const STARTUP: Duration = Duration::from_secs(2);
+
+#[cfg(feature = "cpu")]
+#[axum::debug_handler]
+pub async fn cpus_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().period(config::Stream::Cpus);
+    snapshot::<crate::types::CpuState>("/snapshot/cpus", &state.cpus_broadcast, &params, period)
+        .await
+}
+
+#[cfg(feature = "power")]
+#[axum::debug_handler]
+pub async fn power_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().period(config::Stream::Cpus);
+    snapshot::<crate::types::PowerState>("/snapshot/power", &state.power_broadcast, &params, period)
+        .await
+}
+
+#[cfg(feature = "disks")]
+#[axum::debug_handler]
+pub async fn disks_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().disk_period();
+    snapshot::<crate::types::DiskState>("/snapshot/disks", &state.disks_broadcast, &params, period)
+        .await
+}
+
+#[cfg(feature = "network")]
+#[axum::debug_handler]
+pub async fn network_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().network_period();
+    snapshot::<crate::types::NetState>(
+        "/snapshot/network",
+        &state.network_broadcast,
+        &params,
+        period,
+    )
+    .await
+}
+
+#[cfg(feature = "gpu")]
+#[axum::debug_handler]
+pub async fn gpu_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().gpu_period();
+    snapshot::<crate::types::GpuState>("/snapshot/gpu", &state.gpu_broadcast, &params, period).await
+}
+
+#[cfg(feature = "sensors")]
+#[axum::debug_handler]
+pub async fn sensors_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().sensor_period();
+    snapshot::<crate::types::SensorState>(
+        "/snapshot/sensors",
+        &state.sensors_broadcast,
+        &params,
+        period,
+    )
+    .await
+}
+
+#[cfg(feature = "system")]
+#[axum::debug_handler]
+pub async fn system_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().system_period();
+    snapshot::<crate::types::SystemState>(
+        "/snapshot/system",
+        &state.system_broadcast,
+        &params,
+        period,
+    )
+    .await
+}
+
+#[cfg(feature = "mem")]
+#[axum::debug_handler]
+pub async fn ram_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state.sampler_config.borrow().period(config::Stream::Ram);
+    snapshot::<crate::types::MemState>("/snapshot/ram", &state.ram_broadcast, &params, period).await
+}
+
+#[cfg(feature = "processes")]
+#[axum::debug_handler]
+pub async fn processes_get(
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let period = state
+        .sampler_config
+        .borrow()
+        .period(config::Stream::Processes);
+    snapshot::<Vec<crate::types::ProcessInfo>>(
+        "/snapshot/processes",
+        &state.process_broadcast,
+        &params,
+        period,
+    )
+    .await
+}
+
+#[cfg_attr(
+    not(any(
+        feature = "cpu",
+        feature = "mem",
+        feature = "processes",
+        feature = "power",
+        feature = "disks",
+        feature = "network",
+        feature = "gpu",
+        feature = "sensors",
+        feature = "system"
+    )),
+    allow(dead_code)
+)]
+async fn snapshot<T: Payload + DeserializeOwned>(
+    endpoint: &str,
+    broadcast: &Broadcast,
+    params: &StreamParams,
+    period: Duration,
+) -> Response {
+    let options = match params.validate(
+        endpoint,
+        Caps {
+            convert: true,
+            ..Caps::default()
+        },
+    ) {
+        Ok(options) => options,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    let (protocol, format) = (options.protocol, options.format);
+
+    let Some(frame) = latest(broadcast, period).await else {
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "no sample was taken yet, retry in a moment",
+        );
+    };
+    let text = if format.is_raw() {
+        Some(frame.text(protocol).to_owned())
+    } else {
+        ws::reformat::<T>(&frame, protocol, format)
+    };
+    match text {
+        Some(text) => ([(header::CONTENT_TYPE, "application/json")], text).into_response(),
+        None => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "cannot convert the sample",
+        ),
+    }
+}
+
+/// The current sample: the last one broadcast if the stream is being
+/// sampled, else the next one.
+async fn latest(broadcast: &Broadcast, period: Duration) -> Option<Frame> {
+    if broadcast.receiver_count() > 0 {
+        if let Some(frame) = broadcast.latest() {
+            return Some(frame);
+        }
+    }
+    let mut rx = broadcast.subscribe();
+    loop {
+        match timeout(period + STARTUP, rx.recv()).await {
+            Ok(Ok(frame)) => return Some(frame),
+            Ok(Err(RecvError::Lagged(_))) => continue,
+            // Streams that skip unchanged samples stay quiet while the last
+            // one broadcast still holds.
+            Ok(Err(RecvError::Closed)) | Err(_) => return broadcast.latest(),
+        }
+    }
+}
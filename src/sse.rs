@@ -0,0 +1,348 @@
+//! `GET /sse/*`: the streams as Server-Sent Events, for clients behind
+//! proxies that break WebSockets. It takes the stream's `v`, `mem_unit`,
+//! `round`, `since_seq` and `resync`.
+//!
+//! Each sample is an event with its `seq` as id, so that a client
+//! reconnecting with `Last-Event-ID` resumes where it left off, as with
+//! `since_seq`. `gap` and `resync` notices are events without an id. A
+//! comment is sent while the stream is quiet, at the WebSocket ping
+//! interval, so that proxies keep the connection open.
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::stream;
+use serde::de::DeserializeOwned;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time::timeout,
+};
+
+use crate::{
+    config::WebSocketConfig,
+    sampler::Broadcast,
+    server::{error_response, AppState},
+    stats::{ChannelStats, Stats},
+    types::Payload,
+    ws::{self, Caps, CloseReason, Connection, Frame, SessionOptions, StreamParams},
+};
+
+/// The header a reconnecting `EventSource` sends the last id it saw in.
+const LAST_EVENT_ID: &str = "last-event-id";
+
+#[cfg(feature = "cpu")]
+#[axum::debug_handler]
+pub async fn cpus_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.cpus_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/cpus",
+        broadcast,
+        stats: |stats| &stats.cpus,
+    };
+    events::<crate::types::CpuState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "power")]
+#[axum::debug_handler]
+pub async fn power_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.power_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/power",
+        broadcast,
+        stats: |stats| &stats.power,
+    };
+    events::<crate::types::PowerState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "disks")]
+#[axum::debug_handler]
+pub async fn disks_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.disks_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/disks",
+        broadcast,
+        stats: |stats| &stats.disks,
+    };
+    events::<crate::types::DiskState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "network")]
+#[axum::debug_handler]
+pub async fn network_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.network_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/network",
+        broadcast,
+        stats: |stats| &stats.network,
+    };
+    events::<crate::types::NetState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "gpu")]
+#[axum::debug_handler]
+pub async fn gpu_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.gpu_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/gpu",
+        broadcast,
+        stats: |stats| &stats.gpu,
+    };
+    events::<crate::types::GpuState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "sensors")]
+#[axum::debug_handler]
+pub async fn sensors_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.sensors_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/sensors",
+        broadcast,
+        stats: |stats| &stats.sensors,
+    };
+    events::<crate::types::SensorState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "system")]
+#[axum::debug_handler]
+pub async fn system_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.system_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/system",
+        broadcast,
+        stats: |stats| &stats.system,
+    };
+    events::<crate::types::SystemState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "mem")]
+#[axum::debug_handler]
+pub async fn ram_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.ram_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/ram",
+        broadcast,
+        stats: |stats| &stats.ram,
+    };
+    events::<crate::types::MemState>(state, stream, peer, &headers, &params)
+}
+
+#[cfg(feature = "processes")]
+#[axum::debug_handler]
+pub async fn processes_get(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
+    State(state): State<AppState>,
+) -> Response {
+    let broadcast = state.process_broadcast.clone();
+    let stream = Stream {
+        endpoint: "/sse/processes",
+        broadcast,
+        stats: |stats| &stats.processes,
+    };
+    events::<Vec<crate::types::ProcessInfo>>(state, stream, peer, &headers, &params)
+}
+
+/// The broadcast an endpoint streams, and where its sessions are counted.
+struct Stream {
+    endpoint: &'static str,
+    broadcast: Arc<Broadcast>,
+    stats: fn(&Stats) -> &ChannelStats,
+}
+
+fn events<T: Payload + DeserializeOwned>(
+    state: AppState,
+    stream: Stream,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    params: &StreamParams,
+) -> Response {
+    let mut options = match params.validate(
+        stream.endpoint,
+        Caps {
+            since_seq: true,
+            convert: true,
+            ..Caps::default()
+        },
+    ) {
+        Ok(options) => options,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, err),
+    };
+    // A reconnecting client repeats the URL it was given, so the id it saw
+    // last takes precedence over `since_seq`.
+    if let Some(id) = headers.get(LAST_EVENT_ID) {
+        match id.to_str().ok().and_then(|id| id.trim().parse().ok()) {
+            Some(seq) => options.since_seq = Some(seq),
+            None => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "Last-Event-ID must be the seq of a sample",
+                )
+            }
+        }
+    }
+    let config = state.websocket;
+    let keep_alive = KeepAlive::new().interval(config.ping_interval());
+    // Forwarded through a channel so that the session ends, and is logged,
+    // as soon as the client goes away.
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let conn = Connection::new(
+            &state.stats,
+            &state.access_log,
+            &state.shutdown,
+            stream.endpoint,
+            peer,
+            options.clone(),
+        );
+        let stats = (stream.stats)(&state.stats);
+        forward::<T>(conn, options, &stream.broadcast, stats, config, tx).await;
+    });
+    let events = stream::unfold(rx, |mut rx| async move {
+        let event = rx.recv().await?;
+        Some((Ok::<_, Infallible>(event), rx))
+    });
+    Sse::new(events).keep_alive(keep_alive).into_response()
+}
+
+/// Sends the broadcast to `tx` as events until the client goes away, blocks
+/// our sends or keeps falling behind.
+async fn forward<T: Payload + DeserializeOwned>(
+    mut conn: Connection,
+    options: SessionOptions,
+    broadcast: &Broadcast,
+    stats: &ChannelStats,
+    config: WebSocketConfig,
+    tx: mpsc::Sender<Event>,
+) {
+    let _client = stats.connect();
+    // The id of a sample's event, and its text.
+    let message = |frame: &Frame| {
+        let text = match options.format.is_raw() {
+            true => Some(frame.text(options.protocol).to_owned()),
+            false => ws::reformat::<T>(frame, options.protocol, options.format),
+        };
+        Some((Some(frame.seq()), text?))
+    };
+
+    let mut backlog = vec![];
+    let mut rx = match options.since_seq {
+        Some(since_seq) => {
+            let (rx, resumed) = broadcast.resume(since_seq);
+            if let Some((from, to)) = resumed.gap {
+                backlog.push((None, format!(r#"{{"kind":"gap","from":{from},"to":{to}}}"#)));
+            }
+            backlog.extend(
+                resumed
+                    .frames
+                    .iter()
+                    .filter_map(|frame| message(&frame.replayed())),
+            );
+            rx
+        }
+        None => broadcast.subscribe(),
+    };
+    let mut backlog = backlog.into_iter();
+    // Lag events since the client last caught up with the broadcast.
+    let mut lag_streak = 0;
+
+    let reason = loop {
+        let (id, text) = match backlog.next() {
+            Some(message) => message,
+            None => tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(frame) => {
+                        if rx.is_empty() {
+                            lag_streak = 0;
+                        }
+                        match message(&frame) {
+                            Some(message) => message,
+                            None => continue,
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        stats.record_dropped(skipped);
+                        conn.record_lagged(skipped);
+                        lag_streak += 1;
+                        if config.max_lag_streak != 0 && lag_streak >= config.max_lag_streak {
+                            stats.record_kicked();
+                            break CloseReason::TooSlow;
+                        }
+                        if !options.resync {
+                            continue;
+                        }
+                        (None, format!(r#"{{"resync":{skipped}}}"#))
+                    }
+                    Err(RecvError::Closed) => break CloseReason::Shutdown,
+                },
+                _ = tx.closed() => break CloseReason::ClientClose,
+                _ = conn.ended() => break CloseReason::Shutdown,
+            },
+        };
+
+        let len = text.len() as u64;
+        let event = match id {
+            Some(id) => Event::default().id(id.to_string()).data(text),
+            None => Event::default().data(text),
+        };
+        match timeout(config.send_timeout(), tx.send(event)).await {
+            Ok(Ok(())) => {
+                conn.record_sent(len);
+                stats.record_sent();
+            }
+            Ok(Err(_)) => break CloseReason::ClientClose,
+            Err(_) => {
+                stats.record_kicked();
+                break CloseReason::SendTimeout;
+            }
+        }
+    };
+    conn.close(reason);
+}
@@ -0,0 +1,594 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::Role;
+
+/// Counters about the server itself, shared between the sampler and the
+/// stream handlers and served at `/stats`.
+pub struct Stats {
+    started: Instant,
+    next_connection: AtomicU64,
+    pub cpus: ChannelStats,
+    pub ram: ChannelStats,
+    pub processes: ChannelStats,
+    pub power: ChannelStats,
+    pub disks: ChannelStats,
+    pub network: ChannelStats,
+    pub gpu: ChannelStats,
+    pub sensors: ChannelStats,
+    pub system: ChannelStats,
+    pub alerts: ChannelStats,
+    /// Samples re-broadcast from hub remotes, of all hosts and streams.
+    pub hosts: ChannelStats,
+    pub requests: RequestStats,
+    #[cfg(feature = "kafka")]
+    pub kafka: KafkaStats,
+    pub metrics_log: MetricsLogStats,
+    self_cpu_usage: AtomicU32,
+    self_memory: AtomicU64,
+    sampler_overruns: AtomicU64,
+    pub refresh: RefreshTimes,
+    /// Milliseconds since `started` at the end of the last sampler tick, plus
+    /// one so that 0 means there has not been one yet.
+    last_tick: AtomicU64,
+    sampler_restarts: AtomicU64,
+    sampler_idle: AtomicBool,
+    /// The sampling interval in use, in milliseconds.
+    sampler_interval: AtomicU64,
+    /// When the sampler was restarted within the last `CRASH_LOOP_WINDOW`.
+    recent_restarts: Mutex<VecDeque<Instant>>,
+}
+
+/// This many sampler restarts within `CRASH_LOOP_WINDOW` count as a crash
+/// loop.
+const CRASH_LOOP_RESTARTS: usize = 5;
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct ChannelStats {
+    active: AtomicBool,
+    clients: AtomicUsize,
+    broadcast: AtomicU64,
+    encoded: AtomicU64,
+    sent: AtomicU64,
+    lagged: AtomicU64,
+    dropped: AtomicU64,
+    kicked: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+/// Requests to authenticated endpoints, by the role of their token.
+#[derive(Default)]
+pub struct RequestStats {
+    anonymous: AtomicU64,
+    read: AtomicU64,
+    admin: AtomicU64,
+    /// Without a valid token where one was needed.
+    unauthorized: AtomicU64,
+    /// With a valid token of a role that was not enough.
+    forbidden: AtomicU64,
+    /// Without a token, through a share URL, see [`crate::share`].
+    shared: AtomicU64,
+}
+
+/// What became of the messages handed to the Kafka producer.
+#[cfg(feature = "kafka")]
+#[derive(Default)]
+pub struct KafkaStats {
+    /// Acknowledged as the configured `acks` asks.
+    delivered: AtomicU64,
+    /// Given up on by the producer, after its retries.
+    failed: AtomicU64,
+    /// Dropped from the local queue to make room for newer ones.
+    dropped: AtomicU64,
+}
+
+/// What became of the lines meant for the metrics log.
+#[derive(Default)]
+pub struct MetricsLogStats {
+    written: AtomicU64,
+    /// Not written, e.g. because the disk is full.
+    failed: AtomicU64,
+    /// Dropped because the queue to the writer was full.
+    dropped: AtomicU64,
+}
+
+/// How long the sampler spends in each kind of refresh.
+#[derive(Default)]
+pub struct RefreshTimes {
+    /// A whole tick, refreshes and publishing included.
+    pub tick: RefreshTime,
+    pub cpu: RefreshTime,
+    pub memory: RefreshTime,
+    pub processes: RefreshTime,
+    pub components: RefreshTime,
+    pub energy: RefreshTime,
+    pub disks: RefreshTime,
+    pub network: RefreshTime,
+    pub gpus: RefreshTime,
+    pub hwmon: RefreshTime,
+}
+
+/// The duration of the last run of something, and a moving average over
+/// roughly the last `1 / AVERAGE_WEIGHT` runs. Only the sampler writes it.
+#[derive(Default)]
+pub struct RefreshTime {
+    last_us: AtomicU64,
+    /// An `f64`, 0 before the first run.
+    average_us: AtomicU64,
+}
+
+const AVERAGE_WEIGHT: f64 = 0.1;
+
+/// Decrements the connected client count of a channel when dropped.
+pub struct ClientGuard<'a>(&'a ChannelStats);
+
+#[derive(Serialize, Debug)]
+pub struct StatsReport {
+    uptime_secs: u64,
+    channels: ChannelsReport,
+    requests: RequestsReport,
+    #[cfg(feature = "kafka")]
+    kafka: KafkaReport,
+    metrics_log: MetricsLogReport,
+    process: ProcessReport,
+    sampler: SamplerReport,
+}
+
+#[derive(Serialize, Debug)]
+struct ChannelsReport {
+    cpus: ChannelReport,
+    ram: ChannelReport,
+    processes: ChannelReport,
+    power: ChannelReport,
+    disks: ChannelReport,
+    network: ChannelReport,
+    gpu: ChannelReport,
+    sensors: ChannelReport,
+    system: ChannelReport,
+    alerts: ChannelReport,
+    hosts: ChannelReport,
+}
+
+#[derive(Serialize, Debug)]
+struct ChannelReport {
+    /// Whether the sampler is refreshing this stream, which it only does
+    /// while it has subscribers.
+    active: bool,
+    clients: usize,
+    broadcast: u64,
+    /// JSON encodings made by the sampler: one per protocol version and
+    /// sample, however many clients are connected.
+    encoded: u64,
+    /// Messages written to clients.
+    sent: u64,
+    /// How often a client fell more than `channel_capacity` samples behind.
+    lagged: u64,
+    /// Samples skipped by lagging clients, summed over all clients.
+    dropped: u64,
+    /// Clients disconnected for blocking sends or lagging too often.
+    kicked: u64,
+    /// Samples not broadcast because they equalled the previous one.
+    suppressed: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct RequestsReport {
+    anonymous: u64,
+    read: u64,
+    admin: u64,
+    unauthorized: u64,
+    forbidden: u64,
+    shared: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct MetricsLogReport {
+    written: u64,
+    failed: u64,
+    dropped: u64,
+}
+
+#[cfg(feature = "kafka")]
+#[derive(Serialize, Debug)]
+struct KafkaReport {
+    delivered: u64,
+    failed: u64,
+    dropped: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct RefreshTimesReport {
+    tick: RefreshTimeReport,
+    cpu: RefreshTimeReport,
+    memory: RefreshTimeReport,
+    processes: RefreshTimeReport,
+    components: RefreshTimeReport,
+    energy: RefreshTimeReport,
+    disks: RefreshTimeReport,
+    network: RefreshTimeReport,
+    gpus: RefreshTimeReport,
+    hwmon: RefreshTimeReport,
+}
+
+/// Microseconds.
+#[derive(Serialize, Debug)]
+struct RefreshTimeReport {
+    last_us: u64,
+    average_us: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct SamplerReport {
+    /// "active", or "idle" while paused for lack of subscribers.
+    state: &'static str,
+    /// The sampling interval in use, which differs from `cpu_interval_ms`
+    /// while adaptive sampling stretches it.
+    interval_ms: u64,
+    /// Ticks that took longer than the sampling interval.
+    overruns: u64,
+    /// How often the sampler panicked and was restarted.
+    restarts: u64,
+    /// Wall time spent per tick and per refresh.
+    times: RefreshTimesReport,
+}
+
+/// The `/healthz` response.
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    status: &'static str,
+    /// Why the status is not "ok".
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<&'static str>,
+    /// Milliseconds since the sampler last completed a tick, if it has.
+    last_tick_age_ms: Option<u64>,
+    sampler_restarts: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct ProcessReport {
+    cpu_usage: f32,
+    memory: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            next_connection: AtomicU64::new(1),
+            cpus: ChannelStats::default(),
+            ram: ChannelStats::default(),
+            processes: ChannelStats::default(),
+            power: ChannelStats::default(),
+            disks: ChannelStats::default(),
+            network: ChannelStats::default(),
+            gpu: ChannelStats::default(),
+            sensors: ChannelStats::default(),
+            system: ChannelStats::default(),
+            alerts: ChannelStats::default(),
+            hosts: ChannelStats::default(),
+            requests: RequestStats::default(),
+            #[cfg(feature = "kafka")]
+            kafka: KafkaStats::default(),
+            metrics_log: MetricsLogStats::default(),
+            self_cpu_usage: AtomicU32::new(0),
+            self_memory: AtomicU64::new(0),
+            sampler_overruns: AtomicU64::new(0),
+            refresh: RefreshTimes::default(),
+            last_tick: AtomicU64::new(0),
+            sampler_restarts: AtomicU64::new(0),
+            sampler_idle: AtomicBool::new(false),
+            sampler_interval: AtomicU64::new(0),
+            recent_restarts: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn next_connection_id(&self) -> u64 {
+        self.next_connection.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn set_self_usage(&self, cpu_usage: f32, memory: u64) {
+        self.self_cpu_usage
+            .store(cpu_usage.to_bits(), Ordering::Relaxed);
+        self.self_memory.store(memory, Ordering::Relaxed);
+    }
+
+    pub fn record_overrun(&self) {
+        self.sampler_overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_tick(&self) {
+        let since_start = self.started.elapsed().as_millis() as u64;
+        self.last_tick.store(since_start + 1, Ordering::Relaxed);
+    }
+
+    pub fn set_sampler_idle(&self, idle: bool) {
+        self.sampler_idle.store(idle, Ordering::Relaxed);
+    }
+
+    pub fn set_sampler_interval(&self, interval: Duration) {
+        self.sampler_interval
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// The sampling interval in use, zero before the sampler has started.
+    pub fn sampler_interval(&self) -> Duration {
+        Duration::from_millis(self.sampler_interval.load(Ordering::Relaxed))
+    }
+
+    pub fn record_sampler_restart(&self) {
+        self.sampler_restarts.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent_restarts.lock().unwrap();
+        recent.push_back(Instant::now());
+        if recent.len() > CRASH_LOOP_RESTARTS {
+            recent.pop_front();
+        }
+    }
+
+    /// Healthy while the sampler has ticked within `stale_after` of now (or
+    /// of startup) and is not crash looping.
+    pub fn health(&self, stale_after: Duration) -> HealthReport {
+        let mut problems = vec![];
+
+        let last_tick_age = match self.last_tick.load(Ordering::Relaxed) {
+            0 => None,
+            last_tick => Some(self.started.elapsed() - Duration::from_millis(last_tick - 1)),
+        };
+        if last_tick_age.unwrap_or(self.started.elapsed()) > stale_after {
+            problems.push("sampler stalled");
+        }
+
+        let recent = self.recent_restarts.lock().unwrap();
+        let crash_looping = recent.len() >= CRASH_LOOP_RESTARTS
+            && recent
+                .front()
+                .is_some_and(|oldest| oldest.elapsed() < CRASH_LOOP_WINDOW);
+        if crash_looping {
+            problems.push("sampler crash loop");
+        }
+
+        HealthReport {
+            status: if problems.is_empty() { "ok" } else { "failing" },
+            problems,
+            last_tick_age_ms: last_tick_age.map(|age| age.as_millis() as u64),
+            sampler_restarts: self.sampler_restarts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn report(&self) -> StatsReport {
+        StatsReport {
+            uptime_secs: self.started.elapsed().as_secs(),
+            channels: ChannelsReport {
+                cpus: self.cpus.report(),
+                ram: self.ram.report(),
+                processes: self.processes.report(),
+                power: self.power.report(),
+                disks: self.disks.report(),
+                network: self.network.report(),
+                gpu: self.gpu.report(),
+                sensors: self.sensors.report(),
+                system: self.system.report(),
+                alerts: self.alerts.report(),
+                hosts: self.hosts.report(),
+            },
+            requests: self.requests.report(),
+            #[cfg(feature = "kafka")]
+            kafka: self.kafka.report(),
+            metrics_log: self.metrics_log.report(),
+            process: ProcessReport {
+                cpu_usage: f32::from_bits(self.self_cpu_usage.load(Ordering::Relaxed)),
+                memory: self.self_memory.load(Ordering::Relaxed),
+            },
+            sampler: SamplerReport {
+                state: if self.sampler_idle.load(Ordering::Relaxed) {
+                    "idle"
+                } else {
+                    "active"
+                },
+                interval_ms: self.sampler_interval.load(Ordering::Relaxed),
+                overruns: self.sampler_overruns.load(Ordering::Relaxed),
+                restarts: self.sampler_restarts.load(Ordering::Relaxed),
+                times: self.refresh.report(),
+            },
+        }
+    }
+}
+
+impl HealthReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl RequestStats {
+    /// Counts a request let through with a token of `role`, or none.
+    pub fn record(&self, role: Option<Role>) {
+        let counter = match role {
+            None => &self.anonymous,
+            Some(Role::Read) => &self.read,
+            Some(Role::Admin) => &self.admin,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_shared(&self) {
+        self.shared.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_unauthorized(&self) {
+        self.unauthorized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_forbidden(&self) {
+        self.forbidden.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> RequestsReport {
+        RequestsReport {
+            anonymous: self.anonymous.load(Ordering::Relaxed),
+            read: self.read.load(Ordering::Relaxed),
+            admin: self.admin.load(Ordering::Relaxed),
+            unauthorized: self.unauthorized.load(Ordering::Relaxed),
+            forbidden: self.forbidden.load(Ordering::Relaxed),
+            shared: self.shared.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaStats {
+    pub fn record_delivered(&self) {
+        self.delivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> KafkaReport {
+        KafkaReport {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsLogStats {
+    pub fn record_written(&self) {
+        self.written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> MetricsLogReport {
+        MetricsLogReport {
+            written: self.written.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl RefreshTimes {
+    fn report(&self) -> RefreshTimesReport {
+        RefreshTimesReport {
+            tick: self.tick.report(),
+            cpu: self.cpu.report(),
+            memory: self.memory.report(),
+            processes: self.processes.report(),
+            components: self.components.report(),
+            energy: self.energy.report(),
+            disks: self.disks.report(),
+            network: self.network.report(),
+            gpus: self.gpus.report(),
+            hwmon: self.hwmon.report(),
+        }
+    }
+}
+
+impl RefreshTime {
+    /// Runs `f` and records how long it took.
+    pub fn time<R>(&self, f: impl FnOnce() -> R) -> R {
+        let started = Instant::now();
+        let result = f();
+        self.record(started.elapsed());
+        result
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let us = duration.as_micros() as u64;
+        self.last_us.store(us, Ordering::Relaxed);
+        let average = f64::from_bits(self.average_us.load(Ordering::Relaxed));
+        let average = if average == 0. {
+            us as f64
+        } else {
+            average + (us as f64 - average) * AVERAGE_WEIGHT
+        };
+        self.average_us.store(average.to_bits(), Ordering::Relaxed);
+    }
+
+    fn report(&self) -> RefreshTimeReport {
+        RefreshTimeReport {
+            last_us: self.last_us.load(Ordering::Relaxed),
+            average_us: f64::from_bits(self.average_us.load(Ordering::Relaxed)).round() as u64,
+        }
+    }
+}
+
+impl ChannelStats {
+    pub fn connect(&self) -> ClientGuard<'_> {
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self)
+    }
+
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    pub fn record_broadcast(&self) {
+        self.broadcast.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_encoded(&self, count: u64) {
+        self.encoded.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a client skipping `count` samples because it lagged.
+    pub fn record_dropped(&self, count: u64) {
+        self.lagged.fetch_add(1, Ordering::Relaxed);
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_kicked(&self) {
+        self.kicked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_suppressed(&self) {
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> ChannelReport {
+        ChannelReport {
+            active: self.active.load(Ordering::Relaxed),
+            clients: self.clients.load(Ordering::Relaxed),
+            broadcast: self.broadcast.load(Ordering::Relaxed),
+            encoded: self.encoded.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            lagged: self.lagged.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            kicked: self.kicked.load(Ordering::Relaxed),
+            suppressed: self.suppressed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.0.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,610 @@
+//! `axact tui`: a full-screen dashboard of a server's streams, or of this
+//! machine through an in-process sampler when no server is given. Either way
+//! the samples arrive as the JSON clients receive and are decoded into the
+//! same types, so the dashboard shows what the wire format carries.
+
+use std::{collections::VecDeque, io, sync::Arc};
+
+use clap::Args as ClapArgs;
+use crossterm::event::{
+    Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use futures::StreamExt;
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Cell, Gauge, Paragraph, Row, Sparkline, Table, TableState},
+    Frame,
+};
+#[cfg(any(feature = "cpu", feature = "mem", feature = "processes"))]
+use tokio::sync::broadcast;
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+    client::{self, Event},
+    config::SamplerConfig,
+    sampler::{self, Channels},
+    stats::Stats,
+    types::{CpuState, MemState, ProcessInfo},
+};
+
+/// How many processes to ask a server for; it may clamp this further.
+const TOP: usize = 100;
+/// How many package temperatures the sparkline keeps, more than fit on most
+/// terminals.
+const TEMP_HISTORY: usize = 512;
+/// The sparkline's full height, in °C.
+const TEMP_SCALE: u64 = 100;
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct TuiArgs {
+    /// Base URL of a server to watch, e.g. `ws://host:7032`. Without one,
+    /// this machine is sampled in-process and no server is needed.
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sort {
+    /// Busiest first.
+    Cpu,
+    /// Largest first.
+    Memory,
+    /// Alphabetical.
+    Name,
+}
+
+impl Sort {
+    fn name(self) -> &'static str {
+        match self {
+            Sort::Cpu => "cpu",
+            Sort::Memory => "memory",
+            Sort::Name => "name",
+        }
+    }
+
+    /// Ties are broken by pid, so that rows do not trade places between
+    /// samples.
+    fn apply(self, processes: &mut [ProcessInfo]) {
+        processes.sort_unstable_by(|a, b| {
+            let by = match self {
+                Sort::Cpu => b.cpu_usage.total_cmp(&a.cpu_usage),
+                Sort::Memory => b.memory.cmp(&a.memory),
+                Sort::Name => a.name.cmp(&b.name),
+            };
+            by.then(a.pid.cmp(&b.pid))
+        });
+    }
+}
+
+struct Dashboard {
+    /// Where the samples come from.
+    title: String,
+    cpus: Option<CpuState>,
+    ram: Option<MemState>,
+    processes: Vec<ProcessInfo>,
+    /// Package temperatures in whole °C, oldest first.
+    temps: VecDeque<u64>,
+    sort: Sort,
+    /// While paused, samples are dropped and the screen keeps showing the
+    /// last ones.
+    paused: bool,
+    table: TableState,
+    /// How many process rows were visible on the last draw, for paging.
+    page: usize,
+    offline: Vec<(&'static str, String)>,
+}
+
+/// Keeps the in-process sampler running until dropped.
+struct LocalSampler {
+    _config: watch::Sender<SamplerConfig>,
+    _shutdown: watch::Sender<bool>,
+}
+
+pub async fn run(args: TuiArgs, config: SamplerConfig) -> io::Result<()> {
+    let (tx, mut rx) = mpsc::channel(16);
+    let (title, _local) = match &args.url {
+        Some(url) => {
+            let base = url.trim_end_matches('/').to_string();
+            for stream in client::STREAMS {
+                let top = match stream {
+                    "processes" => format!("&top={TOP}"),
+                    _ => String::new(),
+                };
+                tokio::spawn(client::follow(
+                    format!("{base}/realtime/{stream}?v=2{top}"),
+                    stream,
+                    tx.clone(),
+                ));
+            }
+            (base, None)
+        }
+        None => ("this machine".to_string(), Some(sample_locally(config, tx))),
+    };
+
+    let mut dashboard = Dashboard::new(title);
+    let mut terminal = ratatui::try_init()?;
+    let mut input = EventStream::new();
+    let result = loop {
+        if let Err(err) = terminal.draw(|frame| dashboard.render(frame)) {
+            break Err(err);
+        }
+        tokio::select! {
+            Some(event) = rx.recv() => dashboard.apply(event),
+            input = input.next() => match input {
+                Some(Ok(TermEvent::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if !dashboard.key(key) {
+                        break Ok(());
+                    }
+                }
+                // A resize only needs the redraw at the top of the loop.
+                Some(Ok(_)) => {}
+                Some(Err(err)) => break Err(err),
+                None => break Ok(()),
+            },
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+/// Runs a sampler on this machine and feeds its samples, encoded as they
+/// would be sent to a v2 client, to `tx`.
+#[cfg_attr(
+    not(any(feature = "cpu", feature = "mem", feature = "processes")),
+    allow(unused_variables)
+)]
+fn sample_locally(config: SamplerConfig, tx: mpsc::Sender<Event>) -> LocalSampler {
+    // The samples never leave the dashboard, so nobody needs to know whose
+    // they are.
+    let channels = Channels::new(16, watch::channel(Arc::default()).1);
+    #[cfg(feature = "cpu")]
+    tokio::spawn(forward(
+        channels.cpus.broadcast().subscribe(),
+        "cpus",
+        tx.clone(),
+        |frame| frame.text(crate::ws::Protocol::V2).to_owned(),
+    ));
+    #[cfg(feature = "mem")]
+    tokio::spawn(forward(
+        channels.ram.broadcast().subscribe(),
+        "ram",
+        tx.clone(),
+        |frame| frame.text(crate::ws::Protocol::V2).to_owned(),
+    ));
+    // Every process rather than the top ones, as the table scrolls.
+    #[cfg(feature = "processes")]
+    tokio::spawn(forward(
+        channels.process_list.subscribe(),
+        "processes",
+        tx,
+        |sample| serde_json::to_string(&**sample).unwrap_or_default(),
+    ));
+
+    let (config_tx, config_rx) = watch::channel(config);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(sampler::supervise(
+        config_rx,
+        channels,
+        Arc::new(Stats::new()),
+        shutdown_rx,
+    ));
+    LocalSampler {
+        _config: config_tx,
+        _shutdown: shutdown_tx,
+    }
+}
+
+#[cfg(any(feature = "cpu", feature = "mem", feature = "processes"))]
+async fn forward<T: Clone>(
+    mut rx: broadcast::Receiver<T>,
+    stream: &'static str,
+    tx: mpsc::Sender<Event>,
+    encode: fn(&T) -> String,
+) {
+    loop {
+        match rx.recv().await {
+            Ok(item) => {
+                if tx
+                    .send(Event::Message(stream, encode(&item)))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+impl Dashboard {
+    fn new(title: String) -> Self {
+        Self {
+            title,
+            cpus: None,
+            ram: None,
+            processes: vec![],
+            temps: VecDeque::new(),
+            sort: Sort::Cpu,
+            paused: false,
+            table: TableState::default().with_selected(Some(0)),
+            page: 1,
+            offline: vec![],
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Connected(stream) => self.offline.retain(|(name, _)| *name != stream),
+            Event::Disconnected(stream, reason) => {
+                self.offline.retain(|(name, _)| *name != stream);
+                self.offline.push((stream, reason));
+            }
+            Event::Message(..) if self.paused => {}
+            Event::Message("cpus", text) => {
+                self.cpus = client::data(&text);
+                if let Some(temp) = self.cpus.as_ref().and_then(package_temp) {
+                    if self.temps.len() == TEMP_HISTORY {
+                        self.temps.pop_front();
+                    }
+                    self.temps.push_back(temp.max(0.).round() as u64);
+                }
+            }
+            Event::Message("ram", text) => self.ram = client::data(&text),
+            Event::Message("processes", text) => {
+                self.processes = client::data(&text).unwrap_or_default();
+                self.sort.apply(&mut self.processes);
+                self.clamp_selection();
+            }
+            Event::Message(..) => {}
+        }
+    }
+
+    /// Handles a key press, returning whether to keep running.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        let rows = self.processes.len();
+        let selected = self.table.selected().unwrap_or(0);
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char('c') => self.sort_by(Sort::Cpu),
+            KeyCode::Char('m') => self.sort_by(Sort::Memory),
+            KeyCode::Char('n') => self.sort_by(Sort::Name),
+            KeyCode::Char('p') | KeyCode::Char(' ') => self.paused = !self.paused,
+            KeyCode::Down | KeyCode::Char('j') => self.table.select(Some(selected + 1)),
+            KeyCode::Up | KeyCode::Char('k') => self.table.select(Some(selected.saturating_sub(1))),
+            KeyCode::PageDown => self.table.select(Some(selected + self.page)),
+            KeyCode::PageUp => self.table.select(Some(selected.saturating_sub(self.page))),
+            KeyCode::Home | KeyCode::Char('g') => self.table.select(Some(0)),
+            KeyCode::End | KeyCode::Char('G') => self.table.select(Some(rows.saturating_sub(1))),
+            _ => {}
+        }
+        self.clamp_selection();
+        true
+    }
+
+    fn sort_by(&mut self, sort: Sort) {
+        self.sort = sort;
+        sort.apply(&mut self.processes);
+    }
+
+    fn clamp_selection(&mut self) {
+        let last = self.processes.len().saturating_sub(1);
+        let selected = self.table.selected().unwrap_or(0).min(last);
+        self.table.select(Some(selected));
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let cores = self.cpus.as_ref().map_or(0, |cpus| cpus.cores.len()) as u16;
+        let [top, processes] = Layout::vertical([
+            Constraint::Length((cores + 2).clamp(7, body.height / 2)),
+            Constraint::Min(0),
+        ])
+        .areas(body);
+        let [cpus, side] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(top);
+        let [memory, temps] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(side);
+
+        self.render_header(frame, header);
+        self.render_cpus(frame, cpus);
+        self.render_memory(frame, memory);
+        self.render_temps(frame, temps);
+        self.render_processes(frame, processes);
+        frame.render_widget(
+            Paragraph::new(
+                "q quit  c/m/n sort by cpu/memory/name  p pause  ↑↓ PgUp PgDn Home End scroll",
+            )
+            .dim(),
+            footer,
+        );
+    }
+
+    fn render_header(&self, frame: &mut Frame, area: Rect) {
+        let mut spans = vec![Span::from(format!("axact @ {}", self.title)).bold()];
+        if self.paused {
+            spans.push(Span::from("  PAUSED").yellow().bold());
+        }
+        for (stream, reason) in &self.offline {
+            spans.push(Span::from(format!("  {stream}: {reason}")).red());
+        }
+        frame.render_widget(Line::from(spans), area);
+    }
+
+    /// One bar per core, in as many columns as it takes to fit them all.
+    fn render_cpus(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title("CPU");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let Some(cpus) = &self.cpus else {
+            frame.render_widget(Paragraph::new("waiting for samples…").dim(), inner);
+            return;
+        };
+
+        let rows = usize::from(inner.height.max(1));
+        let columns = cpus.cores.len().div_ceil(rows).max(1);
+        let areas = Layout::horizontal(vec![Constraint::Ratio(1, columns as u32); columns])
+            .spacing(1)
+            .split(inner);
+        for (cores, &area) in cpus.cores.chunks(rows).zip(areas.iter()) {
+            let lines: Vec<Line> = cores
+                .iter()
+                .map(|core| {
+                    let temp = core
+                        .temp
+                        .map_or(String::new(), |temp| format!(" {temp:>3.0}°"));
+                    let label = format!("{:>3} ", core.id);
+                    let value = format!(" {:>5.1}%{temp}", core.usage);
+                    let width =
+                        usize::from(area.width).saturating_sub(label.len() + value.chars().count());
+                    let mut line = bar(core.usage / 100., width);
+                    line.spans.insert(0, Span::from(label));
+                    line.spans.push(Span::from(value));
+                    line
+                })
+                .collect();
+            frame.render_widget(Paragraph::new(lines), area);
+        }
+    }
+
+    fn render_memory(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title("Memory");
+        let Some(ram) = &self.ram else {
+            frame.render_widget(
+                Paragraph::new("waiting for samples…").dim().block(block),
+                area,
+            );
+            return;
+        };
+        let ratio = if ram.total == 0 {
+            0.
+        } else {
+            (ram.used as f64 / ram.total as f64).clamp(0., 1.)
+        };
+        let gib = |bytes: u64| bytes as f64 / (1u64 << 30) as f64;
+        frame.render_widget(
+            Gauge::default()
+                .block(block)
+                .gauge_style(Style::new().fg(Color::Cyan))
+                .ratio(ratio)
+                .label(format!(
+                    "{:.1} / {:.1} GiB ({:.0}%)",
+                    gib(ram.used),
+                    gib(ram.total),
+                    ratio * 100.
+                )),
+            area,
+        );
+    }
+
+    fn render_temps(&self, frame: &mut Frame, area: Rect) {
+        let title = match self.cpus.as_ref().and_then(package_temp) {
+            Some(temp) => format!("Temperature {temp:.1}°C"),
+            None => "Temperature".to_string(),
+        };
+        let block = Block::bordered().title(title);
+        if self.temps.is_empty() {
+            frame.render_widget(Paragraph::new("no sensor").dim().block(block), area);
+            return;
+        }
+        // The newest readings that fit.
+        let width = usize::from(block.inner(area).width);
+        let skip = self.temps.len().saturating_sub(width);
+        let temps: Vec<u64> = self.temps.iter().skip(skip).copied().collect();
+        frame.render_widget(
+            Sparkline::default()
+                .block(block)
+                .data(&temps)
+                .max(TEMP_SCALE)
+                .style(Style::new().fg(Color::Red)),
+            area,
+        );
+    }
+
+    fn render_processes(&mut self, frame: &mut Frame, area: Rect) {
+        let column = |name: &'static str, sort: Sort| {
+            let cell = Cell::from(name);
+            if self.sort == sort {
+                cell.add_modifier(Modifier::UNDERLINED)
+            } else {
+                cell
+            }
+        };
+        let header = Row::new([
+            Cell::from("PID"),
+            column("NAME", Sort::Name),
+            column("CPU %", Sort::Cpu),
+            column("MEMORY", Sort::Memory),
+        ])
+        .bold();
+        let rows = self.processes.iter().map(|process| {
+            let mut name = process.name.clone();
+            if let Some(instances) = process.instances {
+                name += &format!(" ({instances}x)");
+            }
+            if process.self_process {
+                name += " (axact)";
+            }
+            Row::new([
+                Cell::from(process.pid.to_string()),
+                Cell::from(name),
+                Cell::from(format!("{:.1}", process.cpu_usage)),
+                Cell::from(format!(
+                    "{:.1} MiB",
+                    process.memory as f64 / (1u64 << 20) as f64
+                )),
+            ])
+        });
+        let block = Block::bordered().title(format!(
+            "Processes ({}) by {}",
+            self.processes.len(),
+            self.sort.name()
+        ));
+        // The border and the header take three rows.
+        self.page = usize::from(area.height.saturating_sub(3)).max(1);
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(8),
+                Constraint::Min(16),
+                Constraint::Length(8),
+                Constraint::Length(14),
+            ],
+        )
+        .header(header)
+        .block(block)
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+}
+
+/// The package temperature, or the hottest core where there is no package
+/// sensor.
+fn package_temp(cpus: &CpuState) -> Option<f32> {
+    cpus.temp.or_else(|| {
+        cpus.cores
+            .iter()
+            .filter_map(|core| core.temp)
+            .reduce(f32::max)
+    })
+}
+
+/// A bar `width` cells wide, coloured by how full it is.
+fn bar(fraction: f32, width: usize) -> Line<'static> {
+    let fraction = fraction.clamp(0., 1.);
+    let filled = (fraction * width as f32).round() as usize;
+    let color = if fraction >= 0.8 {
+        Color::Red
+    } else if fraction >= 0.5 {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    Line::from(vec![
+        Span::styled("█".repeat(filled), Style::new().fg(color)),
+        Span::styled(
+            "·".repeat(width - filled),
+            Style::new().add_modifier(Modifier::DIM),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        types::{CpuCore, Sample},
+        ws::{Frame, Protocol},
+    };
+
+    fn message(stream: &'static str, data: &impl crate::types::Payload) -> Event {
+        let frame = Frame::encode(&Sample {
+            seq: 0,
+            timestamp_ms: 0,
+            host: None,
+            top: None,
+            format: None,
+            instance: None,
+            data,
+            replayed: false,
+        })
+        .unwrap();
+        Event::Message(stream, frame.text(Protocol::V2).to_owned())
+    }
+
+    fn process(pid: u32, name: &str, cpu_usage: f32, memory: u64) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            name: name.to_string(),
+            cpu_usage,
+            memory,
+            virtual_memory: memory,
+            user: None,
+            cmd: vec![],
+            run_time: 0,
+            instances: None,
+            self_process: false,
+        }
+    }
+
+    #[test]
+    fn decodes_what_the_sampler_publishes() {
+        let cpus = CpuState {
+            cores: vec![CpuCore {
+                id: 0,
+                name: "cpu0".into(),
+                usage: 12.5,
+                temp: Some(47.),
+            }],
+            temp: None,
+            core_temp: true,
+            power_watts: None,
+        };
+        let mut dashboard = Dashboard::new("test".into());
+        dashboard.apply(message("cpus", &cpus));
+        assert_eq!(dashboard.cpus, Some(cpus));
+        // Without a package sensor, the hottest core stands in.
+        assert_eq!(dashboard.temps, [47]);
+    }
+
+    #[test]
+    fn keys_sort_and_pause() {
+        let processes = vec![
+            process(1, "init", 0.5, 10 << 20),
+            process(2, "browser", 30., 900 << 20),
+            process(3, "compiler", 80., 400 << 20),
+        ];
+        let mut dashboard = Dashboard::new("test".into());
+        dashboard.apply(message("processes", &processes));
+        let pids = |dashboard: &Dashboard| -> Vec<u32> {
+            dashboard
+                .processes
+                .iter()
+                .map(|process| process.pid)
+                .collect()
+        };
+        assert_eq!(pids(&dashboard), [3, 2, 1]);
+
+        let press = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        assert!(dashboard.key(press(KeyCode::Char('m'))));
+        assert_eq!(pids(&dashboard), [2, 3, 1]);
+        assert!(dashboard.key(press(KeyCode::Char('n'))));
+        assert_eq!(pids(&dashboard), [2, 3, 1]);
+        assert!(dashboard.key(press(KeyCode::End)));
+        assert_eq!(dashboard.table.selected(), Some(2));
+
+        assert!(dashboard.key(press(KeyCode::Char('p'))));
+        dashboard.apply(message("processes", &processes[..1].to_vec()));
+        assert_eq!(dashboard.processes.len(), 3);
+        assert!(!dashboard.key(press(KeyCode::Char('q'))));
+    }
+}
@@ -0,0 +1,514 @@
+//! The payloads sent over the realtime streams, shared by the server and the
+//! built-in clients.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub cores: Vec<CpuCore>,
+    /// Package temperature in °C, `None` where no sensor for it was found.
+    pub temp: Option<f32>,
+    pub core_temp: bool,
+    /// Power drawn by the CPU packages in W, `None` without readable RAPL
+    /// energy counters. See `/realtime/power` for the detail.
+    #[serde(default)]
+    pub power_watts: Option<f32>,
+}
+
+/// One logical CPU. `cores` always lists them by ascending `id`, and its
+/// length stays the same for the life of the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CpuCore {
+    /// The logical CPU index, also its position in `cores`.
+    pub id: usize,
+    /// The name the OS gives the CPU, e.g. "cpu3" on Linux.
+    pub name: String,
+    pub usage: f32,
+    pub temp: Option<f32>,
+}
+
+/// Memory usage. All sizes are in bytes unless a connection asked for
+/// another unit, as `unit` spells out; the `_mib` fields are the same values
+/// rounded down to MiB.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemState {
+    pub total: u64,
+    /// Computed from the other fields as `mode` says.
+    pub used: u64,
+    /// Memory not in use at all.
+    pub free: u64,
+    /// Memory that can be given to programs without swapping, including
+    /// reclaimable caches.
+    pub available: u64,
+    pub mode: MemMode,
+    pub unit: MemUnit,
+    pub total_mib: u64,
+    pub used_mib: u64,
+    /// 0 without swap. Missing from remotes and recordings that predate
+    /// them.
+    #[serde(default)]
+    pub swap_total: u64,
+    #[serde(default)]
+    pub swap_used: u64,
+}
+
+/// How `MemState::used` is computed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MemMode {
+    /// `total - available`: caches that can be reclaimed count as unused,
+    /// like the "used" of htop and `free`.
+    #[default]
+    Available,
+    /// `total - free`: everything not free counts, caches included.
+    Strict,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MemUnit {
+    #[default]
+    Bytes,
+    Kib,
+    Mib,
+    Gib,
+}
+
+impl MemUnit {
+    /// `bytes` in this unit, rounded down.
+    pub fn convert(self, bytes: u64) -> u64 {
+        match self {
+            MemUnit::Bytes => bytes,
+            MemUnit::Kib => bytes >> 10,
+            MemUnit::Mib => bytes >> 20,
+            MemUnit::Gib => bytes >> 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProcessInfo {
+    /// With `group_processes`, the lowest pid of the group.
+    pub pid: u32,
+    pub name: String,
+    /// Percent of one CPU, so it can exceed 100 for multithreaded processes.
+    pub cpu_usage: f32,
+    /// Resident memory, in bytes unless a connection asked for another
+    /// unit.
+    pub memory: u64,
+    /// In the unit of `memory`.
+    #[serde(default)]
+    pub virtual_memory: u64,
+    /// The name of the user it runs as, where it can be told. Grouped, that
+    /// of the lowest pid, as are `cmd` and `run_time`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The full command line, empty where the server is not allowed to
+    /// read it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cmd: Vec<String>,
+    /// How long it has been running, in seconds.
+    #[serde(default)]
+    pub run_time: u64,
+    /// With `group_processes`, how many processes share this name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instances: Option<usize>,
+    /// Whether this is the monitor itself (or, grouped, includes it).
+    #[serde(default)]
+    pub self_process: bool,
+}
+
+/// CPU power draw, from the RAPL energy counters.
+#[cfg(feature = "power")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PowerState {
+    /// Summed over the CPU packages, in W.
+    pub package_watts: Option<f32>,
+    pub zones: Vec<PowerZone>,
+}
+
+/// One RAPL domain, such as a package, its cores or the DRAM.
+#[cfg(feature = "power")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PowerZone {
+    /// The powercap zone, e.g. "intel-rapl:0:0". A zone whose id extends
+    /// another's is part of it.
+    pub id: String,
+    /// What it measures, e.g. "package-0", "core", "uncore" or "dram".
+    pub name: String,
+    /// `None` until it has been read twice.
+    pub watts: Option<f32>,
+}
+
+/// Space and I/O of every mounted filesystem. Sizes and rates are in bytes
+/// unless a connection asked for another unit, as `unit` spells out.
+#[cfg(feature = "disks")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiskState {
+    pub disks: Vec<DiskInfo>,
+    pub unit: MemUnit,
+}
+
+#[cfg(feature = "disks")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiskInfo {
+    /// The device, e.g. "/dev/sda1". Filesystems on the same device report
+    /// the same I/O.
+    pub name: String,
+    pub mount_point: String,
+    /// e.g. "ext4".
+    pub file_system: String,
+    pub total: u64,
+    /// `total - available`, so space reserved for root counts as used.
+    pub used: u64,
+    /// What unprivileged users can still write.
+    pub available: u64,
+    /// Read from the device per second since the last sample. `None` until
+    /// it has been read twice, and where the OS has no I/O counters.
+    pub read_bytes_per_sec: Option<u64>,
+    pub write_bytes_per_sec: Option<u64>,
+}
+
+/// Traffic of every network interface since the last sample. Rates are in
+/// bytes per second unless a connection asked for another unit, as `unit`
+/// spells out. Every delta is `None` until the interface has been read
+/// twice.
+#[cfg(feature = "network")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetState {
+    pub interfaces: Vec<NetInterface>,
+    pub unit: MemUnit,
+}
+
+#[cfg(feature = "network")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetInterface {
+    /// e.g. "eth0".
+    pub name: String,
+    pub rx_bytes_per_sec: Option<u64>,
+    pub tx_bytes_per_sec: Option<u64>,
+    /// Packets received since the last sample.
+    pub rx_packets: Option<u64>,
+    pub tx_packets: Option<u64>,
+    /// Receive errors since the last sample.
+    pub rx_errors: Option<u64>,
+    pub tx_errors: Option<u64>,
+}
+
+/// Every GPU that could be read. Memory is in bytes unless a connection
+/// asked for another unit, as `unit` spells out. Readings the card's driver
+/// does not report are `None`.
+#[cfg(feature = "gpu")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GpuState {
+    pub gpus: Vec<GpuInfo>,
+    pub unit: MemUnit,
+}
+
+#[cfg(feature = "gpu")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GpuInfo {
+    /// The model, or for AMD cards the DRM card, e.g. "card0".
+    pub name: String,
+    /// What it was read through: "nvml" or "amdgpu".
+    pub driver: String,
+    /// Percent of the time the GPU was busy.
+    pub utilization: Option<f32>,
+    pub memory_used: Option<u64>,
+    pub memory_total: Option<u64>,
+    /// In °C.
+    pub temp: Option<f32>,
+    pub power_watts: Option<f32>,
+    /// The processes using it, on NVIDIA cards; those `process_allow` and
+    /// `process_deny` hide are left out.
+    #[serde(default)]
+    pub processes: Vec<GpuProcessInfo>,
+}
+
+#[cfg(feature = "gpu")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GpuProcessInfo {
+    pub pid: u32,
+    /// `None` where it cannot be told, e.g. for a process in another
+    /// container.
+    pub name: Option<String>,
+    /// `None` where the driver does not account it.
+    pub used_vram: Option<u64>,
+    /// Percent of the streaming multiprocessors' time, `None` where the
+    /// driver cannot tell; the memory is still reported then.
+    pub sm_util: Option<f32>,
+}
+
+/// Every sensor that could be read: the temperatures, including the ones
+/// other streams do not report such as NVMe and chipset sensors, and on
+/// Linux the fans and voltages.
+#[cfg(feature = "sensors")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SensorState {
+    pub sensors: Vec<SensorInfo>,
+}
+
+#[cfg(feature = "sensors")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SensorInfo {
+    pub kind: SensorKind,
+    /// The chip and the sensor, e.g. "nvme Composite".
+    pub label: String,
+    /// In °C, RPM or V, by `kind`.
+    pub value: f32,
+    /// For temperatures, the highest reading since the server started; for
+    /// the others, the highest the chip is set to accept.
+    pub max: Option<f32>,
+    /// Where the hardware starts protecting itself.
+    pub critical: Option<f32>,
+}
+
+/// The load and how long the machine has been up.
+#[cfg(feature = "system")]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SystemState {
+    pub load_average: LoadAverage,
+    pub uptime_secs: u64,
+}
+
+/// The average number of runnable tasks over 1, 5 and 15 minutes.
+#[cfg(feature = "system")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LoadAverage {
+    pub one: f32,
+    pub five: f32,
+    pub fifteen: f32,
+}
+
+/// What a sensor measures, and so its unit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorKind {
+    /// °C.
+    Temperature,
+    /// RPM.
+    Fan,
+    /// V.
+    Voltage,
+}
+
+/// How the CPU state was reported in protocol version 1: 0 for a missing
+/// package temperature.
+#[derive(Serialize, Debug)]
+struct CpuStateV1<'a> {
+    cores: &'a [CpuCore],
+    temp: f32,
+    core_temp: bool,
+}
+
+/// How processes were reported in protocol version 1: no pid, and usage
+/// truncated to whole percent.
+#[derive(Serialize, Debug)]
+struct ProcessInfoV1<'a> {
+    name: &'a str,
+    cpu_usage: i32,
+}
+
+/// How a connection asked for the values of its stream: memory in `mem_unit`
+/// and usages and temperatures rounded to `round` decimals. The default
+/// leaves them as sampled.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Format {
+    pub mem_unit: MemUnit,
+    pub round: Option<u8>,
+}
+
+impl Format {
+    pub fn is_raw(&self) -> bool {
+        *self == Format::default()
+    }
+
+    fn round(&self, value: f32) -> f32 {
+        match self.round {
+            Some(decimals) => {
+                let scale = 10f32.powi(decimals.into());
+                (value * scale).round() / scale
+            }
+            None => value,
+        }
+    }
+}
+
+/// A stream payload. Protocol version 1 clients get it in the shape it had
+/// at that version, which is what `to_v1_json` produces.
+pub trait Payload: Serialize + Clone + PartialEq {
+    fn to_v1_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// Converts the values as `format` asks, for one connection.
+    fn apply_format(&mut self, _format: &Format) {}
+}
+
+impl Payload for CpuState {
+    fn to_v1_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&CpuStateV1 {
+            cores: &self.cores,
+            temp: self.temp.unwrap_or(0.),
+            core_temp: self.core_temp,
+        })
+    }
+
+    fn apply_format(&mut self, format: &Format) {
+        self.temp = self.temp.map(|temp| format.round(temp));
+        self.power_watts = self.power_watts.map(|watts| format.round(watts));
+        for core in &mut self.cores {
+            core.usage = format.round(core.usage);
+            core.temp = core.temp.map(|temp| format.round(temp));
+        }
+    }
+}
+
+#[cfg(feature = "power")]
+impl Payload for PowerState {
+    fn apply_format(&mut self, format: &Format) {
+        self.package_watts = self.package_watts.map(|watts| format.round(watts));
+        for zone in &mut self.zones {
+            zone.watts = zone.watts.map(|watts| format.round(watts));
+        }
+    }
+}
+
+#[cfg(feature = "disks")]
+impl Payload for DiskState {
+    fn apply_format(&mut self, format: &Format) {
+        let unit = format.mem_unit;
+        for disk in &mut self.disks {
+            disk.total = unit.convert(disk.total);
+            disk.used = unit.convert(disk.used);
+            disk.available = unit.convert(disk.available);
+            disk.read_bytes_per_sec = disk.read_bytes_per_sec.map(|rate| unit.convert(rate));
+            disk.write_bytes_per_sec = disk.write_bytes_per_sec.map(|rate| unit.convert(rate));
+        }
+        self.unit = unit;
+    }
+}
+
+#[cfg(feature = "network")]
+impl Payload for NetState {
+    fn apply_format(&mut self, format: &Format) {
+        let unit = format.mem_unit;
+        for interface in &mut self.interfaces {
+            interface.rx_bytes_per_sec = interface.rx_bytes_per_sec.map(|rate| unit.convert(rate));
+            interface.tx_bytes_per_sec = interface.tx_bytes_per_sec.map(|rate| unit.convert(rate));
+        }
+        self.unit = unit;
+    }
+}
+
+#[cfg(feature = "gpu")]
+impl Payload for GpuState {
+    fn apply_format(&mut self, format: &Format) {
+        let unit = format.mem_unit;
+        for gpu in &mut self.gpus {
+            gpu.utilization = gpu.utilization.map(|usage| format.round(usage));
+            gpu.memory_used = gpu.memory_used.map(|used| unit.convert(used));
+            gpu.memory_total = gpu.memory_total.map(|total| unit.convert(total));
+            gpu.temp = gpu.temp.map(|temp| format.round(temp));
+            gpu.power_watts = gpu.power_watts.map(|watts| format.round(watts));
+            for process in &mut gpu.processes {
+                process.used_vram = process.used_vram.map(|used| unit.convert(used));
+                process.sm_util = process.sm_util.map(|util| format.round(util));
+            }
+        }
+        self.unit = unit;
+    }
+}
+
+#[cfg(feature = "sensors")]
+impl Payload for SensorState {
+    fn apply_format(&mut self, format: &Format) {
+        for sensor in &mut self.sensors {
+            sensor.value = format.round(sensor.value);
+            sensor.max = sensor.max.map(|max| format.round(max));
+            sensor.critical = sensor.critical.map(|critical| format.round(critical));
+        }
+    }
+}
+
+#[cfg(feature = "system")]
+impl Payload for SystemState {
+    fn apply_format(&mut self, format: &Format) {
+        let load = &mut self.load_average;
+        load.one = format.round(load.one);
+        load.five = format.round(load.five);
+        load.fifteen = format.round(load.fifteen);
+    }
+}
+
+impl Payload for MemState {
+    fn apply_format(&mut self, format: &Format) {
+        let unit = format.mem_unit;
+        self.total = unit.convert(self.total);
+        self.used = unit.convert(self.used);
+        self.free = unit.convert(self.free);
+        self.available = unit.convert(self.available);
+        self.swap_total = unit.convert(self.swap_total);
+        self.swap_used = unit.convert(self.swap_used);
+        self.unit = unit;
+    }
+}
+
+impl Payload for Vec<ProcessInfo> {
+    fn to_v1_json(&self) -> serde_json::Result<String> {
+        let legacy: Vec<ProcessInfoV1> = self
+            .iter()
+            .map(|process| ProcessInfoV1 {
+                name: &process.name,
+                cpu_usage: process.cpu_usage as i32,
+            })
+            .collect();
+        serde_json::to_string(&legacy)
+    }
+
+    fn apply_format(&mut self, format: &Format) {
+        for process in self {
+            process.cpu_usage = format.round(process.cpu_usage);
+            process.memory = format.mem_unit.convert(process.memory);
+            process.virtual_memory = format.mem_unit.convert(process.virtual_memory);
+        }
+    }
+}
+
+/// The envelope every stream message is wrapped in from protocol version 2
+/// on. `seq` counts up by one per sample on each stream, so a gap means the
+/// client missed samples. Streams that skip unchanged samples can go quiet
+/// without leaving a gap.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sample<T> {
+    pub seq: u64,
+    /// When the sample was taken, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// The remote the sample came from, on streams a hub re-broadcasts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// On the process stream, how many processes a connection asked for with
+    /// `?top=`, after clamping to `max_top_processes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top: Option<usize>,
+    /// How a connection asked for the values, when it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+    /// The instance that took the sample. Hubs pass on their remotes'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<Arc<Instance>>,
+    pub data: T,
+    /// Sent again to a client resuming with `?since_seq=`, rather than live.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replayed: bool,
+}
+
+/// Who is sending, from `instance_name` and `labels`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Instance {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
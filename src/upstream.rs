@@ -0,0 +1,186 @@
+//! Agent mode: pushes every sample to a hub's `/ingest` over an outbound
+//! WebSocket, for instances the hub cannot connect to itself.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use rand::Rng;
+use sysinfo::{System, SystemExt};
+use tokio::{
+    sync::{broadcast, Notify},
+    time::timeout,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+};
+
+use crate::{
+    config::{Stream, UpstreamConfig, WebSocketConfig},
+    hub::{is_host_name, Pushed},
+    ws::{Frame, Protocol},
+};
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Messages waiting for the uplink, the oldest dropped once it is full.
+struct Queue {
+    messages: Mutex<VecDeque<String>>,
+    capacity: usize,
+    /// Dropped since the uplink was last connected.
+    dropped: AtomicU64,
+    ready: Notify,
+}
+
+/// The name we push as and the URL to push to, which carries it as its
+/// `name` parameter. A name in the URL wins over `upstream.name`, which
+/// defaults to the host name.
+pub fn endpoint(config: &UpstreamConfig) -> Result<Option<(String, String)>, String> {
+    let Some(url) = &config.url else {
+        return Ok(None);
+    };
+    let in_url = url
+        .split_once('?')
+        .and_then(|(_, query)| query.split('&').find_map(|pair| pair.strip_prefix("name=")));
+    if let Some(name) = in_url {
+        if !is_host_name(name) {
+            return Err(format!(
+                "upstream name {name:?} may only contain letters, digits, '-', '_' and '.'"
+            ));
+        }
+        return Ok(Some((name.to_string(), url.clone())));
+    }
+
+    let name = match &config.name {
+        Some(name) => name.clone(),
+        None => System::new()
+            .host_name()
+            .filter(|name| is_host_name(name))
+            .ok_or("cannot use the host name as upstream name, set upstream.name")?,
+    };
+    let separator = if url.contains('?') { '&' } else { '?' };
+    Ok(Some((name.clone(), format!("{url}{separator}name={name}"))))
+}
+
+/// Forwards every sample of `streams` to `url` until the process exits,
+/// reconnecting with jittered exponential backoff.
+pub async fn push(
+    url: String,
+    token: Option<String>,
+    capacity: usize,
+    websocket: WebSocketConfig,
+    streams: Vec<(Stream, broadcast::Receiver<Frame>)>,
+) {
+    let queue = Arc::new(Queue {
+        messages: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        dropped: AtomicU64::new(0),
+        ready: Notify::new(),
+    });
+    for (stream, rx) in streams {
+        tokio::spawn(collect(stream, rx, queue.clone()));
+    }
+
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let error = match connect(&url, token.as_deref()).await {
+            Ok(socket) => {
+                backoff = Duration::from_secs(1);
+                let dropped = queue.dropped.swap(0, Ordering::Relaxed);
+                tracing::info!(%url, dropped, "connected to upstream");
+                forward(socket, &queue, websocket).await
+            }
+            Err(err) => err,
+        };
+        // Spreads out the reconnects of agents that lost the same hub.
+        let delay = backoff.mul_f64(rand::thread_rng().gen_range(0.5..1.5));
+        tracing::warn!(
+            %url,
+            %error,
+            retry_in_ms = delay.as_millis() as u64,
+            "upstream unreachable"
+        );
+        tokio::time::sleep(delay).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+type Socket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn connect(url: &str, token: Option<&str>) -> Result<Socket, String> {
+    let mut request = url.into_client_request().map_err(|err| err.to_string())?;
+    if let Some(token) = token {
+        let value = HeaderValue::from_str(&format!("Bearer {token}"))
+            .map_err(|_| "upstream token is not a valid header value".to_string())?;
+        request.headers_mut().insert("authorization", value);
+    }
+    let (socket, _) = connect_async(request)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(socket)
+}
+
+/// Sends queued messages until the uplink fails, returning why. The message
+/// in flight when it fails is lost. Pings the hub while there is nothing to
+/// send, so that it can tell a quiet agent from a dead one.
+async fn forward(mut socket: Socket, queue: &Queue, websocket: WebSocketConfig) -> String {
+    loop {
+        let next = queue.messages.lock().unwrap().pop_front();
+        let message = match next {
+            Some(message) => Message::Text(message),
+            None => tokio::select! {
+                _ = queue.ready.notified() => continue,
+                frame = socket.next() => match frame {
+                    Some(Ok(Message::Close(_))) | None => return "connection closed".into(),
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => return err.to_string(),
+                },
+                _ = tokio::time::sleep(websocket.ping_interval()) => Message::Ping(vec![]),
+            },
+        };
+        match timeout(websocket.send_timeout(), socket.send(message)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return err.to_string(),
+            Err(_) => return "send timed out".into(),
+        }
+    }
+}
+
+/// Queues every sample of one stream.
+async fn collect(stream: Stream, mut rx: broadcast::Receiver<Frame>, queue: Arc<Queue>) {
+    loop {
+        let frame = match rx.recv().await {
+            Ok(frame) => frame,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                queue.dropped.fetch_add(skipped, Ordering::Relaxed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let message = serde_json::from_str(frame.text(Protocol::V2))
+            .and_then(|sample| serde_json::to_string(&Pushed { stream, sample }));
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                tracing::error!(?stream, %err, "cannot encode sample for upstream, skipping it");
+                continue;
+            }
+        };
+        let mut messages = queue.messages.lock().unwrap();
+        if messages.len() == queue.capacity {
+            messages.pop_front();
+            queue.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        messages.push_back(message);
+        drop(messages);
+        queue.ready.notify_one();
+    }
+}
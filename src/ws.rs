@@ -0,0 +1,1300 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    async_trait,
+    extract::{
+        ws::{close_code, rejection::WebSocketUpgradeRejection, CloseFrame, Message, WebSocket},
+        FromRequestParts, WebSocketUpgrade,
+    },
+    http::request::Parts,
+    response::Response,
+};
+use futures::{Future, SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::{self, timeout},
+};
+use tokio_tungstenite::tungstenite::{self, error::ProtocolError};
+
+use crate::{
+    access_log::{AccessLog, SessionRecord},
+    admin::FirstMessageAuth,
+    config::{parse_duration, TopBy, WebSocketConfig},
+    delta::Delta,
+    multiplex::{Event, Subscriptions, Topic},
+    sampler::{self, Broadcast},
+    shutdown::{Session, Shutdown},
+    stats::{ChannelStats, Stats},
+    types::{Format, MemUnit, Payload, Sample},
+};
+
+/// Query parameters accepted by every realtime stream.
+#[derive(Deserialize, Debug, Default)]
+pub struct StreamParams {
+    /// Protocol version, see [`Protocol`].
+    v: Option<u8>,
+    /// Stream a hub remote's samples instead of our own.
+    host: Option<String>,
+    /// Send at most one sample per this long, the latest, e.g. `30s`.
+    interval: Option<String>,
+    /// `interval` in milliseconds, e.g. `2000`.
+    interval_ms: Option<u64>,
+    /// The unit to send memory sizes in, bytes by default.
+    mem_unit: Option<MemUnit>,
+    /// How many decimals to round usages and temperatures to.
+    round: Option<u8>,
+    /// Resume after the sample with this `seq`, sending the retained ones
+    /// that came after it first.
+    since_seq: Option<u64>,
+    /// Tell the client how many samples it skipped when it falls behind,
+    /// with a `{"resync":n}` message.
+    resync: Option<bool>,
+    /// Send binary MessagePack messages instead of JSON text, see
+    /// [`Encoding`].
+    #[serde(rename = "format")]
+    encoding: Option<Encoding>,
+    /// Send only what changed since the last message, ignoring fractional
+    /// values that moved by this much or less, see [`crate::delta`].
+    delta: Option<f64>,
+}
+
+/// [`WebSocketUpgrade`] for the read endpoints: a client that connected
+/// without a token while one is needed is authenticated by its first
+/// message before `on_upgrade`'s callback runs, see [`FirstMessageAuth`].
+pub struct Upgrade {
+    upgrade: WebSocketUpgrade,
+    auth: Option<FirstMessageAuth>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Upgrade {
+    type Rejection = WebSocketUpgradeRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let upgrade = WebSocketUpgrade::from_request_parts(parts, state).await?;
+        let auth = parts.extensions.remove::<FirstMessageAuth>();
+        if let Some(auth) = &auth {
+            auth.claim();
+        }
+        Ok(Self { upgrade, auth })
+    }
+}
+
+impl Upgrade {
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let auth = self.auth;
+        self.upgrade.on_upgrade(move |mut ws| async move {
+            if let Some(auth) = auth {
+                if !auth.authenticate(&mut ws).await {
+                    return;
+                }
+            }
+            callback(ws).await
+        })
+    }
+}
+
+/// Wire format of stream messages.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// The bare payload, in the shape it had in the first release. The
+    /// default.
+    #[default]
+    V1,
+    /// The current payload wrapped in a [`Sample`] envelope.
+    V2,
+}
+
+/// How messages are sent. Samples are encoded as JSON once, by the sampler,
+/// and transcoded to MessagePack once, by the first connection that wants
+/// them so; see [`Frame::message`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Text messages. The default.
+    #[default]
+    Json,
+    /// Binary messages holding the same values, for clients that parse
+    /// JSON slowly or pay for every byte. Floats that are exact as `f32`,
+    /// as every reading is, take 5 bytes rather than 9.
+    Msgpack,
+}
+
+impl Encoding {
+    /// The message carrying `text`, a JSON document, `None` if it cannot be
+    /// transcoded.
+    pub fn message(self, text: String) -> Option<Message> {
+        match self {
+            Encoding::Json => Some(Message::Text(text)),
+            Encoding::Msgpack => to_msgpack(&text)
+                .inspect_err(|err| tracing::error!(%err, "cannot encode message as MessagePack"))
+                .ok()
+                .map(Message::Binary),
+        }
+    }
+}
+
+fn to_msgpack(text: &str) -> Result<Vec<u8>, String> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    rmp_serde::to_vec(&Compact(&value)).map_err(|err| err.to_string())
+}
+
+/// A JSON value as written to MessagePack, with floats narrowed to `f32`
+/// where that loses nothing.
+struct Compact<'a>(&'a serde_json::Value);
+
+impl Serialize for Compact<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde_json::Value;
+
+        match self.0 {
+            Value::Null => serializer.serialize_unit(),
+            Value::Bool(value) => serializer.serialize_bool(*value),
+            Value::Number(number) => {
+                if let Some(value) = number.as_u64() {
+                    serializer.serialize_u64(value)
+                } else if let Some(value) = number.as_i64() {
+                    serializer.serialize_i64(value)
+                } else {
+                    let value = number.as_f64().unwrap_or(f64::NAN);
+                    // Both print the shortest text that reads back the same,
+                    // which is how the sampler wrote the `f32` in the first
+                    // place.
+                    let narrow = value as f32;
+                    match narrow.to_string().parse() == Ok(value) {
+                        true => serializer.serialize_f32(narrow),
+                        false => serializer.serialize_f64(value),
+                    }
+                }
+            }
+            Value::String(text) => serializer.serialize_str(text),
+            Value::Array(items) => serializer.collect_seq(items.iter().map(Compact)),
+            Value::Object(fields) => {
+                serializer.collect_map(fields.iter().map(|(key, value)| (key, Compact(value))))
+            }
+        }
+    }
+}
+
+/// A sample encoded once in every protocol version, so that each client
+/// only has to pick its text instead of serializing the payload again.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    seq: u64,
+    v1: Arc<str>,
+    v2: Arc<str>,
+    /// `v1` and `v2` transcoded to MessagePack, once one connection asked,
+    /// shared by the clones the broadcast hands out. `None` if they could
+    /// not be.
+    v1_msgpack: Arc<OnceLock<Option<Vec<u8>>>>,
+    v2_msgpack: Arc<OnceLock<Option<Vec<u8>>>>,
+}
+
+impl Frame {
+    pub fn encode<T: Payload>(sample: &Sample<&T>) -> serde_json::Result<Self> {
+        Ok(Self {
+            seq: sample.seq,
+            v1: Protocol::V1.encode(sample)?.into(),
+            v2: Protocol::V2.encode(sample)?.into(),
+            v1_msgpack: Arc::default(),
+            v2_msgpack: Arc::default(),
+        })
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// This frame flagged as replayed to a resuming client. Only the v2
+    /// envelope has room for the flag.
+    pub fn replayed(&self) -> Self {
+        let v2 = match self.v2.strip_suffix('}') {
+            Some(rest) => format!("{rest},\"replayed\":true}}").into(),
+            None => self.v2.clone(),
+        };
+        Self {
+            seq: self.seq,
+            v1: self.v1.clone(),
+            v2,
+            v1_msgpack: self.v1_msgpack.clone(),
+            v2_msgpack: Arc::default(),
+        }
+    }
+
+    /// How many encodings `encode` produces.
+    pub const ENCODINGS: u64 = 2;
+
+    pub fn text(&self, protocol: Protocol) -> &str {
+        match protocol {
+            Protocol::V1 => &self.v1,
+            Protocol::V2 => &self.v2,
+        }
+    }
+
+    /// The MessagePack bytes of [`Frame::text`], transcoded by the first
+    /// caller.
+    pub fn msgpack(&self, protocol: Protocol) -> Option<&[u8]> {
+        let cell = match protocol {
+            Protocol::V1 => &self.v1_msgpack,
+            Protocol::V2 => &self.v2_msgpack,
+        };
+        cell.get_or_init(|| {
+            to_msgpack(self.text(protocol))
+                .inspect_err(|err| tracing::error!(%err, "cannot encode message as MessagePack"))
+                .ok()
+        })
+        .as_deref()
+    }
+
+    /// The message carrying this frame to a client.
+    pub fn message(&self, protocol: Protocol, encoding: Encoding) -> Option<Message> {
+        match encoding {
+            Encoding::Json => Some(Message::Text(self.text(protocol).to_owned())),
+            Encoding::Msgpack => Some(Message::Binary(self.msgpack(protocol)?.to_vec())),
+        }
+    }
+}
+
+impl Protocol {
+    /// `sample` as sent in this version.
+    pub fn encode<T: Payload>(self, sample: &Sample<&T>) -> serde_json::Result<String> {
+        match self {
+            Protocol::V1 => sample.data.to_v1_json(),
+            Protocol::V2 => serde_json::to_string(sample),
+        }
+    }
+}
+
+/// The parameters an endpoint supports besides `v` and `resync`, which
+/// every one takes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Caps {
+    /// The interval the stream is sampled at, which `interval` cannot be
+    /// shorter than; `None` where samples cannot be coalesced.
+    pub period: Option<Duration>,
+    /// Whether the stream is relayed from hub remotes.
+    pub host: bool,
+    pub since_seq: bool,
+    /// `mem_unit` and `round`.
+    pub convert: bool,
+    /// `format`, which only WebSocket messages can change.
+    pub encoding: bool,
+    pub delta: bool,
+}
+
+impl StreamParams {
+    /// Checks every parameter at once, refusing those `caps` does not
+    /// list, and gives the options of a session on `endpoint`.
+    pub fn validate(&self, endpoint: &str, caps: Caps) -> Result<SessionOptions, String> {
+        let unsupported = |param: &str| Err(format!("{param} is not supported on {endpoint}"));
+        let format = self.format()?;
+        if self.host.is_some() && !caps.host {
+            return unsupported("host");
+        }
+        if self.has_interval() && caps.period.is_none() {
+            return unsupported("interval");
+        }
+        if self.since_seq.is_some() && !caps.since_seq {
+            return unsupported("since_seq");
+        }
+        if !format.is_raw() && !caps.convert {
+            return unsupported("mem_unit and round");
+        }
+        if self.encoding() != Encoding::Json && !caps.encoding {
+            return unsupported("format");
+        }
+        if self.delta.is_some() && !caps.delta {
+            return unsupported("delta");
+        }
+        Ok(SessionOptions {
+            protocol: self.protocol()?,
+            interval: match caps.period {
+                Some(period) => self.interval(period)?,
+                None => None,
+            },
+            host: self.host.clone(),
+            format,
+            since_seq: self.since_seq()?,
+            resync: self.resync(),
+            encoding: self.encoding(),
+            delta: self.delta()?,
+            ..SessionOptions::default()
+        })
+    }
+
+    fn protocol(&self) -> Result<Protocol, String> {
+        match self.v {
+            None | Some(1) => Ok(Protocol::V1),
+            Some(2) => Ok(Protocol::V2),
+            Some(v) => Err(format!("unsupported protocol version {v}, expected 1 or 2")),
+        }
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// The `interval` or `interval_ms` asked for, which cannot be shorter
+    /// than `period`, the interval the stream is sampled at.
+    fn interval(&self, period: Duration) -> Result<Option<Duration>, String> {
+        match (&self.interval, self.interval_ms) {
+            (Some(_), Some(_)) => Err("give either interval or interval_ms, not both".into()),
+            (Some(text), None) => parse_interval(text, period).map(Some),
+            (None, Some(millis)) => parse_interval(&format!("{millis}ms"), period).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn has_interval(&self) -> bool {
+        self.interval.is_some() || self.interval_ms.is_some()
+    }
+
+    /// The `since_seq` asked for. Only v2 messages carry the `seq` to resume
+    /// from, and hub remotes are not buffered.
+    fn since_seq(&self) -> Result<Option<u64>, String> {
+        if self.since_seq.is_some() {
+            if self.v != Some(2) {
+                return Err("since_seq needs v=2".into());
+            }
+            if self.host.is_some() {
+                return Err("since_seq cannot be combined with host".into());
+            }
+        }
+        Ok(self.since_seq)
+    }
+
+    fn resync(&self) -> bool {
+        self.resync.unwrap_or(false)
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding.unwrap_or_default()
+    }
+
+    /// The epsilon of delta encoding, if asked for.
+    fn delta(&self) -> Result<Option<f64>, String> {
+        match self.delta {
+            Some(epsilon) if !(epsilon >= 0. && epsilon.is_finite()) => Err(format!(
+                "delta must be a number of at least 0, got {epsilon}"
+            )),
+            delta => Ok(delta),
+        }
+    }
+
+    fn format(&self) -> Result<Format, String> {
+        if self.round.is_some_and(|round| round > MAX_ROUND) {
+            return Err(format!("round must be at most {MAX_ROUND}"));
+        }
+        Ok(Format {
+            mem_unit: self.mem_unit.unwrap_or_default(),
+            round: self.round,
+        })
+    }
+}
+
+/// Parses an `interval` parameter, which cannot be shorter than `period`.
+pub fn parse_interval(text: &str, period: Duration) -> Result<Duration, String> {
+    let interval = parse_duration(text).map_err(|err| format!("interval: {err}"))?;
+    if interval < period {
+        return Err(format!(
+            "interval {text} is shorter than the {} ms the stream is sampled at",
+            period.as_millis()
+        ));
+    }
+    Ok(interval)
+}
+
+/// Past this many decimals `f32` values are not any more precise.
+const MAX_ROUND: u8 = 6;
+
+/// `frame` as a connection with a `format` of its own gets it. Frames only
+/// hold encoded text, so its v2 encoding is decoded again.
+pub fn reformat<T: Payload + DeserializeOwned>(
+    frame: &Frame,
+    protocol: Protocol,
+    format: Format,
+) -> Option<String> {
+    let encoded = serde_json::from_str(&frame.v2).and_then(|mut sample: Sample<T>| {
+        sample.data.apply_format(&format);
+        protocol.encode(&Sample {
+            seq: sample.seq,
+            timestamp_ms: sample.timestamp_ms,
+            host: sample.host,
+            top: sample.top,
+            format: Some(format),
+            instance: sample.instance,
+            data: &sample.data,
+            replayed: sample.replayed,
+        })
+    });
+    encoded
+        .inspect_err(|err| tracing::error!(%err, "cannot reformat sample, skipping it"))
+        .ok()
+}
+
+/// What a session negotiated with its query parameters, as recorded in the
+/// access log.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SessionOptions {
+    pub protocol: Protocol,
+    /// Samples arriving sooner than this after the last one sent are held
+    /// back, each replacing the previous one.
+    #[serde(rename = "interval_ms", serialize_with = "serialize_millis")]
+    pub interval: Option<Duration>,
+    pub host: Option<String>,
+    pub format: Format,
+    /// The process name filter, as given.
+    pub filter: Option<String>,
+    pub top: Option<usize>,
+    /// What the process stream picks by, when not `top_processes_by`.
+    pub sort: Option<TopBy>,
+    pub since_seq: Option<u64>,
+    /// Whether skipped samples are announced with a `resync` message.
+    pub resync: bool,
+    /// On `/realtime/all`, the topics subscribed to on connecting.
+    pub topics: Option<Vec<Topic>>,
+    pub encoding: Encoding,
+    /// The epsilon of delta encoding, when the session uses it.
+    pub delta: Option<f64>,
+}
+
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration
+        .map(|duration| duration.as_millis() as u64)
+        .serialize(serializer)
+}
+
+/// A single WebSocket session, used to correlate its connect and
+/// disconnect log events and to write its access log record.
+pub struct Connection {
+    id: u64,
+    endpoint: &'static str,
+    peer: SocketAddr,
+    started: Instant,
+    connected_at: SystemTime,
+    options: SessionOptions,
+    access_log: AccessLog,
+    /// Data messages sent, and their bytes.
+    sent: u64,
+    bytes: u64,
+    /// Times the client fell behind, and samples it skipped because of it.
+    lagged: u64,
+    skipped: u64,
+    /// Messages to send before any from the broadcast: those a resuming
+    /// client missed, and `resync` notices.
+    backlog: VecDeque<String>,
+    session: Session,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    ClientClose,
+    ReceiveError,
+    SendError,
+    SendTimeout,
+    /// Kept lagging behind the broadcast.
+    TooSlow,
+    PingTimeout,
+    Shutdown,
+}
+
+impl Connection {
+    pub fn new(
+        stats: &Stats,
+        access_log: &AccessLog,
+        shutdown: &Arc<Shutdown>,
+        endpoint: &'static str,
+        peer: SocketAddr,
+        options: SessionOptions,
+    ) -> Self {
+        let conn = Self {
+            id: stats.next_connection_id(),
+            endpoint,
+            peer,
+            started: Instant::now(),
+            connected_at: SystemTime::now(),
+            options,
+            access_log: access_log.clone(),
+            sent: 0,
+            bytes: 0,
+            lagged: 0,
+            skipped: 0,
+            backlog: VecDeque::new(),
+            session: shutdown.session(),
+        };
+        tracing::info!(
+            conn = conn.id,
+            endpoint,
+            %peer,
+            protocol = ?conn.options.protocol,
+            interval_ms = conn.options.interval.map(|interval| interval.as_millis() as u64),
+            "client connected"
+        );
+        conn
+    }
+
+    /// Subscribes to `broadcast`. With `since_seq`, the retained messages
+    /// after it are queued to be sent first, flagged as replayed, preceded
+    /// by a `gap` message if some are no longer retained.
+    pub fn subscribe<T: Payload + DeserializeOwned>(
+        &mut self,
+        broadcast: &Broadcast,
+    ) -> broadcast::Receiver<Frame> {
+        let Some(since_seq) = self.options.since_seq else {
+            return broadcast.subscribe();
+        };
+        let (rx, backlog) = broadcast.resume(since_seq);
+        if let Some((from, to)) = backlog.gap {
+            self.backlog
+                .push_back(format!(r#"{{"kind":"gap","from":{from},"to":{to}}}"#));
+        }
+        let protocol = self.options.protocol;
+        for frame in backlog.frames {
+            let frame = frame.replayed();
+            let text = match self.options.format.is_raw() {
+                true => Some(frame.text(protocol).to_owned()),
+                false => reformat::<T>(&frame, protocol, self.options.format),
+            };
+            self.backlog.extend(text);
+        }
+        tracing::debug!(
+            conn = self.id,
+            since_seq,
+            gap = ?backlog.gap,
+            replayed = self.backlog.len(),
+            "client resumed"
+        );
+        rx
+    }
+
+    /// `text` as this connection sends it.
+    fn message(&self, text: String) -> Option<Message> {
+        self.options.encoding.message(text)
+    }
+
+    /// Counts a data message of `len` bytes as sent.
+    pub(crate) fn record_sent(&mut self, len: u64) {
+        self.sent += 1;
+        self.bytes += len;
+    }
+
+    /// Counts falling behind once, skipping `skipped` samples.
+    pub(crate) fn record_lagged(&mut self, skipped: u64) {
+        self.lagged += 1;
+        self.skipped += skipped;
+    }
+
+    /// Resolves once the server starts shutting down.
+    pub(crate) async fn ended(&self) {
+        self.session.ended().await
+    }
+
+    pub(crate) fn close(self, reason: CloseReason) {
+        tracing::info!(
+            conn = self.id,
+            endpoint = self.endpoint,
+            peer = %self.peer,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            sent = self.sent,
+            bytes = self.bytes,
+            lagged = self.lagged,
+            skipped = self.skipped,
+            reason = ?reason,
+            "client disconnected"
+        );
+        self.access_log.record(&SessionRecord {
+            conn: self.id,
+            peer: self.peer,
+            endpoint: self.endpoint,
+            options: &self.options,
+            connected_ms: sampler::unix_millis(self.connected_at),
+            disconnected_ms: sampler::unix_millis(SystemTime::now()),
+            messages: self.sent,
+            bytes: self.bytes,
+            lagged: self.lagged,
+            skipped: self.skipped,
+            reason,
+        });
+    }
+}
+
+/// Tells a client that the server is shutting down, or that the broadcast
+/// it was reading ended, which only happens then as well.
+fn going_away() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: "server shutting down".into(),
+    }))
+}
+
+/// Whether a read failed because the client went away without closing the
+/// connection, as one whose laptop lid is shut does, rather than because it
+/// broke the protocol.
+fn client_gone(err: &axum::Error) -> bool {
+    use tungstenite::Error;
+
+    match std::error::Error::source(err).and_then(|err| err.downcast_ref::<Error>()) {
+        Some(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake))
+        | Some(Error::ConnectionClosed | Error::AlreadyClosed) => true,
+        Some(Error::Io(err)) => matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+/// Forwards a broadcast channel to a WebSocket client, pinging it regularly
+/// and dropping it once it stops answering, blocks our writes or keeps
+/// falling behind.
+pub async fn stream_channel(
+    conn: Connection,
+    rx: broadcast::Receiver<Frame>,
+    stats: &ChannelStats,
+    config: WebSocketConfig,
+    ws: WebSocket,
+) {
+    if conn.options.delta.is_some() {
+        let text = |frame: &Frame, protocol| Some(frame.text(protocol).to_owned());
+        return stream_with(conn, rx, text, stats, config, ws).await;
+    }
+    // Sent as encoded, so that MessagePack is transcoded once per frame
+    // rather than once per connection.
+    let message = |frame: &Frame, conn: &Connection| {
+        frame.message(conn.options.protocol, conn.options.encoding)
+    };
+    stream_messages(conn, rx, message, stats, config, ws).await
+}
+
+/// Like [`stream_channel`], for channels of items that each connection
+/// encodes itself: `encode` gives the text to send for an item, or `None`
+/// to skip it.
+pub async fn stream_with<T: Clone>(
+    conn: Connection,
+    rx: broadcast::Receiver<T>,
+    mut encode: impl FnMut(&T, Protocol) -> Option<String>,
+    stats: &ChannelStats,
+    config: WebSocketConfig,
+    ws: WebSocket,
+) {
+    let mut delta = Delta::new(conn.options.delta, config.delta_keyframe_every);
+    let message = move |item: &T, conn: &Connection| {
+        let text = encode(item, conn.options.protocol)?;
+        conn.message(delta.encode(text)?)
+    };
+    stream_messages(conn, rx, message, stats, config, ws).await
+}
+
+/// What [`stream_channel`] and [`stream_with`] share: `encode` gives the
+/// message to send for an item, or `None` to skip it.
+async fn stream_messages<T: Clone>(
+    mut conn: Connection,
+    mut rx: broadcast::Receiver<T>,
+    mut encode: impl FnMut(&T, &Connection) -> Option<Message>,
+    stats: &ChannelStats,
+    config: WebSocketConfig,
+    ws: WebSocket,
+) {
+    let _client = stats.connect();
+    let (mut sender, mut receiver) = ws.split();
+    let mut ping = time::interval_at(
+        time::Instant::now() + config.ping_interval(),
+        config.ping_interval(),
+    );
+    // Set while a ping is outstanding; any frame from the client clears it.
+    let mut pong_deadline: Option<time::Instant> = None;
+    // Lag events since the client last caught up with the broadcast.
+    let mut lag_streak = 0;
+    // With an interval, when the next sample may be sent, and the latest one
+    // held back until then.
+    let mut next_send = time::Instant::now();
+    let mut pending: Option<T> = None;
+
+    let reason = loop {
+        // A resuming client's backlog goes out first, regardless of its
+        // interval.
+        let outgoing = match conn.backlog.pop_front() {
+            Some(text) => match conn.message(text) {
+                Some(message) => message,
+                None => continue,
+            },
+            None => tokio::select! {
+                msg = rx.recv() => match msg {
+                    Ok(item) => {
+                        if rx.is_empty() {
+                            lag_streak = 0;
+                        }
+                        if let Some(interval) = conn.options.interval {
+                            let now = time::Instant::now();
+                            if now < next_send {
+                                pending = Some(item);
+                                continue;
+                            }
+                            next_send = now + interval;
+                            // Older than this one, it must not follow it.
+                            pending = None;
+                        }
+                        match encode(&item, &conn) {
+                            Some(message) => message,
+                            None => continue,
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        // Slow clients miss samples but stay connected; with v2
+                        // they can tell from the gap in `seq`.
+                        stats.record_dropped(skipped);
+                        conn.record_lagged(skipped);
+                        lag_streak += 1;
+                        tracing::debug!(
+                            conn = conn.id,
+                            endpoint = conn.endpoint,
+                            skipped,
+                            lag_streak,
+                            "client lagged"
+                        );
+                        if config.max_lag_streak != 0 && lag_streak >= config.max_lag_streak {
+                            break CloseReason::TooSlow;
+                        }
+                        if conn.options.resync {
+                            conn.backlog.push_back(format!(r#"{{"resync":{skipped}}}"#));
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break CloseReason::Shutdown,
+                },
+                frame = receiver.next() => match frame {
+                    Some(Ok(Message::Close(_))) | None => break CloseReason::ClientClose,
+                    Some(Ok(Message::Ping(_))) => {
+                        // tungstenite queues the Pong itself; flush so it goes
+                        // out now rather than with the next sample.
+                        pong_deadline = None;
+                        if timeout(config.send_timeout(), sender.flush()).await.is_err() {
+                            break CloseReason::SendTimeout;
+                        }
+                        continue;
+                    }
+                    Some(Ok(_)) => {
+                        pong_deadline = None;
+                        continue;
+                    }
+                    Some(Err(err)) if client_gone(&err) => break CloseReason::ClientClose,
+                    Some(Err(_)) => break CloseReason::ReceiveError,
+                },
+                _ = time::sleep_until(next_send), if pending.is_some() => {
+                    next_send = time::Instant::now() + conn.options.interval.unwrap_or_default();
+                    let Some(item) = pending.take() else { continue };
+                    match encode(&item, &conn) {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                }
+                _ = ping.tick() => {
+                    pong_deadline.get_or_insert(time::Instant::now() + config.pong_timeout());
+                    Message::Ping(Vec::new())
+                }
+                _ = time::sleep_until(pong_deadline.unwrap_or_else(time::Instant::now)),
+                    if pong_deadline.is_some() => break CloseReason::PingTimeout,
+                _ = conn.ended() => break CloseReason::Shutdown,
+            },
+        };
+
+        let data_len = match &outgoing {
+            Message::Text(text) => Some(text.len() as u64),
+            Message::Binary(bytes) => Some(bytes.len() as u64),
+            _ => None,
+        };
+        match timeout(config.send_timeout(), sender.send(outgoing)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                tracing::debug!(
+                    conn = conn.id,
+                    endpoint = conn.endpoint,
+                    peer = %conn.peer,
+                    %err,
+                    "send to client failed"
+                );
+                break CloseReason::SendError;
+            }
+            Err(_) => break CloseReason::SendTimeout,
+        }
+        if let Some(len) = data_len {
+            conn.record_sent(len);
+            stats.record_sent();
+        }
+    };
+    drop(rx);
+
+    match reason {
+        CloseReason::SendError => {}
+        CloseReason::SendTimeout | CloseReason::TooSlow => {
+            stats.record_kicked();
+            // Likely to time out as well on a stalled link, but worth a try
+            // so that the client learns why it was dropped.
+            let close = Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "too slow".into(),
+            }));
+            let _ = timeout(config.send_timeout(), sender.send(close)).await;
+        }
+        CloseReason::Shutdown => {
+            let _ = timeout(config.send_timeout(), sender.send(going_away())).await;
+        }
+        _ => {
+            // Flushes the close handshake, including our reply to a client
+            // Close.
+            let _ = timeout(config.send_timeout(), sender.close()).await;
+        }
+    }
+    conn.close(reason);
+}
+
+/// Like [`stream_with`], for `/realtime/all`: forwards the topics the client
+/// subscribes to, reading its requests to change them.
+pub async fn stream_topics(
+    mut conn: Connection,
+    mut subscriptions: Subscriptions,
+    config: WebSocketConfig,
+    ws: WebSocket,
+) {
+    let (mut sender, mut receiver) = ws.split();
+    let mut ping = time::interval_at(
+        time::Instant::now() + config.ping_interval(),
+        config.ping_interval(),
+    );
+    let mut pong_deadline: Option<time::Instant> = None;
+    let mut lag_streak = 0;
+    conn.backlog.push_back(subscriptions.subscribed());
+
+    let reason = loop {
+        // The topic of a data message, so that it counts as sent on it.
+        let mut sent_on = None;
+        let outgoing = match conn.backlog.pop_front() {
+            Some(text) => match conn.message(text) {
+                Some(message) => message,
+                None => continue,
+            },
+            None => tokio::select! {
+                event = subscriptions.recv() => match event {
+                    Some(Event::Message { topic, message, caught_up }) => {
+                        if caught_up {
+                            lag_streak = 0;
+                        }
+                        sent_on = Some(topic);
+                        message
+                    }
+                    Some(Event::Lagged { topic, skipped }) => {
+                        conn.record_lagged(skipped);
+                        lag_streak += 1;
+                        tracing::debug!(
+                            conn = conn.id,
+                            endpoint = conn.endpoint,
+                            %topic,
+                            skipped,
+                            lag_streak,
+                            "client lagged"
+                        );
+                        if config.max_lag_streak != 0 && lag_streak >= config.max_lag_streak {
+                            break CloseReason::TooSlow;
+                        }
+                        if conn.options.resync {
+                            conn.backlog
+                                .push_back(format!(r#"{{"topic":"{topic}","resync":{skipped}}}"#));
+                        }
+                        continue;
+                    }
+                    None => break CloseReason::Shutdown,
+                },
+                frame = receiver.next() => match frame {
+                    Some(Ok(Message::Close(_))) | None => break CloseReason::ClientClose,
+                    Some(Ok(Message::Ping(_))) => {
+                        pong_deadline = None;
+                        if timeout(config.send_timeout(), sender.flush()).await.is_err() {
+                            break CloseReason::SendTimeout;
+                        }
+                        continue;
+                    }
+                    Some(Ok(Message::Text(text))) => {
+                        pong_deadline = None;
+                        match conn.message(subscriptions.handle(&text)) {
+                            Some(message) => message,
+                            None => continue,
+                        }
+                    }
+                    // A MessagePack client may send its requests that way too.
+                    Some(Ok(Message::Binary(bytes)))
+                        if conn.options.encoding == Encoding::Msgpack =>
+                    {
+                        pong_deadline = None;
+                        let reply = match rmp_serde::from_slice::<serde_json::Value>(&bytes) {
+                            Ok(request) => subscriptions.handle(&request.to_string()),
+                            Err(err) => serde_json::json!({ "error": err.to_string() }).to_string(),
+                        };
+                        match conn.message(reply) {
+                            Some(message) => message,
+                            None => continue,
+                        }
+                    }
+                    Some(Ok(_)) => {
+                        pong_deadline = None;
+                        continue;
+                    }
+                    Some(Err(err)) if client_gone(&err) => break CloseReason::ClientClose,
+                    Some(Err(_)) => break CloseReason::ReceiveError,
+                },
+                _ = ping.tick() => {
+                    pong_deadline.get_or_insert(time::Instant::now() + config.pong_timeout());
+                    Message::Ping(Vec::new())
+                }
+                _ = time::sleep_until(pong_deadline.unwrap_or_else(time::Instant::now)),
+                    if pong_deadline.is_some() => break CloseReason::PingTimeout,
+                _ = conn.ended() => break CloseReason::Shutdown,
+            },
+        };
+
+        let data_len = match &outgoing {
+            Message::Text(text) => Some(text.len() as u64),
+            Message::Binary(bytes) => Some(bytes.len() as u64),
+            _ => None,
+        };
+        match timeout(config.send_timeout(), sender.send(outgoing)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                tracing::debug!(
+                    conn = conn.id,
+                    endpoint = conn.endpoint,
+                    peer = %conn.peer,
+                    %err,
+                    "send to client failed"
+                );
+                break CloseReason::SendError;
+            }
+            Err(_) => break CloseReason::SendTimeout,
+        }
+        if let Some(len) = data_len {
+            conn.record_sent(len);
+        }
+        if let Some(topic) = sent_on {
+            subscriptions.stats(topic).record_sent();
+        }
+    };
+    if matches!(reason, CloseReason::SendTimeout | CloseReason::TooSlow) {
+        subscriptions.record_kicked();
+    }
+    drop(subscriptions);
+
+    match reason {
+        CloseReason::SendError => {}
+        CloseReason::SendTimeout | CloseReason::TooSlow => {
+            let close = Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "too slow".into(),
+            }));
+            let _ = timeout(config.send_timeout(), sender.send(close)).await;
+        }
+        CloseReason::Shutdown => {
+            let _ = timeout(config.send_timeout(), sender.send(going_away())).await;
+        }
+        _ => {
+            let _ = timeout(config.send_timeout(), sender.close()).await;
+        }
+    }
+    conn.close(reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::WebSocketUpgrade, routing::get, Router};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::{
+        tungstenite::{self, protocol::frame::coding::CloseCode},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    use super::*;
+    use crate::config::AccessLogConfig;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Serves `tx` on a local port, the way the realtime routes do, and
+    /// connects a client to it that reads nothing until told to.
+    async fn connect(
+        tx: &broadcast::Sender<String>,
+        options: SessionOptions,
+        config: WebSocketConfig,
+    ) -> Client {
+        connect_with(tx, options, config, Arc::default(), AccessLog::default()).await
+    }
+
+    /// Like [`connect`], counting the session in `stats.ram` and recording
+    /// it to `access_log`.
+    async fn connect_with(
+        tx: &broadcast::Sender<String>,
+        options: SessionOptions,
+        config: WebSocketConfig,
+        stats: Arc<Stats>,
+        access_log: AccessLog,
+    ) -> Client {
+        let sender = tx.clone();
+        let app = Router::new().route(
+            "/",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |ws| async move {
+                    let conn = Connection::new(
+                        &stats,
+                        &access_log,
+                        &Shutdown::new(),
+                        "/test",
+                        ([127, 0, 0, 1], 0).into(),
+                        options,
+                    );
+                    let encode = |text: &String, _| Some(text.clone());
+                    stream_with(conn, sender.subscribe(), encode, &stats.ram, config, ws).await
+                })
+            }),
+        );
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+        let url = format!("ws://{}/", server.local_addr());
+        tokio::spawn(server);
+        let (client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        while tx.receiver_count() == 0 {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        client
+    }
+
+    /// Publishes more than the channel holds without yielding, so that the
+    /// session falls behind as if its client were too slow to keep up.
+    fn overrun(tx: &broadcast::Sender<String>, count: usize) {
+        for index in 0..count {
+            tx.send(index.to_string()).unwrap();
+        }
+    }
+
+    async fn next_text(client: &mut Client) -> String {
+        match client.next().await {
+            Some(Ok(tungstenite::Message::Text(text))) => text,
+            other => panic!("expected a text message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn interval_can_be_given_in_millis() {
+        let period = Duration::from_secs(1);
+        let params = |interval: Option<&str>, interval_ms| StreamParams {
+            interval: interval.map(str::to_string),
+            interval_ms,
+            ..StreamParams::default()
+        };
+        assert_eq!(params(None, None).interval(period), Ok(None));
+        assert_eq!(
+            params(None, Some(2000)).interval(period),
+            Ok(Some(Duration::from_secs(2)))
+        );
+        assert_eq!(
+            params(Some("2s"), None).interval(period),
+            Ok(Some(Duration::from_secs(2)))
+        );
+        assert!(params(None, Some(500)).interval(period).is_err());
+        assert!(params(Some("2s"), Some(2000)).interval(period).is_err());
+    }
+
+    #[tokio::test]
+    async fn lagging_client_is_told_and_keeps_streaming() {
+        let (tx, _) = broadcast::channel(4);
+        let options = SessionOptions {
+            resync: true,
+            ..SessionOptions::default()
+        };
+        let mut client = connect(&tx, options, WebSocketConfig::default()).await;
+
+        overrun(&tx, 20);
+        assert_eq!(next_text(&mut client).await, r#"{"resync":16}"#);
+        for expected in 16..20 {
+            assert_eq!(next_text(&mut client).await, expected.to_string());
+        }
+
+        tx.send("caught up".into()).unwrap();
+        assert_eq!(next_text(&mut client).await, "caught up");
+    }
+
+    #[tokio::test]
+    async fn client_dropping_its_socket_ends_the_session() {
+        let path = std::env::temp_dir().join(format!("axact-ws-drop-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let access_log = AccessLog::open(&AccessLogConfig {
+            enabled: true,
+            path: Some(path.clone()),
+            ..AccessLogConfig::default()
+        })
+        .unwrap();
+        let stats = Arc::new(Stats::new());
+        let (tx, _) = broadcast::channel(4);
+        let options = SessionOptions::default();
+        let config = WebSocketConfig::default();
+        let mut client = connect_with(&tx, options, config, stats.clone(), access_log).await;
+        let clients =
+            || serde_json::to_value(stats.report()).unwrap()["channels"]["ram"]["clients"].clone();
+        assert_eq!(clients(), 1);
+
+        tx.send("first".into()).unwrap();
+        assert_eq!(next_text(&mut client).await, "first");
+        drop(client);
+        // Whichever of reading and writing notices first ends the session.
+        while clients() != 0 {
+            let _ = tx.send("more".into());
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let record: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(
+            ["client_close", "send_error"].contains(&record["reason"].as_str().unwrap()),
+            "{record}"
+        );
+    }
+
+    #[test]
+    fn msgpack_carries_the_json_values_with_narrow_floats() {
+        let text =
+            r#"{"seq":7,"usage":[12.5,3.1],"name":"cpu0","max":null,"ratio":0.1234567890123}"#;
+        let bytes = to_msgpack(text).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded["seq"], 7);
+        assert_eq!(decoded["name"], "cpu0");
+        assert_eq!(decoded["max"], serde_json::Value::Null);
+        assert_eq!(decoded["usage"][1].as_f64().unwrap() as f32, 3.1);
+        assert_eq!(decoded["ratio"], 0.1234567890123);
+        // 0xca marks an f32, 0xcb an f64.
+        assert_eq!(bytes.iter().filter(|byte| **byte == 0xca).count(), 2);
+        assert_eq!(bytes.iter().filter(|byte| **byte == 0xcb).count(), 1);
+    }
+
+    #[test]
+    fn frames_are_transcoded_to_msgpack_once_for_every_clone() {
+        let event = crate::alerts::AlertEvent {
+            rule: "hot".into(),
+            severity: crate::alerts::Severity::Warning,
+            state: crate::alerts::AlertState::Firing,
+            value: 90.5,
+            since: 0,
+        };
+        let frame = Frame::encode(&Sample {
+            seq: 3,
+            timestamp_ms: 0,
+            host: None,
+            top: None,
+            format: None,
+            instance: None,
+            data: &event,
+            replayed: false,
+        })
+        .unwrap();
+        let clone = frame.clone();
+        let bytes = frame.msgpack(Protocol::V2).unwrap();
+        assert_eq!(bytes, to_msgpack(frame.text(Protocol::V2)).unwrap());
+        assert!(std::ptr::eq(bytes, clone.msgpack(Protocol::V2).unwrap()));
+
+        // Only the v2 text changes when replayed.
+        let replayed = frame.replayed();
+        let decoded: serde_json::Value =
+            rmp_serde::from_slice(replayed.msgpack(Protocol::V2).unwrap()).unwrap();
+        assert_eq!(decoded["seq"], 3);
+        assert_eq!(decoded["replayed"], true);
+        assert!(std::ptr::eq(
+            frame.msgpack(Protocol::V1).unwrap(),
+            replayed.msgpack(Protocol::V1).unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn msgpack_sessions_send_binary_messages() {
+        let (tx, _) = broadcast::channel(4);
+        let options = SessionOptions {
+            encoding: Encoding::Msgpack,
+            ..SessionOptions::default()
+        };
+        let mut client = connect(&tx, options, WebSocketConfig::default()).await;
+
+        tx.send(r#"{"usage":50.5}"#.into()).unwrap();
+        match client.next().await {
+            Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+                assert_eq!(decoded, serde_json::json!({ "usage": 50.5 }));
+            }
+            other => panic!("expected a binary message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_client_never_gets_an_older_sample_after_a_newer_one() {
+        let (tx, _) = broadcast::channel(16);
+        let options = SessionOptions {
+            interval: Some(Duration::from_secs(1)),
+            ..SessionOptions::default()
+        };
+        let config = WebSocketConfig {
+            ping_interval_ms: 3_600_000,
+            ..WebSocketConfig::default()
+        };
+        let mut client = connect(&tx, options, config).await;
+
+        let mut seq = 0;
+        for _ in 0..20 {
+            // Sent at once, the next one held back, and a newer one
+            // published right as the interval is up.
+            for wait in [100, 900, 2000] {
+                seq += 1;
+                tx.send(seq.to_string()).unwrap();
+                time::sleep(Duration::from_millis(wait)).await;
+            }
+        }
+
+        let mut last = 0;
+        while let Ok(Some(Ok(tungstenite::Message::Text(text)))) =
+            time::timeout(Duration::from_secs(5), client.next()).await
+        {
+            let seq: u64 = text.parse().unwrap();
+            assert!(seq > last, "got {seq} after {last}");
+            last = seq;
+        }
+        assert_eq!(last, 60);
+    }
+
+    #[tokio::test]
+    async fn client_that_keeps_lagging_is_closed() {
+        let (tx, _) = broadcast::channel(4);
+        let config = WebSocketConfig {
+            max_lag_streak: 1,
+            ..WebSocketConfig::default()
+        };
+        let mut client = connect(&tx, SessionOptions::default(), config).await;
+
+        overrun(&tx, 20);
+        match client.next().await {
+            Some(Ok(tungstenite::Message::Close(Some(frame)))) => {
+                assert_eq!(frame.code, CloseCode::Policy);
+                assert_eq!(frame.reason, "too slow");
+            }
+            other => panic!("expected a close frame, got {other:?}"),
+        }
+    }
+}